@@ -0,0 +1,74 @@
+//! Exact search latency of `vector::Index` at 100k vectors of 1536 dimensions, the size of
+//! OpenAi's small embeddings. Run with `cargo bench --bench vector_index`
+use espionox::vector::{Index, Metric};
+use std::time::{Duration, Instant};
+
+const ENTRIES: usize = 100_000;
+const DIMS: usize = 1536;
+const K: usize = 10;
+const QUERIES: usize = 32;
+const BATCH: usize = 16;
+
+/// Xorshift, so the benchmark needs no random number crate
+struct Rng(u64);
+
+impl Rng {
+    fn vector(&mut self) -> Vec<f32> {
+        (0..DIMS)
+            .map(|_| {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                (self.0 % 2000) as f32 / 1000.0 - 1.0
+            })
+            .collect()
+    }
+}
+
+fn per_query(elapsed: Duration, queries: usize) -> Duration {
+    elapsed / queries as u32
+}
+
+fn main() {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    let mut index = Index::new(DIMS, Metric::Cosine);
+    let start = Instant::now();
+    for i in 0..ENTRIES {
+        index.insert(&i.to_string(), rng.vector(), i % 10).unwrap();
+    }
+    println!("insert {} x {}: {:?}", ENTRIES, DIMS, start.elapsed());
+
+    let queries: Vec<Vec<f32>> = (0..QUERIES).map(|_| rng.vector()).collect();
+    let start = Instant::now();
+    for query in &queries {
+        index.search(query, K).unwrap();
+    }
+    println!(
+        "search, top {}: {:?} per query",
+        K,
+        per_query(start.elapsed(), QUERIES)
+    );
+
+    let start = Instant::now();
+    for query in &queries {
+        index
+            .search_filtered(query, K, |shard| *shard == 3)
+            .unwrap();
+    }
+    println!(
+        "search filtered to a tenth, top {}: {:?} per query",
+        K,
+        per_query(start.elapsed(), QUERIES)
+    );
+
+    let start = Instant::now();
+    for batch in queries.chunks(BATCH) {
+        index.search_batch(batch, K, |_| true).unwrap();
+    }
+    println!(
+        "search in batches of {}, top {}: {:?} per query",
+        BATCH,
+        K,
+        per_query(start.elapsed(), QUERIES)
+    );
+}
@@ -120,7 +120,7 @@ impl<'p> ToMessage for DbStruct<'p> {
                 p.name, p.description
             ));
         });
-        Message { role, content }
+        content.to_message(role)
     }
 }
 
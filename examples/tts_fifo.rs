@@ -0,0 +1,62 @@
+use espionox::{
+    language_models::completions::streaming::{self, TextSink},
+    prelude::*,
+};
+use std::{fs::File, io::Write};
+
+/// Writes streamed text to a named pipe, for a TTS process to read from the other end. Create
+/// the pipe & start the reader first, for example:
+/// `mkfifo /tmp/espionox_tts && espeak < /tmp/espionox_tts`
+pub struct FifoSink {
+    fifo: File,
+}
+
+impl FifoSink {
+    /// Blocks until a reader opens the other end of the pipe
+    fn open(path: &str) -> std::io::Result<Self> {
+        let fifo = std::fs::OpenOptions::new().write(true).open(path)?;
+        Ok(Self { fifo })
+    }
+}
+
+impl TextSink for FifoSink {
+    fn token(&mut self, token: &str) {
+        if let Err(err) = self
+            .fifo
+            .write_all(token.as_bytes())
+            .and_then(|_| self.fifo.flush())
+        {
+            eprintln!("Could not write token to fifo: {:?}", err);
+        }
+    }
+
+    fn finished(&mut self, _message: &str) {
+        let _ = self.fifo.write_all(b"\n");
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    let api_key = std::env::var("ANTHROPIC_KEY").unwrap();
+    let path = std::env::var("TTS_FIFO").unwrap_or("/tmp/espionox_tts".to_string());
+    let mut agent = Agent::new(
+        Some("You are a helpful assistant, keep answers to a sentence or two"),
+        CompletionModel::default_anthropic(&api_key),
+    );
+    agent
+        .cache
+        .push(Message::new_user("What does a terminal multiplexer do?"));
+
+    let sink = FifoSink::open(&path).unwrap();
+    let mut handler = agent.stream_completion().await.unwrap().with_sink(sink);
+
+    // The sink coexists with normal consumption of the stream
+    while let Ok(Some(status)) = handler.receive(&mut agent).await {
+        match status {
+            streaming::CompletionStreamStatus::Working(token) => print!("{}", token),
+            _ => break,
+        }
+    }
+    println!();
+}
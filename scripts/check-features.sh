@@ -0,0 +1,33 @@
+#!/usr/bin/env bash
+# Builds the library & its tests with every combination of the subsystem features, starting
+# from --no-default-features, so a feature that only compiles alongside another is caught
+set -euo pipefail
+cd "$(dirname "$0")/.."
+
+features=(openai anthropic tmux http-sse blocking)
+for ((mask = 0; mask < 1 << ${#features[@]}; mask++)); do
+    enabled=()
+    for i in "${!features[@]}"; do
+        if ((mask & 1 << i)); then
+            enabled+=("${features[$i]}")
+        fi
+    done
+    list=$(IFS=,; echo "${enabled[*]}")
+    echo "==> --no-default-features --features '${list}'"
+    cargo check --quiet --no-default-features --features "${list}" --lib
+    cargo test --quiet --no-default-features --features "${list}" --no-run
+done
+
+# Development features, checked on top of the defaults
+echo "==> --features 'strict-streams'"
+cargo test --quiet --features strict-streams --no-run
+echo "==> --features 'ws-relay'"
+cargo test --quiet --features ws-relay --no-run
+echo "==> --features 'tui'"
+cargo test --quiet --features tui --no-run
+echo "==> --features 'prometheus'"
+cargo test --quiet --features prometheus --no-run
+echo "==> --features 'ipc'"
+cargo test --quiet --features ipc --no-run
+echo "==> --no-default-features --features 'prometheus'"
+cargo check --quiet --no-default-features --features prometheus --lib
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A format every response of a session should follow, such as "Respond with at most 3
+/// bullets". The instruction is appended to the system prompt of every request, and when
+/// limits are set `Agent::io_completion` regenerates a response that breaks them once, with a
+/// reminder of the contract appended
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputContract {
+    pub instruction: String,
+    /// Most non blank lines a response may have
+    #[serde(default)]
+    pub max_lines: Option<usize>,
+    /// Most characters a response may have, ignoring surrounding whitespace
+    #[serde(default)]
+    pub max_chars: Option<usize>,
+}
+
+/// How a response broke its agent's `OutputContract`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContractViolation {
+    TooManyLines { lines: usize, max: usize },
+    TooLong { chars: usize, max: usize },
+}
+
+impl fmt::Display for ContractViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyLines { lines, max } => {
+                write!(f, "it had {} lines, the most allowed is {}", lines, max)
+            }
+            Self::TooLong { chars, max } => {
+                write!(
+                    f,
+                    "it had {} characters, the most allowed is {}",
+                    chars, max
+                )
+            }
+        }
+    }
+}
+
+impl OutputContract {
+    /// A contract that is only an instruction, responses aren't validated until limits are set
+    pub fn new(instruction: &str) -> Self {
+        Self {
+            instruction: instruction.to_owned(),
+            max_lines: None,
+            max_chars: None,
+        }
+    }
+
+    pub fn with_max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = Some(max_lines);
+        self
+    }
+
+    pub fn with_max_chars(mut self, max_chars: usize) -> Self {
+        self.max_chars = Some(max_chars);
+        self
+    }
+
+    /// How `response` breaks the contract's limits, if it does
+    pub fn check(&self, response: &str) -> Option<ContractViolation> {
+        let lines = response.lines().filter(|l| !l.trim().is_empty()).count();
+        if let Some(max) = self.max_lines.filter(|max| lines > *max) {
+            return Some(ContractViolation::TooManyLines { lines, max });
+        }
+        let chars = response.trim().chars().count();
+        if let Some(max) = self.max_chars.filter(|max| chars > *max) {
+            return Some(ContractViolation::TooLong { chars, max });
+        }
+        None
+    }
+
+    /// The user message asking for a response that broke the contract to be written again
+    pub(crate) fn reminder(&self, violation: ContractViolation) -> String {
+        format!(
+            "Your last response did not follow the required output format, {}. Respond again \
+             following this format: {}",
+            violation, self.instruction
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_checked_against_limits() {
+        let contract = OutputContract::new("Respond as 3 bullets max")
+            .with_max_lines(3)
+            .with_max_chars(40);
+        assert_eq!(contract.check("- build ok\n\n- 2 warnings\n"), None);
+        assert_eq!(
+            contract.check("- a\n- b\n- c\n- d"),
+            Some(ContractViolation::TooManyLines { lines: 4, max: 3 })
+        );
+        let long = "- the build finished with two warnings in the parser";
+        assert_eq!(
+            contract.check(long),
+            Some(ContractViolation::TooLong { chars: 52, max: 40 })
+        );
+        assert!(contract
+            .reminder(ContractViolation::TooManyLines { lines: 4, max: 3 })
+            .ends_with("it had 4 lines, the most allowed is 3. Respond again following this format: Respond as 3 bullets max"));
+        assert_eq!(OutputContract::new("terse").check(long), None);
+    }
+}
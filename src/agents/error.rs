@@ -1,4 +1,7 @@
-use crate::{errors::error_chain_fmt, language_models::completions::error::CompletionError};
+use crate::{
+    errors::error_chain_fmt,
+    language_models::completions::{error::CompletionError, streaming::StreamError},
+};
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 
 pub type AgentResult<T> = Result<T, AgentError>;
@@ -7,6 +10,9 @@ pub enum AgentError {
     #[error(transparent)]
     Undefined(#[from] anyhow::Error),
     CompletionError(#[from] CompletionError),
+    /// Receiving a streamed completion failed
+    Stream(#[from] StreamError),
+    LastMessageNotAssistant,
 }
 
 impl Debug for AgentError {
@@ -20,6 +26,49 @@ impl Display for AgentError {
         let display = match self {
             Self::Undefined(err) => err.to_string(),
             Self::CompletionError(err) => err.to_string(),
+            Self::Stream(err) => err.to_string(),
+            Self::LastMessageNotAssistant => "Last message is not an assistant message".to_string(),
+        };
+        write!(f, "{}", display)
+    }
+}
+
+#[cfg(feature = "typed")]
+#[derive(thiserror::Error)]
+pub enum TypedPromptError {
+    #[error(transparent)]
+    Agent(#[from] AgentError),
+    /// The JSON schema of the requested type could not be generated
+    Schema(serde_json::Error),
+    /// The model replied without any JSON
+    Refusal(String),
+    /// The reply still failed to parse after all repair attempts
+    RepairsExhausted {
+        attempts: usize,
+        error: serde_json::Error,
+    },
+}
+
+#[cfg(feature = "typed")]
+impl Debug for TypedPromptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        error_chain_fmt(self, f)
+    }
+}
+
+#[cfg(feature = "typed")]
+impl Display for TypedPromptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let display = match self {
+            Self::Agent(err) => err.to_string(),
+            Self::Schema(err) => format!("Could not generate schema: {}", err),
+            Self::Refusal(reply) => format!("Model refused to reply with JSON: {}", reply),
+            Self::RepairsExhausted { attempts, error } => {
+                format!(
+                    "Reply failed to parse after {} attempts: {}",
+                    attempts, error
+                )
+            }
         };
         write!(f, "{}", display)
     }
@@ -8,6 +8,11 @@ use tracing::warn;
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
 pub struct MessageStack(pub(crate) Vec<Message>);
 
+/// Rough number of characters per token, used for estimating token counts without a tokenizer
+pub(crate) const CHARS_PER_TOKEN: usize = 4;
+/// Tokens used by the formatting of each message, regardless of content
+const TOKENS_PER_MESSAGE: usize = 4;
+
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct MessageStackRef<'stack>(pub(crate) Vec<&'stack Message>);
 
@@ -161,6 +166,19 @@ impl<'stack> MessageStack {
         }
     }
 
+    /// Replace the content of the message at `index` in place, does nothing if there is no
+    /// message at `index` or the content is empty
+    pub fn update_content(&mut self, index: usize, content: &str) {
+        if content.is_empty() {
+            warn!("cannot update message with empty content");
+            return;
+        }
+        match self.0.get_mut(index) {
+            Some(message) => message.content = content.to_owned(),
+            None => warn!("no message at index {} to update", index),
+        }
+    }
+
     /// Append another MessageStack to the end of this one
     pub fn append(&mut self, mut messages: Self) {
         self.as_mut().append(messages.as_mut());
@@ -183,6 +201,43 @@ impl<'stack> MessageStack {
         self.0.len()
     }
 
+    /// Estimate of how many tokens the stack takes up in a request. This is a heuristic based on
+    /// character count, not an exact count from a model's tokenizer
+    pub fn estimated_token_count(&self) -> u32 {
+        self.0
+            .iter()
+            .map(|m| {
+                m.request_content()
+                    .chars()
+                    .count()
+                    .div_ceil(CHARS_PER_TOKEN)
+                    + TOKENS_PER_MESSAGE
+            })
+            .sum::<usize>() as u32
+    }
+
+    /// Evicts messages until at most `max_len` remain. The system prompt is never evicted, of
+    /// the rest the lowest importance messages go first, oldest first among equals
+    pub fn trim_to(&mut self, max_len: usize) {
+        while self.len() > max_len {
+            let lowest = self
+                .0
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| m.role.actual() != &MessageRole::System)
+                .fold(None, |lowest: Option<(usize, f32)>, (i, m)| match lowest {
+                    Some((_, imp)) if imp <= m.importance => lowest,
+                    _ => Some((i, m.importance)),
+                });
+            match lowest {
+                Some((i, _)) => {
+                    self.0.remove(i);
+                }
+                None => return,
+            }
+        }
+    }
+
     /// Mutates message vector in place. Excludes/Explicitly includes given message role
     pub fn mut_filter_by(&mut self, role: &MessageRole, inclusive: bool) {
         match inclusive {
@@ -254,6 +309,24 @@ impl<'stack> MessageStackRef<'stack> {
 mod tests {
     use super::{Message, MessageStack};
 
+    #[test]
+    fn trim_evicts_lowest_importance_first() {
+        let mut stack = MessageStack::new("System");
+        stack.push(Message::new_user("routine status").with_importance(0.1));
+        stack.push(Message::new_user("error[E0308]").with_importance(0.9));
+        stack.push(Message::new_user("older default"));
+        stack.push(Message::new_user("newer default"));
+        stack.push(Message::new_user("more noise").with_importance(0.1));
+
+        stack.trim_to(3);
+        let contents: Vec<&str> = stack.as_ref().iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["System", "error[E0308]", "newer default"]);
+
+        stack.trim_to(0);
+        assert_eq!(stack.ref_system_prompt_content(), Some("System"));
+        assert_eq!(stack.len(), 1);
+    }
+
     #[test]
     fn message_from_correct() {
         let messages = vec![
@@ -2,21 +2,36 @@ use super::MessageStack;
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::fmt;
+use std::{borrow::Cow, collections::HashMap, fmt, path::Path};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message {
     pub role: MessageRole,
     pub content: String,
+    /// How worth keeping this message is when the stack is trimmed, lowest importance messages
+    /// are evicted first. Defaults to `Message::DEFAULT_IMPORTANCE`
+    #[serde(default = "Message::default_importance")]
+    pub importance: f32,
+    /// Arbitrary key value pairs describing where a message came from. Never sent to providers
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    /// Files & snippets the message refers to, formatted after its content in requests
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
 }
 
 impl PartialEq for Message {
     fn eq(&self, other: &Self) -> bool {
-        self.role == other.role && self.content == other.content
+        self.role == other.role
+            && self.content == other.content
+            && self.attachments == other.attachments
     }
 }
 impl Eq for Message {}
 
+/// Between a message's content & each of its attachments in requests
+const ATTACHMENT_SEPARATOR: &str = "\n\n";
+
 pub trait ToMessage: std::fmt::Debug + Send + Sync {
     fn to_message(&self, role: MessageRole) -> Message;
 }
@@ -30,10 +45,89 @@ impl ToMessage for String {
         Message {
             role,
             content: self.to_owned(),
+            importance: Message::DEFAULT_IMPORTANCE,
+            metadata: HashMap::new(),
+            attachments: vec![],
         }
     }
 }
 
+/// A file path or code snippet attached to a message, such as the source of a failing test,
+/// so the model can reason about more than the text of the message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Attachment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    /// The snippet's language, for the info string of its code block. Defaults to the path's
+    /// extension
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+impl Attachment {
+    /// A reference to a file, without its content
+    pub fn path(path: &str) -> Self {
+        Self {
+            path: Some(path.to_owned()),
+            snippet: None,
+            language: None,
+        }
+    }
+
+    pub fn snippet(snippet: &str) -> Self {
+        Self {
+            path: None,
+            snippet: Some(snippet.to_owned()),
+            language: None,
+        }
+    }
+
+    /// Sets the file the snippet is from
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn with_snippet(mut self, snippet: &str) -> Self {
+        self.snippet = Some(snippet.to_owned());
+        self
+    }
+
+    pub fn with_language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_owned());
+        self
+    }
+
+    /// The attachment as it is sent to models, its path followed by its snippet in a fenced
+    /// code block
+    pub fn formatted(&self) -> String {
+        let mut formatted = match &self.path {
+            Some(path) if self.snippet.is_some() => format!("`{}`:\n", path),
+            Some(path) => format!("Attached file: `{}`", path),
+            None => String::new(),
+        };
+        if let Some(snippet) = &self.snippet {
+            let language = self.language.as_deref().or_else(|| {
+                let path = Path::new(self.path.as_deref()?);
+                path.extension()?.to_str()
+            });
+            // Longer than any run of backticks in the snippet, so it can't end the block early
+            let longest_run = snippet.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+            let fence = "`".repeat(longest_run.max(2) + 1);
+            formatted.push_str(&format!(
+                "{}{}\n{}\n{}",
+                fence,
+                language.unwrap_or(""),
+                snippet.trim_end_matches('\n'),
+                fence
+            ));
+        }
+        formatted
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum OtherRoleTo {
     Assistant,
@@ -102,6 +196,46 @@ impl MessageRole {
 }
 
 impl Message {
+    pub const DEFAULT_IMPORTANCE: f32 = 0.5;
+
+    fn default_importance() -> f32 {
+        Self::DEFAULT_IMPORTANCE
+    }
+
+    /// Add a metadata entry
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn with_attachment(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    /// The content followed by each attachment, as it is sent to models
+    pub fn request_content(&self) -> Cow<'_, str> {
+        if self.attachments.is_empty() {
+            return Cow::Borrowed(&self.content);
+        }
+        let mut content = self.content.to_owned();
+        self.push_attachments(&mut content);
+        Cow::Owned(content)
+    }
+
+    fn push_attachments(&self, content: &mut String) {
+        for attachment in &self.attachments {
+            content.push_str(ATTACHMENT_SEPARATOR);
+            content.push_str(&attachment.formatted());
+        }
+    }
+
+    /// Set how important this message is to keep when trimming
+    pub fn with_importance(mut self, importance: f32) -> Self {
+        self.importance = importance;
+        self
+    }
+
     pub fn new_other(alias: &str, content: &str, coerce_to: OtherRoleTo) -> Self {
         Message {
             role: MessageRole::Other {
@@ -109,6 +243,9 @@ impl Message {
                 coerce_to,
             },
             content: content.to_string(),
+            importance: Message::DEFAULT_IMPORTANCE,
+            metadata: HashMap::new(),
+            attachments: vec![],
         }
     }
 
@@ -116,6 +253,9 @@ impl Message {
         Message {
             role: MessageRole::System,
             content: content.to_string(),
+            importance: Message::DEFAULT_IMPORTANCE,
+            metadata: HashMap::new(),
+            attachments: vec![],
         }
     }
 
@@ -123,6 +263,9 @@ impl Message {
         Message {
             role: MessageRole::User,
             content: content.to_string(),
+            importance: Message::DEFAULT_IMPORTANCE,
+            metadata: HashMap::new(),
+            attachments: vec![],
         }
     }
 
@@ -130,6 +273,9 @@ impl Message {
         Message {
             role: MessageRole::Assistant,
             content: content.to_string(),
+            importance: Message::DEFAULT_IMPORTANCE,
+            metadata: HashMap::new(),
+            attachments: vec![],
         }
     }
 }
@@ -166,18 +312,26 @@ impl TryFrom<Value> for Message {
             .get("content")
             .expect("Couldn't get content")
             .to_string();
-        Ok(Message { role, content })
+        Ok(Message {
+            role,
+            content,
+            importance: Message::DEFAULT_IMPORTANCE,
+            metadata: HashMap::new(),
+            attachments: vec![],
+        })
     }
 }
 
 impl Into<Value> for Message {
     fn into(self) -> Value {
-        let content = self
+        let mut content = self
             .content
             .split_whitespace()
             .collect::<Vec<&str>>()
             .join(" ")
             .replace('\n', " ");
+        // Attachments keep their line breaks, code blocks need them
+        self.push_attachments(&mut content);
         json!({"role": self.role.actual().to_string(), "content": content})
     }
 }
@@ -192,3 +346,32 @@ impl fmt::Display for Message {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachments_are_formatted_into_requests() {
+        let snippet = "fn parses() {\n    assert_eq!(parse(\"```\"), None);\n}\n";
+        let message = Message::new_user("Why does   this test fail?")
+            .with_attachment(Attachment::snippet(snippet).with_path("src/parser.rs"))
+            .with_attachment(Attachment::path("Cargo.toml"))
+            .with_attachment(Attachment::snippet("cargo test").with_language("sh"));
+        let expected = "Why does this test fail?\n\n\
+            `src/parser.rs`:\n````rs\nfn parses() {\n    assert_eq!(parse(\"```\"), None);\n}\n````\n\n\
+            Attached file: `Cargo.toml`\n\n\
+            ```sh\ncargo test\n```";
+        let value: Value = message.clone().into();
+        assert_eq!(value["content"], expected);
+        assert!(message
+            .request_content()
+            .starts_with("Why does   this test fail?\n\n`src/parser.rs`"));
+
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["attachments"][1], json!({"path": "Cargo.toml"}));
+        let plain = serde_json::to_value(Message::new_user("hi")).unwrap();
+        assert!(plain.get("attachments").is_none());
+        assert_eq!(serde_json::from_value::<Message>(json).unwrap(), message);
+    }
+}
@@ -1,4 +1,6 @@
 mod message_stack;
 pub mod messages;
+#[cfg(feature = "tmux")]
+pub(crate) use message_stack::CHARS_PER_TOKEN;
 pub use message_stack::{MessageStack, MessageStackRef};
 pub use messages::*;
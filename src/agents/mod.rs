@@ -1,11 +1,16 @@
+mod contract;
 pub mod error;
 pub mod memory;
+#[cfg(feature = "typed")]
+mod typed;
 use crate::language_models::completions::{
     functions::Function, streaming::ProviderStreamHandler, CompletionModel,
 };
+pub use contract::{ContractViolation, OutputContract};
 pub use error::AgentError;
-use memory::MessageStack;
-use std::fmt::Debug;
+use memory::{Message, MessageRole, MessageStack};
+use std::{borrow::Cow, fmt::Debug};
+use tracing::warn;
 
 use error::AgentResult;
 
@@ -13,6 +18,95 @@ use error::AgentResult;
 pub struct Agent {
     pub cache: MessageStack,
     pub completion_model: CompletionModel,
+    /// Few-shot (user, assistant) example pairs. These are inserted right after the system
+    /// prompt of every request, but are never part of the cache
+    #[serde(default)]
+    pub examples: Vec<(Message, Message)>,
+    /// Ordered instruction layers appended to the cache's system prompt at request time. Each
+    /// layer can be changed independently, the cache's system prompt is never modified
+    #[serde(default)]
+    pub system_prompt_layers: Vec<String>,
+    /// Join consecutive messages with the same role into one at request time, for models that
+    /// expect roles to alternate. The cache keeps every message
+    #[serde(default)]
+    pub coalesce_consecutive_roles: bool,
+    /// Format every response should follow, its instruction is appended to the system prompt
+    /// after any layers
+    #[serde(default)]
+    pub output_contract: Option<OutputContract>,
+}
+
+/// Separates the cache's system prompt & each system prompt layer in the effective system prompt
+pub const SYSTEM_PROMPT_LAYER_SEPARATOR: &str = "\n\n";
+
+/// Separates the content of coalesced messages
+pub const COALESCED_MESSAGE_SEPARATOR: &str = "\n";
+
+/// Whether any two adjacent messages would be sent with the same role
+fn has_consecutive_roles(messages: &[Message]) -> bool {
+    messages
+        .windows(2)
+        .any(|w| w[0].role.actual() == w[1].role.actual())
+}
+
+/// Joins runs of messages that would be sent with the same role into the first message of the
+/// run
+fn coalesce_consecutive_roles(messages: Vec<Message>) -> Vec<Message> {
+    let mut coalesced: Vec<Message> = Vec::with_capacity(messages.len());
+    for message in messages {
+        match coalesced.last_mut() {
+            Some(last) if last.role.actual() == message.role.actual() => {
+                last.content.push_str(COALESCED_MESSAGE_SEPARATOR);
+                last.content.push_str(&message.content);
+                last.attachments.extend(message.attachments);
+            }
+            _ => coalesced.push(message),
+        }
+    }
+    coalesced
+}
+
+/// Returns the stack sent with requests. The cache's system prompt is joined with any
+/// `system_layers`, examples are inserted right after the system prompt, and consecutive
+/// messages with the same role are joined if `coalesce_roles` is set
+pub(crate) fn build_request_stack<'c>(
+    cache: &'c MessageStack,
+    examples: &[(Message, Message)],
+    system_layers: &[String],
+    coalesce_roles: bool,
+) -> Cow<'c, MessageStack> {
+    let layers: Vec<&str> = system_layers
+        .iter()
+        .map(|l| l.as_str())
+        .filter(|l| !l.is_empty())
+        .collect();
+    let coalesce = coalesce_roles && has_consecutive_roles(cache.as_ref());
+    if examples.is_empty() && layers.is_empty() && !coalesce {
+        return Cow::Borrowed(cache);
+    }
+    let mut messages = cache.as_ref().to_owned();
+    if !layers.is_empty() {
+        let layered = layers.join(SYSTEM_PROMPT_LAYER_SEPARATOR);
+        match cache.ref_system_prompt_content() {
+            Some(base) => {
+                messages[0].content =
+                    format!("{}{}{}", base, SYSTEM_PROMPT_LAYER_SEPARATOR, layered)
+            }
+            None => messages.insert(0, Message::new_system(&layered)),
+        }
+    }
+    let idx = match layers.is_empty() && cache.ref_system_prompt_content().is_none() {
+        true => 0,
+        false => 1,
+    };
+    let flattened = examples
+        .iter()
+        .flat_map(|(user, assistant)| [user.clone(), assistant.clone()]);
+    messages.splice(idx..idx, flattened);
+    if coalesce_roles {
+        messages = coalesce_consecutive_roles(messages);
+    }
+    Cow::Owned(MessageStack(messages))
 }
 
 impl Agent {
@@ -25,32 +119,525 @@ impl Agent {
         Agent {
             cache,
             completion_model,
+            examples: vec![],
+            system_prompt_layers: vec![],
+            coalesce_consecutive_roles: false,
+            output_contract: None,
+        }
+    }
+
+    /// Set few-shot (user, assistant) example pairs
+    pub fn with_examples(mut self, examples: Vec<(Message, Message)>) -> Self {
+        self.examples = examples;
+        self
+    }
+
+    /// Add a system prompt layer after any existing ones
+    pub fn with_system_prompt_layer(mut self, layer: &str) -> Self {
+        self.system_prompt_layers.push(layer.to_owned());
+        self
+    }
+
+    /// Join consecutive messages with the same role in requests
+    pub fn with_coalesced_roles(mut self) -> Self {
+        self.coalesce_consecutive_roles = true;
+        self
+    }
+
+    /// Hold every response to `contract`
+    pub fn with_output_contract(mut self, contract: OutputContract) -> Self {
+        self.output_contract = Some(contract);
+        self
+    }
+
+    /// System prompt layers followed by the output contract's instruction, if there is one
+    pub(crate) fn system_layers(&self) -> Cow<'_, [String]> {
+        match &self.output_contract {
+            Some(contract) => {
+                let mut layers = self.system_prompt_layers.to_owned();
+                layers.push(contract.instruction.to_owned());
+                Cow::Owned(layers)
+            }
+            None => Cow::Borrowed(&self.system_prompt_layers),
         }
     }
 
-    /// Get a simple string response from a model
+    /// The stack sent with requests, with system prompt layers, the output contract &
+    /// examples applied
+    pub(crate) fn request_stack(&self) -> Cow<'_, MessageStack> {
+        build_request_stack(
+            &self.cache,
+            &self.examples,
+            &self.system_layers(),
+            self.coalesce_consecutive_roles,
+        )
+    }
+
+    /// The JSON body `io_completion` or, when `stream` is set, `stream_completion` would send
+    /// for the current cache, with system prompt layers, the output contract, examples &
+    /// coalescing applied. Nothing is sent
+    pub fn build_request_body(&self, stream: bool) -> AgentResult<serde_json::Value> {
+        let stack = self.request_stack();
+        Ok(self.completion_model.build_request_body(&stack, stream)?)
+    }
+
+    /// Estimated number of tokens left in the model's context window after the current cache,
+    /// examples & a response of `max_tokens`. Negative means the request would be over budget.
+    /// The request's tokens are estimated from its character count, not counted with the
+    /// model's tokenizer, so leave some headroom
+    pub fn remaining_budget(&self, max_tokens: u32) -> i64 {
+        let stack = self.request_stack();
+        self.completion_model.context_window() as i64
+            - stack.estimated_token_count() as i64
+            - max_tokens as i64
+    }
+
+    /// Get a simple string response from a model. A response breaking the output contract is
+    /// regenerated once, the regenerated response is returned whether it follows the contract
+    /// or not
     pub async fn io_completion(&mut self) -> AgentResult<String> {
-        Ok(self.completion_model.get_io_completion(&self.cache).await?)
+        let stack = build_request_stack(
+            &self.cache,
+            &self.examples,
+            &self.system_layers(),
+            self.coalesce_consecutive_roles,
+        )
+        .into_owned();
+        self.io_completion_under_contract(stack).await
+    }
+
+    /// Same as `io_completion`, but without few-shot examples. Useful for meta queries about the
+    /// conversation itself
+    pub async fn io_completion_without_examples(&mut self) -> AgentResult<String> {
+        let stack = build_request_stack(
+            &self.cache,
+            &[],
+            &self.system_layers(),
+            self.coalesce_consecutive_roles,
+        )
+        .into_owned();
+        self.io_completion_under_contract(stack).await
     }
 
-    /// Get a streamed response from a model
+    /// Completes `stack`, regenerating once with a reminder if the response breaks the output
+    /// contract. Neither the response nor the reminder is cached
+    async fn io_completion_under_contract(
+        &mut self,
+        mut stack: MessageStack,
+    ) -> AgentResult<String> {
+        let response = self.completion_model.get_io_completion(&stack).await?;
+        let Some(contract) = self.output_contract.as_ref() else {
+            return Ok(response);
+        };
+        let Some(violation) = contract.check(&response) else {
+            return Ok(response);
+        };
+        warn!(
+            "Response broke the output contract, regenerating: {}",
+            violation
+        );
+        let reminder = contract.reminder(violation);
+        stack.push(Message::new_assistant(&response));
+        stack.push(Message::new_user(&reminder));
+        let regenerated = self.completion_model.get_io_completion(&stack).await?;
+        if let Some(violation) = contract.check(&regenerated) {
+            warn!(
+                "Regenerated response still broke the output contract: {}",
+                violation
+            );
+        }
+        Ok(regenerated)
+    }
+
+    /// Get a streamed response from a model. The output contract's instruction is sent & the
+    /// finished content is checked against it, see `ProviderStreamHandler::contract_violation`.
+    /// Streamed responses aren't regenerated since their tokens have already been received, use
+    /// `regenerate_stream_completion` for that
     pub async fn stream_completion(&mut self) -> AgentResult<ProviderStreamHandler> {
-        let cs = self
-            .completion_model
-            .get_stream_completion(&self.cache)
-            .await?;
+        let stack = self.request_stack();
+        let cs = self.completion_model.get_stream_completion(&stack).await?;
 
         Ok(cs.into())
     }
 
-    /// Get a function completion from a model, returns a JSON object
+    /// Same as `stream_completion`, but without few-shot examples
+    pub async fn stream_completion_without_examples(
+        &mut self,
+    ) -> AgentResult<ProviderStreamHandler> {
+        let stack = build_request_stack(
+            &self.cache,
+            &[],
+            &self.system_layers(),
+            self.coalesce_consecutive_roles,
+        );
+        Ok(self.completion_model.get_stream_completion(&stack).await?)
+    }
+
+    /// Removes the last message from the cache, which must be an assistant message
+    fn pop_last_assistant_message(&mut self) -> AgentResult<Message> {
+        match self.cache.as_ref().last() {
+            Some(m) if m.role.actual() == &MessageRole::Assistant => {
+                Ok(self.cache.pop(None).expect("cache should not be empty"))
+            }
+            _ => Err(AgentError::LastMessageNotAssistant),
+        }
+    }
+
+    /// Discard the last assistant response and get a new simple string response from the
+    /// remaining history. If the request fails, the discarded message is put back
+    pub async fn regenerate_io_completion(&mut self) -> AgentResult<String> {
+        let last = self.pop_last_assistant_message()?;
+        match self.io_completion().await {
+            Ok(response) => Ok(response),
+            Err(err) => {
+                self.cache.push(last);
+                Err(err)
+            }
+        }
+    }
+
+    /// Discard the last assistant response and get a new streamed response from the remaining
+    /// history. If the request fails, the discarded message is put back
+    pub async fn regenerate_stream_completion(&mut self) -> AgentResult<ProviderStreamHandler> {
+        let last = self.pop_last_assistant_message()?;
+        match self.stream_completion().await {
+            Ok(handler) => Ok(handler),
+            Err(err) => {
+                self.cache.push(last);
+                Err(err)
+            }
+        }
+    }
+
+    /// Sends the conversation up to the last user turn to each of `models` concurrently, for
+    /// comparing their responses. A trailing assistant response in the cache is left out. Each
+    /// result is labeled with its model's name, usage is recorded on the passed models and the
+    /// cache is never modified
+    pub async fn compare_models(
+        &self,
+        models: &mut [CompletionModel],
+    ) -> Vec<(String, AgentResult<String>)> {
+        let mut stack = self.request_stack().into_owned();
+        if stack
+            .as_ref()
+            .last()
+            .is_some_and(|m| m.role.actual() == &MessageRole::Assistant)
+        {
+            stack.pop(None);
+        }
+        let completions = models.iter_mut().map(|model| {
+            let stack = &stack;
+            async move {
+                let name = model.provider.model_str().to_owned();
+                let result = model.get_io_completion(stack).await.map_err(|e| e.into());
+                (name, result)
+            }
+        });
+        futures::future::join_all(completions).await
+    }
+
+    /// Get a function completion from a model, returns a JSON object. The output contract isn't
+    /// applied, the function's schema is the format
     pub async fn function_completion(
         &mut self,
         function: Function,
     ) -> AgentResult<serde_json::Value> {
+        let stack = build_request_stack(
+            &self.cache,
+            &self.examples,
+            &self.system_prompt_layers,
+            self.coalesce_consecutive_roles,
+        );
         Ok(self
             .completion_model
-            .get_fn_completion(&self.cache, function)
+            .get_fn_completion(&stack, function)
             .await?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn examples_inserted_after_system_prompt() {
+        let mut cache = MessageStack::new("system");
+        cache.push(Message::new_user("real question"));
+        let examples = vec![(
+            Message::new_user("example question"),
+            Message::new_assistant("example answer"),
+        )];
+
+        let stack = build_request_stack(&cache, &examples, &[], false);
+        let contents: Vec<&str> = stack
+            .as_ref()
+            .as_ref()
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "system",
+                "example question",
+                "example answer",
+                "real question"
+            ]
+        );
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[cfg(feature = "openai")]
+    #[test]
+    fn system_prompt_layers_joined_at_request_time() {
+        let mut agent = Agent::new(Some("base"), CompletionModel::default_openai(""))
+            .with_system_prompt_layer("monitoring a Rust build")
+            .with_system_prompt_layer("be terse")
+            .with_examples(vec![(Message::new_user("q"), Message::new_assistant("a"))]);
+        agent.cache.push(Message::new_user("real question"));
+
+        agent.system_prompt_layers[1] = "be very terse".to_string();
+        let stack = agent.request_stack();
+        let contents: Vec<&str> = stack
+            .as_ref()
+            .as_ref()
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "base\n\nmonitoring a Rust build\n\nbe very terse",
+                "q",
+                "a",
+                "real question"
+            ]
+        );
+        assert_eq!(agent.cache.ref_system_prompt_content(), Some("base"));
+
+        let empty = MessageStack::init();
+        let no_base = build_request_stack(&empty, &[], &["layer".to_string()], false);
+        assert_eq!(no_base.ref_system_prompt_content(), Some("layer"));
+    }
+
+    #[cfg(feature = "openai")]
+    #[test]
+    fn consecutive_roles_coalesced_without_touching_cache() {
+        let mut agent = Agent::new(Some("system"), CompletionModel::default_openai(""));
+        agent.cache.push(Message::new_user("pane output 1"));
+        agent.cache.push(Message::new_user("pane output 2"));
+        agent.cache.push(Message::new_assistant("looks fine"));
+        agent.cache.push(Message::new_user("pane output 3"));
+        assert_eq!(agent.request_stack().len(), 5);
+
+        let agent = agent.with_coalesced_roles();
+        let stack = agent.request_stack();
+        let contents: Vec<&str> = stack
+            .as_ref()
+            .as_ref()
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "system",
+                "pane output 1\npane output 2",
+                "looks fine",
+                "pane output 3"
+            ]
+        );
+        assert_eq!(agent.cache.len(), 5);
+
+        let mut alternating = MessageStack::new("system");
+        alternating.push(Message::new_user("q"));
+        assert!(matches!(
+            build_request_stack(&alternating, &[], &[], true),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn models_compared_on_last_user_turn() {
+        use crate::language_models::completions::{
+            openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+            testing::serve_once,
+            ModelParameters,
+        };
+        let response = |content: &str| {
+            serde_json::json!({
+                "usage": {"prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12},
+                "choices": [{"message": {"role": "assistant", "content": content}}]
+            })
+            .to_string()
+            .into_bytes()
+        };
+        let mut models = vec![];
+        for (model, content) in [
+            (OpenAiCompletionModel::Gpt3, "from gpt3"),
+            (OpenAiCompletionModel::Gpt4, "from gpt4"),
+        ] {
+            let url = serve_once(
+                vec![("Content-Type", "application/json")],
+                response(content),
+            )
+            .await;
+            let deployment = AzureOpenAiDeployment::new(&url, "d", "2024-06-01", model);
+            models.push(CompletionModel::new(
+                deployment,
+                ModelParameters::default(),
+                "",
+            ));
+        }
+
+        let mut agent = Agent::new(Some("system"), CompletionModel::default_openai(""));
+        agent.cache.push(Message::new_user("question"));
+        agent.cache.push(Message::new_assistant("old answer"));
+        let results = agent.compare_models(&mut models).await;
+
+        let results: Vec<(String, String)> = results
+            .into_iter()
+            .map(|(name, result)| (name, result.unwrap()))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("gpt-3.5-turbo-0125".to_string(), "from gpt3".to_string()),
+                ("gpt-4-0125-preview".to_string(), "from gpt4".to_string()),
+            ]
+        );
+        assert!(models.iter().all(|m| m.params.total_token_count == 12));
+        assert_eq!(agent.cache.len(), 3);
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn contract_breaking_response_regenerated_once() {
+        use crate::language_models::completions::{
+            openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+            testing::serve_in_order,
+            ModelParameters,
+        };
+        let response = |content: &str| {
+            serde_json::json!({
+                "usage": {"prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12},
+                "choices": [{"message": {"role": "assistant", "content": content}}]
+            })
+            .to_string()
+            .into_bytes()
+        };
+        let url = serve_in_order(
+            vec![("Content-Type", "application/json")],
+            vec![
+                response("The build ran.\nIt compiled 40 crates.\nThere were 2 warnings.\nAll tests passed."),
+                response("- build ok\n- 2 warnings"),
+            ],
+        )
+        .await;
+        let deployment =
+            AzureOpenAiDeployment::new(&url, "d", "2024-06-01", OpenAiCompletionModel::Gpt4);
+        let model = CompletionModel::new(deployment, ModelParameters::default(), "");
+        let contract = OutputContract::new("Respond as 3 bullets max").with_max_lines(3);
+        let mut agent = Agent::new(Some("system"), model).with_output_contract(contract);
+        agent.cache.push(Message::new_user("pane output"));
+
+        assert_eq!(
+            agent.request_stack().ref_system_prompt_content(),
+            Some("system\n\nRespond as 3 bullets max")
+        );
+        let response = agent.io_completion().await.unwrap();
+        assert_eq!(response, "- build ok\n- 2 warnings");
+        assert_eq!(agent.completion_model.params.total_token_count, 24);
+        assert_eq!(agent.cache.len(), 2);
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn request_body_matches_sent_body() {
+        use crate::language_models::completions::{
+            openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+            testing::serve_recording,
+            ModelParameters,
+        };
+        use memory::Attachment;
+
+        let agent = |url: &str| {
+            let deployment =
+                AzureOpenAiDeployment::new(url, "d", "2024-06-01", OpenAiCompletionModel::Gpt4);
+            let model = CompletionModel::new(deployment, ModelParameters::default(), "");
+            let mut agent = Agent::new(Some("system"), model)
+                .with_examples(vec![(
+                    Message::new_user("example question"),
+                    Message::new_assistant("example answer"),
+                )])
+                .with_system_prompt_layer("Be brief")
+                .with_coalesced_roles()
+                .with_output_contract(OutputContract::new("Respond as 3 bullets max"));
+            agent.cache.push(Message::new_user("pane output"));
+            agent.cache.push(
+                Message::new_user("what failed?")
+                    .with_attachment(Attachment::snippet("error[E0308]").with_path("build.log")),
+            );
+            agent
+        };
+
+        let response = serde_json::json!({
+            "usage": {"prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12},
+            "choices": [{"message": {"role": "assistant", "content": "- a type mismatch"}}]
+        });
+        let (url, mut sent) = serve_recording(
+            vec![("Content-Type", "application/json")],
+            vec![response.to_string().into_bytes()],
+        )
+        .await;
+        let mut io_agent = agent(&url);
+        let body = io_agent.build_request_body(false).unwrap();
+        io_agent.io_completion().await.unwrap();
+        assert_eq!(sent.recv().await.unwrap(), body);
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(
+            messages[0]["content"],
+            "system Be brief Respond as 3 bullets max"
+        );
+        assert_eq!(messages[1]["content"], "example question");
+        // The two user messages are coalesced, with the attachment after the second
+        assert_eq!(messages.len(), 4);
+        assert!(messages[3]["content"]
+            .as_str()
+            .unwrap()
+            .contains("error[E0308]"));
+
+        let (url, mut sent) = serve_recording(
+            vec![("Content-Type", "text/event-stream")],
+            vec![b"data: [DONE]\n\n".to_vec()],
+        )
+        .await;
+        let mut stream_agent = agent(&url);
+        let body = stream_agent.build_request_body(true).unwrap();
+        stream_agent.stream_completion().await.unwrap();
+        assert_eq!(sent.recv().await.unwrap(), body);
+        assert_eq!(body["stream"], true);
+    }
+
+    #[cfg(feature = "openai")]
+    #[test]
+    fn remaining_budget_accounts_for_cache_and_response() {
+        let model = CompletionModel::default_openai("");
+        let window = model.context_window() as i64;
+        let mut agent = Agent::new(Some("system"), model);
+        let empty_budget = agent.remaining_budget(0);
+        assert!(empty_budget < window);
+
+        agent.cache.push(Message::new_user(&"word ".repeat(1000)));
+        assert!(agent.remaining_budget(0) < empty_budget - 1000);
+        assert!(agent.remaining_budget(window as u32) < 0);
+
+        let model = CompletionModel::default_openai("").with_context_window(window as u32 * 2);
+        let mut agent = Agent::new(Some("system"), model);
+        assert!(agent.remaining_budget(window as u32) > 0);
+        let provider = agent.completion_model.provider.clone();
+        agent.completion_model.switch_provider(provider);
+        assert!(agent.remaining_budget(window as u32) < 0);
+    }
+}
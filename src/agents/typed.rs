@@ -0,0 +1,203 @@
+use super::{
+    error::{AgentError, TypedPromptError},
+    memory::Message,
+    Agent,
+};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use tracing::warn;
+
+impl Agent {
+    /// Prompt the model with `user_text` and parse its reply into `T`. When the model supports
+    /// structured output the JSON schema of `T` is sent as the format the reply must follow,
+    /// otherwise it is appended to the prompt. If the reply fails to parse, the serde error is
+    /// fed back to the model for up to `max_repairs` more attempts. Repair rounds are not kept
+    /// in the cache, only the prompt & the final raw assistant reply are.
+    pub async fn prompt_typed<T>(
+        &mut self,
+        user_text: &str,
+        max_repairs: usize,
+    ) -> Result<T, TypedPromptError>
+    where
+        T: DeserializeOwned + JsonSchema,
+    {
+        let root = schemars::schema_for!(T);
+        let structured = self.completion_model.capabilities().structured_output;
+        let schema = serde_json::to_value(&root).map_err(TypedPromptError::Schema)?;
+        let name = schema_name(
+            root.schema
+                .metadata
+                .as_ref()
+                .and_then(|m| m.title.as_deref()),
+        );
+        let prompt = match structured {
+            true => user_text.to_owned(),
+            false => format!(
+                "{}\n\nRespond only with JSON matching this schema, with no other text:\n{}",
+                user_text,
+                serde_json::to_string_pretty(&schema).map_err(TypedPromptError::Schema)?
+            ),
+        };
+        self.cache.push(Message::new_user(&prompt));
+
+        let mut scratch = self.request_stack().into_owned();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let raw = match structured {
+                true => {
+                    self.completion_model
+                        .get_structured_completion(&scratch, &name, &schema)
+                        .await
+                }
+                false => self.completion_model.get_io_completion(&scratch).await,
+            }
+            .map_err(AgentError::from)?;
+
+            let error = match parse_json_reply::<T>(&raw) {
+                Some(Ok(value)) => {
+                    self.cache.push(Message::new_assistant(&raw));
+                    return Ok(value);
+                }
+                Some(Err(err)) => err,
+                None => {
+                    self.cache.push(Message::new_assistant(&raw));
+                    return Err(TypedPromptError::Refusal(raw));
+                }
+            };
+
+            warn!(
+                "Typed reply failed to parse on attempt {}: {}",
+                attempts, error
+            );
+            if attempts > max_repairs {
+                self.cache.push(Message::new_assistant(&raw));
+                return Err(TypedPromptError::RepairsExhausted { attempts, error });
+            }
+            scratch.push(Message::new_assistant(&raw));
+            scratch.push(Message::new_user(&format!(
+                "Your last response could not be parsed: {}. Respond again with only JSON matching the schema.",
+                error
+            )));
+        }
+    }
+}
+
+/// A name for the schema of a structured reply from its title, with only the characters
+/// providers accept in one
+fn schema_name(title: Option<&str>) -> String {
+    let name: String = title
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .collect();
+    match name.is_empty() {
+        true => "response".to_string(),
+        false => name,
+    }
+}
+
+/// Returns `None` if the reply contains nothing that looks like JSON, which is treated as a
+/// refusal. Markdown code fences or text around the JSON value are ignored
+fn parse_json_reply<T: DeserializeOwned>(raw: &str) -> Option<Result<T, serde_json::Error>> {
+    let start = raw.find(['{', '['])?;
+    let end = raw.rfind(['}', ']'])?;
+    if end < start {
+        return None;
+    }
+    Some(serde_json::from_str(&raw[start..=end]))
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::{parse_json_reply, schema_name};
+    use crate::{
+        agents::Agent,
+        language_models::completions::{
+            capabilities::ModelCapabilities,
+            openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+            testing::serve_recording,
+            CompletionModel, ModelParameters,
+        },
+    };
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq, JsonSchema)]
+    struct Weather {
+        location: String,
+        degrees: i32,
+    }
+
+    #[test]
+    fn json_reply_parsed_through_fences() {
+        let raw = "Here you go:\n```json\n{\"location\": \"Detroit\", \"degrees\": 20}\n```";
+        let parsed = parse_json_reply::<Weather>(raw).unwrap().unwrap();
+        assert_eq!(
+            parsed,
+            Weather {
+                location: "Detroit".to_string(),
+                degrees: 20
+            }
+        );
+        assert!(parse_json_reply::<Weather>("{\"location\": 1}")
+            .unwrap()
+            .is_err());
+        assert!(parse_json_reply::<Weather>("I can't help with that").is_none());
+    }
+
+    #[tokio::test]
+    async fn schema_sent_as_response_format_when_supported() {
+        let reply = serde_json::json!({
+            "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+            "choices": [{"message": {"role": "assistant", "content": "{\"location\": \"Detroit\", \"degrees\": 20}"}}]
+        })
+        .to_string()
+        .into_bytes();
+        let (url, mut sent) = serve_recording(
+            vec![("Content-Type", "application/json")],
+            vec![reply.clone(), reply],
+        )
+        .await;
+        let model = || {
+            let deployment =
+                AzureOpenAiDeployment::new(&url, "d", "2024-06-01", OpenAiCompletionModel::Gpt4);
+            CompletionModel::new(deployment, ModelParameters::default(), "")
+        };
+        let detroit = Weather {
+            location: "Detroit".to_string(),
+            degrees: 20,
+        };
+
+        let structured = model().with_capabilities(ModelCapabilities {
+            structured_output: true,
+            ..model().capabilities()
+        });
+        let mut agent = Agent::new(None, structured);
+        let weather: Weather = agent.prompt_typed("Weather in Detroit?", 0).await.unwrap();
+        assert_eq!(weather, detroit);
+        let body = sent.recv().await.unwrap();
+        let format = &body["response_format"];
+        assert_eq!(format["type"], "json_schema");
+        assert_eq!(format["json_schema"]["name"], "Weather");
+        assert_eq!(
+            format["json_schema"]["schema"]["required"],
+            serde_json::json!(["degrees", "location"])
+        );
+        assert_eq!(agent.cache.as_ref()[0].content, "Weather in Detroit?");
+
+        // Models without structured output get the schema in the prompt
+        let mut agent = Agent::new(None, model());
+        let weather: Weather = agent.prompt_typed("Weather in Detroit?", 0).await.unwrap();
+        assert_eq!(weather, detroit);
+        let body = sent.recv().await.unwrap();
+        assert!(body.get("response_format").is_none());
+        assert!(agent.cache.as_ref()[0]
+            .content
+            .contains("Respond only with JSON matching this schema"));
+
+        assert_eq!(schema_name(Some("Array_of_Weather")), "Array_of_Weather");
+        assert_eq!(schema_name(Some("Map<String>")), "MapString");
+        assert_eq!(schema_name(None), "response");
+    }
+}
@@ -0,0 +1,196 @@
+//! Blocking versions of the async completion & embedding calls, for scripts & other programs
+//! without an async runtime of their own
+use crate::{
+    agents::{error::AgentResult, memory::Message, Agent},
+    language_models::{
+        completions::streaming::{CompletionStreamStatus, StreamError},
+        embeddings::{error::EmbeddingResult, EmbeddingModel},
+    },
+};
+use anyhow::anyhow;
+use std::{future::Future, time::Duration};
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// How long dropping a client waits for tasks still running on its runtime
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+const ASYNC_CONTEXT_PANIC: &str =
+    "BlockingClient can't be used from within an async runtime, use the async calls instead";
+
+/// Runs the async calls to completion on a current thread runtime it owns. They take the same
+/// agents & models, and return the same errors, as their async versions, so parameters, retry
+/// policies & spend caps apply the same way
+#[derive(Debug)]
+pub struct BlockingClient {
+    /// Only taken when the client is dropped
+    runtime: Option<Runtime>,
+}
+
+impl BlockingClient {
+    /// Panics if called from within an async runtime, where blocking would stall it
+    pub fn new() -> std::io::Result<Self> {
+        if Handle::try_current().is_ok() {
+            panic!("{}", ASYNC_CONTEXT_PANIC);
+        }
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        Ok(Self {
+            runtime: Some(runtime),
+        })
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        if Handle::try_current().is_ok() {
+            panic!("{}", ASYNC_CONTEXT_PANIC);
+        }
+        self.runtime
+            .as_ref()
+            .expect("runtime is only taken on drop")
+            .block_on(future)
+    }
+
+    /// Blocking `Agent::io_completion`
+    pub fn io_completion(&self, agent: &mut Agent) -> AgentResult<String> {
+        self.block_on(agent.io_completion())
+    }
+
+    /// Streams a completion like `Agent::stream_completion`, calling `on_token` with each non
+    /// empty token as it is received. Returns the message the stream finished with, which is also cached.
+    /// Completions finishing with tool calls need the async handler & fail here
+    pub fn stream_completion(
+        &self,
+        agent: &mut Agent,
+        mut on_token: impl FnMut(&str),
+    ) -> AgentResult<Message> {
+        self.block_on(async {
+            let mut handler = agent.stream_completion().await?;
+            loop {
+                match handler.receive(agent).await {
+                    Ok(Some(CompletionStreamStatus::Working(token))) if token.is_empty() => {}
+                    Ok(Some(CompletionStreamStatus::Working(token))) => on_token(&token),
+                    Err(StreamError::ReceiverTimeout) => continue,
+                    Ok(Some(
+                        CompletionStreamStatus::Finished(content)
+                        | CompletionStreamStatus::Truncated(content),
+                    )) => {
+                        let cached = agent.cache.as_ref().last().cloned();
+                        return Ok(cached.unwrap_or_else(|| Message::new_assistant(&content)));
+                    }
+                    Ok(Some(CompletionStreamStatus::ToolCalls(calls))) => {
+                        return Err(anyhow!(
+                            "Stream finished with {} tool calls, which need the async handler",
+                            calls.len()
+                        )
+                        .into())
+                    }
+                    Ok(None) => return Err(StreamError::PrematureClose.into()),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        })
+    }
+
+    /// Blocking `EmbeddingModel::get_embedding`
+    pub fn embed(&self, model: &EmbeddingModel, text: &str) -> EmbeddingResult<Vec<f32>> {
+        self.block_on(model.get_embedding(text))
+    }
+}
+
+impl Drop for BlockingClient {
+    fn drop(&mut self) {
+        let Some(runtime) = self.runtime.take() else {
+            return;
+        };
+        // Waiting on the runtime's tasks would block an async caller's thread
+        match Handle::try_current() {
+            Ok(_) => runtime.shutdown_background(),
+            Err(_) => runtime.shutdown_timeout(SHUTDOWN_TIMEOUT),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::{
+        openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+        testing::serve_once,
+        CompletionModel, ModelParameters,
+    };
+    use serde_json::json;
+
+    fn io_body() -> Vec<u8> {
+        json!({
+            "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7},
+            "choices": [{"message": {"role": "assistant", "content": "Build passed"}, "finish_reason": "stop"}]
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn stream_body() -> Vec<u8> {
+        let chunks = [
+            json!({"choices": [{"delta": {"content": "Build"}}]}),
+            json!({"choices": [{"delta": {"content": " passed"}}]}),
+            json!({"choices": [{"delta": {}, "finish_reason": "stop"}]}),
+            json!({"choices": [], "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7}}),
+        ];
+        let body: String = chunks
+            .iter()
+            .map(|chunk| format!("data: {}\n\n", chunk))
+            .collect();
+        (body + "data: [DONE]\n\n").into_bytes()
+    }
+
+    /// An agent whose completions are requested from a local server serving `body` once
+    async fn served_agent(headers: Vec<(&str, &str)>, body: Vec<u8>) -> Agent {
+        let url = serve_once(headers, body).await;
+        let deployment =
+            AzureOpenAiDeployment::new(&url, "d", "2024-06-01", OpenAiCompletionModel::Gpt4);
+        let model = CompletionModel::new(deployment, ModelParameters::default(), "");
+        Agent::new(Some("You check builds"), model)
+    }
+
+    #[test]
+    fn completions_match_the_async_calls() {
+        let server = tokio::runtime::Runtime::new().unwrap();
+        let sse = || vec![("Content-Type", "text/event-stream")];
+        let client = BlockingClient::new().unwrap();
+
+        let mut agent = server.block_on(served_agent(vec![], io_body()));
+        let blocking = client.io_completion(&mut agent).unwrap();
+        let mut agent = server.block_on(served_agent(vec![], io_body()));
+        let expected = server.block_on(agent.io_completion()).unwrap();
+        assert_eq!(blocking, expected);
+
+        let mut agent = server.block_on(served_agent(sse(), stream_body()));
+        let mut tokens = vec![];
+        let message = client
+            .stream_completion(&mut agent, |token| tokens.push(token.to_owned()))
+            .unwrap();
+        assert_eq!(tokens, ["Build", " passed"]);
+        assert_eq!(message.content, "Build passed");
+        assert_eq!(agent.completion_model.params.total_token_count, 7);
+
+        let mut agent = server.block_on(served_agent(sse(), stream_body()));
+        let expected = server.block_on(async {
+            let mut handler = agent.stream_completion().await.unwrap();
+            handler.collect(&mut agent).await.unwrap();
+            agent.cache.as_ref().last().unwrap().clone()
+        });
+        assert_eq!(message, expected);
+        assert_eq!(agent.completion_model.params.total_token_count, 7);
+
+        // Spend caps refuse requests the same way
+        let mut agent = server.block_on(served_agent(vec![], io_body()));
+        agent.completion_model = agent.completion_model.clone().with_spend_cap(0.0);
+        assert_eq!(
+            client.io_completion(&mut agent).unwrap_err().to_string(),
+            "Budget Exceeded"
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "can't be used from within an async runtime")]
+    async fn refused_in_async_context() {
+        let _ = BlockingClient::new();
+    }
+}
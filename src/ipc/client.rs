@@ -0,0 +1,216 @@
+use super::{
+    read_frame, write_frame, AgentEvent, IpcError, IpcFrame, IpcRequest, IpcResponse, IpcResult,
+    IPC_VERSION,
+};
+use crate::agents::memory::Message;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, MutexGuard,
+    },
+};
+use tokio::{
+    net::{unix::OwnedWriteHalf, UnixStream},
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+
+type Pending = Arc<StdMutex<HashMap<u64, mpsc::UnboundedSender<IpcResponse>>>>;
+
+fn pending(pending: &Pending) -> MutexGuard<'_, HashMap<u64, mpsc::UnboundedSender<IpcResponse>>> {
+    pending.lock().expect("ipc pending lock poisoned")
+}
+
+/// A connection to an `IpcServer` from another process. Requests can be made concurrently,
+/// responses are matched to them by id
+#[derive(Debug)]
+pub struct RemoteAgentClient {
+    writer: Mutex<OwnedWriteHalf>,
+    pending: Pending,
+    next_id: AtomicU64,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for RemoteAgentClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl RemoteAgentClient {
+    /// Connects to the server listening at `path`, failing with `IpcError::Handshake` if it
+    /// doesn't speak this `IPC_VERSION`
+    pub async fn connect(path: impl AsRef<Path>) -> IpcResult<Self> {
+        let (mut reader, mut writer) = UnixStream::connect(path).await?.into_split();
+        let hello = IpcFrame {
+            id: 0,
+            body: IpcRequest::Hello {
+                version: IPC_VERSION,
+            },
+        };
+        write_frame(&mut writer, &hello).await?;
+        match read_frame(&mut reader).await? {
+            Some(IpcFrame {
+                body: IpcResponse::Welcome { .. },
+                ..
+            }) => {}
+            Some(IpcFrame {
+                body: IpcResponse::Error { message },
+                ..
+            }) => return Err(IpcError::Handshake(message)),
+            Some(frame) => return Err(unexpected(&frame.body)),
+            None => return Err(IpcError::Closed),
+        }
+
+        let responses = Pending::default();
+        let reader = tokio::spawn({
+            let responses = Arc::clone(&responses);
+            async move {
+                while let Ok(Some(frame)) = read_frame::<_, IpcResponse>(&mut reader).await {
+                    // Responses to requests nobody is waiting on any more are dropped
+                    if let Some(sender) = pending(&responses).get(&frame.id) {
+                        let _ = sender.send(frame.body);
+                    }
+                }
+                // Ends every request still waiting with `IpcError::Closed`
+                pending(&responses).clear();
+            }
+        });
+        Ok(Self {
+            writer: Mutex::new(writer),
+            pending: responses,
+            next_id: AtomicU64::new(1),
+            reader,
+        })
+    }
+
+    async fn request(&self, body: IpcRequest) -> IpcResult<RemoteEvents> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        pending(&self.pending).insert(id, sender);
+        let events = RemoteEvents {
+            id,
+            receiver,
+            pending: Arc::clone(&self.pending),
+            completion: false,
+            ended: false,
+        };
+        let mut writer = self.writer.lock().await;
+        write_frame(&mut *writer, &IpcFrame { id, body }).await?;
+        Ok(events)
+    }
+
+    /// The single response to a request
+    async fn call(&self, body: IpcRequest) -> IpcResult<IpcResponse> {
+        let mut events = self.request(body).await?;
+        match events.receiver.recv().await {
+            Some(IpcResponse::Error { message }) => Err(IpcError::Remote(message)),
+            Some(response) => Ok(response),
+            None => Err(IpcError::Closed),
+        }
+    }
+
+    /// Names of the agents the server serves, sorted
+    pub async fn list_agents(&self) -> IpcResult<Vec<String>> {
+        match self.call(IpcRequest::ListAgents).await? {
+            IpcResponse::Agents { names } => Ok(names),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// The last `count` messages in the agent's cache, oldest first
+    pub async fn cache_tail(&self, agent: &str, count: usize) -> IpcResult<Vec<Message>> {
+        let request = IpcRequest::CacheTail {
+            agent: agent.to_owned(),
+            count,
+        };
+        match self.call(request).await? {
+            IpcResponse::Messages { messages } => Ok(messages),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    pub async fn push_message(&self, agent: &str, message: &Message) -> IpcResult<()> {
+        let request = IpcRequest::PushMessage {
+            agent: agent.to_owned(),
+            message: message.clone(),
+        };
+        match self.call(request).await? {
+            IpcResponse::Pushed => Ok(()),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Streams a completion of the agent's cache, the events end with `AgentEvent::Finished`
+    /// or `AgentEvent::Failed`. The server cancels the completion if the client disconnects
+    /// before then
+    pub async fn complete(&self, agent: &str) -> IpcResult<RemoteEvents> {
+        let request = IpcRequest::Complete {
+            agent: agent.to_owned(),
+        };
+        let mut events = self.request(request).await?;
+        events.completion = true;
+        Ok(events)
+    }
+
+    /// Everything `agents` do through any connection to the server from now on, every agent's
+    /// when `agents` is empty. The events last as long as the connection
+    pub async fn subscribe(&self, agents: &[&str]) -> IpcResult<RemoteEvents> {
+        let request = IpcRequest::Subscribe {
+            agents: agents.iter().map(|name| name.to_string()).collect(),
+        };
+        let mut events = self.request(request).await?;
+        match events.receiver.recv().await {
+            Some(IpcResponse::Subscribed) => Ok(events),
+            Some(IpcResponse::Error { message }) => Err(IpcError::Remote(message)),
+            Some(response) => Err(unexpected(&response)),
+            None => Err(IpcError::Closed),
+        }
+    }
+}
+
+fn unexpected(response: &IpcResponse) -> IpcError {
+    IpcError::Protocol(format!("Unexpected response {:?}", response))
+}
+
+/// Events of a completion or subscription requested with a `RemoteAgentClient`, by the name
+/// of the agent they happened to
+#[derive(Debug)]
+pub struct RemoteEvents {
+    id: u64,
+    receiver: mpsc::UnboundedReceiver<IpcResponse>,
+    pending: Pending,
+    /// Whether the events end with the completion, rather than the connection
+    completion: bool,
+    ended: bool,
+}
+
+impl Drop for RemoteEvents {
+    fn drop(&mut self) {
+        pending(&self.pending).remove(&self.id);
+    }
+}
+
+impl RemoteEvents {
+    /// The next event, `None` once a completion has ended or a subscription's connection has
+    /// closed. A completion's connection closing before it ends is `IpcError::Closed`
+    pub async fn next(&mut self) -> Option<IpcResult<(String, AgentEvent)>> {
+        if self.ended {
+            return None;
+        }
+        let received = match self.receiver.recv().await {
+            Some(IpcResponse::Event { agent, event }) => {
+                self.ended = self.completion && event.is_final();
+                return Some(Ok((agent, event)));
+            }
+            Some(IpcResponse::Error { message }) => Some(Err(IpcError::Remote(message))),
+            Some(response) => Some(Err(unexpected(&response))),
+            None if self.completion => Some(Err(IpcError::Closed)),
+            None => None,
+        };
+        self.ended = true;
+        received
+    }
+}
@@ -0,0 +1,358 @@
+//! Serving agents to other processes over a Unix domain socket, so small tools can list them,
+//! read & push messages, stream completions and follow what the agents do without linking
+//! against the process running them. Frames are a 4 byte big endian length followed by that
+//! many bytes of JSON, an `IpcFrame` of an `IpcRequest` from clients or an `IpcResponse` from
+//! the server. Responses carry the id of the request they answer. A connection starts with
+//! `IpcRequest::Hello`, which the server answers with its `IPC_VERSION` or refuses
+mod client;
+mod server;
+
+pub use client::{RemoteAgentClient, RemoteEvents};
+pub use server::{IpcServer, IpcServerHandle};
+
+use crate::{
+    agents::memory::Message,
+    errors::error_chain_fmt,
+    language_models::completions::streaming::{CollectedCompletion, WireStreamError},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    io,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Version of the protocol, bumped when requests or responses change incompatibly
+pub const IPC_VERSION: u32 = 1;
+/// Frames longer than this are refused & end the connection
+pub const MAX_FRAME_BYTES: usize = 16 * 1024 * 1024;
+
+pub type IpcResult<T> = Result<T, IpcError>;
+
+#[derive(thiserror::Error)]
+pub enum IpcError {
+    Io(#[from] io::Error),
+    Json(#[from] serde_json::Error),
+    /// The server refused the connection's `Hello`, such as for an unsupported version
+    Handshake(String),
+    /// A frame longer than `MAX_FRAME_BYTES`
+    FrameTooLarge(usize),
+    /// A response that doesn't answer the request it was for
+    Protocol(String),
+    /// The server refused or failed the request
+    Remote(String),
+    /// The connection closed before the request was answered
+    Closed,
+}
+
+impl Debug for IpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl Display for IpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let display = match self {
+            Self::Io(err) => err.to_string(),
+            Self::Json(err) => err.to_string(),
+            Self::Handshake(reason) => format!("IPC handshake refused: {}", reason),
+            Self::FrameTooLarge(len) => format!("IPC frame of {} bytes is too large", len),
+            Self::Protocol(reason) => format!("IPC protocol violation: {}", reason),
+            Self::Remote(message) => message.to_owned(),
+            Self::Closed => "IPC connection closed".to_owned(),
+        };
+        write!(f, "{}", display)
+    }
+}
+
+/// A request or response, with the id responses are correlated to requests by
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpcFrame<T> {
+    pub id: u64,
+    pub body: T,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IpcRequest {
+    /// Must be the first request of a connection, answered with `IpcResponse::Welcome`
+    Hello { version: u32 },
+    /// Answered with `IpcResponse::Agents`
+    ListAgents,
+    /// The last `count` messages of an agent's cache, answered with `IpcResponse::Messages`
+    CacheTail { agent: String, count: usize },
+    /// Answered with `IpcResponse::Pushed`
+    PushMessage { agent: String, message: Message },
+    /// Streams a completion of the agent's cache, answered with `IpcResponse::Event`s ending
+    /// with `AgentEvent::Finished` or `AgentEvent::Failed`. Disconnecting cancels it
+    Complete { agent: String },
+    /// Answered with `IpcResponse::Subscribed`, then an `IpcResponse::Event` for everything
+    /// the agents do until the connection closes. Every agent when `agents` is empty
+    Subscribe { agents: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IpcResponse {
+    Welcome {
+        version: u32,
+    },
+    /// Names of the served agents, sorted
+    Agents {
+        names: Vec<String>,
+    },
+    Messages {
+        messages: Vec<Message>,
+    },
+    Pushed,
+    Subscribed,
+    Event {
+        agent: String,
+        event: AgentEvent,
+    },
+    /// The request was refused or failed
+    Error {
+        message: String,
+    },
+}
+
+/// Something an agent did, through any of the server's connections
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentEvent {
+    MessagePushed {
+        message: Message,
+    },
+    Token {
+        token: String,
+    },
+    /// A completion ended. Cancelled completions end as `CollectedCompletion::Partial`
+    Finished {
+        completion: CollectedCompletion,
+    },
+    Failed {
+        error: WireStreamError,
+    },
+}
+
+impl AgentEvent {
+    /// Whether the event ends a completion
+    pub fn is_final(&self) -> bool {
+        matches!(self, Self::Finished { .. } | Self::Failed { .. })
+    }
+}
+
+pub(crate) async fn write_frame<W, T>(writer: &mut W, frame: &IpcFrame<T>) -> IpcResult<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let json = serde_json::to_vec(frame)?;
+    if json.len() > MAX_FRAME_BYTES {
+        return Err(IpcError::FrameTooLarge(json.len()));
+    }
+    writer.write_all(&(json.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&json).await?;
+    Ok(writer.flush().await?)
+}
+
+/// The next frame, `None` if the connection closed between frames
+pub(crate) async fn read_frame<R, T>(reader: &mut R) -> IpcResult<Option<IpcFrame<T>>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(IpcError::FrameTooLarge(len));
+    }
+    let mut json = vec![0u8; len];
+    reader.read_exact(&mut json).await?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::Agent,
+        language_models::completions::{
+            openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+            testing::serve_slowly,
+            CompletionModel, ModelParameters,
+        },
+    };
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+    use tokio::{net::UnixStream, sync::Mutex};
+
+    const TOKENS: [&str; 4] = ["Build", " passed", " with", " warnings"];
+
+    async fn served_agent(cadence: Duration) -> Arc<Mutex<Agent>> {
+        let url = serve_slowly(&TOKENS, cadence).await;
+        let deployment =
+            AzureOpenAiDeployment::new(&url, "d", "2024-06-01", OpenAiCompletionModel::Gpt4);
+        let model = CompletionModel::new(deployment, ModelParameters::default(), "");
+        Arc::new(Mutex::new(Agent::new(Some("You check builds"), model)))
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        let file = format!("espionox-{}-{}.sock", std::process::id(), name);
+        std::env::temp_dir().join(file)
+    }
+
+    #[tokio::test]
+    async fn concurrent_clients_share_agents() {
+        let builds = served_agent(Duration::from_millis(10)).await;
+        let tests = served_agent(Duration::from_millis(10)).await;
+        let path = socket_path("concurrent");
+        let server = IpcServer::new()
+            .with_agent("tests", &tests)
+            .with_agent("builds", &builds)
+            .bind(&path)
+            .await
+            .unwrap();
+
+        let watcher = RemoteAgentClient::connect(&path).await.unwrap();
+        let mut events = watcher.subscribe(&["builds"]).await.unwrap();
+        let client = RemoteAgentClient::connect(&path).await.unwrap();
+        assert_eq!(client.list_agents().await.unwrap(), ["builds", "tests"]);
+
+        let prompt = Message::new_user("Did the build pass?");
+        client.push_message("builds", &prompt).await.unwrap();
+        client.push_message("tests", &prompt).await.unwrap();
+        let (mut completion, mut other) = (
+            client.complete("builds").await.unwrap(),
+            watcher.complete("tests").await.unwrap(),
+        );
+        let mut tokens = String::new();
+        while let Some(event) = completion.next().await {
+            match event.unwrap() {
+                (_, AgentEvent::Token { token }) => tokens.push_str(&token),
+                (agent, event) => {
+                    assert_eq!(agent, "builds");
+                    let completion = CollectedCompletion::Finished(TOKENS.concat());
+                    assert_eq!(event, AgentEvent::Finished { completion });
+                }
+            }
+        }
+        assert_eq!(tokens, TOKENS.concat());
+        while let Some(event) = other.next().await {
+            event.unwrap();
+        }
+
+        // The subscription only sees the builds agent, and everything done to it
+        let (agent, event) = events.next().await.unwrap().unwrap();
+        assert_eq!(agent, "builds");
+        assert_eq!(
+            event,
+            AgentEvent::MessagePushed {
+                message: prompt.clone()
+            }
+        );
+        let mut seen = vec![];
+        while let Some(event) = events.next().await {
+            let (agent, event) = event.unwrap();
+            assert_eq!(agent, "builds");
+            if event.is_final() {
+                break;
+            }
+            seen.push(event);
+        }
+        assert_eq!(seen.len(), TOKENS.len());
+
+        let tail = client.cache_tail("builds", 2).await.unwrap();
+        assert_eq!(tail, [prompt, Message::new_assistant(&TOKENS.concat())]);
+        let err = client.cache_tail("deploys", 2).await.unwrap_err();
+        assert_eq!(err.to_string(), "No agent named deploys");
+
+        let mut raw = UnixStream::connect(&path).await.unwrap();
+        let hello = IpcFrame {
+            id: 0,
+            body: IpcRequest::Hello { version: 99 },
+        };
+        write_frame(&mut raw, &hello).await.unwrap();
+        let refused: IpcFrame<IpcResponse> = read_frame(&mut raw).await.unwrap().unwrap();
+        assert!(matches!(refused.body, IpcResponse::Error { .. }));
+        assert!(read_frame::<_, IpcResponse>(&mut raw)
+            .await
+            .unwrap()
+            .is_none());
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stopped_server_releases_its_socket() {
+        let path = socket_path("stopped");
+        let mut server = IpcServer::new().bind(&path).await.unwrap();
+        assert_eq!(server.path(), path);
+        assert!(server.is_running());
+
+        server.stop();
+        assert!(!path.exists());
+        assert!(RemoteAgentClient::connect(&path).await.is_err());
+        tokio::task::yield_now().await;
+        assert!(!server.is_running());
+
+        // The old handle leaves a server bound to the same path alone
+        let rebound = IpcServer::new().bind(&path).await.unwrap();
+        server.stop();
+        drop(server);
+        assert!(path.exists());
+        assert!(rebound.is_running());
+    }
+
+    #[tokio::test]
+    async fn vanishing_clients_cancel_their_streams() {
+        let agent = served_agent(Duration::from_millis(50)).await;
+        agent
+            .lock()
+            .await
+            .cache
+            .push(Message::new_user("Did the build pass?"));
+        let path = socket_path("vanishing");
+        let _server = IpcServer::new()
+            .with_agent("builds", &agent)
+            .bind(&path)
+            .await
+            .unwrap();
+
+        let client = RemoteAgentClient::connect(&path).await.unwrap();
+        let mut completion = client.complete("builds").await.unwrap();
+        let (_, first) = completion.next().await.unwrap().unwrap();
+        assert_eq!(
+            first,
+            AgentEvent::Token {
+                token: TOKENS[0].to_owned()
+            }
+        );
+        drop(completion);
+        drop(client);
+
+        let cached = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                let last = agent.lock().await.cache.as_ref().last().cloned().unwrap();
+                if last.content.starts_with(TOKENS[0]) {
+                    return last.content;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_ne!(cached, TOKENS.concat());
+
+        // Other clients are unaffected, & can see how the stream ended
+        let client = RemoteAgentClient::connect(&path).await.unwrap();
+        assert_eq!(client.list_agents().await.unwrap(), ["builds"]);
+        let tail = client.cache_tail("builds", 1).await.unwrap();
+        assert_eq!(tail[0].content, cached);
+    }
+}
@@ -0,0 +1,438 @@
+use super::{
+    read_frame, write_frame, AgentEvent, IpcError, IpcFrame, IpcRequest, IpcResponse, IpcResult,
+    IPC_VERSION,
+};
+use crate::{
+    agents::Agent,
+    language_models::completions::streaming::{
+        lock_agent, CancelReason, CollectedCompletion, CompletionStreamStatus, StreamCanceller,
+        StreamError, WireStreamError, DEFAULT_AGENT_LOCK_TIMEOUT,
+    },
+};
+use std::{
+    collections::BTreeMap,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener, UnixStream,
+    },
+    sync::{broadcast, mpsc, Mutex},
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{debug, warn};
+
+/// Responses queued for a connection before the tasks producing them wait for it to catch up
+const FRAME_BUFFER: usize = 64;
+/// Events kept for subscribers that fall behind before they miss some
+const EVENT_BUFFER: usize = 256;
+
+/// Agents to serve over a Unix domain socket, by the names clients refer to them with
+#[derive(Debug, Default)]
+pub struct IpcServer {
+    agents: BTreeMap<String, Arc<Mutex<Agent>>>,
+}
+
+impl IpcServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `agent` as `name`, replacing any agent already served as `name`
+    pub fn with_agent(mut self, name: &str, agent: &Arc<Mutex<Agent>>) -> Self {
+        self.agents.insert(name.to_owned(), Arc::clone(agent));
+        self
+    }
+
+    /// Listens on a socket at `path`, readable & writable only by the current user since
+    /// clients can do anything with the agents. A socket left at `path` by a server that's no
+    /// longer running is replaced, one still accepting connections is an error
+    pub async fn bind(self, path: impl AsRef<Path>) -> IpcResult<IpcServerHandle> {
+        let path = path.as_ref().to_owned();
+        if path.exists() {
+            if UnixStream::connect(&path).await.is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("A server is already listening on {}", path.display()),
+                )
+                .into());
+            }
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let shared = Arc::new(Shared {
+            agents: self.agents,
+            events,
+        });
+        let task = tokio::spawn(accept(listener, shared));
+        Ok(IpcServerHandle {
+            path,
+            task,
+            stopped: false,
+        })
+    }
+}
+
+/// A running `IpcServer`. Dropping it stops the server, disconnecting its clients & removing
+/// its socket
+#[derive(Debug)]
+pub struct IpcServerHandle {
+    path: PathBuf,
+    task: JoinHandle<()>,
+    /// Set once the socket is removed, so it isn't removed again after another server binds
+    /// the same path
+    stopped: bool,
+}
+
+impl IpcServerHandle {
+    /// Where the server's socket is bound
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the server is still accepting connections. False once it has been stopped,
+    /// though an aborted server may take until the runtime's next poll to finish
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    /// Stops the server, disconnecting its clients & removing its socket so a new server can
+    /// bind the path. Dropping the handle does the same, stopping an already stopped server
+    /// does nothing
+    pub fn stop(&mut self) {
+        if self.stopped {
+            return;
+        }
+        self.stopped = true;
+        self.task.abort();
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!(
+                    "Failed to remove IPC socket {}: {}",
+                    self.path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+impl Drop for IpcServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    agents: BTreeMap<String, Arc<Mutex<Agent>>>,
+    events: broadcast::Sender<(String, AgentEvent)>,
+}
+
+impl Shared {
+    fn agent(&self, name: &str) -> Result<&Arc<Mutex<Agent>>, String> {
+        self.agents
+            .get(name)
+            .ok_or_else(|| format!("No agent named {}", name))
+    }
+
+    fn emit(&self, agent: &str, event: AgentEvent) {
+        // Nobody is subscribed when sending fails
+        let _ = self.events.send((agent.to_owned(), event));
+    }
+}
+
+async fn accept(listener: UnixListener, shared: Arc<Shared>) {
+    // Aborted along with the accept loop when the server stops
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    connections.spawn(serve(Arc::clone(&shared), stream));
+                }
+                Err(err) => warn!("Failed to accept IPC connection: {}", err),
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+}
+
+async fn serve(shared: Arc<Shared>, stream: UnixStream) {
+    let (mut reader, mut writer) = stream.into_split();
+    if let Err(err) = handshake(&mut reader, &mut writer).await {
+        debug!("IPC client refused: {}", err);
+        return;
+    }
+    let (frames, mut outgoing) = mpsc::channel::<IpcFrame<IpcResponse>>(FRAME_BUFFER);
+    let writing = tokio::spawn(async move {
+        while let Some(frame) = outgoing.recv().await {
+            if write_frame(&mut writer, &frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let mut connection = Connection {
+        shared,
+        frames,
+        writing,
+        completions: vec![],
+        subscriptions: JoinSet::new(),
+    };
+    if let Err(err) = connection.run(&mut reader).await {
+        debug!("IPC connection ended: {}", err);
+    }
+}
+
+async fn handshake(reader: &mut OwnedReadHalf, writer: &mut OwnedWriteHalf) -> IpcResult<()> {
+    let Some(frame) = read_frame::<_, IpcRequest>(reader).await? else {
+        return Err(IpcError::Closed);
+    };
+    let refusal = match frame.body {
+        IpcRequest::Hello { version } if version == IPC_VERSION => None,
+        IpcRequest::Hello { version } => Some(format!(
+            "Unsupported protocol version {}, expected {}",
+            version, IPC_VERSION
+        )),
+        _ => Some("Expected a hello".to_owned()),
+    };
+    let body = match &refusal {
+        None => IpcResponse::Welcome {
+            version: IPC_VERSION,
+        },
+        Some(message) => IpcResponse::Error {
+            message: message.to_owned(),
+        },
+    };
+    write_frame(writer, &IpcFrame { id: frame.id, body }).await?;
+    match refusal {
+        None => Ok(()),
+        Some(reason) => Err(IpcError::Handshake(reason)),
+    }
+}
+
+/// One client's requests, along with the completions & subscriptions it started, which end
+/// when it disconnects
+struct Connection {
+    shared: Arc<Shared>,
+    frames: mpsc::Sender<IpcFrame<IpcResponse>>,
+    writing: JoinHandle<()>,
+    /// Cancellers of the completions the client started, with the tasks streaming them
+    completions: Vec<(StreamCanceller, JoinHandle<()>)>,
+    subscriptions: JoinSet<()>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Stopping the writer first fails the completions' sends even if they haven't attached
+        // their canceller yet
+        self.writing.abort();
+        for (canceller, _) in &self.completions {
+            canceller.cancel(CancelReason::User);
+        }
+    }
+}
+
+impl Connection {
+    async fn run(&mut self, reader: &mut OwnedReadHalf) -> IpcResult<()> {
+        while let Some(frame) = read_frame::<_, IpcRequest>(reader).await? {
+            self.completions.retain(|(_, task)| !task.is_finished());
+            let body = match self.respond(frame.id, frame.body).await {
+                Ok(Some(body)) => body,
+                Ok(None) => continue,
+                Err(message) => IpcResponse::Error { message },
+            };
+            if self
+                .frames
+                .send(IpcFrame { id: frame.id, body })
+                .await
+                .is_err()
+            {
+                return Err(IpcError::Closed);
+            }
+        }
+        Ok(())
+    }
+
+    /// The immediate response to a request if any, or why it was refused
+    async fn respond(
+        &mut self,
+        id: u64,
+        request: IpcRequest,
+    ) -> Result<Option<IpcResponse>, String> {
+        let response = match request {
+            IpcRequest::Hello { .. } => return Err("Already connected".to_owned()),
+            IpcRequest::ListAgents => IpcResponse::Agents {
+                names: self.shared.agents.keys().cloned().collect(),
+            },
+            IpcRequest::CacheTail { agent, count } => {
+                let agent = lock(self.shared.agent(&agent)?).await?;
+                let messages = agent.cache.as_ref();
+                let start = messages.len().saturating_sub(count);
+                IpcResponse::Messages {
+                    messages: messages[start..].to_vec(),
+                }
+            }
+            IpcRequest::PushMessage {
+                agent: name,
+                message,
+            } => {
+                let mut agent = lock(self.shared.agent(&name)?).await?;
+                agent.cache.push(message.clone());
+                self.shared
+                    .emit(&name, AgentEvent::MessagePushed { message });
+                IpcResponse::Pushed
+            }
+            IpcRequest::Complete { agent: name } => {
+                let agent = Arc::clone(self.shared.agent(&name)?);
+                let canceller = StreamCanceller::new();
+                let completion = Completion {
+                    shared: Arc::clone(&self.shared),
+                    name,
+                    id,
+                    frames: self.frames.clone(),
+                    canceller: canceller.clone(),
+                };
+                let task = tokio::spawn(completion.run(agent));
+                self.completions.push((canceller, task));
+                return Ok(None);
+            }
+            IpcRequest::Subscribe { agents } => {
+                for name in &agents {
+                    self.shared.agent(name)?;
+                }
+                let events = self.shared.events.subscribe();
+                let frames = self.frames.clone();
+                self.subscriptions
+                    .spawn(forward(events, agents, id, frames));
+                IpcResponse::Subscribed
+            }
+        };
+        Ok(Some(response))
+    }
+}
+
+async fn lock(agent: &Mutex<Agent>) -> Result<tokio::sync::MutexGuard<'_, Agent>, String> {
+    lock_agent(agent, DEFAULT_AGENT_LOCK_TIMEOUT)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Sends a subscriber the events of the agents it subscribed to
+async fn forward(
+    mut events: broadcast::Receiver<(String, AgentEvent)>,
+    agents: Vec<String>,
+    id: u64,
+    frames: mpsc::Sender<IpcFrame<IpcResponse>>,
+) {
+    loop {
+        let (agent, event) = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("IPC subscriber fell behind, missing {} events", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if !agents.is_empty() && !agents.contains(&agent) {
+            continue;
+        }
+        let body = IpcResponse::Event { agent, event };
+        if frames.send(IpcFrame { id, body }).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// A completion streamed to the client that requested it & every subscriber
+struct Completion {
+    shared: Arc<Shared>,
+    name: String,
+    id: u64,
+    frames: mpsc::Sender<IpcFrame<IpcResponse>>,
+    canceller: StreamCanceller,
+}
+
+impl Completion {
+    async fn run(self, agent: Arc<Mutex<Agent>>) {
+        let handler = match lock_agent(&agent, DEFAULT_AGENT_LOCK_TIMEOUT).await {
+            Ok(mut agent) => agent
+                .stream_completion()
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        let mut handler = match handler {
+            Ok(handler) => handler.with_canceller(&self.canceller),
+            Err(message) => {
+                let body = IpcResponse::Error { message };
+                let _ = self.frames.send(IpcFrame { id: self.id, body }).await;
+                return;
+            }
+        };
+        // The client may have gone before the canceller was attached
+        if self.frames.is_closed() {
+            self.canceller.cancel(CancelReason::User);
+        }
+        let event = loop {
+            let received = handler.receive_shared(&agent).await;
+            let completion = match received {
+                Ok(Some(CompletionStreamStatus::Working(token))) => {
+                    if !token.is_empty() {
+                        self.send(AgentEvent::Token { token }).await;
+                    }
+                    continue;
+                }
+                Err(StreamError::ReceiverTimeout) => continue,
+                Ok(Some(CompletionStreamStatus::Finished(content))) => {
+                    CollectedCompletion::Finished(content)
+                }
+                Ok(Some(CompletionStreamStatus::ToolCalls(calls))) => {
+                    CollectedCompletion::ToolCalls(calls)
+                }
+                Ok(Some(CompletionStreamStatus::Truncated(content))) => {
+                    CollectedCompletion::Partial {
+                        content,
+                        reason: CancelReason::ContentLimit,
+                    }
+                }
+                Err(StreamError::Cancelled(reason)) => CollectedCompletion::Partial {
+                    content: handler.message_content().to_owned(),
+                    reason,
+                },
+                Ok(None) => break failed(&StreamError::PrematureClose),
+                Err(err) => break failed(&err),
+            };
+            break AgentEvent::Finished { completion };
+        };
+        self.send(event).await;
+    }
+
+    /// Sends `event` to subscribers & the client, cancelling the stream if the client is gone
+    async fn send(&self, event: AgentEvent) {
+        self.shared.emit(&self.name, event.clone());
+        let body = IpcResponse::Event {
+            agent: self.name.clone(),
+            event,
+        };
+        if self
+            .frames
+            .send(IpcFrame { id: self.id, body })
+            .await
+            .is_err()
+        {
+            self.canceller.cancel(CancelReason::User);
+        }
+    }
+}
+
+fn failed(err: &StreamError) -> AgentEvent {
+    AgentEvent::Failed {
+        error: WireStreamError::from(err),
+    }
+}
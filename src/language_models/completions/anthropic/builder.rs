@@ -1,8 +1,9 @@
 use super::{
     super::{
+        capabilities::ModelCapabilities,
         error::CompletionResult,
         inference::{CompletionRequest, CompletionRequestBuilder},
-        ModelParameters,
+        ModelParameters, TokenPrice,
     },
     requests::AnthropicIoRequest,
 };
@@ -23,6 +24,32 @@ const OPUS_MODEL_STR: &str = "claude-3-opus-20240229";
 const SONNET_MODEL_STR: &str = "claude-3-sonnet-20240229";
 const HAIKU_MODEL_STR: &str = "claude-3-haiku-20240307";
 
+/// All claude 3 models share the same context window
+const CLAUDE_3_CONTEXT_WINDOW: u32 = 200_000;
+/// Claude models don't take penalties or `n`, function calls aren't implemented for them
+const CLAUDE_3_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    function_calling: false,
+    streaming: true,
+    penalties: false,
+    multiple_choices: false,
+    max_temperature: 100,
+    max_output_tokens: Some(4096),
+    structured_output: false,
+};
+
+const OPUS_PRICE: TokenPrice = TokenPrice {
+    input: 0.015,
+    output: 0.075,
+};
+const SONNET_PRICE: TokenPrice = TokenPrice {
+    input: 0.003,
+    output: 0.015,
+};
+const HAIKU_PRICE: TokenPrice = TokenPrice {
+    input: 0.00025,
+    output: 0.00125,
+};
+
 impl CompletionRequestBuilder for AnthropicCompletionModel {
     fn model_str(&self) -> &str {
         match self {
@@ -32,8 +59,36 @@ impl CompletionRequestBuilder for AnthropicCompletionModel {
         }
     }
 
-    fn url_str(&self) -> &str {
-        "https://api.anthropic.com/v1/messages"
+    fn url(&self) -> String {
+        "https://api.anthropic.com/v1/messages".to_string()
+    }
+
+    fn price_per_k_tokens(&self) -> TokenPrice {
+        match self {
+            Self::Opus => OPUS_PRICE,
+            Self::Sonnet => SONNET_PRICE,
+            Self::Haiku => HAIKU_PRICE,
+        }
+    }
+
+    fn context_window(&self) -> u32 {
+        CLAUDE_3_CONTEXT_WINDOW
+    }
+
+    fn supports_assistant_prefill(&self) -> bool {
+        true
+    }
+
+    fn default_params(&self) -> ModelParameters {
+        ModelParameters {
+            n: None,
+            max_tokens: Some(1000),
+            ..Default::default()
+        }
+    }
+
+    fn capabilities(&self) -> ModelCapabilities {
+        CLAUDE_3_CAPABILITIES
     }
 
     fn headers(&self, api_key: &str) -> HeaderMap {
@@ -54,6 +109,7 @@ impl CompletionRequestBuilder for AnthropicCompletionModel {
                 Some(mut m) => {
                     if message.role.to_string() == m.role.to_string() {
                         m.content = format!("{}. {}", m.content, message.content);
+                        m.attachments.extend(message.attachments);
                         last_message = Some(m);
                     } else {
                         let val: Value = m.into();
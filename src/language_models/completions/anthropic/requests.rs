@@ -2,22 +2,18 @@ use super::{
     super::{
         error::{CompletionResult, ProviderResponseError},
         inference::{CompletionRequest, CompletionRequestBuilder, CompletionResponse},
-        ModelParameters,
+        ModelParameters, TokenUsage,
     },
     builder::AnthropicCompletionModel,
     streaming::AnthropicStreamResponse,
 };
 use crate::agents::memory::{MessageRole, MessageStack};
-use crate::language_models::completions::error::CompletionError;
 use crate::language_models::completions::inference::ProcessResponseReturn;
 use crate::language_models::completions::streaming::{
-    CompletionStream, ProviderStreamHandler, StreamedCompletionHandler,
+    sse::response_event_stream, CompletionStream, ProviderStreamHandler, StreamedCompletionHandler,
 };
-use futures::TryStreamExt;
-use reqwest_streams::JsonStreamResponse;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::time::Duration;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct AnthropicIoRequest {
@@ -75,21 +71,17 @@ impl CompletionRequest for AnthropicIoRequest {
                     match response {
                         AnthropicResponse::Success(mut suc) => {
                             let content = suc.content.remove(0).text;
-                            Ok(CompletionResponse::from(content))
+                            Ok(CompletionResponse::Io {
+                                content,
+                                usage: suc.usage.into(),
+                                finish_reason: suc.stop_reason,
+                            })
                         }
                         AnthropicResponse::Err { error } => Err(error.into_error()),
                     }
                 }
                 true => {
-                    let response_stream: CompletionStream = Box::new(
-                        tokio::time::timeout(Duration::from_secs(10), async {
-                            response
-                                .json_array_stream::<Value>(1024)
-                                .map_err(|err| err.into())
-                        })
-                        .await
-                        .map_err(|_| CompletionError::StreamTimeout)?,
-                    );
+                    let response_stream: CompletionStream = response_event_stream(response).await?;
                     let handler: ProviderStreamHandler =
                         StreamedCompletionHandler::<AnthropicStreamResponse>::from(response_stream)
                             .into();
@@ -111,6 +103,8 @@ pub enum AnthropicResponse {
 pub struct AnthropicSuccess {
     content: Vec<AnthropicResponseContent>,
     usage: AnthropicUsage,
+    #[serde(default)]
+    stop_reason: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -129,3 +123,12 @@ pub struct AnthropicUsage {
     input_tokens: i32,
     output_tokens: i32,
 }
+
+impl From<AnthropicUsage> for TokenUsage {
+    fn from(value: AnthropicUsage) -> Self {
+        Self {
+            input_tokens: value.input_tokens.max(0) as u32,
+            output_tokens: value.output_tokens.max(0) as u32,
+        }
+    }
+}
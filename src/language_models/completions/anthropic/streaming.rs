@@ -1,7 +1,58 @@
-use crate::language_models::completions::streaming::{CompletionStreamStatus, StreamResponse};
+use crate::language_models::completions::{
+    streaming::{CompletionStreamStatus, StreamResponse, ToolCallDelta},
+    TokenUsage,
+};
 use serde::Deserialize;
 
-impl StreamResponse for AnthropicStreamResponse {}
+impl StreamResponse for AnthropicStreamResponse {
+    fn usage(&self) -> Option<TokenUsage> {
+        let usage = match self {
+            Self::MessageStart { message } => &message.usage,
+            Self::MessageDelta { usage, .. } => usage,
+            _ => return None,
+        };
+        Some(TokenUsage {
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+        })
+    }
+
+    fn finish_reason(&self) -> Option<String> {
+        match self {
+            Self::MessageDelta { delta, .. } => delta.stop_reason.to_owned(),
+            _ => None,
+        }
+    }
+
+    fn tool_call_deltas(&self) -> Vec<ToolCallDelta> {
+        let delta = match self {
+            Self::ContentBlockStart {
+                index,
+                content_block: ContentBlock::ToolUse { id, name },
+            } => ToolCallDelta {
+                index: *index,
+                id: Some(id.to_owned()),
+                name: Some(name.to_owned()),
+                arguments: String::new(),
+            },
+            Self::ContentBlockDelta {
+                index,
+                delta: Delta::InputJsonDelta { partial_json },
+            } => ToolCallDelta {
+                index: *index,
+                id: None,
+                name: None,
+                arguments: partial_json.to_owned(),
+            },
+            _ => return vec![],
+        };
+        vec![delta]
+    }
+
+    fn is_tool_call_finish(reason: &str) -> bool {
+        reason == "tool_use"
+    }
+}
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type")]
@@ -39,10 +90,13 @@ struct Message {
 }
 
 #[derive(Debug, Deserialize, Clone)]
-struct ContentBlock {
-    #[serde(rename = "type")]
-    block_type: String,
-    text: String,
+#[serde(tag = "type")]
+enum ContentBlock {
+    #[serde(rename = "text")]
+    Text { text: String },
+    /// Input is always empty when the block starts, it is streamed as `input_json_delta`s
+    #[serde(rename = "tool_use")]
+    ToolUse { id: String, name: String },
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -50,12 +104,16 @@ struct ContentBlock {
 enum Delta {
     #[serde(rename = "text_delta")]
     TextDelta { text: String },
+    #[serde(rename = "input_json_delta")]
+    InputJsonDelta { partial_json: String },
 }
 
 impl Delta {
     fn inner_text(self) -> String {
         match self {
             Self::TextDelta { text } => text,
+            // Tool input isn't part of the message content
+            Self::InputJsonDelta { .. } => String::new(),
         }
     }
 }
@@ -68,6 +126,8 @@ struct MessageDelta {
 
 #[derive(Debug, Deserialize, Clone)]
 struct Usage {
+    /// `message_delta` events only report output tokens
+    #[serde(default)]
     input_tokens: u32,
     output_tokens: u32,
 }
@@ -75,7 +135,7 @@ struct Usage {
 impl Into<CompletionStreamStatus> for AnthropicStreamResponse {
     fn into(self) -> CompletionStreamStatus {
         match self {
-            Self::MessageStop | Self::ContentBlockStop { .. } => CompletionStreamStatus::Finished,
+            Self::MessageStop => CompletionStreamStatus::Finished(String::new()),
             Self::ContentBlockDelta { delta, .. } => {
                 return CompletionStreamStatus::Working(delta.inner_text());
             }
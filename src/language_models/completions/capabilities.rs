@@ -0,0 +1,164 @@
+//! What each model supports, so requests it would reject are refused before they are sent
+use super::{
+    error::{CompletionError, CompletionResult},
+    ModelParameters,
+};
+use serde::{Deserialize, Serialize};
+
+/// The kind of completion being requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Io,
+    Stream,
+    Function,
+    /// An io completion whose reply must follow a JSON schema
+    Structured,
+}
+
+/// Features & parameter ranges a model accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelCapabilities {
+    pub function_calling: bool,
+    pub streaming: bool,
+    /// Whether `frequency_penalty` & `presence_penalty` are accepted
+    pub penalties: bool,
+    /// Whether more than one choice can be generated with `n`
+    pub multiple_choices: bool,
+    /// Highest temperature accepted, in the units of `ModelParameters::temperature`
+    pub max_temperature: u8,
+    pub max_output_tokens: Option<u32>,
+    /// Whether replies can be held to a JSON schema sent with the request, such as OpenAi's
+    /// `response_format`
+    #[serde(default)]
+    pub structured_output: bool,
+}
+
+impl ModelCapabilities {
+    /// Assumed for models without known capabilities, nothing is refused
+    pub const PERMISSIVE: Self = Self {
+        function_calling: true,
+        streaming: true,
+        penalties: true,
+        multiple_choices: true,
+        max_temperature: u8::MAX,
+        max_output_tokens: None,
+        structured_output: true,
+    };
+
+    /// Checks that a request of `kind` with `params` only uses what the model supports. `model`
+    /// is the model's name, used in the error
+    pub fn validate(
+        &self,
+        model: &str,
+        params: &ModelParameters,
+        kind: RequestKind,
+    ) -> CompletionResult<()> {
+        let unsupported = |feature: String| CompletionError::Unsupported {
+            model: model.to_owned(),
+            feature,
+        };
+        match kind {
+            RequestKind::Function if !self.function_calling => {
+                return Err(unsupported("tool calling".to_string()))
+            }
+            RequestKind::Stream if !self.streaming => {
+                return Err(unsupported("streaming".to_string()))
+            }
+            RequestKind::Structured if !self.structured_output => {
+                return Err(unsupported("structured output".to_string()))
+            }
+            _ => {}
+        }
+        if !self.penalties {
+            if params.frequency_penalty.is_some() {
+                return Err(unsupported("frequency_penalty".to_string()));
+            }
+            if params.presence_penalty.is_some() {
+                return Err(unsupported("presence_penalty".to_string()));
+            }
+        }
+        if !self.multiple_choices && params.n.is_some_and(|n| n > 1) {
+            return Err(unsupported("more than one choice".to_string()));
+        }
+        if let Some(temperature) = params.temperature.filter(|t| *t > self.max_temperature) {
+            return Err(unsupported(format!(
+                "a temperature of {}, the maximum is {}",
+                temperature as f32 / 100.0,
+                self.max_temperature as f32 / 100.0
+            )));
+        }
+        if let (Some(max_tokens), Some(limit)) = (params.max_tokens, self.max_output_tokens) {
+            if max_tokens > limit {
+                return Err(unsupported(format!(
+                    "max_tokens of {}, the maximum is {}",
+                    max_tokens, limit
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::CompletionModel;
+
+    #[cfg(all(feature = "openai", feature = "anthropic"))]
+    #[test]
+    fn unsupported_parameters_refused() {
+        let anthropic = CompletionModel::default_anthropic("");
+        assert!(anthropic.validate(RequestKind::Io).is_ok());
+        match anthropic.validate(RequestKind::Function) {
+            Err(err @ CompletionError::Unsupported { .. }) => assert_eq!(
+                err.to_string(),
+                "Model claude-3-opus-20240229 does not support tool calling"
+            ),
+            other => panic!("expected unsupported, got {:?}", other),
+        }
+
+        let mut params = ModelParameters {
+            temperature: Some(150),
+            ..Default::default()
+        };
+        let capabilities = anthropic.capabilities();
+        assert!(capabilities
+            .validate("claude", &params, RequestKind::Io)
+            .is_err());
+        params.temperature = Some(100);
+        params.presence_penalty = Some(1);
+        assert!(capabilities
+            .validate("claude", &params, RequestKind::Io)
+            .is_err());
+        params.presence_penalty = None;
+        params.max_tokens = Some(100_000);
+        assert!(capabilities
+            .validate("claude", &params, RequestKind::Stream)
+            .is_err());
+
+        // Other models accept the same parameters
+        let openai = CompletionModel::default_openai("");
+        params.max_tokens = Some(1000);
+        params.temperature = Some(150);
+        params.presence_penalty = Some(1);
+        assert!(openai
+            .capabilities()
+            .validate("gpt", &params, RequestKind::Function)
+            .is_ok());
+        // Capabilities can be overridden, for models the table doesn't know
+        let limited = openai.with_capabilities(ModelCapabilities {
+            streaming: false,
+            ..ModelCapabilities::PERMISSIVE
+        });
+        assert!(limited.validate(RequestKind::Stream).is_err());
+        assert!(limited.validate(RequestKind::Io).is_ok());
+        assert!(limited.validate(RequestKind::Structured).is_ok());
+        match CompletionModel::default_openai("").validate(RequestKind::Structured) {
+            Err(err) => assert_eq!(
+                err.to_string(),
+                "Model gpt-3.5-turbo-0125 does not support structured output"
+            ),
+            Ok(()) => panic!("expected structured output to be unsupported"),
+        }
+    }
+}
@@ -15,6 +15,17 @@ pub enum CompletionError {
     FunctionNotImplemented,
     StreamTimeout,
     CouldNotCoerce,
+    BudgetExceeded,
+    /// A streamed completion's response wasn't `text/event-stream`, contains the body's start
+    UnexpectedContentType {
+        content_type: String,
+        snippet: String,
+    },
+    /// The model doesn't support a feature or parameter value a request uses
+    Unsupported {
+        model: String,
+        feature: String,
+    },
 }
 
 pub trait ProviderResponseError: Debug {
@@ -38,6 +49,14 @@ impl Display for CompletionError {
             Self::StreamTimeout => "Stream Timeout".to_string(),
             Self::Provider(err) => err.to_string(),
             Self::CouldNotCoerce => "Could Not Coerce".to_string(),
+            Self::BudgetExceeded => "Budget Exceeded".to_string(),
+            Self::UnexpectedContentType {
+                content_type,
+                snippet,
+            } => format!("Expected SSE, got {}: {}", content_type, snippet),
+            Self::Unsupported { model, feature } => {
+                format!("Model {} does not support {}", model, feature)
+            }
             Self::FunctionNotImplemented => "Function Not Implemented".to_string(),
         };
         write!(f, "{}", display)
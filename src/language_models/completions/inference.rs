@@ -1,8 +1,9 @@
 use super::{
+    capabilities::ModelCapabilities,
     error::{CompletionError, CompletionResult},
     functions::Function,
     streaming::ProviderStreamHandler,
-    ModelParameters,
+    ModelParameters, TokenPrice, TokenUsage,
 };
 use crate::agents::memory::MessageStack;
 use futures::Future;
@@ -14,9 +15,27 @@ use std::{fmt::Debug, pin::Pin};
 #[allow(unused)]
 pub(crate) trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
     fn model_str(&self) -> &str;
-    fn url_str(&self) -> &str;
+    fn url(&self) -> String;
+    fn price_per_k_tokens(&self) -> TokenPrice;
+    /// Maximum number of tokens of input and output combined
+    fn context_window(&self) -> u32;
+    /// Whether a trailing assistant message is continued by the model rather than replied to
+    fn supports_assistant_prefill(&self) -> bool {
+        false
+    }
+    /// Features the model supports, requests using others are refused before being sent
+    fn capabilities(&self) -> ModelCapabilities {
+        ModelCapabilities::PERMISSIVE
+    }
+    /// Parameters requests use where the model's overrides leave them unset
+    fn default_params(&self) -> ModelParameters {
+        ModelParameters::default()
+    }
     fn serialize_messages(&self, stack: &MessageStack) -> Value;
     fn headers(&self, api_key: &str) -> HeaderMap;
+    /// Adds `schema` to the body of an io request as the format its reply must follow. Only
+    /// called for models with `ModelCapabilities::structured_output`
+    fn add_response_format(&self, body: &mut Value, name: &str, schema: &Value) {}
     fn into_io_req(
         &self,
         stack: &MessageStack,
@@ -43,6 +62,9 @@ pub(crate) trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
     fn process_function_response(&self, response_json: Value) -> CompletionResult<Value> {
         Err(CompletionError::FunctionNotImplemented)
     }
+    fn usage_from_function_response(&self, response_json: &Value) -> Option<TokenUsage> {
+        None
+    }
 }
 
 pub type ProcessResponseReturn<'r> =
@@ -57,23 +79,21 @@ pub trait CompletionRequest: Debug + Sync + Send + 'static {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CompletionResponse {
     /// For IO completions
-    Io(String),
+    Io {
+        content: String,
+        usage: TokenUsage,
+        finish_reason: Option<String>,
+    },
     /// For streamed completions
     #[serde(skip)]
-    Stream(ProviderStreamHandler),
+    Stream(Box<ProviderStreamHandler>),
     /// For function inference
     Function(Value),
 }
 
-impl From<String> for CompletionResponse {
-    fn from(value: String) -> Self {
-        Self::Io(value)
-    }
-}
-
 impl From<ProviderStreamHandler> for CompletionResponse {
     fn from(value: ProviderStreamHandler) -> Self {
-        Self::Stream(value)
+        Self::Stream(Box::new(value))
     }
 }
 
@@ -86,8 +106,8 @@ impl From<Value> for CompletionResponse {
 impl TryInto<String> for CompletionResponse {
     type Error = CompletionError;
     fn try_into(self) -> Result<String, Self::Error> {
-        if let Self::Io(s) = self {
-            return Ok(s);
+        if let Self::Io { content, .. } = self {
+            return Ok(content);
         }
         Err(CompletionError::CouldNotCoerce)
     }
@@ -97,7 +117,7 @@ impl TryInto<ProviderStreamHandler> for CompletionResponse {
     type Error = CompletionError;
     fn try_into(self) -> Result<ProviderStreamHandler, Self::Error> {
         if let Self::Stream(s) = self {
-            return Ok(s);
+            return Ok(*s);
         }
         Err(CompletionError::CouldNotCoerce)
     }
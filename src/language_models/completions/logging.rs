@@ -0,0 +1,252 @@
+//! Optional JSONL logging of every completion's request & response, for auditing, and tracing
+//! of the messages each completion is sent
+use super::TokenUsage;
+use crate::agents::memory::MessageStack;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const LOG_FILE_PREFIX: &str = "completions-";
+const LOG_FILE_EXTENSION: &str = "jsonl";
+/// What redacted text is replaced with in traced context
+const REDACTED: &str = "[redacted]";
+
+/// One line of a completion log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompletionLogEntry {
+    /// Milliseconds since the unix epoch when the completion finished
+    pub timestamp: u64,
+    pub agent_id: String,
+    /// The JSON body that was sent. Headers, which contain the api key, are never logged
+    pub request_body: Value,
+    pub response_text: String,
+    pub usage: TokenUsage,
+    pub finish_reason: Option<String>,
+}
+
+impl CompletionLogEntry {
+    pub(crate) fn new(
+        agent_id: &str,
+        request_body: Value,
+        response_text: &str,
+        usage: TokenUsage,
+        finish_reason: Option<String>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        Self {
+            timestamp,
+            agent_id: agent_id.to_owned(),
+            request_body,
+            response_text: response_text.to_owned(),
+            usage,
+            finish_reason,
+        }
+    }
+}
+
+/// A logger attached to a model, along with the id its entries are logged under
+#[derive(Debug, Clone)]
+pub(crate) struct AttachedLogger {
+    pub(crate) logger: std::sync::Arc<CompletionLogger>,
+    pub(crate) agent_id: String,
+}
+
+#[derive(Debug)]
+struct LogFileState {
+    index: usize,
+    size: u64,
+}
+
+/// Appends completion log entries to `completions-<n>.jsonl` files in a directory, starting a
+/// new file once the current one would grow past `max_file_bytes`. Share one logger between
+/// models with an `Arc`
+#[derive(Debug)]
+pub struct CompletionLogger {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    state: Mutex<LogFileState>,
+}
+
+impl CompletionLogger {
+    /// Creates `dir` if it doesn't exist, continues from the newest existing log file in it
+    pub fn new(dir: impl AsRef<Path>, max_file_bytes: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let index = fs::read_dir(&dir)?
+            .filter_map(|entry| log_file_index(&entry.ok()?.path()))
+            .max()
+            .unwrap_or_default();
+        let size = fs::metadata(log_file_path(&dir, index))
+            .map(|m| m.len())
+            .unwrap_or_default();
+        Ok(Self {
+            dir,
+            max_file_bytes,
+            state: Mutex::new(LogFileState { index, size }),
+        })
+    }
+
+    /// Path of the file currently being written to
+    pub fn current_file(&self) -> PathBuf {
+        let state = self.state.lock().expect("log state lock poisoned");
+        log_file_path(&self.dir, state.index)
+    }
+
+    pub fn log(&self, entry: &CompletionLogEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut state = self.state.lock().expect("log state lock poisoned");
+        if state.size > 0 && state.size + line.len() as u64 > self.max_file_bytes {
+            state.index += 1;
+            state.size = 0;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file_path(&self.dir, state.index))?;
+        file.write_all(line.as_bytes())?;
+        state.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Records the messages sent with each completion as a debug level tracing event, along with
+/// the request's id, which is also recorded on the completion's span
+#[derive(Debug, Clone)]
+pub struct ContextTrace {
+    /// Message contents are cut to this many characters
+    pub max_message_chars: usize,
+    /// Matches of these are replaced with `[redacted]` before anything is recorded
+    pub redact: Vec<Regex>,
+}
+
+impl ContextTrace {
+    pub fn new(max_message_chars: usize) -> Self {
+        Self {
+            max_message_chars,
+            redact: vec![],
+        }
+    }
+
+    pub fn with_redaction(mut self, pattern: Regex) -> Self {
+        self.redact.push(pattern);
+        self
+    }
+
+    /// Roles & redacted, truncated contents of the messages
+    fn messages(&self, stack: &MessageStack) -> Value {
+        let messages: Vec<Value> = stack
+            .as_ref()
+            .iter()
+            .map(|message| {
+                let content = self
+                    .redact
+                    .iter()
+                    .fold(message.content.to_owned(), |content, pattern| {
+                        pattern.replace_all(&content, REDACTED).into_owned()
+                    });
+                let len = content.chars().count();
+                let content = match len > self.max_message_chars {
+                    true => format!(
+                        "{}… ({} more characters)",
+                        content
+                            .chars()
+                            .take(self.max_message_chars)
+                            .collect::<String>(),
+                        len - self.max_message_chars
+                    ),
+                    false => content,
+                };
+                json!({"role": message.role.to_string(), "content": content})
+            })
+            .collect();
+        Value::Array(messages)
+    }
+
+    pub(crate) fn record(&self, request_id: &str, stack: &MessageStack) {
+        if tracing::enabled!(tracing::Level::DEBUG) {
+            tracing::debug!(request_id, messages = %self.messages(stack), "Completion context");
+        }
+    }
+}
+
+fn log_file_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!(
+        "{}{}.{}",
+        LOG_FILE_PREFIX, index, LOG_FILE_EXTENSION
+    ))
+}
+
+fn log_file_index(path: &Path) -> Option<usize> {
+    if path.extension()? != LOG_FILE_EXTENSION {
+        return None;
+    }
+    path.file_stem()?
+        .to_str()?
+        .strip_prefix(LOG_FILE_PREFIX)?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::memory::Message;
+
+    #[test]
+    fn entries_rotate_into_new_files() {
+        let dir = std::env::temp_dir().join(format!("espionox-log-{}", uuid::Uuid::new_v4()));
+        let entry = CompletionLogEntry::new(
+            "agent",
+            json!({"model": "m"}),
+            "response",
+            TokenUsage::default(),
+            Some("stop".to_string()),
+        );
+        let line_len = serde_json::to_string(&entry).unwrap().len() as u64 + 1;
+
+        let logger = CompletionLogger::new(&dir, line_len * 2).unwrap();
+        for _ in 0..3 {
+            logger.log(&entry).unwrap();
+        }
+        let first = fs::read_to_string(log_file_path(&dir, 0)).unwrap();
+        assert_eq!(first.lines().count(), 2);
+        assert_eq!(logger.current_file(), log_file_path(&dir, 1));
+
+        let read: CompletionLogEntry = serde_json::from_str(first.lines().next().unwrap()).unwrap();
+        assert_eq!(read, entry);
+
+        // A new logger continues where the last one left off
+        let logger = CompletionLogger::new(&dir, line_len * 2).unwrap();
+        logger.log(&entry).unwrap();
+        let second = fs::read_to_string(log_file_path(&dir, 1)).unwrap();
+        assert_eq!(second.lines().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn traced_context_redacted_then_truncated() {
+        let stack = MessageStack::from(vec![
+            Message::new_system("Watch the pane"),
+            Message::new_user("export API_KEY=sk-abcdef123456 && cargo run"),
+        ]);
+        let trace = ContextTrace::new(30).with_redaction(Regex::new(r"sk-[a-z0-9]+").unwrap());
+        assert_eq!(
+            trace.messages(&stack),
+            json!([
+                {"role": "system", "content": "Watch the pane"},
+                {"role": "user", "content": "export API_KEY=[redacted] && c… (8 more characters)"},
+            ])
+        );
+    }
+}
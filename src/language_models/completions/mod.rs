@@ -1,50 +1,174 @@
+#[cfg(feature = "anthropic")]
 pub mod anthropic;
+pub mod capabilities;
 pub mod error;
 pub mod functions;
 #[cfg(feature = "bert")]
 pub mod huggingface;
 mod inference;
+pub mod logging;
+#[cfg(feature = "openai")]
 pub mod openai;
 pub mod streaming;
+#[cfg(test)]
+pub(crate) mod testing;
+#[cfg(feature = "anthropic")]
+use self::anthropic::builder::AnthropicCompletionModel;
+#[cfg(feature = "openai")]
+use self::openai::{
+    azure::AzureOpenAiDeployment,
+    builder::{OpenAiCompletionModel, ORGANIZATION_HEADER, PROJECT_HEADER},
+    responses::OpenAiResponsesModel,
+};
 use self::{
-    anthropic::builder::AnthropicCompletionModel, error::CompletionResult, functions::Function,
-    inference::CompletionRequestBuilder, openai::builder::OpenAiCompletionModel,
-    streaming::ProviderStreamHandler,
+    capabilities::{ModelCapabilities, RequestKind},
+    error::{CompletionError, CompletionResult},
+    functions::Function,
+    inference::{CompletionRequestBuilder, CompletionResponse},
+    logging::{AttachedLogger, CompletionLogEntry, CompletionLogger, ContextTrace},
+    streaming::{retry_after, ProviderStreamHandler, RetryCategory, RetryState, StreamDefaults},
 };
 
 use crate::agents::memory::MessageStack;
 use anyhow::anyhow;
-use reqwest::Client;
+use reqwest::{header::HeaderMap, Client, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc};
 use tracing::{info, warn};
 
+/// The providers of the enabled provider features. Without any there are no variants, the core
+/// builds but nothing can be requested
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CompletionProvider {
+    #[cfg(feature = "openai")]
     OpenAi(OpenAiCompletionModel),
+    #[cfg(feature = "anthropic")]
     Anthropic(AnthropicCompletionModel),
+    #[cfg(feature = "openai")]
+    AzureOpenAi(AzureOpenAiDeployment),
+    /// OpenAi's Responses API, rather than Chat Completions
+    #[cfg(feature = "openai")]
+    OpenAiResponses(OpenAiResponsesModel),
 }
 
+#[cfg(feature = "openai")]
 impl From<OpenAiCompletionModel> for CompletionProvider {
     fn from(value: OpenAiCompletionModel) -> Self {
         Self::OpenAi(value)
     }
 }
 
+#[cfg(feature = "anthropic")]
 impl From<AnthropicCompletionModel> for CompletionProvider {
     fn from(value: AnthropicCompletionModel) -> Self {
         Self::Anthropic(value)
     }
 }
 
+#[cfg(feature = "openai")]
+impl From<AzureOpenAiDeployment> for CompletionProvider {
+    fn from(value: AzureOpenAiDeployment) -> Self {
+        Self::AzureOpenAi(value)
+    }
+}
+
+#[cfg(feature = "openai")]
+impl From<OpenAiResponsesModel> for CompletionProvider {
+    fn from(value: OpenAiResponsesModel) -> Self {
+        Self::OpenAiResponses(value)
+    }
+}
+
 impl CompletionProvider {
     fn inner_builder(&self) -> Box<&dyn CompletionRequestBuilder> {
         match &self {
-            Self::OpenAi(b) => return Box::new(b),
-            Self::Anthropic(b) => return Box::new(b),
+            #[cfg(feature = "openai")]
+            Self::OpenAi(b) => Box::new(b),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(b) => Box::new(b),
+            #[cfg(feature = "openai")]
+            Self::AzureOpenAi(b) => Box::new(b),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(b) => Box::new(b),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
         }
     }
+
+    /// Name of the provider's model as sent in requests
+    pub fn model_str(&self) -> &str {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(b) => b.model_str(),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(b) => b.model_str(),
+            #[cfg(feature = "openai")]
+            Self::AzureOpenAi(b) => b.model_str(),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(b) => b.model_str(),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Dollar price per 1K tokens of the provider's model
+    pub fn price_per_k_tokens(&self) -> TokenPrice {
+        self.inner_builder().price_per_k_tokens()
+    }
+
+    /// Maximum number of tokens of input and output combined the provider's model accepts
+    pub fn context_window(&self) -> u32 {
+        self.inner_builder().context_window()
+    }
+
+    /// Whether the model continues a trailing assistant message instead of replying to it
+    pub fn supports_assistant_prefill(&self) -> bool {
+        self.inner_builder().supports_assistant_prefill()
+    }
+
+    /// Features & parameter ranges the provider's model supports
+    pub fn capabilities(&self) -> ModelCapabilities {
+        self.inner_builder().capabilities()
+    }
+
+    /// Parameters that suit the provider's model, used for whatever overrides leave unset
+    pub fn default_params(&self) -> ModelParameters {
+        self.inner_builder().default_params()
+    }
+}
+
+/// Dollar cost of 1K input & output tokens for a given model
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TokenPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+/// Token usage reported by a provider for a completion
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+impl TokenUsage {
+    pub fn total(&self) -> u32 {
+        self.input_tokens + self.output_tokens
+    }
+
+    /// Estimated dollar cost of this usage given a price table entry
+    pub fn cost(&self, price: &TokenPrice) -> f64 {
+        (self.input_tokens as f64 / 1000.0) * price.input
+            + (self.output_tokens as f64 / 1000.0) * price.output
+    }
+
+    /// Providers report usage as running totals while streaming, so merging keeps the largest
+    /// count seen for each field rather than summing them
+    pub(crate) fn merge(&mut self, other: TokenUsage) {
+        self.input_tokens = self.input_tokens.max(other.input_tokens);
+        self.output_tokens = self.output_tokens.max(other.output_tokens);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,6 +176,45 @@ pub struct CompletionModel {
     pub provider: CompletionProvider,
     pub params: ModelParameters,
     pub api_key: String,
+    /// Cumulative estimated spend in dollars of all completions made with this model
+    #[serde(default)]
+    pub total_spend: f64,
+    /// Optional dollar cap, once `total_spend` reaches it new completions are refused with
+    /// `CompletionError::BudgetExceeded`
+    #[serde(default)]
+    pub spend_cap: Option<f64>,
+    /// OpenAi organization requests are billed to, sent as the `OpenAI-Organization` header.
+    /// Ignored by other providers
+    #[serde(default)]
+    pub organization: Option<String>,
+    /// OpenAi project requests are billed to, sent as the `OpenAI-Project` header. Ignored by
+    /// other providers
+    #[serde(default)]
+    pub project: Option<String>,
+    /// Overrides the capabilities of the provider's model, such as for fine tunes or Azure
+    /// deployments of models the capabilities table doesn't know
+    #[serde(default)]
+    pub capabilities: Option<ModelCapabilities>,
+    /// Overrides the context window of the provider's model, such as for models the context
+    /// window table doesn't know
+    #[serde(default)]
+    pub context_window: Option<u32>,
+    /// Parameters set for every provider, merged over each provider's defaults when it is
+    /// switched to. Unset fields take the provider's default
+    #[serde(default)]
+    pub overrides: Option<ModelParameters>,
+    /// Sends requests here instead of the provider's url, such as to a proxy or a server
+    /// compatible with the provider's API
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(skip)]
+    logger: Option<AttachedLogger>,
+    #[serde(skip)]
+    context_trace: Option<ContextTrace>,
+    /// Timeouts & retry policy every stream requested with this model starts with. The retry
+    /// policy also retries any request answered with a rate limit or overloaded status
+    #[serde(skip)]
+    pub stream_defaults: StreamDefaults,
     #[serde(skip)]
     client: Client,
 }
@@ -69,6 +232,7 @@ impl PartialEq for CompletionModel {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ModelParameters {
     /// Total token usage count of the model
+    #[serde(default)]
     pub total_token_count: u32,
     /// What sampling temperature to use, between 0 and 2.
     /// Higher values like 0.8 will make the output more random,
@@ -109,6 +273,38 @@ impl ModelParameters {
     fn temperature(&self) -> Result<f32, anyhow::Error> {
         Ok((self.temperature.ok_or(anyhow!("No temperature"))? / 100) as f32)
     }
+
+    /// These parameters where they are set, `defaults` where they aren't. The token count is
+    /// kept from `self`
+    pub fn merged_onto(&self, defaults: &ModelParameters) -> ModelParameters {
+        ModelParameters {
+            total_token_count: self.total_token_count,
+            temperature: self.temperature.or(defaults.temperature),
+            frequency_penalty: self.frequency_penalty.or(defaults.frequency_penalty),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            n: self.n.or(defaults.n),
+            presence_penalty: self.presence_penalty.or(defaults.presence_penalty),
+        }
+    }
+
+    /// Drops the parameters a model with `capabilities` doesn't take, and lowers temperature &
+    /// `max_tokens` to its limits, so requests with them aren't refused
+    pub fn fitted_to(mut self, capabilities: &ModelCapabilities) -> ModelParameters {
+        if !capabilities.penalties {
+            self.frequency_penalty = None;
+            self.presence_penalty = None;
+        }
+        if !capabilities.multiple_choices && self.n.is_some_and(|n| n > 1) {
+            self.n = None;
+        }
+        self.temperature = self
+            .temperature
+            .map(|t| t.min(capabilities.max_temperature));
+        if let Some(limit) = capabilities.max_output_tokens {
+            self.max_tokens = self.max_tokens.map(|max| max.min(limit));
+        }
+        self
+    }
 }
 
 impl CompletionModel {
@@ -123,10 +319,22 @@ impl CompletionModel {
             params,
             client,
             api_key: api_key.to_owned(),
+            total_spend: 0.0,
+            spend_cap: None,
+            organization: None,
+            project: None,
+            capabilities: None,
+            context_window: None,
+            overrides: None,
+            url: None,
+            logger: None,
+            context_trace: None,
+            stream_defaults: StreamDefaults::default(),
         }
     }
 
     ///  openai gpt3 handler with 0.7 temp
+    #[cfg(feature = "openai")]
     pub fn default_openai(api_key: &str) -> CompletionModel {
         let provider = CompletionProvider::OpenAi(OpenAiCompletionModel::default());
         let client = reqwest::Client::new();
@@ -134,11 +342,23 @@ impl CompletionModel {
             provider,
             params: ModelParameters::default(),
             api_key: api_key.to_owned(),
+            total_spend: 0.0,
+            spend_cap: None,
+            organization: None,
+            project: None,
+            capabilities: None,
+            context_window: None,
+            overrides: None,
+            url: None,
+            logger: None,
+            context_trace: None,
+            stream_defaults: StreamDefaults::default(),
             client,
         }
     }
 
     ///  anthropic Haiku handler with 0.7 temp
+    #[cfg(feature = "anthropic")]
     pub fn default_anthropic(api_key: &str) -> CompletionModel {
         let provider = CompletionProvider::Anthropic(AnthropicCompletionModel::default());
         let client = reqwest::Client::new();
@@ -146,35 +366,301 @@ impl CompletionModel {
             provider,
             params: ModelParameters::default(),
             api_key: api_key.to_owned(),
+            total_spend: 0.0,
+            spend_cap: None,
+            organization: None,
+            project: None,
+            capabilities: None,
+            context_window: None,
+            overrides: None,
+            url: None,
+            logger: None,
+            context_trace: None,
+            stream_defaults: StreamDefaults::default(),
             client,
         }
     }
 
-    #[tracing::instrument(name = "io completion", skip_all)]
-    pub(crate) async fn get_io_completion(
+    /// Timeouts & retry policy every stream requested with this model starts with, unless set
+    /// on the stream's handler. Clones of the model, such as those given to other agents, keep
+    /// them. Requests answered with 429 or 503 are retried within the policy's budgets too
+    pub fn with_stream_defaults(mut self, defaults: StreamDefaults) -> Self {
+        self.stream_defaults = defaults;
+        self
+    }
+
+    /// Set a dollar cap on the total spend of this model
+    pub fn with_spend_cap(mut self, cap: f64) -> Self {
+        self.spend_cap = Some(cap);
+        self
+    }
+
+    /// Set the OpenAi organization to attribute usage to
+    pub fn with_organization(mut self, organization: &str) -> Self {
+        self.organization = Some(organization.to_owned());
+        self
+    }
+
+    /// Set the OpenAi project to attribute usage to
+    pub fn with_project(mut self, project: &str) -> Self {
+        self.project = Some(project.to_owned());
+        self
+    }
+
+    /// Send requests to `url` instead of the provider's url
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.url = Some(url.to_owned());
+        self
+    }
+
+    /// Override the capabilities requests are validated against
+    pub fn with_capabilities(mut self, capabilities: ModelCapabilities) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Override the context window budgets are estimated against
+    pub fn with_context_window(mut self, context_window: u32) -> Self {
+        self.context_window = Some(context_window);
+        self
+    }
+
+    /// Parameters set regardless of the provider, the provider's defaults fill in the rest. The
+    /// current parameters are rebuilt from them
+    pub fn with_overrides(mut self, overrides: ModelParameters) -> Self {
+        self.overrides = Some(overrides);
+        self.params = self.effective_params();
+        self
+    }
+
+    /// Swaps the provider, such as to fall back to another after failures. The overrides are
+    /// merged over the new provider's defaults, parameters it doesn't take are dropped, and the
+    /// capabilities, context window & url overrides are cleared since they described the
+    /// previous model. Without overrides the current parameters are kept where the new model
+    /// takes them
+    pub fn switch_provider(&mut self, provider: impl Into<CompletionProvider>) {
+        let previous = self.provider.model_str().to_owned();
+        self.provider = provider.into();
+        self.capabilities = None;
+        self.context_window = None;
+        self.url = None;
+        self.params = self.effective_params();
+        info!(
+            "Switched from {} to {}, with parameters: {:?}",
+            previous,
+            self.provider.model_str(),
+            self.params
+        );
+    }
+
+    /// The overrides, or the current parameters if there are none, merged over the provider's
+    /// defaults & fitted to its capabilities
+    fn effective_params(&self) -> ModelParameters {
+        let overrides = self.overrides.as_ref().unwrap_or(&self.params);
+        let mut params = overrides
+            .merged_onto(&self.provider.default_params())
+            .fitted_to(&self.capabilities());
+        params.total_token_count = self.params.total_token_count;
+        params
+    }
+
+    /// Capabilities requests are validated against, the override if set or the provider's
+    pub fn capabilities(&self) -> ModelCapabilities {
+        self.capabilities
+            .unwrap_or_else(|| self.provider.capabilities())
+    }
+
+    /// Maximum number of tokens of input and output combined, the override if set or the
+    /// provider's
+    pub fn context_window(&self) -> u32 {
+        self.context_window
+            .unwrap_or_else(|| self.provider.context_window())
+    }
+
+    /// Checks that a request of `kind` with the current parameters only uses what the model
+    /// supports, without sending anything. Completions run this before every request
+    pub fn validate(&self, kind: RequestKind) -> CompletionResult<()> {
+        self.capabilities()
+            .validate(self.provider.model_str(), &self.params, kind)
+    }
+
+    /// Log the request & response of every completion made with this model under `agent_id`
+    pub fn with_logger(mut self, logger: Arc<CompletionLogger>, agent_id: &str) -> Self {
+        self.logger = Some(AttachedLogger {
+            logger,
+            agent_id: agent_id.to_owned(),
+        });
+        self
+    }
+
+    /// Record the messages sent with every completion as a debug level tracing event
+    pub fn with_context_trace(mut self, trace: ContextTrace) -> Self {
+        self.context_trace = Some(trace);
+        self
+    }
+
+    /// Records a new request id on the current span, and the messages being sent if they are
+    /// traced
+    fn trace_request(&self, messages: &MessageStack) {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        tracing::Span::current().record("request_id", request_id.as_str());
+        if let Some(trace) = &self.context_trace {
+            trace.record(&request_id, messages);
+        }
+    }
+
+    /// Writes a completion to the attached logger, if there is one. Failing to log is only warned
+    /// about, it never fails the completion
+    pub(crate) fn log_completion(
         &self,
+        request_body: Value,
+        response_text: &str,
+        usage: TokenUsage,
+        finish_reason: Option<String>,
+    ) {
+        if let Some(attached) = &self.logger {
+            let entry = CompletionLogEntry::new(
+                &attached.agent_id,
+                request_body,
+                response_text,
+                usage,
+                finish_reason,
+            );
+            if let Err(err) = attached.logger.log(&entry) {
+                warn!("Failed to log completion: {:?}", err);
+            }
+        }
+    }
+
+    /// Whether completions are being logged
+    pub(crate) fn is_logging(&self) -> bool {
+        self.logger.is_some()
+    }
+
+    /// Provider headers plus the OpenAi organization & project headers when they are set
+    fn headers(&self) -> HeaderMap {
+        #[cfg_attr(not(feature = "openai"), allow(unused_mut))]
+        let mut headers = self.provider.inner_builder().headers(&self.api_key);
+        #[cfg(feature = "openai")]
+        if let CompletionProvider::OpenAi(_) | CompletionProvider::OpenAiResponses(_) =
+            self.provider
+        {
+            let optional = [
+                (ORGANIZATION_HEADER, &self.organization),
+                (PROJECT_HEADER, &self.project),
+            ];
+            for (name, value) in optional {
+                if let Some(value) = value {
+                    match value.parse() {
+                        Ok(value) => {
+                            headers.insert(name, value);
+                        }
+                        Err(err) => warn!("Invalid {} header value: {:?}", name, err),
+                    }
+                }
+            }
+        }
+        headers
+    }
+
+    /// Returns `Err(CompletionError::BudgetExceeded)` if the spend cap has been reached
+    fn check_budget(&self) -> CompletionResult<()> {
+        if let Some(cap) = self.spend_cap {
+            if self.total_spend >= cap {
+                warn!(
+                    "Refusing completion, spent ${} with a cap of ${}",
+                    self.total_spend, cap
+                );
+                return Err(CompletionError::BudgetExceeded);
+            }
+        }
+        Ok(())
+    }
+
+    /// Accumulates token count & estimated spend from a completion's usage
+    pub(crate) fn record_usage(&mut self, usage: TokenUsage) {
+        let cost = usage.cost(&self.provider.price_per_k_tokens());
+        self.params.total_token_count += usage.total();
+        self.total_spend += cost;
+        info!(
+            "Recorded usage: {:?}, cost: ${}, total spend: ${}",
+            usage, cost, self.total_spend
+        );
+    }
+
+    /// The JSON body that would be sent for a completion of exactly `messages`, without sending
+    /// it. Agents add layers, examples & their output contract to the cache before requesting,
+    /// `Agent::build_request_body` gives the body they send
+    pub fn build_request_body(
+        &self,
+        messages: &MessageStack,
+        stream: bool,
+    ) -> CompletionResult<Value> {
+        let builder = self.provider.inner_builder();
+        let req = match stream {
+            true => builder.into_stream_req(messages, &self.params)?,
+            false => builder.into_io_req(messages, &self.params)?,
+        };
+        req.as_json()
+    }
+
+    #[tracing::instrument(name = "io completion", skip_all, fields(request_id = tracing::field::Empty))]
+    pub(crate) async fn get_io_completion(
+        &mut self,
         messages: &MessageStack,
     ) -> CompletionResult<String> {
+        self.validate(RequestKind::Io)?;
+        self.send_io_request(messages, None).await
+    }
+
+    /// An io completion whose reply follows the JSON `schema`, named `name`. Refused with
+    /// `CompletionError::Unsupported` unless the model supports structured output
+    #[tracing::instrument(name = "structured completion", skip_all, fields(request_id = tracing::field::Empty))]
+    pub(crate) async fn get_structured_completion(
+        &mut self,
+        messages: &MessageStack,
+        name: &str,
+        schema: &Value,
+    ) -> CompletionResult<String> {
+        self.validate(RequestKind::Structured)?;
+        self.send_io_request(messages, Some((name, schema))).await
+    }
+
+    /// Sends an io request for `messages`, with `response_format` as the name & schema its
+    /// reply must follow if given
+    async fn send_io_request(
+        &mut self,
+        messages: &MessageStack,
+        response_format: Option<(&str, &Value)>,
+    ) -> CompletionResult<String> {
+        self.check_budget()?;
+        self.trace_request(messages);
         let builder = self.provider.inner_builder();
-        let headers = builder.headers(&self.api_key);
-        let url = builder.url_str();
+        let headers = self.headers();
+        let url = self.url.clone().unwrap_or_else(|| builder.url());
         let req = builder.into_io_req(messages, &self.params)?;
-        let json_req = req.as_json()?;
+        let mut json_req = req.as_json()?;
+        if let Some((name, schema)) = response_format {
+            builder.add_response_format(&mut json_req, name, schema);
+        }
         info!(
             "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
             json_req, url, headers
         );
 
-        let response = self
-            .client
-            .post(url)
-            .headers(headers)
-            .json(&json_req)
-            .send()
-            .await?;
+        let response = self.send_request(&url, headers, &json_req).await?;
 
         match req.process_response(response).await {
-            Ok(r) => return Ok(TryInto::<String>::try_into(r)?),
+            Ok(CompletionResponse::Io {
+                content,
+                usage,
+                finish_reason,
+            }) => {
+                self.record_usage(usage);
+                self.log_completion(json_req, &content, usage, finish_reason);
+                Ok(content)
+            }
+            Ok(_) => Err(CompletionError::CouldNotCoerce),
             Err(err) => {
                 warn!("Error getting Io completion: {:?}", err);
                 Err(err)
@@ -182,14 +668,56 @@ impl CompletionModel {
         }
     }
 
-    #[tracing::instrument(name = "streamed completion", skip_all)]
+    /// Posts `body` to `url`. A response with a status in a `RetryCategory` is requested again
+    /// within the category's budget in the model's default `RetryPolicy`, after waiting as long
+    /// as its `Retry-After` header asks, or the budget's backoff without one. Once the budget is
+    /// spent, or `Retry-After` asks for longer than the budget's `max_delay`, the error response
+    /// is returned
+    async fn send_request(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+        body: &Value,
+    ) -> CompletionResult<Response> {
+        let mut retry = RetryState::new(self.stream_defaults.retry_policy.clone());
+        loop {
+            let response = self
+                .client
+                .post(url)
+                .headers(headers.clone())
+                .json(body)
+                .send()
+                .await?;
+            let Some(category) = RetryCategory::from_status(response.status()) else {
+                return Ok(response);
+            };
+            let asked = retry_after(response.headers());
+            let Some(delay) = retry.attempt_after(category, asked) else {
+                if let Some(asked) = asked {
+                    warn!("Not retrying, Retry-After asks to wait {:?}", asked);
+                }
+                return Ok(response);
+            };
+            warn!(
+                "Request answered with {}, requesting again in {:?}",
+                response.status(),
+                delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    #[tracing::instrument(name = "streamed completion", skip_all, fields(request_id = tracing::field::Empty))]
     pub(crate) async fn get_stream_completion(
         &self,
         messages: &MessageStack,
     ) -> CompletionResult<ProviderStreamHandler> {
+        self.check_budget()?;
+        self.validate(RequestKind::Stream)?;
+        self.trace_request(messages);
         let builder = self.provider.inner_builder();
-        let headers = builder.headers(&self.api_key);
-        let url = builder.url_str();
+        let headers = self.headers();
+        let url = self.url.clone().unwrap_or_else(|| builder.url());
         let req = builder.into_stream_req(messages, &self.params)?;
         let json_req = req.as_json()?;
         info!(
@@ -197,16 +725,17 @@ impl CompletionModel {
             json_req, url, headers
         );
 
-        let response = self
-            .client
-            .post(url)
-            .headers(headers)
-            .json(&json_req)
-            .send()
-            .await?;
+        let response = self.send_request(&url, headers, &json_req).await?;
 
         match req.process_response(response).await {
-            Ok(r) => return Ok(TryInto::<ProviderStreamHandler>::try_into(r)?),
+            Ok(r) => {
+                let mut handler = TryInto::<ProviderStreamHandler>::try_into(r)?
+                    .with_defaults(&self.stream_defaults);
+                if self.is_logging() {
+                    handler.set_request_body(json_req);
+                }
+                Ok(handler)
+            }
             Err(err) => {
                 warn!("Error getting streamed Io completion: {:?}", err);
                 Err(err.into())
@@ -214,31 +743,35 @@ impl CompletionModel {
         }
     }
 
-    #[tracing::instrument(name = "function completion", skip_all)]
+    #[tracing::instrument(name = "function completion", skip_all, fields(request_id = tracing::field::Empty))]
     pub(crate) async fn get_fn_completion(
-        &self,
+        &mut self,
         messages: &MessageStack,
         function: Function,
     ) -> CompletionResult<Value> {
+        self.check_budget()?;
+        self.validate(RequestKind::Function)?;
+        self.trace_request(messages);
         let builder = self.provider.inner_builder();
-        let headers = builder.headers(&self.api_key);
-        let url = builder.url_str();
+        let headers = self.headers();
+        let url = self.url.clone().unwrap_or_else(|| builder.url());
         let req = builder.serialize_function(messages, function)?;
         info!(
             "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
             req, url, headers
         );
 
-        let response = self
-            .client
-            .post(url)
-            .headers(headers)
-            .json(&req)
-            .send()
-            .await?;
-        let json = response.json().await?;
+        let response = self.send_request(&url, headers, &req).await?;
+        let json: Value = response.json().await?;
         info!("Got response: {json:#?}");
-        match builder.process_function_response(json) {
+        let usage = builder.usage_from_function_response(&json);
+        let response_text = json.to_string();
+        let result = builder.process_function_response(json);
+        if let Some(usage) = usage {
+            self.record_usage(usage);
+        }
+        self.log_completion(req, &response_text, usage.unwrap_or_default(), None);
+        match result {
             Ok(r) => return Ok(r),
             Err(err) => {
                 warn!("Error getting function completion: {:?}", err);
@@ -247,3 +780,295 @@ impl CompletionModel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::{
+        streaming::{CollectedCompletion, RetryBudget, RetryPolicy},
+        testing::serve_statuses,
+    };
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    #[cfg(feature = "openai")]
+    fn azure_model(url: &str, policy: RetryPolicy) -> CompletionModel {
+        let deployment =
+            AzureOpenAiDeployment::new(url, "d", "2024-06-01", OpenAiCompletionModel::Gpt4);
+        CompletionModel::new(deployment, ModelParameters::default(), "")
+            .with_stream_defaults(StreamDefaults::new().with_retry_policy(policy))
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn rate_limited_request_retried_after_retry_after() {
+        let limited = json!({"error": {"code": "429", "message": "Rate limit reached"}});
+        let limited = || {
+            (
+                "429 Too Many Requests",
+                vec![("Content-Type", "application/json"), ("Retry-After", "1")],
+                limited.to_string().into_bytes(),
+            )
+        };
+        let success = json!({
+            "choices": [{"message": {"role": "assistant", "content": "retried"}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 2, "completion_tokens": 1, "total_tokens": 3},
+        });
+        let success = (
+            "200 OK",
+            vec![("Content-Type", "application/json")],
+            success.to_string().into_bytes(),
+        );
+        let messages = MessageStack::new("system");
+        let policy = RetryPolicy::none().with_budget(
+            RetryCategory::RateLimited,
+            RetryBudget::new(1, Duration::from_millis(10)),
+        );
+
+        let url = serve_statuses(vec![limited(), success]).await;
+        let mut model = azure_model(&url, policy);
+        let start = Instant::now();
+        let content = model.get_io_completion(&messages).await.unwrap();
+        assert_eq!(content, "retried");
+        // Retry-After replaces the budget's backoff
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(model.params.total_token_count, 3);
+
+        // Retry-After past the budget's max delay isn't waited on
+        let url = serve_statuses(vec![(
+            "429 Too Many Requests",
+            vec![
+                ("Content-Type", "application/json"),
+                ("Retry-After", "86400"),
+            ],
+            json!({"error": {"code": "429", "message": "Rate limit reached"}})
+                .to_string()
+                .into_bytes(),
+        )])
+        .await;
+        let policy = RetryPolicy::none().with_budget(
+            RetryCategory::RateLimited,
+            RetryBudget::new(3, Duration::from_millis(10)),
+        );
+        let mut model = azure_model(&url, policy);
+        let start = Instant::now();
+        assert!(model.get_io_completion(&messages).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Without a budget the error response is processed as is
+        let url = serve_statuses(vec![limited()]).await;
+        let mut model = azure_model(&url, RetryPolicy::none());
+        let start = Instant::now();
+        assert!(model.get_io_completion(&messages).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn unavailable_stream_request_retried_with_backoff() {
+        let unavailable = || ("503 Service Unavailable", vec![], b"overloaded".to_vec());
+        let body = [
+            json!({"choices": [{"delta": {"content": "retried"}}]}),
+            json!({"choices": [{"delta": {}, "finish_reason": "stop"}]}),
+        ]
+        .iter()
+        .map(|chunk| format!("data: {}\n\n", chunk))
+        .collect::<String>()
+            + "data: [DONE]\n\n";
+        let stream = (
+            "200 OK",
+            vec![("Content-Type", "text/event-stream")],
+            body.into_bytes(),
+        );
+        let url = serve_statuses(vec![unavailable(), unavailable(), stream]).await;
+        let policy = RetryPolicy::none().with_budget(
+            RetryCategory::ServerError,
+            RetryBudget::new(2, Duration::from_millis(50)),
+        );
+        let model = azure_model(&url, policy);
+        let mut agent = crate::agents::Agent::new(Some("system"), model);
+
+        let start = Instant::now();
+        let mut handler = agent.stream_completion().await.unwrap();
+        // Waits 50ms then 100ms before requesting again
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(
+            handler.collect(&mut agent).await.unwrap(),
+            CollectedCompletion::Finished("retried".to_string())
+        );
+    }
+
+    #[cfg(feature = "openai")]
+    #[test]
+    fn openai_organization_and_project_headers_only_sent_when_set() {
+        let model = CompletionModel::default_openai("key");
+        let headers = model.headers();
+        assert!(headers.get(ORGANIZATION_HEADER).is_none());
+        assert!(headers.get(PROJECT_HEADER).is_none());
+
+        let headers = model
+            .with_organization("org-1")
+            .with_project("proj_1")
+            .headers();
+        assert_eq!(headers.get(ORGANIZATION_HEADER).unwrap(), "org-1");
+        assert_eq!(headers.get(PROJECT_HEADER).unwrap(), "proj_1");
+        assert_eq!(headers.get("Authorization").unwrap(), "Bearer key");
+
+        #[cfg(feature = "anthropic")]
+        {
+            let headers = CompletionModel::default_anthropic("key")
+                .with_organization("org-1")
+                .headers();
+            assert!(headers.get(ORGANIZATION_HEADER).is_none());
+        }
+    }
+
+    #[cfg(feature = "openai")]
+    #[test]
+    fn request_body_built_without_sending() {
+        let model = CompletionModel::default_openai("key");
+        let mut stack = MessageStack::new("system");
+        stack.push(crate::agents::memory::Message::new_user("hi"));
+
+        let body = model.build_request_body(&stack, false).unwrap();
+        assert_eq!(body["model"], "gpt-3.5-turbo-0125");
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["messages"][1]["content"], "hi");
+
+        let body = model.build_request_body(&stack, true).unwrap();
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["stream_options"]["include_usage"], true);
+    }
+
+    #[cfg(all(feature = "openai", feature = "anthropic"))]
+    #[test]
+    fn switched_provider_takes_its_defaults_under_overrides() {
+        let mut model = CompletionModel::default_openai("key").with_overrides(ModelParameters {
+            temperature: Some(150),
+            presence_penalty: Some(1),
+            n: Some(2),
+            max_tokens: None,
+            ..Default::default()
+        });
+        model.params.total_token_count = 50;
+        assert_eq!(model.params.temperature, Some(150));
+        assert_eq!(model.params.n, Some(2));
+
+        model.switch_provider(AnthropicCompletionModel::Haiku);
+        assert!(model.validate(RequestKind::Stream).is_ok());
+        assert_eq!(model.params.temperature, Some(100));
+        assert_eq!(model.params.presence_penalty, None);
+        assert_eq!(model.params.n, None);
+        assert_eq!(model.params.max_tokens, Some(1000));
+        assert_eq!(model.params.total_token_count, 50);
+
+        // Switching back restores what only the previous model dropped
+        model.switch_provider(OpenAiCompletionModel::default());
+        assert_eq!(model.params.temperature, Some(150));
+        assert_eq!(model.params.presence_penalty, Some(1));
+        assert_eq!(model.params.n, Some(2));
+        assert_eq!(model.params.max_tokens, None);
+    }
+
+    #[cfg(feature = "openai")]
+    #[test]
+    fn cost_accumulates_across_usages() {
+        let price = TokenPrice {
+            input: 0.5,
+            output: 1.5,
+        };
+        let usage = TokenUsage {
+            input_tokens: 2000,
+            output_tokens: 500,
+        };
+        assert_eq!(usage.cost(&price), 1.75);
+        assert_eq!(TokenUsage::default().cost(&price), 0.0);
+
+        let mut model = CompletionModel::default_openai("key");
+        let price = model.provider.price_per_k_tokens();
+        let first = TokenUsage {
+            input_tokens: 1200,
+            output_tokens: 300,
+        };
+        let second = TokenUsage {
+            input_tokens: 800,
+            output_tokens: 100,
+        };
+        model.record_usage(first);
+        assert_eq!(model.total_spend, first.cost(&price));
+        model.record_usage(second);
+        assert_eq!(model.total_spend, first.cost(&price) + second.cost(&price));
+
+        // Small costs summed over a long session don't drift
+        let mut long_session = CompletionModel::default_openai("key");
+        let tiny = TokenUsage {
+            input_tokens: 1,
+            output_tokens: 1,
+        };
+        for _ in 0..10_000 {
+            long_session.record_usage(tiny);
+        }
+        let expected = tiny.cost(&price) * 10_000.0;
+        assert!((long_session.total_spend - expected).abs() < expected * 1e-9);
+        assert_eq!(model.params.total_token_count, 2400);
+    }
+
+    #[test]
+    fn merged_usage_keeps_largest_running_totals() {
+        let mut usage = TokenUsage {
+            input_tokens: 40,
+            output_tokens: 3,
+        };
+        // A later running total of a stream, input is only reported at its start
+        usage.merge(TokenUsage {
+            input_tokens: 0,
+            output_tokens: 12,
+        });
+        assert_eq!(
+            usage,
+            TokenUsage {
+                input_tokens: 40,
+                output_tokens: 12
+            }
+        );
+        // Merging an earlier total changes nothing
+        usage.merge(TokenUsage {
+            input_tokens: 40,
+            output_tokens: 5,
+        });
+        assert_eq!(usage.total(), 52);
+    }
+
+    #[cfg(feature = "openai")]
+    #[test]
+    fn completions_refused_once_spend_reaches_cap() {
+        let usage = TokenUsage {
+            input_tokens: 1000,
+            output_tokens: 1000,
+        };
+        let mut model = CompletionModel::default_openai("key");
+        let cost = usage.cost(&model.provider.price_per_k_tokens());
+        assert!(cost > 0.0);
+        model = model.with_spend_cap(cost * 2.0);
+        assert!(model.check_budget().is_ok());
+
+        // Below the cap
+        model.record_usage(usage);
+        assert!(model.check_budget().is_ok());
+        // Exactly at the cap
+        model.record_usage(usage);
+        assert_eq!(model.total_spend, cost * 2.0);
+        assert!(matches!(
+            model.check_budget(),
+            Err(CompletionError::BudgetExceeded)
+        ));
+        // Past it
+        model.record_usage(usage);
+        assert!(matches!(
+            model.check_budget(),
+            Err(CompletionError::BudgetExceeded)
+        ));
+        model.spend_cap = None;
+        assert!(model.check_budget().is_ok());
+    }
+}
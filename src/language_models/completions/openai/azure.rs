@@ -0,0 +1,192 @@
+//! Azure OpenAi, which serves OpenAi models from per resource deployments
+use super::{builder::OpenAiCompletionModel, requests::OpenAiIoRequest};
+use crate::{
+    agents::memory::MessageStack,
+    language_models::completions::{
+        capabilities::ModelCapabilities,
+        error::CompletionResult,
+        functions::Function,
+        inference::{CompletionRequest, CompletionRequestBuilder},
+        ModelParameters, TokenPrice, TokenUsage,
+    },
+};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub(crate) const API_KEY_HEADER: &str = "api-key";
+
+/// A deployment of an OpenAi model on an Azure OpenAi resource. Requests are routed by the
+/// deployment name in the url & authenticated with the `api-key` header, everything else is the
+/// same as OpenAi
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AzureOpenAiDeployment {
+    /// The resource's endpoint, like `https://my-resource.openai.azure.com`
+    pub endpoint: String,
+    /// Name given to the deployment when it was created
+    pub deployment: String,
+    /// Value of the `api-version` query parameter, like `2024-06-01`
+    pub api_version: String,
+    /// The model the deployment serves, used for pricing & context window
+    pub model: OpenAiCompletionModel,
+}
+
+impl AzureOpenAiDeployment {
+    pub fn new(
+        endpoint: &str,
+        deployment: &str,
+        api_version: &str,
+        model: OpenAiCompletionModel,
+    ) -> Self {
+        Self {
+            endpoint: endpoint.to_owned(),
+            deployment: deployment.to_owned(),
+            api_version: api_version.to_owned(),
+            model,
+        }
+    }
+}
+
+impl CompletionRequestBuilder for AzureOpenAiDeployment {
+    fn model_str(&self) -> &str {
+        self.model.model_str()
+    }
+
+    fn url(&self) -> String {
+        format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            self.deployment,
+            self.api_version
+        )
+    }
+
+    fn price_per_k_tokens(&self) -> TokenPrice {
+        self.model.price_per_k_tokens()
+    }
+
+    fn context_window(&self) -> u32 {
+        self.model.context_window()
+    }
+
+    fn capabilities(&self) -> ModelCapabilities {
+        self.model.capabilities()
+    }
+
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert(API_KEY_HEADER, api_key.parse().unwrap());
+        map.insert("Content-Type", "application/json".parse().unwrap());
+        map
+    }
+
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        self.model.serialize_messages(stack)
+    }
+
+    fn add_response_format(&self, body: &mut Value, name: &str, schema: &Value) {
+        self.model.add_response_format(body, name, schema)
+    }
+
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(OpenAiIoRequest::new(
+            stack, params, self.model, false,
+        )))
+    }
+
+    fn into_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(OpenAiIoRequest::new(
+            stack, params, self.model, true,
+        )))
+    }
+
+    fn serialize_function(
+        &self,
+        stack: &MessageStack,
+        function: Function,
+    ) -> CompletionResult<Value> {
+        self.model.serialize_function(stack, function)
+    }
+
+    fn process_function_response(&self, response_json: Value) -> CompletionResult<Value> {
+        self.model.process_function_response(response_json)
+    }
+
+    fn usage_from_function_response(&self, response_json: &Value) -> Option<TokenUsage> {
+        self.model.usage_from_function_response(response_json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::Agent,
+        language_models::completions::{
+            streaming::CompletionStreamStatus, testing::serve_once, CompletionModel,
+        },
+    };
+
+    #[test]
+    fn url_built_from_deployment() {
+        let deployment = AzureOpenAiDeployment::new(
+            "https://my-resource.openai.azure.com/",
+            "gpt4-prod",
+            "2024-06-01",
+            OpenAiCompletionModel::Gpt4,
+        );
+        assert_eq!(
+            deployment.url(),
+            "https://my-resource.openai.azure.com/openai/deployments/gpt4-prod/chat/completions?api-version=2024-06-01"
+        );
+        let headers = CompletionModel::new(deployment, ModelParameters::default(), "key")
+            .with_organization("org-1")
+            .headers();
+        assert_eq!(headers.get(API_KEY_HEADER).unwrap(), "key");
+        assert!(headers.get("Authorization").is_none());
+        assert!(headers
+            .get(super::super::builder::ORGANIZATION_HEADER)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn stream_with_prompt_filter_chunk_completes() {
+        // Azure sends content filter results in a first chunk with no choices
+        let sse = [
+            r#"data: {"choices":[],"prompt_filter_results":[{"prompt_index":0,"content_filter_results":{}}]}"#,
+            r#"data: {"choices":[{"delta":{"role":"assistant","content":""}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":"Hello"}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":" world"}}]}"#,
+            r#"data: {"choices":[{"delta":{},"finish_reason":"stop"}]}"#,
+            "data: [DONE]",
+        ]
+        .join("\n\n");
+        let url = serve_once(
+            vec![("Content-Type", "text/event-stream")],
+            sse.into_bytes(),
+        )
+        .await;
+        let deployment =
+            AzureOpenAiDeployment::new(&url, "gpt35", "2024-06-01", OpenAiCompletionModel::Gpt3);
+        let model = CompletionModel::new(deployment, ModelParameters::default(), "key");
+        let mut agent = Agent::new(None, model);
+        let mut handler = agent.stream_completion().await.unwrap();
+
+        let mut finished = None;
+        while let Ok(Some(status)) = handler.receive(&mut agent).await {
+            if let CompletionStreamStatus::Finished(content) = status {
+                finished = Some(content);
+                break;
+            }
+        }
+        assert_eq!(finished.as_deref(), Some("Hello world"));
+    }
+}
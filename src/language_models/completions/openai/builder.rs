@@ -2,12 +2,13 @@ use std::collections::HashMap;
 
 use super::{
     super::inference::{CompletionRequest, CompletionRequestBuilder},
-    requests::OpenAiIoRequest,
+    requests::{OpenAiIoRequest, OpenAiUsage},
 };
 use crate::language_models::completions::{
+    capabilities::ModelCapabilities,
     error::{CompletionError, CompletionResult},
     functions::{FunctionParam, ParamType},
-    ModelParameters,
+    ModelParameters, TokenPrice, TokenUsage,
 };
 use anyhow::anyhow;
 use reqwest::header::HeaderMap;
@@ -25,8 +26,33 @@ pub enum OpenAiCompletionModel {
 const GPT3_MODEL_STR: &str = "gpt-3.5-turbo-0125";
 const GPT4_MODEL_STR: &str = "gpt-4-0125-preview";
 
+pub(crate) const ORGANIZATION_HEADER: &str = "OpenAI-Organization";
+pub(crate) const PROJECT_HEADER: &str = "OpenAI-Project";
+
+const GPT3_CONTEXT_WINDOW: u32 = 16_385;
+const GPT4_CONTEXT_WINDOW: u32 = 128_000;
+const GPT_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    function_calling: true,
+    streaming: true,
+    penalties: true,
+    multiple_choices: true,
+    max_temperature: 200,
+    max_output_tokens: Some(4096),
+    // Only later models than these take a `json_schema` response format
+    structured_output: false,
+};
+
+const GPT3_PRICE: TokenPrice = TokenPrice {
+    input: 0.0005,
+    output: 0.0015,
+};
+const GPT4_PRICE: TokenPrice = TokenPrice {
+    input: 0.01,
+    output: 0.03,
+};
+
 impl OpenAiCompletionModel {
-    fn serialize_function_params(params: HashMap<String, FunctionParam>) -> Value {
+    pub(super) fn serialize_function_params(params: HashMap<String, FunctionParam>) -> Value {
         let mut all_params = Map::new();
         let mut req = vec![];
         for (name, param) in params.iter() {
@@ -71,8 +97,26 @@ impl CompletionRequestBuilder for OpenAiCompletionModel {
         }
     }
 
-    fn url_str(&self) -> &str {
-        "https://api.openai.com/v1/chat/completions"
+    fn url(&self) -> String {
+        "https://api.openai.com/v1/chat/completions".to_string()
+    }
+
+    fn price_per_k_tokens(&self) -> TokenPrice {
+        match self {
+            Self::Gpt3 => GPT3_PRICE,
+            Self::Gpt4 => GPT4_PRICE,
+        }
+    }
+
+    fn context_window(&self) -> u32 {
+        match self {
+            Self::Gpt3 => GPT3_CONTEXT_WINDOW,
+            Self::Gpt4 => GPT4_CONTEXT_WINDOW,
+        }
+    }
+
+    fn capabilities(&self) -> ModelCapabilities {
+        GPT_CAPABILITIES
     }
 
     fn headers(&self, api_key: &str) -> HeaderMap {
@@ -109,6 +153,12 @@ impl CompletionRequestBuilder for OpenAiCompletionModel {
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
         Ok(Box::new(OpenAiIoRequest::new(stack, params, *self, true)))
     }
+    fn add_response_format(&self, body: &mut Value, name: &str, schema: &Value) {
+        body["response_format"] = json!({
+            "type": "json_schema",
+            "json_schema": {"name": name, "schema": schema},
+        });
+    }
     fn serialize_function(
         &self,
         stack: &crate::prelude::MessageStack,
@@ -162,6 +212,13 @@ impl CompletionRequestBuilder for OpenAiCompletionModel {
         tracing::info!("Args output: {:?}", args_output);
         Ok(args_output)
     }
+
+    fn usage_from_function_response(&self, response_json: &Value) -> Option<TokenUsage> {
+        let usage = response_json.get("usage")?.to_owned();
+        serde_json::from_value::<OpenAiUsage>(usage)
+            .ok()
+            .map(|u| u.into())
+    }
 }
 
 mod tests {
@@ -1,3 +1,5 @@
+pub mod azure;
 pub mod builder;
 pub mod requests;
+pub mod responses;
 pub mod streaming;
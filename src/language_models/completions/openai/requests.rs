@@ -7,17 +7,17 @@ use crate::{
     language_models::completions::{
         error::{CompletionError, CompletionResult, ProviderResponseError},
         inference::{CompletionRequestBuilder, CompletionResponse, ProcessResponseReturn},
-        streaming::{CompletionStream, ProviderStreamHandler, StreamedCompletionHandler},
-        ModelParameters,
+        streaming::{
+            sse::response_event_stream, CompletionStream, ProviderStreamHandler,
+            StreamedCompletionHandler,
+        },
+        ModelParameters, TokenUsage,
     },
 };
 use anyhow::anyhow;
-use futures::TryStreamExt;
 use reqwest::Response;
-use reqwest_streams::JsonStreamResponse;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::time::Duration;
 use tracing::info;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
@@ -27,6 +27,9 @@ pub struct OpenAiIoRequest {
     pub temperature: f32,
     pub max_tokens: u32,
     pub stream: bool,
+    /// Only set on streamed requests so the final chunk reports token usage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<Value>,
     pub n: u32,
 }
 
@@ -46,6 +49,7 @@ impl OpenAiIoRequest {
             messages: CompletionRequestBuilder::serialize_messages(&typ, stack),
             temperature,
             stream,
+            stream_options: stream.then(|| json!({ "include_usage": true })),
             max_tokens: params.max_tokens.unwrap_or(1000),
             n: params.n.unwrap_or(1),
         }
@@ -66,24 +70,21 @@ impl CompletionRequest for OpenAiIoRequest {
                     let response: OpenAiResponse = serde_json::from_value(json)?;
                     return match response {
                         OpenAiResponse::Success(mut suc) => {
-                            let content = suc.choices.remove(0).message.content.ok_or(
-                                CompletionError::from(anyhow!("No content in success message")),
-                            )?;
-                            Ok(CompletionResponse::from(content))
+                            let choice = suc.choices.remove(0);
+                            let content = choice.message.content.ok_or(CompletionError::from(
+                                anyhow!("No content in success message"),
+                            ))?;
+                            Ok(CompletionResponse::Io {
+                                content,
+                                usage: suc.usage.into(),
+                                finish_reason: choice.finish_reason,
+                            })
                         }
                         OpenAiResponse::Err { error } => Err(error.into_error()),
                     };
                 }
                 true => {
-                    let response_stream: CompletionStream = Box::new(
-                        tokio::time::timeout(Duration::from_secs(10), async {
-                            response
-                                .json_array_stream::<Value>(1024)
-                                .map_err(|err| err.into())
-                        })
-                        .await
-                        .map_err(|_| CompletionError::StreamTimeout)?,
-                    );
+                    let response_stream: CompletionStream = response_event_stream(response).await?;
                     let handler: ProviderStreamHandler =
                         StreamedCompletionHandler::<OpenAiStreamResponse>::from(response_stream)
                             .into();
@@ -117,6 +118,8 @@ pub struct OpenAiErr {
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub struct Choice {
     pub message: GptMessage,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -133,6 +136,15 @@ pub struct OpenAiUsage {
     pub total_tokens: i32,
 }
 
+impl From<OpenAiUsage> for TokenUsage {
+    fn from(value: OpenAiUsage) -> Self {
+        Self {
+            input_tokens: value.prompt_tokens.max(0) as u32,
+            output_tokens: value.completion_tokens.unwrap_or(0).max(0) as u32,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -176,9 +188,66 @@ mod tests {
                         content: Some("\n\nThis is a test!".to_string()),
                         function_call: None,
                     },
+                    finish_reason: Some("stop".to_string()),
                 }
             }],
         });
         assert_eq!(res, expected);
     }
+
+    #[tokio::test]
+    async fn gzip_compressed_stream_decodes() {
+        use crate::{
+            agents::Agent,
+            language_models::completions::{
+                streaming::CompletionStreamStatus, testing::serve_once, CompletionModel,
+            },
+        };
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let sse = [
+            r#"data: {"choices":[{"delta":{"role":"assistant","content":""}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":"Hello"}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":" world"}}]}"#,
+            r#"data: {"choices":[{"delta":{},"finish_reason":"stop"}]}"#,
+            "data: [DONE]",
+        ]
+        .join("\n\n");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(sse.as_bytes()).unwrap();
+        let url = serve_once(
+            vec![
+                ("Content-Type", "text/event-stream"),
+                ("Content-Encoding", "gzip"),
+            ],
+            encoder.finish().unwrap(),
+        )
+        .await;
+
+        let req = OpenAiIoRequest::new(
+            &MessageStack::init(),
+            &ModelParameters::default(),
+            OpenAiCompletionModel::default(),
+            true,
+        );
+        let response = reqwest::Client::new().post(url).send().await.unwrap();
+        let mut handler: ProviderStreamHandler = req
+            .process_response(response)
+            .await
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let mut finished = None;
+        while let Ok(Some(status)) = handler.receive(&mut agent).await {
+            if let CompletionStreamStatus::Finished(content) = status {
+                finished = Some(content);
+                break;
+            }
+        }
+        assert_eq!(finished.as_deref(), Some("Hello world"));
+        assert_eq!(agent.cache.as_ref()[0].content, "Hello world");
+    }
 }
@@ -0,0 +1,614 @@
+//! OpenAi's Responses API, which serves the same models as Chat Completions from `/v1/responses`
+//! and streams typed events instead of choice deltas
+use super::{builder::OpenAiCompletionModel, requests::OpenAiErr};
+use crate::{
+    agents::memory::MessageStack,
+    language_models::completions::{
+        capabilities::ModelCapabilities,
+        error::{CompletionError, CompletionResult, ProviderResponseError},
+        functions::Function,
+        inference::{
+            CompletionRequest, CompletionRequestBuilder, CompletionResponse, ProcessResponseReturn,
+        },
+        streaming::{
+            sse::response_event_stream, CompletionStream, CompletionStreamStatus,
+            ProviderStreamHandler, StreamResponse, StreamedCompletionHandler, ToolCallDelta,
+        },
+        ModelParameters, TokenPrice, TokenUsage,
+    },
+};
+use anyhow::anyhow;
+use reqwest::{header::HeaderMap, Response};
+use serde::{de::Error, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+/// Finish reason reported for responses that end in function calls, the same as Chat
+/// Completions'
+const TOOL_CALLS_FINISH_REASON: &str = "tool_calls";
+
+/// An OpenAi model requested through the Responses API rather than Chat Completions. Pricing,
+/// context window & authentication are the same as `OpenAiCompletionModel`'s
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct OpenAiResponsesModel {
+    pub model: OpenAiCompletionModel,
+    /// Requests are sent to `{base_url}/responses`
+    pub base_url: String,
+}
+
+impl Default for OpenAiResponsesModel {
+    fn default() -> Self {
+        Self::new(OpenAiCompletionModel::default())
+    }
+}
+
+impl From<OpenAiCompletionModel> for OpenAiResponsesModel {
+    fn from(model: OpenAiCompletionModel) -> Self {
+        Self::new(model)
+    }
+}
+
+impl OpenAiResponsesModel {
+    pub fn new(model: OpenAiCompletionModel) -> Self {
+        Self {
+            model,
+            base_url: DEFAULT_BASE_URL.to_string(),
+        }
+    }
+
+    /// Send requests somewhere other than `https://api.openai.com/v1`, like a proxy
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_owned();
+        self
+    }
+}
+
+impl CompletionRequestBuilder for OpenAiResponsesModel {
+    fn model_str(&self) -> &str {
+        self.model.model_str()
+    }
+
+    fn url(&self) -> String {
+        format!("{}/responses", self.base_url)
+    }
+
+    fn price_per_k_tokens(&self) -> TokenPrice {
+        self.model.price_per_k_tokens()
+    }
+
+    fn context_window(&self) -> u32 {
+        self.model.context_window()
+    }
+
+    /// The Responses API doesn't take penalties or `n`
+    fn capabilities(&self) -> ModelCapabilities {
+        ModelCapabilities {
+            penalties: false,
+            multiple_choices: false,
+            ..self.model.capabilities()
+        }
+    }
+
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        self.model.headers(api_key)
+    }
+
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        self.model.serialize_messages(stack)
+    }
+
+    fn add_response_format(&self, body: &mut Value, name: &str, schema: &Value) {
+        body["text"] = json!({
+            "format": {"type": "json_schema", "name": name, "schema": schema},
+        });
+    }
+
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(OpenAiResponsesRequest::new(
+            stack, params, self, false,
+        )))
+    }
+
+    fn into_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(OpenAiResponsesRequest::new(
+            stack, params, self, true,
+        )))
+    }
+
+    fn serialize_function(
+        &self,
+        stack: &MessageStack,
+        function: Function,
+    ) -> CompletionResult<Value> {
+        let params = OpenAiCompletionModel::serialize_function_params(function.params);
+        Ok(json!({
+            "model": self.model_str(),
+            "input": self.serialize_messages(stack),
+            "tools": [{
+                "type": "function",
+                "name": function.name,
+                "description": function.description,
+                "parameters": params,
+            }],
+            "tool_choice": {"type": "function", "name": function.name},
+        }))
+    }
+
+    fn process_function_response(&self, response_json: Value) -> CompletionResult<Value> {
+        let response: ResponsesOutcome = serde_json::from_value(response_json)?;
+        let response = match response {
+            ResponsesOutcome::Success(response) => response,
+            ResponsesOutcome::Err { error } => return Err(error.into_error()),
+        };
+        let arguments = response
+            .output
+            .into_iter()
+            .find_map(|item| match item {
+                OutputItem::FunctionCall { arguments, .. } => Some(arguments),
+                _ => None,
+            })
+            .ok_or(serde_json::Error::missing_field("function_call"))?;
+        match serde_json::from_str::<Value>(&arguments)? {
+            args @ Value::Object(_) => Ok(args),
+            other => Err(CompletionError::from(anyhow!(
+                "Function arguments aren't an object: {}",
+                other
+            ))),
+        }
+    }
+
+    fn usage_from_function_response(&self, response_json: &Value) -> Option<TokenUsage> {
+        let usage = response_json.get("usage")?.to_owned();
+        serde_json::from_value::<ResponsesUsage>(usage)
+            .ok()
+            .map(|u| u.into())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct OpenAiResponsesRequest {
+    pub model: String,
+    pub input: Value,
+    pub temperature: f32,
+    pub max_output_tokens: u32,
+    pub stream: bool,
+}
+
+impl OpenAiResponsesRequest {
+    pub fn new(
+        stack: &MessageStack,
+        params: &ModelParameters,
+        model: &OpenAiResponsesModel,
+        stream: bool,
+    ) -> Self {
+        Self {
+            model: model.model_str().to_string(),
+            input: model.serialize_messages(stack),
+            temperature: params.temperature().unwrap_or(0.7),
+            max_output_tokens: params.max_tokens.unwrap_or(1000),
+            stream,
+        }
+    }
+}
+
+impl CompletionRequest for OpenAiResponsesRequest {
+    fn as_json(&self) -> CompletionResult<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    fn process_response<'r>(&'r self, response: Response) -> ProcessResponseReturn<'r> {
+        Box::pin(async move {
+            if self.stream {
+                let response_stream: CompletionStream = response_event_stream(response).await?;
+                let handler: ProviderStreamHandler =
+                    StreamedCompletionHandler::<OpenAiResponsesStreamEvent>::from(response_stream)
+                        .into();
+                return Ok(handler.into());
+            }
+            let json = response.json().await?;
+            match serde_json::from_value::<ResponsesOutcome>(json)? {
+                ResponsesOutcome::Success(response) => Ok(CompletionResponse::Io {
+                    content: response.text(),
+                    usage: response.usage.into(),
+                    finish_reason: Some(response.finish_reason()),
+                }),
+                ResponsesOutcome::Err { error } => Err(error.into_error()),
+            }
+        })
+    }
+}
+
+/// A response, as returned by a request & the events that end a stream
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct ResponsesSuccess {
+    /// `completed`, `incomplete` or `failed`
+    pub status: String,
+    pub output: Vec<OutputItem>,
+    #[serde(default)]
+    pub incomplete_details: Option<IncompleteDetails>,
+    #[serde(default)]
+    pub usage: ResponsesUsage,
+}
+
+impl ResponsesSuccess {
+    /// The text of the response's messages
+    fn text(&self) -> String {
+        self.output
+            .iter()
+            .filter_map(|item| match item {
+                OutputItem::Message { content } => Some(content),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|content| match content {
+                OutputContent::OutputText { text } => Some(text.as_str()),
+                OutputContent::Other => None,
+            })
+            .collect()
+    }
+
+    /// `tool_calls` when the response ends in function calls, otherwise why it was incomplete
+    /// or its status
+    fn finish_reason(&self) -> String {
+        if self
+            .output
+            .iter()
+            .any(|item| matches!(item, OutputItem::FunctionCall { .. }))
+        {
+            return TOOL_CALLS_FINISH_REASON.to_string();
+        }
+        match &self.incomplete_details {
+            Some(details) => details.reason.to_owned(),
+            None => self.status.to_owned(),
+        }
+    }
+}
+
+/// Either a response or an error, like `OpenAiResponse`
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+enum ResponsesOutcome {
+    Success(ResponsesSuccess),
+    Err { error: OpenAiErr },
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct IncompleteDetails {
+    /// Like `max_output_tokens` or `content_filter`
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum OutputItem {
+    #[serde(rename = "message")]
+    Message { content: Vec<OutputContent> },
+    #[serde(rename = "function_call")]
+    FunctionCall {
+        call_id: String,
+        name: String,
+        /// Empty when the item is added to a stream, the arguments are streamed after it
+        #[serde(default)]
+        arguments: String,
+    },
+    /// Reasoning & built in tool calls, which aren't part of the message content
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum OutputContent {
+    #[serde(rename = "output_text")]
+    OutputText { text: String },
+    /// Refusals, which have no text
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResponsesUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+impl From<ResponsesUsage> for TokenUsage {
+    fn from(value: ResponsesUsage) -> Self {
+        Self {
+            input_tokens: value.input_tokens,
+            output_tokens: value.output_tokens,
+        }
+    }
+}
+
+/// An event of a Responses API stream. `error` & `response.failed` events fail to parse, so
+/// the stream ends with them as `StreamError::StreamRecievedErr`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(try_from = "ResponsesEvent")]
+pub struct OpenAiResponsesStreamEvent(ResponsesEvent);
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type")]
+enum ResponsesEvent {
+    #[serde(rename = "response.output_text.delta")]
+    OutputTextDelta { delta: String },
+    #[serde(rename = "response.output_item.added")]
+    OutputItemAdded {
+        output_index: usize,
+        item: OutputItem,
+    },
+    #[serde(rename = "response.function_call_arguments.delta")]
+    FunctionCallArgumentsDelta { output_index: usize, delta: String },
+    /// Ends the stream, as do `Incomplete` & `Failed`
+    #[serde(rename = "response.completed")]
+    Completed { response: ResponsesSuccess },
+    #[serde(rename = "response.incomplete")]
+    Incomplete { response: ResponsesSuccess },
+    #[serde(rename = "response.failed")]
+    Failed,
+    #[serde(rename = "error")]
+    Error,
+    /// Lifecycle events & the `done` events repeating what was streamed
+    #[serde(other)]
+    Other,
+}
+
+impl TryFrom<ResponsesEvent> for OpenAiResponsesStreamEvent {
+    type Error = String;
+    fn try_from(event: ResponsesEvent) -> Result<Self, Self::Error> {
+        match event {
+            ResponsesEvent::Failed => Err("The response failed".to_string()),
+            ResponsesEvent::Error => Err("The stream reported an error".to_string()),
+            event => Ok(Self(event)),
+        }
+    }
+}
+
+impl StreamResponse for OpenAiResponsesStreamEvent {
+    fn usage(&self) -> Option<TokenUsage> {
+        match &self.0 {
+            ResponsesEvent::Completed { response } | ResponsesEvent::Incomplete { response } => {
+                Some(response.usage.into())
+            }
+            _ => None,
+        }
+    }
+
+    fn finish_reason(&self) -> Option<String> {
+        match &self.0 {
+            ResponsesEvent::Completed { response } | ResponsesEvent::Incomplete { response } => {
+                Some(response.finish_reason())
+            }
+            _ => None,
+        }
+    }
+
+    fn tool_call_deltas(&self) -> Vec<ToolCallDelta> {
+        let delta = match &self.0 {
+            ResponsesEvent::OutputItemAdded {
+                output_index,
+                item:
+                    OutputItem::FunctionCall {
+                        call_id,
+                        name,
+                        arguments,
+                    },
+            } => ToolCallDelta {
+                index: *output_index,
+                id: Some(call_id.to_owned()),
+                name: Some(name.to_owned()),
+                arguments: arguments.to_owned(),
+            },
+            ResponsesEvent::FunctionCallArgumentsDelta {
+                output_index,
+                delta,
+            } => ToolCallDelta {
+                index: *output_index,
+                id: None,
+                name: None,
+                arguments: delta.to_owned(),
+            },
+            _ => return vec![],
+        };
+        vec![delta]
+    }
+
+    fn is_tool_call_finish(reason: &str) -> bool {
+        reason == TOOL_CALLS_FINISH_REASON
+    }
+}
+
+impl From<OpenAiResponsesStreamEvent> for CompletionStreamStatus {
+    fn from(event: OpenAiResponsesStreamEvent) -> Self {
+        match event.0 {
+            ResponsesEvent::OutputTextDelta { delta } => Self::Working(delta),
+            ResponsesEvent::Completed { .. } | ResponsesEvent::Incomplete { .. } => {
+                Self::Finished(String::new())
+            }
+            _ => Self::Working(String::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::Agent,
+        language_models::completions::{
+            streaming::{CollectedCompletion, StreamError, ToolCall},
+            testing::serve_once,
+            CompletionModel,
+        },
+    };
+
+    /// An agent requesting from a server that streams `events`
+    async fn streaming_agent(events: &[Value]) -> Agent {
+        let sse: String = events
+            .iter()
+            .map(|event| {
+                format!(
+                    "event: {}\ndata: {}\n\n",
+                    event["type"].as_str().unwrap(),
+                    event
+                )
+            })
+            .collect();
+        let url = serve_once(
+            vec![("Content-Type", "text/event-stream")],
+            sse.into_bytes(),
+        )
+        .await;
+        let model = OpenAiResponsesModel::new(OpenAiCompletionModel::Gpt3).with_base_url(&url);
+        Agent::new(
+            None,
+            CompletionModel::new(model, ModelParameters::default(), "key"),
+        )
+    }
+
+    #[test]
+    fn requests_sent_to_responses_endpoint() {
+        let model = OpenAiResponsesModel::default().with_base_url("http://localhost:8080/v1/");
+        assert_eq!(model.url(), "http://localhost:8080/v1/responses");
+        assert_eq!(
+            OpenAiResponsesModel::default().url(),
+            "https://api.openai.com/v1/responses"
+        );
+        let body = CompletionModel::new(model, ModelParameters::default(), "key")
+            .build_request_body(&MessageStack::init(), true)
+            .unwrap();
+        assert_eq!(body["stream"], true);
+        assert!(body.get("input").is_some());
+        assert!(body.get("messages").is_none());
+    }
+
+    #[tokio::test]
+    async fn text_deltas_streamed() {
+        let mut agent = streaming_agent(&[
+            json!({"type": "response.created", "response": {"status": "in_progress", "output": []}}),
+            json!({"type": "response.output_item.added", "output_index": 0,
+                "item": {"type": "message", "id": "msg_1", "role": "assistant", "content": []}}),
+            json!({"type": "response.output_text.delta", "output_index": 0, "delta": "Hello"}),
+            json!({"type": "response.output_text.delta", "output_index": 0, "delta": " world"}),
+            json!({"type": "response.output_text.done", "output_index": 0, "text": "Hello world"}),
+            json!({"type": "response.completed", "response": {
+                "status": "completed",
+                "output": [{"type": "message", "content": [
+                    {"type": "output_text", "text": "Hello world", "annotations": []}
+                ]}],
+                "usage": {"input_tokens": 12, "output_tokens": 2, "total_tokens": 14}
+            }}),
+        ])
+        .await;
+        let mut handler = agent.stream_completion().await.unwrap();
+        assert_eq!(
+            handler.collect(&mut agent).await.unwrap(),
+            CollectedCompletion::Finished("Hello world".to_string())
+        );
+        assert_eq!(agent.cache.as_ref()[0].content, "Hello world");
+        assert!(agent.completion_model.total_spend > 0.0);
+    }
+
+    #[tokio::test]
+    async fn function_calls_streamed_as_tool_calls() {
+        let call = json!({"type": "function_call", "id": "fc_1", "call_id": "call_1",
+            "name": "get_weather", "arguments": ""});
+        let mut done = call.clone();
+        done["arguments"] = json!(r#"{"city":"Paris"}"#);
+        let mut agent = streaming_agent(&[
+            json!({"type": "response.output_item.added", "output_index": 1, "item": call}),
+            json!({"type": "response.function_call_arguments.delta", "output_index": 1,
+                "delta": r#"{"city":"#}),
+            json!({"type": "response.function_call_arguments.delta", "output_index": 1,
+                "delta": r#""Paris"}"#}),
+            json!({"type": "response.output_item.done", "output_index": 1, "item": done}),
+            json!({"type": "response.completed", "response": {
+                "status": "completed",
+                "output": [{"type": "reasoning", "summary": []}, done],
+                "usage": {"input_tokens": 30, "output_tokens": 9}
+            }}),
+        ])
+        .await;
+        let mut handler = agent.stream_completion().await.unwrap();
+        assert_eq!(
+            handler.collect(&mut agent).await.unwrap(),
+            CollectedCompletion::ToolCalls(vec![ToolCall {
+                id: "call_1".to_string(),
+                name: "get_weather".to_string(),
+                arguments: r#"{"city":"Paris"}"#.to_string(),
+            }])
+        );
+        assert!(agent.cache.as_ref().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_response_ends_stream_with_error() {
+        let mut agent = streaming_agent(&[
+            json!({"type": "response.output_text.delta", "output_index": 0, "delta": "Hel"}),
+            json!({"type": "response.failed", "response": {
+                "status": "failed",
+                "error": {"code": "server_error", "message": "The server had an error"},
+                "output": []
+            }}),
+        ])
+        .await;
+        let mut handler = agent.stream_completion().await.unwrap();
+        match handler.collect(&mut agent).await {
+            Err(StreamError::StreamRecievedErr(event)) => {
+                assert_eq!(event["response"]["error"]["code"], "server_error")
+            }
+            other => panic!("expected the failed event, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn responses_parsed() {
+        let response: ResponsesOutcome = serde_json::from_value(json!({
+            "id": "resp_1",
+            "status": "incomplete",
+            "incomplete_details": {"reason": "max_output_tokens"},
+            "output": [
+                {"type": "reasoning", "id": "rs_1", "summary": []},
+                {"type": "message", "role": "assistant", "content": [
+                    {"type": "output_text", "text": "Partial ", "annotations": []},
+                    {"type": "refusal", "refusal": "no"},
+                    {"type": "output_text", "text": "answer", "annotations": []}
+                ]}
+            ],
+            "usage": {"input_tokens": 5, "output_tokens": 10, "total_tokens": 15}
+        }))
+        .unwrap();
+        let ResponsesOutcome::Success(response) = response else {
+            panic!("expected a response, got {:?}", response);
+        };
+        assert_eq!(response.text(), "Partial answer");
+        assert_eq!(response.finish_reason(), "max_output_tokens");
+
+        let function_response = json!({
+            "status": "completed",
+            "output": [{"type": "function_call", "call_id": "call_1", "name": "get_weather",
+                "arguments": r#"{"city":"Paris"}"#}],
+            "usage": {"input_tokens": 20, "output_tokens": 8}
+        });
+        let model = OpenAiResponsesModel::default();
+        assert_eq!(
+            model.usage_from_function_response(&function_response),
+            Some(TokenUsage {
+                input_tokens: 20,
+                output_tokens: 8
+            })
+        );
+        assert_eq!(
+            model.process_function_response(function_response).unwrap(),
+            json!({"city": "Paris"})
+        );
+    }
+}
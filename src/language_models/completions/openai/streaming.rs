@@ -1,34 +1,162 @@
-use super::super::streaming::{CompletionStreamStatus, StreamResponse};
-use serde::Deserialize;
+use super::{
+    super::{
+        streaming::{CompletionStreamStatus, ProviderStreamError, StreamResponse, ToolCallDelta},
+        TokenUsage,
+    },
+    requests::OpenAiUsage,
+};
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::Value;
 
-impl StreamResponse for OpenAiStreamResponse {}
+impl StreamResponse for OpenAiStreamResponse {
+    const STRICT: bool = cfg!(feature = "strict-streams");
 
+    fn usage(&self) -> Option<TokenUsage> {
+        self.usage.to_owned().map(|u| u.into())
+    }
+
+    fn finish_reason(&self) -> Option<String> {
+        self.choices.first()?.finish_reason.to_owned()
+    }
+
+    fn tool_call_deltas(&self) -> Vec<ToolCallDelta> {
+        let choice = match self.choices.first() {
+            Some(choice) => choice,
+            None => return vec![],
+        };
+        choice
+            .delta
+            .tool_calls
+            .iter()
+            .map(|call| ToolCallDelta {
+                index: call.index,
+                id: call.id.to_owned(),
+                name: call.function.name.to_owned(),
+                arguments: call.function.arguments.to_owned().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    fn is_tool_call_finish(reason: &str) -> bool {
+        reason == "tool_calls"
+    }
+
+    fn stream_error(frame: &Value) -> Option<ProviderStreamError> {
+        let frame = serde_json::from_value::<OpenAiStreamErrorFrame>(frame.clone()).ok()?;
+        Some(ProviderStreamError {
+            kind: frame.error.kind,
+            code: frame.error.code,
+            message: frame.error.message,
+        })
+    }
+}
+
+/// A frame sent in place of a chunk when generation fails partway
+#[derive(Debug, Deserialize)]
+struct OpenAiStreamErrorFrame {
+    error: OpenAiStreamError,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiStreamError {
+    #[serde(default)]
+    message: String,
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+    #[serde(default)]
+    code: Option<String>,
+}
+
+/// With the `strict-streams` feature, a field not declared here or on the types it contains
+/// fails the chunk. Fields that are sent but not used are declared as `IgnoredAny`
 #[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(feature = "strict-streams", serde(deny_unknown_fields))]
 pub struct OpenAiStreamResponse {
     pub choices: Vec<StreamChoice>,
+    /// Only present on the final chunk, which has no choices
+    #[serde(default)]
+    pub usage: Option<OpenAiUsage>,
+    #[serde(default, rename = "id")]
+    _id: IgnoredAny,
+    #[serde(default, rename = "object")]
+    _object: IgnoredAny,
+    #[serde(default, rename = "created")]
+    _created: IgnoredAny,
+    #[serde(default, rename = "model")]
+    _model: IgnoredAny,
+    #[serde(default, rename = "system_fingerprint")]
+    _system_fingerprint: IgnoredAny,
+    #[serde(default, rename = "service_tier")]
+    _service_tier: IgnoredAny,
+    #[serde(default, rename = "obfuscation")]
+    _obfuscation: IgnoredAny,
+    /// Sent by Azure
+    #[serde(default, rename = "prompt_filter_results")]
+    _prompt_filter_results: IgnoredAny,
 }
 
 #[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(feature = "strict-streams", serde(deny_unknown_fields))]
 struct StreamChoice {
     pub delta: StreamDelta,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+    #[serde(default, rename = "index")]
+    _index: IgnoredAny,
+    #[serde(default, rename = "logprobs")]
+    _logprobs: IgnoredAny,
+    /// Sent by Azure
+    #[serde(default, rename = "content_filter_results")]
+    _content_filter_results: IgnoredAny,
 }
 
 #[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(feature = "strict-streams", serde(deny_unknown_fields))]
 struct StreamDelta {
     pub role: Option<String>,
     pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<StreamToolCall>,
+    #[serde(default, rename = "refusal")]
+    _refusal: IgnoredAny,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(feature = "strict-streams", serde(deny_unknown_fields))]
+struct StreamToolCall {
+    pub index: usize,
+    pub id: Option<String>,
+    #[serde(default)]
+    pub function: StreamFunction,
+    #[serde(default, rename = "type")]
+    _kind: IgnoredAny,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "strict-streams", serde(deny_unknown_fields))]
+struct StreamFunction {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
 }
 
 impl Into<CompletionStreamStatus> for OpenAiStreamResponse {
     fn into(self) -> CompletionStreamStatus {
-        match self.choices[0].delta.content.to_owned() {
+        // The usage chunk is sent after the chunk containing the finish reason, so the stream is
+        // only finished once it comes through. Azure also sends chunks with no choices, carrying
+        // content filter results, which aren't the end of the stream
+        let choice = match self.choices.first() {
+            Some(choice) => choice,
+            None if self.usage.is_some() => return CompletionStreamStatus::Finished(String::new()),
+            None => return CompletionStreamStatus::Working(String::new()),
+        };
+        match choice.delta.content.to_owned() {
             Some(response) => CompletionStreamStatus::Working(
                 response
                     .trim_start_matches('"')
                     .trim_end_matches('"')
                     .to_string(),
             ),
-            None => CompletionStreamStatus::Finished,
+            None => CompletionStreamStatus::Working(String::new()),
         }
     }
 }
@@ -0,0 +1,323 @@
+//! Driving a stream handler on its own task, handing what it receives to callbacks
+use super::{
+    lock_agent, CancelReason, CompletionStreamStatus, ProviderStreamHandler, StreamCanceller,
+    StreamError, StreamResult,
+};
+use crate::agents::{
+    memory::{Message, MessageRole},
+    Agent,
+};
+use anyhow::anyhow;
+use std::{
+    any::Any,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::Arc,
+};
+use tokio::{sync::Mutex, task::JoinHandle};
+use tracing::warn;
+
+/// Finish reason passed to `on_finish` for completions cut at the handler's maximum length
+const CONTENT_LIMIT_FINISH: &str = "content_limit";
+
+type StartCallback = Box<dyn FnMut(MessageRole) + Send>;
+type TokenCallback = Box<dyn FnMut(&str) + Send>;
+type FinishCallback = Box<dyn FnMut(&Message, Option<&str>) + Send>;
+type ErrorCallback = Box<dyn FnMut(&StreamError) + Send>;
+
+/// Closures called by `ProviderStreamHandler::drive` as the stream progresses, for consumers
+/// that can't poll the handler themselves, such as FFI layers or GUI event loops. They are all
+/// called from the task driving the stream, one at a time & in order: `on_start` before the
+/// first token, `on_token` with each non empty token, then either `on_finish` or `on_error`.
+/// They shouldn't block, since they hold up receiving. A panic in one is caught & ends the
+/// stream through `on_error`
+#[derive(Default)]
+pub struct StreamCallbacks {
+    on_start: Option<StartCallback>,
+    on_token: Option<TokenCallback>,
+    on_finish: Option<FinishCallback>,
+    on_error: Option<ErrorCallback>,
+}
+
+impl std::fmt::Debug for StreamCallbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamCallbacks")
+            .field("on_start", &self.on_start.is_some())
+            .field("on_token", &self.on_token.is_some())
+            .field("on_finish", &self.on_finish.is_some())
+            .field("on_error", &self.on_error.is_some())
+            .finish()
+    }
+}
+
+impl StreamCallbacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called with the role of the message being streamed, once the stream starts producing
+    pub fn with_on_start(mut self, on_start: impl FnMut(MessageRole) + Send + 'static) -> Self {
+        self.on_start = Some(Box::new(on_start));
+        self
+    }
+
+    /// Called with each non empty token as it is received
+    pub fn with_on_token(mut self, on_token: impl FnMut(&str) + Send + 'static) -> Self {
+        self.on_token = Some(Box::new(on_token));
+        self
+    }
+
+    /// Called with the cached message & the provider's finish reason once the completion
+    /// finishes. Truncated completions finish with `"content_limit"`
+    pub fn with_on_finish(
+        mut self,
+        on_finish: impl FnMut(&Message, Option<&str>) + Send + 'static,
+    ) -> Self {
+        self.on_finish = Some(Box::new(on_finish));
+        self
+    }
+
+    /// Called with the error that ended the stream, including cancels & panicking callbacks
+    pub fn with_on_error(mut self, on_error: impl FnMut(&StreamError) + Send + 'static) -> Self {
+        self.on_error = Some(Box::new(on_error));
+        self
+    }
+}
+
+/// Returned by `ProviderStreamHandler::drive`, cancels the driven stream with
+/// `CancelReason::User` when cancelled or dropped, unless detached
+#[must_use = "dropping the guard cancels the stream, detach it to let the stream run"]
+#[derive(Debug)]
+pub struct CallbackGuard {
+    canceller: Option<StreamCanceller>,
+}
+
+impl CallbackGuard {
+    /// Cancels the stream if it is still in flight, returning whether it was
+    pub fn cancel(mut self) -> bool {
+        self.canceller
+            .take()
+            .is_some_and(|canceller| canceller.cancel(CancelReason::User))
+    }
+
+    /// Lets the stream run to the end without the guard
+    pub fn detach(mut self) {
+        self.canceller = None;
+    }
+}
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        if let Some(canceller) = self.canceller.take() {
+            canceller.cancel(CancelReason::User);
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Runs a callback, turning a panic into the error ending the stream
+fn guarded(callback: impl FnOnce()) -> StreamResult<()> {
+    catch_unwind(AssertUnwindSafe(callback)).map_err(|payload| {
+        StreamError::Undefined(anyhow!(
+            "Stream callback panicked: {}",
+            panic_message(payload.as_ref())
+        ))
+    })
+}
+
+/// Receives from `handler` until it ends, calling the callbacks. Returns the finished message
+async fn drive(
+    handler: &mut ProviderStreamHandler,
+    agent: &Mutex<Agent>,
+    callbacks: &mut StreamCallbacks,
+) -> StreamResult<Message> {
+    let mut started = false;
+    let mut start = |callbacks: &mut StreamCallbacks| {
+        if std::mem::replace(&mut started, true) {
+            return Ok(());
+        }
+        guarded(|| {
+            if let Some(on_start) = callbacks.on_start.as_mut() {
+                on_start(MessageRole::Assistant)
+            }
+        })
+    };
+    loop {
+        let received = handler.receive_shared(agent).await;
+        let (content, finish_reason) = match received {
+            Ok(Some(CompletionStreamStatus::Working(token))) => {
+                start(callbacks)?;
+                if !token.is_empty() {
+                    guarded(|| {
+                        if let Some(on_token) = callbacks.on_token.as_mut() {
+                            on_token(&token)
+                        }
+                    })?;
+                }
+                continue;
+            }
+            Err(StreamError::ReceiverTimeout) => continue,
+            Ok(Some(CompletionStreamStatus::Finished(content))) => {
+                (content, handler.finish_reason())
+            }
+            Ok(Some(CompletionStreamStatus::Truncated(content))) => {
+                (content, Some(CONTENT_LIMIT_FINISH.to_owned()))
+            }
+            Ok(Some(CompletionStreamStatus::ToolCalls(calls))) => {
+                return Err(anyhow!(
+                    "Stream finished with {} tool calls, which need to be received directly",
+                    calls.len()
+                )
+                .into())
+            }
+            Ok(None) => return Err(StreamError::PrematureClose),
+            Err(err) => return Err(err),
+        };
+        start(callbacks)?;
+        let cached = lock_agent(agent, handler.agent_lock_timeout())
+            .await?
+            .cache
+            .as_ref()
+            .last()
+            .cloned();
+        let message = cached.unwrap_or_else(|| Message::new_assistant(&content));
+        guarded(|| {
+            if let Some(on_finish) = callbacks.on_finish.as_mut() {
+                on_finish(&message, finish_reason.as_deref())
+            }
+        })?;
+        return Ok(message);
+    }
+}
+
+impl ProviderStreamHandler {
+    /// Receives the whole stream on a spawned task, calling `callbacks` as it goes, see
+    /// `StreamCallbacks`. The finished message is cached as usual & resolves the join handle.
+    /// The stream's canceller is replaced by the returned guard's
+    pub fn drive(
+        self,
+        agent: Arc<Mutex<Agent>>,
+        mut callbacks: StreamCallbacks,
+    ) -> (JoinHandle<StreamResult<Message>>, CallbackGuard) {
+        let canceller = StreamCanceller::new();
+        let mut handler = self.with_canceller(&canceller);
+        let handle = tokio::spawn(async move {
+            let result = drive(&mut handler, &agent, &mut callbacks).await;
+            let Err(err) = result else {
+                return result;
+            };
+            if !handler.is_finished() {
+                warn!("Driven stream failed, cancelling it: {}", err);
+                match lock_agent(&agent, handler.agent_lock_timeout()).await {
+                    Ok(mut agent) => handler.cancel(&mut agent, CancelReason::User),
+                    Err(lock_err) => warn!("Couldn't cancel the driven stream: {}", lock_err),
+                }
+            }
+            if let Some(on_error) = callbacks.on_error.as_mut() {
+                if guarded(|| on_error(&err)).is_err() {
+                    warn!("Stream error callback panicked");
+                }
+            }
+            Err(err)
+        });
+        let guard = CallbackGuard {
+            canceller: Some(canceller),
+        };
+        (handle, guard)
+    }
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::{streaming::StreamReplay, CompletionModel};
+    use std::time::Duration;
+
+    const RECORDED: &str = "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{\"content\":\" world\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n\
+        data: [DONE]\n\n";
+
+    fn replay(cadence: Duration, agent: &Agent) -> ProviderStreamHandler {
+        StreamReplay::from_reader(std::io::Cursor::new(RECORDED.as_bytes().to_vec()))
+            .with_cadence(cadence)
+            .into_handler(&agent.completion_model.provider)
+    }
+
+    /// Callbacks recording each call to `calls`
+    fn recording(calls: &Arc<std::sync::Mutex<Vec<String>>>) -> StreamCallbacks {
+        let (start, token, finish, error) =
+            (calls.clone(), calls.clone(), calls.clone(), calls.clone());
+        StreamCallbacks::new()
+            .with_on_start(move |role| start.lock().unwrap().push(format!("start {:?}", role)))
+            .with_on_token(move |t| token.lock().unwrap().push(t.to_owned()))
+            .with_on_finish(move |message, reason| {
+                let finished = format!("finish {} {:?}", message.content, reason);
+                finish.lock().unwrap().push(finished)
+            })
+            .with_on_error(move |err| error.lock().unwrap().push(format!("error {}", err)))
+    }
+
+    #[tokio::test]
+    async fn callbacks_called_in_order() {
+        let agent = Agent::new(None, CompletionModel::default_openai(""));
+        let handler = replay(Duration::ZERO, &agent);
+        let agent = Arc::new(Mutex::new(agent));
+        let calls = Arc::new(std::sync::Mutex::new(vec![]));
+        let (handle, guard) = handler.drive(agent.clone(), recording(&calls));
+        guard.detach();
+        let message = handle.await.unwrap().unwrap();
+        assert_eq!(message.content, "Hello world");
+        assert_eq!(agent.lock().await.cache.as_ref().last(), Some(&message));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "start Assistant",
+                "Hello",
+                " world",
+                "finish Hello world Some(\"stop\")"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn panics_and_cancels_end_through_on_error() {
+        let agent = Agent::new(None, CompletionModel::default_openai(""));
+        let handler = replay(Duration::ZERO, &agent);
+        let agent = Arc::new(Mutex::new(agent));
+        let errors = Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = errors.clone();
+        let callbacks = StreamCallbacks::new()
+            .with_on_token(|token| assert_ne!(token, " world", "bad token"))
+            .with_on_error(move |err| recorded.lock().unwrap().push(err.to_string()));
+        let (handle, guard) = handler.drive(agent.clone(), callbacks);
+        let err = handle.await.unwrap().unwrap_err();
+        assert!(err.to_string().starts_with("Stream callback panicked"));
+        assert_eq!(*errors.lock().unwrap(), [err.to_string()]);
+        assert!(!guard.cancel());
+        assert_eq!(
+            agent.lock().await.cache.as_ref().last().unwrap().content,
+            "Hello world"
+        );
+
+        let handler = replay(Duration::from_millis(100), &*agent.lock().await);
+        let calls = Arc::new(std::sync::Mutex::new(vec![]));
+        let (handle, guard) = handler.drive(agent.clone(), recording(&calls));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(guard.cancel());
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(StreamError::Cancelled(CancelReason::User))
+        ));
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            "error Stream cancelled: cancelled by user"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Cancelling a stream from somewhere other than where it is received
+use super::CancelReason;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct CancelState {
+    next_id: u64,
+    /// Id of the attached stream that hasn't ended yet
+    in_flight: Option<u64>,
+    requested: Option<(u64, CancelReason)>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<CancelState>,
+    notify: Notify,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, CancelState> {
+        self.state.lock().expect("cancel state lock poisoned")
+    }
+}
+
+/// Cancels whichever stream is attached to it & in flight, such as from another task watching
+/// for the completion to become irrelevant. Streams are attached with
+/// `ProviderStreamHandler::with_canceller`, one at a time. The cancel is carried out by the
+/// stream's next `receive`, so a completion that has already finished is never cancelled
+#[derive(Debug, Clone, Default)]
+pub struct StreamCanceller {
+    shared: Arc<Shared>,
+}
+
+impl StreamCanceller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the stream in flight to cancel with `reason`. Returns false if there is none, such
+    /// as when the last attached stream just finished
+    pub fn cancel(&self, reason: CancelReason) -> bool {
+        let mut state = self.shared.state();
+        let Some(id) = state.in_flight else {
+            return false;
+        };
+        state.requested = Some((id, reason));
+        self.shared.notify.notify_waiters();
+        true
+    }
+
+    /// Whether an attached stream hasn't ended yet
+    pub fn is_in_flight(&self) -> bool {
+        let state = self.shared.state();
+        state.in_flight.is_some()
+    }
+
+    /// Makes a stream the one in flight, replacing any earlier one
+    pub(super) fn attach(&self) -> AttachedCanceller {
+        let mut state = self.shared.state();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.in_flight = Some(id);
+        state.requested = None;
+        AttachedCanceller {
+            canceller: self.clone(),
+            id,
+        }
+    }
+}
+
+/// A stream's attachment to a `StreamCanceller`, it is no longer in flight once this is ended
+/// or dropped
+#[derive(Debug)]
+pub(super) struct AttachedCanceller {
+    canceller: StreamCanceller,
+    id: u64,
+}
+
+impl AttachedCanceller {
+    fn requested(&self) -> Option<CancelReason> {
+        let state = self.canceller.shared.state();
+        state
+            .requested
+            .filter(|(id, _)| *id == self.id)
+            .map(|(_, reason)| reason)
+    }
+
+    /// Waits for a cancel to be requested for this stream
+    pub(super) async fn wait(&self) -> CancelReason {
+        loop {
+            let notified = self.canceller.shared.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(reason) = self.requested() {
+                return reason;
+            }
+            notified.await;
+        }
+    }
+
+    /// Marks the stream as no longer in flight, later cancels don't reach it
+    pub(super) fn end(&self) {
+        let mut state = self.canceller.shared.state();
+        if state.in_flight == Some(self.id) {
+            state.in_flight = None;
+        }
+        if state.requested.is_some_and(|(id, _)| id == self.id) {
+            state.requested = None;
+        }
+    }
+}
+
+impl Drop for AttachedCanceller {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::Agent,
+        language_models::completions::{
+            streaming::{
+                CollectedCompletion, CompletionStreamStatus, ProviderStreamHandler, StreamError,
+                StreamReplay,
+            },
+            CompletionModel,
+        },
+    };
+    use std::time::Duration;
+
+    const RECORDED: &str = "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{\"content\":\" world\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n\
+        data: [DONE]\n\n";
+
+    fn replay(cadence: Duration, agent: &Agent) -> ProviderStreamHandler {
+        StreamReplay::from_reader(std::io::Cursor::new(RECORDED.as_bytes().to_vec()))
+            .with_cadence(cadence)
+            .into_handler(&agent.completion_model.provider)
+    }
+
+    #[tokio::test]
+    async fn only_the_stream_in_flight_cancelled() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let canceller = StreamCanceller::new();
+        assert!(!canceller.cancel(CancelReason::User));
+
+        let mut handler = replay(Duration::from_millis(100), &agent).with_canceller(&canceller);
+        assert!(canceller.is_in_flight());
+        let first = handler.receive(&mut agent).await.unwrap();
+        assert!(matches!(first, Some(CompletionStreamStatus::Working(ref t)) if t == "Hello"));
+        assert!(canceller.cancel(CancelReason::User));
+        assert!(matches!(
+            handler.receive(&mut agent).await,
+            Err(StreamError::Cancelled(CancelReason::User))
+        ));
+        assert_eq!(agent.cache.as_ref().last().unwrap().content, "Hello");
+        assert!(!canceller.is_in_flight());
+        assert!(!canceller.cancel(CancelReason::User));
+
+        // A stream that already finished is left as it is
+        let mut handler = replay(Duration::ZERO, &agent).with_canceller(&canceller);
+        let collected = handler.collect(&mut agent).await.unwrap();
+        assert!(matches!(collected, CollectedCompletion::Finished(ref c) if c == "Hello world"));
+        assert!(!canceller.cancel(CancelReason::User));
+        assert!(handler.cancel_reason().is_none());
+        assert_eq!(agent.cache.len(), 2);
+    }
+}
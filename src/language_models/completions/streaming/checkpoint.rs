@@ -0,0 +1,55 @@
+use crate::agents::memory::{Message, MessageStack};
+use std::time::{Duration, Instant};
+
+/// How often a streamed completion's content is checkpointed to the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointInterval {
+    /// After every N received tokens
+    Tokens(usize),
+    /// When a token is received at least this long after the last checkpoint
+    Period(Duration),
+}
+
+#[derive(Debug)]
+pub(super) struct CheckpointState {
+    interval: CheckpointInterval,
+    /// Index of the in progress assistant message once the first checkpoint is written
+    pub(super) index: Option<usize>,
+    tokens_since: usize,
+    since: Instant,
+}
+
+impl CheckpointState {
+    pub(super) fn new(interval: CheckpointInterval) -> Self {
+        Self {
+            interval,
+            index: None,
+            tokens_since: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Writes `content` to the cache if a checkpoint is due. Empty tokens aren't counted
+    pub(super) fn token_received(&mut self, cache: &mut MessageStack, token: &str, content: &str) {
+        if token.is_empty() {
+            return;
+        }
+        self.tokens_since += 1;
+        let due = match self.interval {
+            CheckpointInterval::Tokens(n) => self.tokens_since >= n,
+            CheckpointInterval::Period(period) => self.since.elapsed() >= period,
+        };
+        if !due || content.is_empty() {
+            return;
+        }
+        match self.index {
+            Some(index) => cache.update_content(index, content),
+            None => {
+                cache.push(Message::new_assistant(content));
+                self.index = Some(cache.len() - 1);
+            }
+        }
+        self.tokens_since = 0;
+        self.since = Instant::now();
+    }
+}
@@ -1,7 +1,12 @@
+use super::CancelReason;
 use reqwest_streams::error::StreamBodyError;
 
 use crate::errors::error_chain_fmt;
-use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    time::Duration,
+};
 
 pub type StreamResult<T> = Result<T, StreamError>;
 #[derive(thiserror::Error)]
@@ -9,10 +14,24 @@ pub enum StreamError {
     #[error(transparent)]
     Undefined(#[from] anyhow::Error),
     Json(#[from] serde_json::Error),
+    /// The stream's body couldn't be decoded
     StreamBody(#[from] StreamBodyError),
+    /// Reading the stream's body failed, such as the connection dropping
+    Connection(#[source] std::io::Error),
     StreamRecievedErr(serde_json::Value),
+    /// An error object the provider sent in the stream itself, such as a failure partway
+    /// through generating
+    Provider(ProviderStreamError),
     ReceiverTimeout,
     RetryError,
+    PrematureClose,
+    /// The stream was cancelled, it won't produce anything more
+    Cancelled(CancelReason),
+    /// A shared agent's lock wasn't acquired within the timeout, such as while other tasks held
+    /// it. Nothing was received, the stream can be received from again
+    AgentLockTimeout(Duration),
+    /// A lock was poisoned by a panic while it was held, naming what it guards
+    LockPoisoned(&'static str),
 }
 
 impl Debug for StreamError {
@@ -21,6 +40,14 @@ impl Debug for StreamError {
     }
 }
 
+impl StreamError {
+    /// Whether re-requesting the completion could succeed. Connection failures, premature
+    /// closes, rate limits & server errors are recoverable, malformed responses are not
+    pub fn is_recoverable(&self) -> bool {
+        self.retry_category().is_some()
+    }
+}
+
 impl From<serde_json::Value> for StreamError {
     fn from(value: serde_json::Value) -> Self {
         Self::StreamRecievedErr(value)
@@ -33,10 +60,83 @@ impl Display for StreamError {
             Self::Json(err) => err.to_string(),
             Self::Undefined(err) => err.to_string(),
             Self::StreamBody(err) => err.to_string(),
+            Self::Connection(_) => "Stream connection error".to_string(),
             Self::StreamRecievedErr(err) => err.to_string(),
+            Self::Provider(err) => err.to_string(),
             Self::RetryError => "Retry Error".to_string(),
             Self::ReceiverTimeout => "Receiver Timeout".to_string(),
+            Self::PrematureClose => "Stream closed before completion finished".to_string(),
+            Self::Cancelled(reason) => format!("Stream cancelled: {}", reason),
+            Self::AgentLockTimeout(timeout) => {
+                format!("Timed out after {:?} waiting for the agent's lock", timeout)
+            }
+            Self::LockPoisoned(guarded) => format!("The {} lock was poisoned by a panic", guarded),
         };
         write!(f, "{}", display)
     }
 }
+
+/// The `error` object of a stream frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderStreamError {
+    /// The error's `type`
+    pub kind: Option<String>,
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl Display for ProviderStreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Provider sent an error mid stream: {}", self.message)?;
+        if let Some(code) = self.code.as_ref().or(self.kind.as_ref()) {
+            write!(f, " ({})", code)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `StreamError` in a form that can be sent to another process, see `crate::wire`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WireStreamError {
+    /// The error's variant, in snake case
+    pub kind: String,
+    /// The error's `Display`
+    pub message: String,
+    pub recoverable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<ProviderStreamError>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancel_reason: Option<CancelReason>,
+}
+
+impl From<&StreamError> for WireStreamError {
+    fn from(err: &StreamError) -> Self {
+        let kind = match err {
+            StreamError::Undefined(_) => "undefined",
+            StreamError::Json(_) => "json",
+            StreamError::StreamBody(_) => "stream_body",
+            StreamError::Connection(_) => "connection",
+            StreamError::StreamRecievedErr(_) => "received_error",
+            StreamError::Provider(_) => "provider",
+            StreamError::ReceiverTimeout => "receiver_timeout",
+            StreamError::RetryError => "retry",
+            StreamError::PrematureClose => "premature_close",
+            StreamError::Cancelled(_) => "cancelled",
+            StreamError::AgentLockTimeout(_) => "agent_lock_timeout",
+            StreamError::LockPoisoned(_) => "lock_poisoned",
+        };
+        Self {
+            kind: kind.to_owned(),
+            message: err.to_string(),
+            recoverable: err.is_recoverable(),
+            provider: match err {
+                StreamError::Provider(provider) => Some(provider.clone()),
+                _ => None,
+            },
+            cancel_reason: match err {
+                StreamError::Cancelled(reason) => Some(*reason),
+                _ => None,
+            },
+        }
+    }
+}
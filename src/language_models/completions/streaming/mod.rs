@@ -1,21 +1,54 @@
 use serde_json::Value;
 use std::fmt::Debug;
 use std::marker::PhantomData;
-use std::time::Duration;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 use tracing::warn;
 use tracing_log::log::info;
+mod callbacks;
+mod canceller;
+mod checkpoint;
 pub mod error;
+mod replay;
+mod retry;
+#[cfg(feature = "http-sse")]
+mod server_events;
+mod sink;
+pub(crate) mod sse;
+mod timeouts;
+mod tool_calls;
+#[cfg(feature = "tui")]
+mod tui;
 use crate::agents::memory::Message;
-use crate::agents::Agent;
+use crate::agents::{build_request_stack, Agent, ContractViolation};
 use anyhow::anyhow;
+pub use callbacks::{CallbackGuard, StreamCallbacks};
+use canceller::AttachedCanceller;
+pub use canceller::StreamCanceller;
+pub use checkpoint::CheckpointInterval;
+use checkpoint::CheckpointState;
 pub use error::*;
 use futures::Stream;
 use futures_util::StreamExt;
-use serde::Deserialize;
+pub use replay::StreamReplay;
+pub(crate) use retry::{retry_after, RetryState};
+pub use retry::{RetryBudget, RetryCategory, RetryPolicy, DEFAULT_MAX_RETRY_DELAY};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "http-sse")]
+pub use server_events::{SseBody, DEFAULT_KEEP_ALIVE, SSE_CONTENT_TYPE};
+pub use sink::TextSink;
+use timeouts::TimeoutState;
+pub use timeouts::{StreamDefaults, StreamTimeouts, DEFAULT_AGENT_LOCK_TIMEOUT};
+use tool_calls::ToolCallAccumulator;
+pub use tool_calls::{ToolCall, ToolCallDelta};
+#[cfg(feature = "tui")]
+pub use tui::{TuiBuffer, TuiSink, DEFAULT_MAX_LINES};
 
-use super::{
-    anthropic::streaming::AnthropicStreamResponse, openai::streaming::OpenAiStreamResponse,
-};
+#[cfg(feature = "anthropic")]
+use super::anthropic::streaming::AnthropicStreamResponse;
+#[cfg(feature = "openai")]
+use super::openai::{responses::OpenAiResponsesStreamEvent, streaming::OpenAiStreamResponse};
+use super::{error::CompletionError, TokenUsage};
 
 pub(in crate::language_models) type CompletionStream =
     Box<dyn Stream<Item = StreamResult<Value>> + Send + Unpin>;
@@ -28,40 +61,172 @@ pub(in crate::language_models) type CompletionStreamSender =
 pub trait StreamResponse:
     for<'de> Deserialize<'de> + Debug + Into<CompletionStreamStatus> + Clone + Send + Sync + 'static
 {
+    /// Whether frames that don't parse fail the stream with the parse error, rather than being
+    /// passed on as `StreamError::StreamRecievedErr`. For types rejecting unknown fields
+    const STRICT: bool = false;
+    /// Token usage reported by this chunk of the stream, if any
+    fn usage(&self) -> Option<TokenUsage> {
+        None
+    }
+    /// The reason the provider gave for finishing the completion, if this chunk contains one
+    fn finish_reason(&self) -> Option<String> {
+        None
+    }
+    /// Pieces of tool calls contained in this chunk
+    fn tool_call_deltas(&self) -> Vec<ToolCallDelta> {
+        vec![]
+    }
+    /// Whether `reason` means the completion stopped to make tool calls rather than with text
+    fn is_tool_call_finish(_reason: &str) -> bool {
+        false
+    }
+    /// The error a frame of the stream carries instead of a chunk, if the provider sends them
+    fn stream_error(_frame: &Value) -> Option<ProviderStreamError> {
+        None
+    }
 }
 
+/// How many times a stream that closes before the provider finishes it is resumed by default
+pub const DEFAULT_MAX_RESUMES: usize = 2;
+/// Set to `true` on the message cached for a stream that went over its maximum content length
+pub const TRUNCATED_METADATA_KEY: &str = "truncated";
+
 #[derive(Debug)]
 struct CompletionStreamingThread;
 
-#[derive(Debug)]
+/// Serialized with its variant as `type` & its contents as `content`, see `crate::wire`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content", rename_all = "snake_case")]
 pub enum CompletionStreamStatus {
     Working(String),
-    Finished,
+    /// Contains the complete message, including content from before any resumes. Providers
+    /// produce it empty, it is filled in by the handler
+    Finished(String),
+    /// The completion finished in order to make tool calls. No assistant message is pushed to the
+    /// cache, the calls should be executed instead
+    ToolCalls(Vec<ToolCall>),
+    /// The content went over the handler's maximum, so the stream was cancelled with
+    /// `CancelReason::ContentLimit`. Contains the content up to the maximum, which is cached
+    Truncated(String),
+}
+
+/// The end result of collecting a whole stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content", rename_all = "snake_case")]
+pub enum CollectedCompletion {
+    /// The full content of a finished completion
+    Finished(String),
+    /// The completion finished in order to make tool calls
+    ToolCalls(Vec<ToolCall>),
+    /// The stream was cancelled before the completion finished, contains the content received
+    /// until then
+    Partial {
+        content: String,
+        reason: CancelReason,
+    },
+}
+
+/// Why a stream was cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    /// A deadline passed, as in `ProviderStreamHandler::collect_with_deadline`
+    Deadline,
+    /// No token arrived within `StreamTimeouts::first_token`
+    FirstTokenTimeout,
+    /// No token arrived within `StreamTimeouts::inter_token` of the last one
+    InterTokenTimeout,
+    /// The completion didn't finish within `StreamTimeouts::total`
+    TotalTimeout,
+    /// The user asked for it to stop
+    User,
+    /// Resuming the stream was refused because the model's spend cap was reached
+    Budget,
+    /// The content went over the handler's maximum length
+    ContentLimit,
+    /// Something happened that made the completion irrelevant, such as a watched pane's
+    /// command being run again
+    Superseded,
+}
+
+impl std::fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let display = match self {
+            Self::Deadline => "deadline passed",
+            Self::FirstTokenTimeout => "timed out waiting for the first token",
+            Self::InterTokenTimeout => "timed out waiting for the next token",
+            Self::TotalTimeout => "timed out before finishing",
+            Self::User => "cancelled by user",
+            Self::Budget => "over budget",
+            Self::ContentLimit => "content too long",
+            Self::Superseded => "superseded",
+        };
+        write!(f, "{}", display)
+    }
+}
+
+/// What the polling thread has gathered from the stream besides tokens
+#[derive(Debug, Default)]
+struct StreamSummary {
+    usage: TokenUsage,
+    finish_reason: Option<String>,
+    tool_calls: ToolCallAccumulator,
 }
 
+/// Without a provider feature there are no variants, so no handler can be made
 #[derive(Debug)]
 pub enum ProviderStreamHandler {
+    #[cfg(feature = "openai")]
     OpenAi(StreamedCompletionHandler<OpenAiStreamResponse>),
+    #[cfg(feature = "anthropic")]
     Anthropic(StreamedCompletionHandler<AnthropicStreamResponse>),
+    #[cfg(feature = "openai")]
+    OpenAiResponses(StreamedCompletionHandler<OpenAiResponsesStreamEvent>),
 }
 
+#[cfg(feature = "openai")]
 impl From<StreamedCompletionHandler<OpenAiStreamResponse>> for ProviderStreamHandler {
     fn from(value: StreamedCompletionHandler<OpenAiStreamResponse>) -> Self {
         Self::OpenAi(value)
     }
 }
 
+#[cfg(feature = "anthropic")]
 impl From<StreamedCompletionHandler<AnthropicStreamResponse>> for ProviderStreamHandler {
     fn from(value: StreamedCompletionHandler<AnthropicStreamResponse>) -> Self {
         Self::Anthropic(value)
     }
 }
 
+#[cfg(feature = "openai")]
+impl From<StreamedCompletionHandler<OpenAiResponsesStreamEvent>> for ProviderStreamHandler {
+    fn from(value: StreamedCompletionHandler<OpenAiResponsesStreamEvent>) -> Self {
+        Self::OpenAiResponses(value)
+    }
+}
+
 pub struct StreamedCompletionHandler<T> {
     phantom: PhantomData<T>,
     stream: Option<CompletionStream>,
     sender: Option<CompletionStreamSender>,
     receiver: CompletionStreamReceiver,
+    summary: Arc<Mutex<StreamSummary>>,
+    typing_delay: Duration,
+    last_emission: Option<Instant>,
+    retry: RetryState,
+    timeouts: TimeoutState,
+    checkpoint: Option<CheckpointState>,
+    task: Option<tokio::task::JoinHandle<StreamResult<()>>>,
+    sinks: Vec<Box<dyn TextSink>>,
+    /// Kept for logging the completion once it finishes
+    request_body: Option<Value>,
+    cancelled: Option<CancelReason>,
+    canceller: Option<AttachedCanceller>,
+    max_content_bytes: Option<usize>,
+    /// Set once `receive` has returned the completion's content or tool calls
+    finished: bool,
+    /// How the finished content broke the agent's output contract, if it did
+    contract_violation: Option<ContractViolation>,
     pub message_content: String,
 }
 
@@ -72,6 +237,8 @@ impl<T> std::fmt::Debug for StreamedCompletionHandler<T> {
             .field("sender", &self.sender)
             .field("phantom", &self.phantom)
             .field("receiver", &self.receiver)
+            .field("typing_delay", &self.typing_delay)
+            .field("sinks", &self.sinks.len())
             .finish()
     }
 }
@@ -85,24 +252,727 @@ impl<T> From<CompletionStream> for StreamedCompletionHandler<T> {
             stream: Some(stream),
             sender: Some(tx),
             receiver: rx,
+            summary: Arc::new(Mutex::new(StreamSummary::default())),
+            typing_delay: Duration::ZERO,
+            last_emission: None,
+            retry: RetryState::default(),
+            timeouts: TimeoutState::default(),
+            checkpoint: None,
+            task: None,
+            sinks: vec![],
+            request_body: None,
+            cancelled: None,
+            canceller: None,
+            max_content_bytes: None,
+            finished: false,
+            contract_violation: None,
             message_content: String::new(),
         }
     }
 }
 
 impl ProviderStreamHandler {
+    /// Artificial delay between emitted tokens so they appear at a human readable pace. Only
+    /// affects when `Working` statuses are returned from `receive`, not the accumulated content
+    pub fn with_typing_delay(self, delay: Duration) -> Self {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => Self::OpenAi(inner.with_typing_delay(delay)),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => Self::Anthropic(inner.with_typing_delay(delay)),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => Self::OpenAiResponses(inner.with_typing_delay(delay)),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// How many times a stream that closes before the provider finishes it, or whose connection
+    /// fails, will be re-requested, continuing from the content received so far. Only providers
+    /// that support assistant prefill are resumed once content has been received
+    pub fn with_max_resumes(self, max_resumes: usize) -> Self {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => Self::OpenAi(inner.with_max_resumes(max_resumes)),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => Self::Anthropic(inner.with_max_resumes(max_resumes)),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => {
+                Self::OpenAiResponses(inner.with_max_resumes(max_resumes))
+            }
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Retry budgets for each category of error, replacing those set by `with_max_resumes`
+    pub fn with_retry_policy(self, policy: RetryPolicy) -> Self {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => Self::OpenAi(inner.with_retry_policy(policy)),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => Self::Anthropic(inner.with_retry_policy(policy)),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => Self::OpenAiResponses(inner.with_retry_policy(policy)),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Cancel the stream when it takes longer than `timeouts`, with the `CancelReason` of the
+    /// timeout that passed first, replacing the model's default timeouts. The cancel is returned by `receive` as
+    /// `StreamError::Cancelled`
+    pub fn with_timeouts(self, timeouts: StreamTimeouts) -> Self {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => Self::OpenAi(inner.with_timeouts(timeouts)),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => Self::Anthropic(inner.with_timeouts(timeouts)),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => Self::OpenAiResponses(inner.with_timeouts(timeouts)),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Starts the stream with the timeouts & retry policy a model gives all its streams
+    pub(crate) fn with_defaults(self, defaults: &StreamDefaults) -> Self {
+        self.with_timeouts(defaults.timeouts)
+            .with_retry_policy(defaults.retry_policy.clone())
+    }
+
+    /// Let `canceller` cancel this stream while it is in flight, replacing the stream it had
+    /// attached. A cancel is carried out by the next `receive`, which returns
+    /// `StreamError::Cancelled`
+    pub fn with_canceller(self, canceller: &StreamCanceller) -> Self {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => Self::OpenAi(inner.with_canceller(canceller)),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => Self::Anthropic(inner.with_canceller(canceller)),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => Self::OpenAiResponses(inner.with_canceller(canceller)),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
     #[tracing::instrument("Receive tokens from completion stream", skip(self))]
     pub async fn receive(
         &mut self,
         agent: &mut Agent,
     ) -> StreamResult<Option<CompletionStreamStatus>> {
-        let response = match self {
-            Self::OpenAi(inner) => inner.receive(agent).await,
-            Self::Anthropic(inner) => inner.receive(agent).await,
+        let deadline = match self.is_finished() {
+            true => None,
+            false => self.timeout_state().deadline(),
         };
+        let received = match deadline {
+            Some((deadline, reason)) => {
+                match tokio::time::timeout_at(deadline, self.receive_cancellable(agent)).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        warn!("Stream timed out, cancelling it: {}", reason);
+                        self.cancel(agent, reason);
+                        Err(StreamError::Cancelled(reason))
+                    }
+                }
+            }
+            None => self.receive_cancellable(agent).await,
+        };
+        match &received {
+            Ok(Some(CompletionStreamStatus::Working(token))) if !token.is_empty() => {
+                self.timeout_state().token_received()
+            }
+            Ok(_) | Err(StreamError::ReceiverTimeout) => {}
+            Err(err) => {
+                let sinks = std::mem::take(self.sinks());
+                sinks.into_iter().for_each(|mut s| s.failed(err));
+            }
+        }
+        received
+    }
+
+    /// `receive` for an agent shared behind a mutex, locking it for just this receive so other
+    /// tasks can use it between tokens. A receive holds the lock until a token arrives or the
+    /// one second receiver timeout passes, plus the time to cache the finished message, and
+    /// longer while resuming a failed stream with a retry policy's backoff. Returns
+    /// `StreamError::AgentLockTimeout` if the lock isn't acquired within
+    /// `StreamTimeouts::agent_lock`, rather than waiting on it indefinitely
+    pub async fn receive_shared(
+        &mut self,
+        agent: &tokio::sync::Mutex<Agent>,
+    ) -> StreamResult<Option<CompletionStreamStatus>> {
+        let mut agent = lock_agent(agent, self.agent_lock_timeout()).await?;
+        self.receive(&mut agent).await
+    }
+
+    /// How long `receive_shared` waits for the agent's lock
+    pub(crate) fn agent_lock_timeout(&mut self) -> Duration {
+        let timeouts = &self.timeout_state().timeouts;
+        timeouts.agent_lock.unwrap_or(DEFAULT_AGENT_LOCK_TIMEOUT)
+    }
+
+    /// Receives, unless the stream's canceller asks for a cancel first
+    async fn receive_cancellable(
+        &mut self,
+        agent: &mut Agent,
+    ) -> StreamResult<Option<CompletionStreamStatus>> {
+        let Some(canceller) = self.take_canceller() else {
+            return self.receive_resuming(agent).await;
+        };
+        let reason = tokio::select! {
+            biased;
+            reason = canceller.wait() => reason,
+            received = self.receive_resuming(agent) => {
+                // Once the completion is returned it can't be cancelled
+                if !self.is_finished() {
+                    self.set_canceller(canceller);
+                }
+                return received;
+            }
+        };
+        self.cancel(agent, reason);
+        Err(StreamError::Cancelled(reason))
+    }
+
+    fn take_canceller(&mut self) -> Option<AttachedCanceller> {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => inner.canceller.take(),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => inner.canceller.take(),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => inner.canceller.take(),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    fn set_canceller(&mut self, canceller: AttachedCanceller) {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => inner.canceller = Some(canceller),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => inner.canceller = Some(canceller),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => inner.canceller = Some(canceller),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Receives, resuming the stream after recoverable errors
+    async fn receive_resuming(
+        &mut self,
+        agent: &mut Agent,
+    ) -> StreamResult<Option<CompletionStreamStatus>> {
+        loop {
+            let response = match self {
+                #[cfg(feature = "openai")]
+                Self::OpenAi(inner) => inner.receive(agent).await,
+                #[cfg(feature = "anthropic")]
+                Self::Anthropic(inner) => inner.receive(agent).await,
+                #[cfg(feature = "openai")]
+                Self::OpenAiResponses(inner) => inner.receive(agent).await,
+                #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+                _ => unreachable!("no provider features are enabled"),
+            };
+
+            tracing::warn!("got stream response:  {response:#?}");
+            let Err(err) = &response else {
+                return response;
+            };
+            let delay = match err.retry_category() {
+                Some(category) if self.can_resume(agent) => self.retry_state().attempt(category),
+                _ => None,
+            };
+            let Some(delay) = delay else {
+                return response;
+            };
+            warn!(
+                "Resuming stream after recoverable error in {:?}: {:?}",
+                delay, err
+            );
+            tokio::time::sleep(delay).await;
+            self.resume(agent).await?
+        }
+    }
+
+    /// Periodically write the content received so far to the agent's cache as an in progress
+    /// assistant message, so a crash mid stream doesn't lose it. The message is updated in
+    /// place at each checkpoint and finalized when the stream finishes
+    pub fn with_checkpoint_interval(self, interval: CheckpointInterval) -> Self {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => Self::OpenAi(inner.with_checkpoint_interval(interval)),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => Self::Anthropic(inner.with_checkpoint_interval(interval)),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => {
+                Self::OpenAiResponses(inner.with_checkpoint_interval(interval))
+            }
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Hand streamed text to `sink` as it is received. Any number of sinks can be added
+    pub fn with_sink(self, sink: impl TextSink + 'static) -> Self {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => Self::OpenAi(inner.with_sink(sink)),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => Self::Anthropic(inner.with_sink(sink)),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => Self::OpenAiResponses(inner.with_sink(sink)),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Stop the stream once its content would go over `max_bytes`, returning
+    /// `CompletionStreamStatus::Truncated`. Unlimited by default
+    pub fn with_max_content_bytes(self, max_bytes: usize) -> Self {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => Self::OpenAi(inner.with_max_content_bytes(max_bytes)),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => Self::Anthropic(inner.with_max_content_bytes(max_bytes)),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => {
+                Self::OpenAiResponses(inner.with_max_content_bytes(max_bytes))
+            }
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Receives until the stream finishes, returning the full content or tool calls. Waits out
+    /// receiver timeouts instead of returning them. Truncated content is returned as
+    /// `CollectedCompletion::Partial`
+    pub async fn collect(&mut self, agent: &mut Agent) -> StreamResult<CollectedCompletion> {
+        loop {
+            match self.receive(agent).await {
+                Ok(Some(CompletionStreamStatus::Working(_)))
+                | Err(StreamError::ReceiverTimeout) => continue,
+                Ok(Some(CompletionStreamStatus::Finished(content))) => {
+                    return Ok(CollectedCompletion::Finished(content))
+                }
+                Ok(Some(CompletionStreamStatus::ToolCalls(calls))) => {
+                    return Ok(CollectedCompletion::ToolCalls(calls))
+                }
+                Ok(Some(CompletionStreamStatus::Truncated(content))) => {
+                    return Ok(CollectedCompletion::Partial {
+                        content,
+                        reason: CancelReason::ContentLimit,
+                    })
+                }
+                Ok(None) => return Err(StreamError::PrematureClose),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Same as `collect`, but once `deadline` passes the stream is cancelled and the content
+    /// received so far is cached & returned as `CollectedCompletion::Partial`
+    pub async fn collect_with_deadline(
+        &mut self,
+        agent: &mut Agent,
+        deadline: tokio::time::Instant,
+    ) -> StreamResult<CollectedCompletion> {
+        if let Ok(result) = tokio::time::timeout_at(deadline, self.collect(agent)).await {
+            return result;
+        }
+        warn!("Deadline passed before stream finished, returning partial content");
+        self.cancel(agent, CancelReason::Deadline);
+        Ok(CollectedCompletion::Partial {
+            content: self.message_content().to_owned(),
+            reason: CancelReason::Deadline,
+        })
+    }
+
+    /// Stops the stream & caches the content received so far. Receiving afterwards returns
+    /// `StreamError::Cancelled` with `reason`
+    pub fn cancel(&mut self, agent: &mut Agent, reason: CancelReason) {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => inner.cancel(agent, reason),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => inner.cancel(agent, reason),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => inner.cancel(agent, reason),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Why the stream was cancelled, if it was
+    pub fn cancel_reason(&self) -> Option<CancelReason> {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => inner.cancelled,
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => inner.cancelled,
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => inner.cancelled,
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Whether the first `receive` has started polling the stream
+    pub fn has_started(&self) -> bool {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => inner.has_started(),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => inner.has_started(),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => inner.has_started(),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Whether `receive` has nothing left to return, without waiting on it
+    pub fn is_finished(&self) -> bool {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => inner.is_finished(),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => inner.is_finished(),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => inner.is_finished(),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// The reason the provider gave for finishing the completion, once it has given one
+    pub fn finish_reason(&self) -> Option<String> {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => inner.finish_reason(),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => inner.finish_reason(),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => inner.finish_reason(),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// How the finished content broke the agent's output contract, if it did. Checked once
+    /// the stream finishes, streamed responses aren't regenerated since their tokens have
+    /// already been returned
+    pub fn contract_violation(&self) -> Option<ContractViolation> {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => inner.contract_violation,
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => inner.contract_violation,
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => inner.contract_violation,
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Content received so far
+    pub fn message_content(&self) -> &str {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => &inner.message_content,
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => &inner.message_content,
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => &inner.message_content,
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// The body of the request that started this stream, logged once it finishes
+    pub(crate) fn set_request_body(&mut self, body: Value) {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => inner.request_body = Some(body),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => inner.request_body = Some(body),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => inner.request_body = Some(body),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Content received so far can only be continued by providers that support prefill
+    fn can_resume(&self, agent: &Agent) -> bool {
+        self.message_content().is_empty()
+            || agent.completion_model.provider.supports_assistant_prefill()
+    }
+
+    fn retry_state(&mut self) -> &mut RetryState {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => &mut inner.retry,
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => &mut inner.retry,
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => &mut inner.retry,
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    fn sinks(&mut self) -> &mut Vec<Box<dyn TextSink>> {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => &mut inner.sinks,
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => &mut inner.sinks,
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => &mut inner.sinks,
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    fn timeout_state(&mut self) -> &mut TimeoutState {
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => &mut inner.timeouts,
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => &mut inner.timeouts,
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => &mut inner.timeouts,
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+    }
+
+    /// Re-request the completion with the content received so far as an assistant prefill
+    #[tracing::instrument("Resume prematurely closed completion stream", skip_all)]
+    async fn resume(&mut self, agent: &mut Agent) -> StreamResult<()> {
+        let (content, checkpoint_index): (String, Option<usize>) = match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => (inner.message_content.to_owned(), inner.checkpoint_index()),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => (inner.message_content.to_owned(), inner.checkpoint_index()),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => {
+                (inner.message_content.to_owned(), inner.checkpoint_index())
+            }
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        };
+        // Usage of the dropped request is recorded now, the resumed request reports its own
+        match self {
+            #[cfg(feature = "openai")]
+            Self::OpenAi(inner) => agent.completion_model.record_usage(inner.usage()),
+            #[cfg(feature = "anthropic")]
+            Self::Anthropic(inner) => agent.completion_model.record_usage(inner.usage()),
+            #[cfg(feature = "openai")]
+            Self::OpenAiResponses(inner) => agent.completion_model.record_usage(inner.usage()),
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        }
+
+        // A checkpointed partial is replaced by the prefill
+        let mut cache = agent.cache.clone();
+        if let Some(index) = checkpoint_index {
+            cache.as_mut().remove(index);
+        }
+        let mut stack = build_request_stack(
+            &cache,
+            &agent.examples,
+            &agent.system_layers(),
+            agent.coalesce_consecutive_roles,
+        )
+        .into_owned();
+        // Providers reject a prefill ending in whitespace, and an empty one. The trailing
+        // whitespace is dropped from the content too, the resumed request sends it again
+        let prefill = content.trim_end();
+        if !prefill.is_empty() {
+            stack.push(Message::new_assistant(prefill));
+        }
+        let next = match agent.completion_model.get_stream_completion(&stack).await {
+            Ok(next) => next,
+            Err(CompletionError::BudgetExceeded) => {
+                warn!("Spend cap reached, cancelling stream rather than resuming it");
+                self.cancel(agent, CancelReason::Budget);
+                return Err(StreamError::Cancelled(CancelReason::Budget));
+            }
+            Err(err) => {
+                return Err(StreamError::Undefined(anyhow!(
+                    "Failed to resume stream: {:?}",
+                    err
+                )))
+            }
+        };
+
+        let kept = prefill.len();
+        match (self, next) {
+            #[cfg(feature = "openai")]
+            (Self::OpenAi(inner), Self::OpenAi(next)) => inner.continue_with(next, kept),
+            #[cfg(feature = "anthropic")]
+            (Self::Anthropic(inner), Self::Anthropic(next)) => inner.continue_with(next, kept),
+            #[cfg(feature = "openai")]
+            (Self::OpenAiResponses(inner), Self::OpenAiResponses(next)) => {
+                inner.continue_with(next, kept)
+            }
+            #[cfg(any(feature = "openai", not(feature = "anthropic")))]
+            _ => {
+                return Err(StreamError::Undefined(anyhow!(
+                    "Resumed stream changed provider"
+                )))
+            }
+        }
+        tracing::info!("Resumed stream after premature close");
+        Ok(())
+    }
+}
+
+impl<T> StreamedCompletionHandler<T> {
+    /// Artificial delay between emitted tokens, zero by default
+    pub fn with_typing_delay(mut self, delay: Duration) -> Self {
+        self.typing_delay = delay;
+        self
+    }
+
+    /// How many times a prematurely closed stream is resumed, defaults to `DEFAULT_MAX_RESUMES`.
+    /// Sets the budgets of connection failures & premature closes, keeping their backoff
+    pub fn with_max_resumes(mut self, max_resumes: usize) -> Self {
+        for category in [RetryCategory::Connection, RetryCategory::PrematureClose] {
+            let backoff = self.retry.policy.budget(category).backoff;
+            let budget = RetryBudget::new(max_resumes, backoff);
+            self.retry.policy.set_budget(category, budget);
+        }
+        self
+    }
+
+    /// Retry budgets for each category of error, defaults to `RetryPolicy::default`
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry.policy = policy;
+        self
+    }
 
-        tracing::warn!("got stream response:  {response:#?}");
-        response
+    /// Cancel the stream when it takes longer than `timeouts`, none by default
+    pub fn with_timeouts(mut self, timeouts: StreamTimeouts) -> Self {
+        self.timeouts.timeouts = timeouts;
+        self
+    }
+
+    /// Checkpoint the content received so far to the cache at `interval`, disabled by default
+    pub fn with_checkpoint_interval(mut self, interval: CheckpointInterval) -> Self {
+        self.checkpoint = Some(CheckpointState::new(interval));
+        self
+    }
+
+    /// Hand streamed text to `sink` as it is received
+    pub fn with_sink(mut self, sink: impl TextSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Cancel the stream once its content would go over `max_bytes`
+    pub fn with_max_content_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_content_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Let `canceller` cancel the stream while it is in flight
+    pub fn with_canceller(mut self, canceller: &StreamCanceller) -> Self {
+        self.canceller = Some(canceller.attach());
+        self
+    }
+
+    /// Whether the stream's polling task has been spawned, which the first `receive` does
+    pub fn has_started(&self) -> bool {
+        self.task.is_some()
+    }
+
+    /// Whether `receive` has nothing left to return: the completion was returned, the stream
+    /// was cancelled, or its task ended & everything it sent has been received. A stream about
+    /// to be resumed isn't finished
+    pub fn is_finished(&self) -> bool {
+        let drained = self.stream.is_none()
+            && self.task.as_ref().is_some_and(|task| task.is_finished())
+            && self.receiver.is_empty();
+        self.finished || self.cancelled.is_some() || drained
+    }
+
+    /// Index of the in progress message in the cache, if a checkpoint has been written
+    fn checkpoint_index(&self) -> Option<usize> {
+        self.checkpoint.as_ref().and_then(|c| c.index)
+    }
+
+    /// Swaps in the stream of a resumed request, keeping the first `kept` bytes of the content
+    /// received so far, the prefill the request continues from
+    fn continue_with(&mut self, mut next: Self, kept: usize) {
+        self.message_content.truncate(kept);
+        self.stream = next.stream.take();
+        self.sender = next.sender.take();
+        self.receiver = next.receiver;
+        self.summary = next.summary;
+        // The resumed request is the one that finishes, so it is the one logged
+        self.request_body = next.request_body.take();
+    }
+
+    /// Writes the content received so far to the cache, updating the checkpointed message if
+    /// there is one
+    fn cache_content(&self, agent: &mut Agent) {
+        match self.checkpoint_index() {
+            Some(index) => agent.cache.update_content(index, &self.message_content),
+            None => agent
+                .cache
+                .push(Message::new_assistant(&self.message_content)),
+        }
+    }
+
+    /// Stops the polling thread & caches the content received so far
+    fn cancel(&mut self, agent: &mut Agent, reason: CancelReason) {
+        if self.cancelled.is_some() {
+            return;
+        }
+        tracing::info!("Cancelling stream: {}", reason);
+        self.cancelled = Some(reason);
+        self.canceller = None;
+        if let Some(task) = self.task.as_ref() {
+            task.abort();
+        }
+        self.stream = None;
+        self.sender = None;
+        self.cache_content(agent);
+    }
+
+    /// Cancels the stream for going over its maximum length, marking the cached content
+    fn truncate(&mut self, agent: &mut Agent) {
+        self.cancel(agent, CancelReason::ContentLimit);
+        let index = self
+            .checkpoint_index()
+            .unwrap_or(agent.cache.len().saturating_sub(1));
+        if let Some(message) = agent.cache.as_mut().get_mut(index) {
+            message
+                .metadata
+                .insert(TRUNCATED_METADATA_KEY.to_owned(), "true".to_owned());
+        }
+        let content = &self.message_content;
+        self.sinks.iter_mut().for_each(|s| s.finished(content));
+    }
+
+    /// Waits out whatever is left of the typing delay since the last emitted token
+    async fn pace_emission(&mut self) {
+        if self.typing_delay.is_zero() {
+            return;
+        }
+        if let Some(last) = self.last_emission {
+            let elapsed = last.elapsed();
+            if elapsed < self.typing_delay {
+                tokio::time::sleep(self.typing_delay - elapsed).await;
+            }
+        }
+        self.last_emission = Some(Instant::now());
     }
 }
 
@@ -114,6 +984,9 @@ where
     /// message. Best used in a while loop
     #[tracing::instrument("Receive tokens from completion stream", skip(self))]
     async fn receive(&mut self, agent: &mut Agent) -> StreamResult<Option<CompletionStreamStatus>> {
+        if let Some(reason) = self.cancelled {
+            return Err(StreamError::Cancelled(reason));
+        }
         if self.sender.is_some() && self.stream.is_some() {
             tracing::info!("Telling thread to run");
             self.spawn()?;
@@ -124,38 +997,135 @@ where
                 .map_err(|_| StreamError::ReceiverTimeout)?
         {
             match result? {
-                CompletionStreamStatus::Working(token) => {
+                CompletionStreamStatus::Working(mut token) => {
+                    let limit = self
+                        .max_content_bytes
+                        .filter(|max| self.message_content.len() + token.len() > *max);
+                    if let Some(max) = limit {
+                        let room = max.saturating_sub(self.message_content.len());
+                        let end = (0..=room)
+                            .rev()
+                            .find(|i| token.is_char_boundary(*i))
+                            .unwrap_or(0);
+                        token.truncate(end);
+                    }
                     self.message_content.push_str(&token);
+                    if let Some(checkpoint) = self.checkpoint.as_mut() {
+                        checkpoint.token_received(&mut agent.cache, &token, &self.message_content);
+                    }
+                    if !token.is_empty() {
+                        self.sinks.iter_mut().for_each(|s| s.token(&token));
+                    }
+                    if let Some(max) = limit {
+                        warn!("Stream content went over {} bytes, cancelling it", max);
+                        self.truncate(agent);
+                        return Ok(Some(CompletionStreamStatus::Truncated(
+                            self.message_content.to_owned(),
+                        )));
+                    }
+                    self.pace_emission().await;
                     return Ok(Some(CompletionStreamStatus::Working(token.to_string())));
                 }
                 // CompletionStreamStatus::Error(json) => {
                 //     tracing::info!("Stream recieved an error: {json:#?}");
                 //     return Err(StreamError::from(json));
                 // }
-                CompletionStreamStatus::Finished => {
+                CompletionStreamStatus::Finished(_) => {
+                    let (usage, finish_reason, tool_calls) = {
+                        let summary = self
+                            .summary
+                            .lock()
+                            .map_err(|_| StreamError::LockPoisoned("stream summary"))?;
+                        let finish_reason = summary.finish_reason.to_owned();
+                        (summary.usage, finish_reason, summary.tool_calls.calls())
+                    };
+                    agent.completion_model.record_usage(usage);
+                    if let Some(body) = self.request_body.take() {
+                        agent.completion_model.log_completion(
+                            body,
+                            &self.message_content,
+                            usage,
+                            finish_reason.clone(),
+                        );
+                    }
+                    if finish_reason.is_some_and(|r| T::is_tool_call_finish(&r)) {
+                        tracing::info!("Stream finished with tool calls: {:?}", tool_calls);
+                        if let Some(index) = self.checkpoint_index() {
+                            agent.cache.as_mut().remove(index);
+                        }
+                        self.finished = true;
+                        return Ok(Some(CompletionStreamStatus::ToolCalls(tool_calls)));
+                    }
                     tracing::info!("Stream finished with content: {}", self.message_content);
-                    let message = Message::new_assistant(&self.message_content);
-                    agent.cache.push(message);
-                    return Ok(Some(CompletionStreamStatus::Finished));
+                    self.contract_violation = agent
+                        .output_contract
+                        .as_ref()
+                        .and_then(|contract| contract.check(&self.message_content));
+                    if let Some(violation) = self.contract_violation {
+                        warn!("Streamed response broke the output contract: {}", violation);
+                    }
+                    self.cache_content(agent);
+                    let content = &self.message_content;
+                    self.sinks.iter_mut().for_each(|s| s.finished(content));
+                    self.finished = true;
+                    return Ok(Some(CompletionStreamStatus::Finished(
+                        self.message_content.to_owned(),
+                    )));
                 }
+                // Only produced above, never sent by the polling thread
+                status @ (CompletionStreamStatus::ToolCalls(_)
+                | CompletionStreamStatus::Truncated(_)) => return Ok(Some(status)),
             }
         }
         tracing::info!("received none");
         Ok(None)
     }
 
+    /// Token usage reported by the provider so far. Read even if the polling task panicked
+    pub fn usage(&self) -> TokenUsage {
+        self.summary
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .usage
+    }
+
+    /// The reason the provider gave for finishing the completion, once it has given one
+    pub fn finish_reason(&self) -> Option<String> {
+        let summary = self.summary.lock().unwrap_or_else(PoisonError::into_inner);
+        summary.finish_reason.to_owned()
+    }
+
     #[tracing::instrument("Spawn completion stream thread", skip(self))]
     fn spawn(&mut self) -> Result<(), StreamError> {
         let mut stream = self.stream.take().unwrap();
         let tx = self.sender.take().unwrap();
-        tokio::spawn(async move {
+        let summary = Arc::clone(&self.summary);
+        self.task = Some(tokio::spawn(async move {
+            let mut finish_seen = false;
             loop {
                 tracing::info!("Beginning of completion stream thread loop");
                 match CompletionStreamingThread::poll_stream_for_type::<T>(&mut stream).await {
                     Ok(type_option) => {
                         let status: CompletionStreamStatus = match type_option {
                             Some(ret) => match ret {
-                                StreamPollReturn::Ok(typ) => <T as Clone>::clone(&(typ)).into(),
+                                StreamPollReturn::Ok(typ) => {
+                                    {
+                                        // Poisoning is reported once the stream finishes
+                                        let mut summary =
+                                            summary.lock().unwrap_or_else(PoisonError::into_inner);
+                                        if let Some(u) = typ.usage() {
+                                            summary.usage.merge(u);
+                                        }
+                                        if let Some(reason) = typ.finish_reason() {
+                                            summary.finish_reason = Some(reason);
+                                            finish_seen = true;
+                                        }
+                                        for delta in typ.tool_call_deltas() {
+                                            summary.tool_calls.push(delta);
+                                        }
+                                    }
+                                    <T as Clone>::clone(&(typ)).into()
+                                }
                                 StreamPollReturn::Err(json) => {
                                     tx.send(Err(StreamError::from(json)))
                                         .await
@@ -163,12 +1133,19 @@ where
                                     break;
                                 }
                             },
-                            None => CompletionStreamStatus::Finished,
+                            None if finish_seen => CompletionStreamStatus::Finished(String::new()),
+                            // The stream ended without the provider ever finishing the completion
+                            None => {
+                                tx.send(Err(StreamError::PrematureClose))
+                                    .await
+                                    .expect("could not send");
+                                break;
+                            }
                         };
                         tracing::info!("Got status: {:?}", status);
 
                         let break_loop = match &status {
-                            &CompletionStreamStatus::Finished => true,
+                            &CompletionStreamStatus::Finished(_) => true,
                             _ => false,
                         };
 
@@ -190,7 +1167,7 @@ where
             }
             tracing::info!("outside of loop");
             return Ok::<(), StreamError>(());
-        });
+        }));
 
         Ok(())
     }
@@ -224,10 +1201,20 @@ impl CompletionStreamingThread {
     where
         T: StreamResponse,
     {
-        while let Some(Ok(stream_response)) = stream.next().await {
+        while let Some(stream_response) = stream.next().await {
+            let stream_response = stream_response?;
             warn!("Stream response json: {:?}", stream_response);
+            if let Some(error) = T::stream_error(&stream_response) {
+                warn!("Provider sent an error in the stream: {:?}", error);
+                return Err(StreamError::Provider(error));
+            }
             match serde_json::from_value::<T>(stream_response.clone()) {
                 Ok(val) => return Ok(Some(StreamPollReturn::from(val))),
+                // Most likely a chunk with a field the provider has started sending
+                Err(err) if T::STRICT => {
+                    warn!("Stream chunk didn't parse strictly: {err:#?}");
+                    return Err(StreamError::Json(err));
+                }
                 Err(err) => {
                     warn!("poll stream for type failed to coerce to T: {err:#?}");
                     return Ok(Some(StreamPollReturn::from(stream_response)));
@@ -238,3 +1225,752 @@ impl CompletionStreamingThread {
         Ok(None)
     }
 }
+
+/// Locks a shared agent, giving up after `timeout`
+pub(crate) async fn lock_agent(
+    agent: &tokio::sync::Mutex<Agent>,
+    timeout: Duration,
+) -> StreamResult<tokio::sync::MutexGuard<'_, Agent>> {
+    tokio::time::timeout(timeout, agent.lock())
+        .await
+        .map_err(|_| StreamError::AgentLockTimeout(timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::CompletionModel;
+    use serde_json::json;
+
+    #[cfg(feature = "openai")]
+    fn openai_chunk(content: &str) -> Value {
+        json!({"choices": [{"delta": {"content": content}}], "usage": null})
+    }
+
+    #[cfg(feature = "openai")]
+    fn openai_handler(chunks: Vec<Value>) -> ProviderStreamHandler {
+        let stream: CompletionStream = Box::new(futures::stream::iter(chunks.into_iter().map(Ok)));
+        StreamedCompletionHandler::<OpenAiStreamResponse>::from(stream).into()
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn handler_state_read_without_receiving() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let chunks = vec![
+            openai_chunk("one "),
+            json!({"choices": [{"delta": {"content": "two"}, "finish_reason": "stop"}]}),
+        ];
+        let mut handler = openai_handler(chunks.clone());
+        assert!(!handler.has_started());
+        assert!(!handler.is_finished());
+
+        handler.receive(&mut agent).await.unwrap();
+        assert!(handler.has_started());
+        assert!(!handler.is_finished());
+        handler.collect(&mut agent).await.unwrap();
+        assert!(handler.is_finished());
+
+        let mut cancelled = openai_handler(chunks);
+        cancelled.cancel(&mut agent, CancelReason::Deadline);
+        assert!(!cancelled.has_started());
+        assert!(cancelled.is_finished());
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn contended_and_poisoned_locks_fail_receives() {
+        let agent = tokio::sync::Mutex::new(Agent::new(None, CompletionModel::default_openai("")));
+        let finish = json!({"choices": [{"delta": {"content": "two"}, "finish_reason": "stop"}]});
+        let mut handler = openai_handler(vec![openai_chunk("one "), finish])
+            .with_timeouts(StreamTimeouts::new().with_agent_lock(Duration::from_millis(20)));
+        let held = agent.lock().await;
+        assert!(matches!(
+            handler.receive_shared(&agent).await,
+            Err(StreamError::AgentLockTimeout(_))
+        ));
+        drop(held);
+        assert!(!handler.is_finished());
+        let received = handler.receive_shared(&agent).await.unwrap();
+        assert_eq!(
+            received,
+            Some(CompletionStreamStatus::Working("one ".to_owned()))
+        );
+
+        let ProviderStreamHandler::OpenAi(inner) = &handler else {
+            unreachable!()
+        };
+        let summary = Arc::clone(&inner.summary);
+        let _ = std::thread::spawn(move || {
+            let _summary = summary.lock().unwrap();
+            panic!("polling panicked");
+        })
+        .join();
+        let mut agent = agent.lock().await;
+        let err = loop {
+            match handler.receive(&mut agent).await {
+                Ok(Some(CompletionStreamStatus::Working(_))) => continue,
+                received => break received.unwrap_err(),
+            }
+        };
+        assert_eq!(
+            err.to_string(),
+            "The stream summary lock was poisoned by a panic"
+        );
+        assert_eq!(handler.finish_reason(), Some("stop".to_owned()));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn typing_delay_paces_tokens_without_changing_content() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let chunks = vec![
+            openai_chunk("one "),
+            openai_chunk("two "),
+            openai_chunk("three"),
+            json!({"choices": [], "usage": {"prompt_tokens": 3, "completion_tokens": 3, "total_tokens": 6}}),
+        ];
+        let mut handler = openai_handler(chunks).with_typing_delay(Duration::from_millis(20));
+
+        let start = Instant::now();
+        let mut tokens = String::new();
+        while let Ok(Some(status)) = handler.receive(&mut agent).await {
+            match status {
+                CompletionStreamStatus::Working(t) => tokens.push_str(&t),
+                _ => break,
+            }
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(tokens, "one two three");
+        assert_eq!(agent.cache.len(), 1);
+        assert_eq!(agent.cache.as_ref()[0].content, "one two three");
+        assert_eq!(agent.completion_model.params.total_token_count, 6);
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn stream_closed_before_finish_is_not_treated_as_finished() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let mut handler = openai_handler(vec![openai_chunk("cut "), openai_chunk("off")]);
+
+        let mut result = handler.receive(&mut agent).await;
+        while let Ok(Some(CompletionStreamStatus::Working(_))) = result {
+            result = handler.receive(&mut agent).await;
+        }
+        assert!(matches!(result, Err(StreamError::PrematureClose)));
+        assert_eq!(agent.cache.len(), 0);
+
+        let finish = json!({"choices": [{"delta": {}, "finish_reason": "stop"}]});
+        let mut handler = openai_handler(vec![openai_chunk("done"), finish]);
+        let mut result = handler.receive(&mut agent).await;
+        while let Ok(Some(CompletionStreamStatus::Working(_))) = result {
+            result = handler.receive(&mut agent).await;
+        }
+        assert!(matches!(
+            result,
+            Ok(Some(CompletionStreamStatus::Finished(_)))
+        ));
+        assert_eq!(agent.cache.as_ref()[0].content, "done");
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn checkpoints_update_one_message_in_place() {
+        let mut agent = Agent::new(Some("system"), CompletionModel::default_openai(""));
+        let finish = json!({"choices": [{"delta": {}, "finish_reason": "stop"}]});
+        let chunks = vec![
+            openai_chunk("a"),
+            openai_chunk("b"),
+            openai_chunk("c"),
+            openai_chunk("d"),
+            finish,
+        ];
+        let mut handler =
+            openai_handler(chunks).with_checkpoint_interval(CheckpointInterval::Tokens(2));
+
+        let mut checkpoints = vec![];
+        let mut result = handler.receive(&mut agent).await;
+        while let Ok(Some(CompletionStreamStatus::Working(_))) = result {
+            checkpoints.push((
+                agent.cache.len(),
+                agent.cache.as_ref().last().unwrap().content.clone(),
+            ));
+            result = handler.receive(&mut agent).await;
+        }
+        assert!(matches!(
+            result,
+            Ok(Some(CompletionStreamStatus::Finished(_)))
+        ));
+        assert_eq!(
+            checkpoints,
+            vec![
+                (1, "system".to_string()),
+                (2, "ab".to_string()),
+                (2, "ab".to_string()),
+                (2, "abcd".to_string()),
+                // The finish chunk's empty delta
+                (2, "abcd".to_string()),
+            ]
+        );
+        assert_eq!(agent.cache.len(), 2);
+        assert_eq!(agent.cache.as_ref()[1].content, "abcd");
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn collect_returns_partial_content_at_deadline() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let finish = json!({"choices": [{"delta": {}, "finish_reason": "stop"}]});
+        let mut handler = openai_handler(vec![openai_chunk("all "), openai_chunk("done"), finish]);
+        let collected = handler.collect(&mut agent).await.unwrap();
+        assert_eq!(
+            collected,
+            CollectedCompletion::Finished("all done".to_string())
+        );
+
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let chunks =
+            futures::stream::iter(vec![Ok(openai_chunk("half ")), Ok(openai_chunk("way"))]);
+        let stream: CompletionStream = Box::new(chunks.chain(futures::stream::pending()));
+        let mut handler: ProviderStreamHandler =
+            StreamedCompletionHandler::<OpenAiStreamResponse>::from(stream).into();
+
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(200);
+        let collected = handler
+            .collect_with_deadline(&mut agent, deadline)
+            .await
+            .unwrap();
+        assert_eq!(
+            collected,
+            CollectedCompletion::Partial {
+                content: "half way".to_string(),
+                reason: CancelReason::Deadline
+            }
+        );
+        assert_eq!(agent.cache.len(), 1);
+        assert_eq!(agent.cache.as_ref()[0].content, "half way");
+        assert_eq!(handler.cancel_reason(), Some(CancelReason::Deadline));
+        assert!(matches!(
+            handler.receive(&mut agent).await,
+            Err(StreamError::Cancelled(CancelReason::Deadline))
+        ));
+
+        // Cancelling again doesn't cache the content twice or change the reason
+        handler.cancel(&mut agent, CancelReason::User);
+        assert_eq!(agent.cache.len(), 1);
+        assert_eq!(handler.cancel_reason(), Some(CancelReason::Deadline));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn stream_cancelled_past_max_content_bytes() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let finish = json!({"choices": [{"delta": {}, "finish_reason": "stop"}]});
+        let chunks = vec![openai_chunk("runaway "), openai_chunk("cafés"), finish];
+        let sink = RecordingSink::default();
+        let mut handler = openai_handler(chunks)
+            .with_max_content_bytes(12)
+            .with_sink(sink.clone());
+
+        // The cut doesn't split the multibyte character
+        let collected = handler.collect(&mut agent).await.unwrap();
+        assert_eq!(
+            collected,
+            CollectedCompletion::Partial {
+                content: "runaway caf".to_string(),
+                reason: CancelReason::ContentLimit
+            }
+        );
+        assert_eq!(handler.cancel_reason(), Some(CancelReason::ContentLimit));
+        assert_eq!(agent.cache.len(), 1);
+        let cached = &agent.cache.as_ref()[0];
+        assert_eq!(cached.content, "runaway caf");
+        assert_eq!(cached.metadata[TRUNCATED_METADATA_KEY], "true");
+        assert_eq!(sink.0.lock().unwrap().1.as_deref(), Some("runaway caf"));
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingSink(Arc<Mutex<(Vec<String>, Option<String>)>>);
+
+    impl TextSink for RecordingSink {
+        fn token(&mut self, token: &str) {
+            self.0.lock().unwrap().0.push(token.to_owned());
+        }
+        fn finished(&mut self, message: &str) {
+            self.0.lock().unwrap().1 = Some(message.to_owned());
+        }
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn sinks_receive_tokens_and_final_message() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let finish = json!({"choices": [{"delta": {}, "finish_reason": "stop"}]});
+        let sink = RecordingSink::default();
+        let mut handler = openai_handler(vec![openai_chunk("to "), openai_chunk("speech"), finish])
+            .with_sink(sink.clone());
+
+        let collected = handler.collect(&mut agent).await.unwrap();
+        assert_eq!(
+            collected,
+            CollectedCompletion::Finished("to speech".to_string())
+        );
+        let (tokens, finished) = sink.0.lock().unwrap().clone();
+        assert_eq!(tokens, vec!["to ", "speech"]);
+        assert_eq!(finished.as_deref(), Some("to speech"));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn finished_stream_is_logged() {
+        use crate::language_models::completions::logging::{CompletionLogEntry, CompletionLogger};
+
+        let dir = std::env::temp_dir().join(format!("espionox-log-{}", uuid::Uuid::new_v4()));
+        let logger = Arc::new(CompletionLogger::new(&dir, 1024 * 1024).unwrap());
+        let model = CompletionModel::default_openai("secret").with_logger(logger.clone(), "pane-1");
+        let mut agent = Agent::new(None, model);
+        let chunks = vec![
+            openai_chunk("logged"),
+            json!({"choices": [{"delta": {}, "finish_reason": "stop"}]}),
+            json!({"choices": [], "usage": {"prompt_tokens": 2, "completion_tokens": 1, "total_tokens": 3}}),
+        ];
+        let mut handler = openai_handler(chunks);
+        handler.set_request_body(json!({"model": "gpt"}));
+        handler.collect(&mut agent).await.unwrap();
+
+        let log = std::fs::read_to_string(logger.current_file()).unwrap();
+        let entry: CompletionLogEntry = serde_json::from_str(log.trim()).unwrap();
+        assert_eq!(entry.agent_id, "pane-1");
+        assert_eq!(entry.request_body, json!({"model": "gpt"}));
+        assert_eq!(entry.response_text, "logged");
+        assert_eq!(entry.usage.total(), 3);
+        assert_eq!(entry.finish_reason.as_deref(), Some("stop"));
+        assert!(!log.contains("secret"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn tool_call_finish_surfaces_calls_instead_of_message() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let call = |index: usize, id: Option<&str>, name: Option<&str>, arguments: &str| {
+            json!({"choices": [{"delta": {"content": null, "tool_calls": [
+                {"index": index, "id": id, "function": {"name": name, "arguments": arguments}}
+            ]}}]})
+        };
+        let chunks = vec![
+            call(0, Some("call_a"), Some("get_pane"), ""),
+            call(0, None, None, "{\"pane\": "),
+            call(1, Some("call_b"), Some("list_panes"), ""),
+            call(0, None, None, "1}"),
+            json!({"choices": [{"delta": {}, "finish_reason": "tool_calls"}]}),
+            json!({"choices": [], "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7}}),
+        ];
+        let mut handler = openai_handler(chunks);
+
+        let mut result = handler.receive(&mut agent).await;
+        while let Ok(Some(CompletionStreamStatus::Working(_))) = result {
+            result = handler.receive(&mut agent).await;
+        }
+        let calls = match result {
+            Ok(Some(CompletionStreamStatus::ToolCalls(calls))) => calls,
+            other => panic!("expected tool calls, got {other:?}"),
+        };
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call_a");
+        assert_eq!(calls[0].name, "get_pane");
+        assert_eq!(calls[0].arguments_json().unwrap(), json!({"pane": 1}));
+        assert_eq!(calls[1].name, "list_panes");
+        assert_eq!(calls[1].arguments_json().unwrap(), json!({}));
+        assert_eq!(agent.cache.len(), 0);
+        assert_eq!(agent.completion_model.params.total_token_count, 7);
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn resumed_mid_stream_from_trimmed_prefill() {
+        use crate::language_models::completions::testing::serve_recording;
+
+        let events = |events: Vec<Value>| {
+            events
+                .iter()
+                .map(|event| {
+                    format!(
+                        "event: {}\ndata: {}\n\n",
+                        event["type"].as_str().unwrap(),
+                        event
+                    )
+                })
+                .collect::<String>()
+                .into_bytes()
+        };
+        let text = |text: &str| json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": text}});
+        let start = json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}});
+        // The first response closes before the provider finishes it, right after whitespace
+        let closed = events(vec![start.clone(), text("The build"), text(" failed\n")]);
+        let resumed = events(vec![
+            start,
+            text("\nin the parser"),
+            json!({"type": "message_delta", "delta": {"stop_reason": "end_turn", "stop_sequence": null}, "usage": {"output_tokens": 3}}),
+            json!({"type": "message_stop"}),
+        ]);
+        let (url, mut sent) = serve_recording(
+            vec![("Content-Type", "text/event-stream")],
+            vec![closed, resumed],
+        )
+        .await;
+        let model = CompletionModel::default_anthropic("").with_url(&url);
+        let mut agent = Agent::new(Some("system"), model);
+        agent.cache.push(Message::new_user("what happened?"));
+
+        let mut handler = agent.stream_completion().await.unwrap();
+        let collected = handler.collect(&mut agent).await.unwrap();
+        assert_eq!(
+            collected,
+            CollectedCompletion::Finished("The build failed\nin the parser".to_string())
+        );
+        assert_eq!(
+            agent.cache.as_ref().last().unwrap().content,
+            "The build failed\nin the parser"
+        );
+
+        sent.recv().await.unwrap();
+        let resumed_request = sent.recv().await.unwrap();
+        let messages = resumed_request["messages"].as_array().unwrap();
+        assert_eq!(messages.last().unwrap()["role"], "assistant");
+        assert_eq!(messages.last().unwrap()["content"], "The build failed");
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn resume_over_spend_cap_cancels_stream() {
+        use crate::language_models::completions::testing::serve_once;
+
+        let closed = [
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "The build"}}),
+        ]
+        .iter()
+        .map(|event| format!("event: {}\ndata: {}\n\n", event["type"].as_str().unwrap(), event))
+        .collect::<String>();
+        let url = serve_once(
+            vec![("Content-Type", "text/event-stream")],
+            closed.into_bytes(),
+        )
+        .await;
+        let model = CompletionModel::default_anthropic("").with_url(&url);
+        let mut agent = Agent::new(Some("system"), model);
+        agent.cache.push(Message::new_user("what happened?"));
+
+        let mut handler = agent.stream_completion().await.unwrap();
+        // The cap is reached while the first request streams
+        agent.completion_model.spend_cap = Some(0.0);
+        assert!(matches!(
+            handler.collect(&mut agent).await,
+            Err(StreamError::Cancelled(CancelReason::Budget))
+        ));
+        assert_eq!(handler.cancel_reason(), Some(CancelReason::Budget));
+        assert_eq!(agent.cache.as_ref().last().unwrap().content, "The build");
+    }
+
+    #[cfg(feature = "anthropic")]
+    #[tokio::test]
+    async fn anthropic_tool_use_blocks_are_assembled() {
+        let mut agent = Agent::new(None, CompletionModel::default_anthropic(""));
+        let events = vec![
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Checking"}}),
+            json!({"type": "content_block_start", "index": 1, "content_block": {"type": "tool_use", "id": "toolu_1", "name": "get_pane", "input": {}}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": "{\"pane\": 2}"}}),
+            json!({"type": "message_delta", "delta": {"stop_reason": "tool_use", "stop_sequence": null}, "usage": {"output_tokens": 4}}),
+            json!({"type": "message_stop"}),
+        ];
+        let stream: CompletionStream = Box::new(futures::stream::iter(events.into_iter().map(Ok)));
+        let mut handler: ProviderStreamHandler =
+            StreamedCompletionHandler::<AnthropicStreamResponse>::from(stream).into();
+
+        let mut content = String::new();
+        let mut result = handler.receive(&mut agent).await;
+        while let Ok(Some(CompletionStreamStatus::Working(token))) = result {
+            content.push_str(&token);
+            result = handler.receive(&mut agent).await;
+        }
+        let calls = match result {
+            Ok(Some(CompletionStreamStatus::ToolCalls(calls))) => calls,
+            other => panic!("expected tool calls, got {other:?}"),
+        };
+        assert_eq!(content, "Checking");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "toolu_1");
+        assert_eq!(calls[0].arguments_json().unwrap(), json!({"pane": 2}));
+        assert_eq!(agent.cache.len(), 0);
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn error_frame_mid_stream_surfaced() {
+        let error = json!({"error": {
+            "message": "The server had an error while processing your request",
+            "type": "server_error",
+            "code": null,
+        }});
+        let mut handler =
+            openai_handler(vec![openai_chunk("Hel"), error]).with_retry_policy(RetryPolicy::none());
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+
+        let first = handler.receive(&mut agent).await.unwrap();
+        assert!(matches!(first, Some(CompletionStreamStatus::Working(ref t)) if t == "Hel"));
+        let err = loop {
+            match handler.receive(&mut agent).await {
+                Err(StreamError::ReceiverTimeout) => continue,
+                Err(err) => break err,
+                other => panic!("expected the error frame, got {:?}", other),
+            }
+        };
+        assert_eq!(err.retry_category(), Some(RetryCategory::ServerError));
+        assert_eq!(
+            err.to_string(),
+            "Provider sent an error mid stream: The server had an error while processing your \
+             request (server_error)"
+        );
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn rate_limited_stream_retried_within_its_budget() {
+        use crate::language_models::completions::{
+            openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+            testing::serve_once,
+            ModelParameters,
+        };
+
+        let rate_limited = || {
+            let error = json!({"error": {"type": "requests", "code": "rate_limit_exceeded"}});
+            openai_handler(vec![error])
+        };
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let result = rate_limited().collect(&mut agent).await;
+        assert!(matches!(
+            result,
+            Err(StreamError::Provider(ProviderStreamError { code: Some(ref code), .. }))
+                if code == "rate_limit_exceeded"
+        ));
+
+        let body = [
+            openai_chunk("retried"),
+            json!({"choices": [{"delta": {}, "finish_reason": "stop"}]}),
+            json!({"choices": [], "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}}),
+        ]
+        .iter()
+        .map(|chunk| format!("data: {}\n\n", chunk))
+        .collect::<String>()
+            + "data: [DONE]\n\n";
+        let url = serve_once(
+            vec![("Content-Type", "text/event-stream")],
+            body.into_bytes(),
+        )
+        .await;
+        let deployment =
+            AzureOpenAiDeployment::new(&url, "d", "2024-06-01", OpenAiCompletionModel::Gpt4);
+        let model = CompletionModel::new(deployment, ModelParameters::default(), "");
+        let mut agent = Agent::new(None, model);
+        let policy = RetryPolicy::none().with_budget(
+            RetryCategory::RateLimited,
+            RetryBudget::new(1, Duration::from_millis(50)),
+        );
+        let start = Instant::now();
+        let collected = rate_limited()
+            .with_retry_policy(policy)
+            .collect(&mut agent)
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            collected,
+            CollectedCompletion::Finished("retried".to_string())
+        );
+        assert_eq!(agent.completion_model.params.total_token_count, 2);
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn contract_checked_when_stream_finishes() {
+        use crate::agents::{ContractViolation, OutputContract};
+
+        let chunks = || {
+            vec![
+                openai_chunk("- build ok\n- 2 warnings\n"),
+                openai_chunk("- tests passed"),
+                json!({"choices": [{"delta": {}, "finish_reason": "stop"}]}),
+            ]
+        };
+        let contract = OutputContract::new("Respond as 2 bullets max").with_max_lines(2);
+        let mut agent =
+            Agent::new(None, CompletionModel::default_openai("")).with_output_contract(contract);
+
+        let mut handler = openai_handler(chunks());
+        assert_eq!(handler.contract_violation(), None);
+        let collected = handler.collect(&mut agent).await.unwrap();
+        // The streamed content is kept & cached as is, not regenerated
+        assert_eq!(
+            collected,
+            CollectedCompletion::Finished("- build ok\n- 2 warnings\n- tests passed".to_string())
+        );
+        assert_eq!(
+            handler.contract_violation(),
+            Some(ContractViolation::TooManyLines { lines: 3, max: 2 })
+        );
+        assert_eq!(agent.cache.len(), 1);
+
+        agent.output_contract =
+            Some(OutputContract::new("Respond as 3 bullets max").with_max_lines(3));
+        let mut handler = openai_handler(chunks());
+        handler.collect(&mut agent).await.unwrap();
+        assert_eq!(handler.contract_violation(), None);
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn stalled_streams_time_out() {
+        let recorded = "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+            data: {\"choices\":[{\"delta\":{\"content\":\" world\"}}]}\n\n\
+            data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n\
+            data: [DONE]\n\n";
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let provider = agent.completion_model.provider.clone();
+        let replay = |timeouts: StreamTimeouts| {
+            StreamReplay::from_reader(std::io::Cursor::new(recorded.as_bytes().to_vec()))
+                .with_cadence(Duration::from_millis(100))
+                .into_handler(&provider)
+                .with_timeouts(timeouts)
+        };
+        let short = Duration::from_millis(20);
+        let long = Duration::from_millis(500);
+
+        let mut handler = replay(StreamTimeouts::new().with_first_token(short));
+        assert!(matches!(
+            handler.collect(&mut agent).await,
+            Err(StreamError::Cancelled(CancelReason::FirstTokenTimeout))
+        ));
+        // Nothing was received to cache
+        assert_eq!(agent.cache.len(), 0);
+
+        let timeouts = StreamTimeouts::new()
+            .with_first_token(long)
+            .with_inter_token(short);
+        let mut handler = replay(timeouts);
+        assert!(matches!(
+            handler.collect(&mut agent).await,
+            Err(StreamError::Cancelled(CancelReason::InterTokenTimeout))
+        ));
+        assert_eq!(
+            handler.cancel_reason(),
+            Some(CancelReason::InterTokenTimeout)
+        );
+        assert_eq!(agent.cache.as_ref().last().unwrap().content, "Hello");
+
+        let timeouts = StreamTimeouts::new()
+            .with_first_token(long)
+            .with_inter_token(long)
+            .with_total(Duration::from_millis(150));
+        let mut handler = replay(timeouts);
+        assert!(matches!(
+            handler.collect(&mut agent).await,
+            Err(StreamError::Cancelled(CancelReason::TotalTimeout))
+        ));
+        assert_eq!(agent.cache.as_ref().last().unwrap().content, "Hello");
+
+        let timeouts = StreamTimeouts::new()
+            .with_first_token(long)
+            .with_inter_token(long);
+        let collected = replay(timeouts).collect(&mut agent).await.unwrap();
+        assert_eq!(
+            collected,
+            CollectedCompletion::Finished("Hello world".to_string())
+        );
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn streams_start_with_model_defaults() {
+        use crate::language_models::completions::{
+            openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+            testing::serve_once,
+            ModelParameters,
+        };
+
+        let timeouts = StreamTimeouts::new().with_total(Duration::from_secs(30));
+        let policy = RetryPolicy::none().with_budget(
+            RetryCategory::ServerError,
+            RetryBudget::new(3, Duration::from_millis(100)),
+        );
+        let defaults = StreamDefaults::new()
+            .with_timeouts(timeouts)
+            .with_retry_policy(policy.clone());
+        let url = serve_once(vec![("Content-Type", "text/event-stream")], vec![]).await;
+        let deployment =
+            AzureOpenAiDeployment::new(&url, "d", "2024-06-01", OpenAiCompletionModel::Gpt4);
+        let model = CompletionModel::new(deployment, ModelParameters::default(), "")
+            .with_stream_defaults(defaults);
+        let mut agent = Agent::new(None, model.clone());
+
+        let mut handler = agent.stream_completion().await.unwrap();
+        assert_eq!(handler.timeout_state().timeouts, timeouts);
+        assert_eq!(handler.retry_state().policy, policy);
+
+        // Settings on the handler win
+        let overridden = StreamTimeouts::new().with_first_token(Duration::from_secs(1));
+        let mut handler = handler
+            .with_timeouts(overridden)
+            .with_retry_policy(RetryPolicy::none());
+        assert_eq!(handler.timeout_state().timeouts, overridden);
+        assert_eq!(handler.retry_state().policy, RetryPolicy::none());
+        assert_eq!(
+            Agent::new(None, model)
+                .completion_model
+                .stream_defaults
+                .timeouts,
+            timeouts
+        );
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn sent_chunk_fields_accepted() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let chunks = vec![
+            json!({
+                "id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 1718000000,
+                "model": "gpt-4o", "system_fingerprint": "fp_1", "service_tier": "default",
+                "choices": [{"index": 0, "logprobs": null, "content_filter_results": {},
+                    "delta": {"role": "assistant", "content": "Build passed", "refusal": null}}]
+            }),
+            json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}], "prompt_filter_results": []}),
+        ];
+        let collected = openai_handler(chunks).collect(&mut agent).await.unwrap();
+        assert_eq!(
+            collected,
+            CollectedCompletion::Finished("Build passed".to_string())
+        );
+    }
+
+    #[cfg(feature = "openai")]
+    #[cfg(feature = "strict-streams")]
+    #[tokio::test]
+    async fn unknown_chunk_field_fails_strict_stream() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let chunks = vec![
+            openai_chunk("Build "),
+            json!({"choices": [{"delta": {"content": "passed", "reasoning": "ran cargo"}}]}),
+        ];
+        let err = openai_handler(chunks)
+            .collect(&mut agent)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, StreamError::Json(ref e) if e.to_string().contains("unknown field `reasoning`")),
+            "{:?}",
+            err
+        );
+    }
+}
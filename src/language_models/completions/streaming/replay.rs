@@ -0,0 +1,182 @@
+//! Playing recorded server sent events through a stream handler as if they came from a provider
+use super::{
+    sse::json_event_stream, CompletionStream, ProviderStreamHandler, RetryPolicy,
+    StreamedCompletionHandler,
+};
+#[cfg(feature = "anthropic")]
+use crate::language_models::completions::anthropic::streaming::AnthropicStreamResponse;
+#[cfg(feature = "openai")]
+use crate::language_models::completions::openai::{
+    responses::OpenAiResponsesStreamEvent, streaming::OpenAiStreamResponse,
+};
+use crate::language_models::completions::CompletionProvider;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::{io::Read, path::Path, time::Duration};
+
+/// Prefix of sources given as urls to `StreamReplay::open`
+const FILE_URL_PREFIX: &str = "file://";
+/// Source given to `StreamReplay::open` to read from stdin
+const STDIN_SOURCE: &str = "-";
+const READ_CHUNK_LEN: usize = 8192;
+
+/// A recorded event stream, such as a captured `.sse` file, read & parsed the same way as a
+/// provider's response body
+pub struct StreamReplay {
+    source: Box<dyn Read + Send>,
+    /// Delay before each event
+    cadence: Duration,
+}
+
+impl std::fmt::Debug for StreamReplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamReplay")
+            .field("source", &"<<skipped>>")
+            .field("cadence", &self.cadence)
+            .finish()
+    }
+}
+
+impl StreamReplay {
+    /// Replays events as fast as they are read
+    pub fn from_reader(source: impl Read + Send + 'static) -> Self {
+        Self {
+            source: Box::new(source),
+            cadence: Duration::ZERO,
+        }
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_reader(std::fs::File::open(path)?))
+    }
+
+    pub fn from_stdin() -> Self {
+        Self::from_reader(std::io::stdin())
+    }
+
+    /// `-` for stdin, otherwise a path or a `file://` url
+    pub fn open(source: &str) -> std::io::Result<Self> {
+        match source {
+            STDIN_SOURCE => Ok(Self::from_stdin()),
+            _ => Self::from_path(source.strip_prefix(FILE_URL_PREFIX).unwrap_or(source)),
+        }
+    }
+
+    /// Wait `cadence` before each event, to replay at something like a live stream's pace
+    pub fn with_cadence(mut self, cadence: Duration) -> Self {
+        self.cadence = cadence;
+        self
+    }
+
+    /// The events as a stream of their JSON data, the source is read on the blocking pool
+    fn into_stream(self) -> CompletionStream {
+        let bytes = futures::stream::unfold(Some(self.source), |source| async move {
+            let mut source = source?;
+            let read = tokio::task::spawn_blocking(move || {
+                let mut buf = vec![0; READ_CHUNK_LEN];
+                let read = source.read(&mut buf).map(|len| {
+                    buf.truncate(len);
+                    Bytes::from(buf)
+                });
+                (read, source)
+            })
+            .await;
+            match read {
+                Ok((Ok(bytes), _)) if bytes.is_empty() => None,
+                Ok((Ok(bytes), source)) => Some((Ok(bytes), Some(source))),
+                Ok((Err(err), _)) => Some((Err(err), None)),
+                Err(err) => Some((Err(std::io::Error::other(err)), None)),
+            }
+        });
+        let cadence = self.cadence;
+        let events = json_event_stream(Box::pin(bytes)).then(move |event| async move {
+            tokio::time::sleep(cadence).await;
+            event
+        });
+        Box::new(events.boxed())
+    }
+
+    /// A handler receiving the events as a response from `provider` would be received. Recorded
+    /// streams that end early aren't resumed, since that would request a live completion
+    pub fn into_handler(self, provider: &CompletionProvider) -> ProviderStreamHandler {
+        let stream = self.into_stream();
+        let handler: ProviderStreamHandler = match provider {
+            #[cfg(feature = "openai")]
+            CompletionProvider::OpenAi(_) | CompletionProvider::AzureOpenAi(_) => {
+                StreamedCompletionHandler::<OpenAiStreamResponse>::from(stream).into()
+            }
+            #[cfg(feature = "anthropic")]
+            CompletionProvider::Anthropic(_) => {
+                StreamedCompletionHandler::<AnthropicStreamResponse>::from(stream).into()
+            }
+            #[cfg(feature = "openai")]
+            CompletionProvider::OpenAiResponses(_) => {
+                StreamedCompletionHandler::<OpenAiResponsesStreamEvent>::from(stream).into()
+            }
+            #[cfg(not(any(feature = "openai", feature = "anthropic")))]
+            _ => unreachable!("no provider features are enabled"),
+        };
+        handler.with_retry_policy(RetryPolicy::none())
+    }
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::Agent,
+        language_models::completions::{
+            streaming::{CollectedCompletion, CompletionStreamStatus, StreamError},
+            CompletionModel,
+        },
+    };
+    use std::time::Instant;
+
+    const RECORDED: &str = "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\",\"content\":\"\"}}]}\r\n\r\n\
+        data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\r\n\r\n\
+        : keep-alive\r\n\r\n\
+        data: {\"choices\":[{\"delta\":{\"content\":\" world\"},\"finish_reason\":\"stop\"}]}\r\n\r\n\
+        data: [DONE]\r\n\r\n";
+
+    #[tokio::test]
+    async fn recorded_file_replayed_at_cadence() {
+        let path =
+            std::env::temp_dir().join(format!("espionox-replay-{}.sse", uuid::Uuid::new_v4()));
+        std::fs::write(&path, RECORDED).unwrap();
+        let source = format!("{}{}", FILE_URL_PREFIX, path.display());
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let mut handler = StreamReplay::open(&source)
+            .unwrap()
+            .with_cadence(Duration::from_millis(20))
+            .into_handler(&agent.completion_model.provider);
+
+        let start = Instant::now();
+        let collected = handler.collect(&mut agent).await.unwrap();
+        assert!(
+            matches!(collected, CollectedCompletion::Finished(ref content) if content == "Hello world")
+        );
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert_eq!(agent.cache.as_ref().last().unwrap().content, "Hello world");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn truncated_recording_not_resumed() {
+        let cut = &RECORDED[..RECORDED.find(": keep-alive").unwrap()];
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let mut handler = StreamReplay::from_reader(std::io::Cursor::new(cut.as_bytes().to_vec()))
+            .into_handler(&agent.completion_model.provider);
+
+        let mut content = String::new();
+        let err = loop {
+            match handler.receive(&mut agent).await {
+                Ok(Some(CompletionStreamStatus::Working(token))) => content.push_str(&token),
+                Err(StreamError::ReceiverTimeout) => continue,
+                Err(err) => break err,
+                other => panic!("expected the stream to close early, got {:?}", other),
+            }
+        };
+        assert!(matches!(err, StreamError::PrematureClose));
+        assert_eq!(content, "Hello");
+    }
+}
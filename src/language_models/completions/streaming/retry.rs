@@ -0,0 +1,312 @@
+//! Budgets for re-requesting failed streams, per category of error
+use super::{StreamError, DEFAULT_MAX_RESUMES};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+use std::{collections::HashMap, time::Duration};
+
+/// Kinds of stream errors that can be retried, each with its own `RetryBudget`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RetryCategory {
+    /// Reading the stream's body failed, such as the connection dropping
+    Connection,
+    /// The stream closed before the provider finished the completion
+    PrematureClose,
+    /// The provider sent a rate limit error in the stream, or answered the request with 429
+    RateLimited,
+    /// The provider sent an overloaded or internal server error in the stream, or answered the
+    /// request with 503 or Anthropic's 529 overloaded
+    ServerError,
+}
+
+impl RetryCategory {
+    /// The retry category of an error status a request was answered with, `None` for statuses
+    /// requesting again can't fix
+    pub fn from_status(status: StatusCode) -> Option<Self> {
+        match status.as_u16() {
+            429 => Some(Self::RateLimited),
+            503 | 529 => Some(Self::ServerError),
+            _ => None,
+        }
+    }
+}
+
+/// How long a response's `Retry-After` header asks to wait before requesting again. Only the
+/// delay in seconds is read, an HTTP date is ignored
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// `error.type` & `error.code` values providers send in streams for rate limits
+const RATE_LIMIT_ERROR_TYPES: [&str; 3] = ["rate_limit_error", "rate_limit_exceeded", "requests"];
+/// `error.type` & `error.code` values providers send in streams for server side failures
+const SERVER_ERROR_TYPES: [&str; 3] = ["overloaded_error", "api_error", "server_error"];
+
+impl StreamError {
+    /// The retry category of this error, `None` for errors re-requesting can't fix
+    pub fn retry_category(&self) -> Option<RetryCategory> {
+        match self {
+            Self::Connection(_) => Some(RetryCategory::Connection),
+            Self::PrematureClose => Some(RetryCategory::PrematureClose),
+            Self::StreamRecievedErr(json) => {
+                let error = &json["error"];
+                error_category([error["type"].as_str(), error["code"].as_str()])
+            }
+            Self::Provider(error) => error_category([error.kind.as_deref(), error.code.as_deref()]),
+            _ => None,
+        }
+    }
+}
+
+/// The retry category of an error a provider sent with `kinds` as its type & code
+fn error_category(kinds: [Option<&str>; 2]) -> Option<RetryCategory> {
+    let is = |types: &[&str]| kinds.iter().flatten().any(|k| types.contains(k));
+    if is(&RATE_LIMIT_ERROR_TYPES) {
+        Some(RetryCategory::RateLimited)
+    } else if is(&SERVER_ERROR_TYPES) {
+        Some(RetryCategory::ServerError)
+    } else {
+        None
+    }
+}
+
+/// Longest a retry waits unless its budget sets another, see `RetryBudget::max_delay`
+pub const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How many times errors of a category are retried, and how long to wait before each retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryBudget {
+    pub max_attempts: usize,
+    /// Wait before the first retry, doubled for every retry after it
+    pub backoff: Duration,
+    /// Longest wait before a retry, `DEFAULT_MAX_RETRY_DELAY` by default. Backoff stops
+    /// doubling at it, and a response whose `Retry-After` asks for longer isn't retried
+    pub max_delay: Duration,
+}
+
+impl RetryBudget {
+    pub fn new(max_attempts: usize, backoff: Duration) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+            max_delay: DEFAULT_MAX_RETRY_DELAY,
+        }
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Never retry
+    pub fn none() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+
+    /// Wait before retry number `attempt`, starting from 0, at most `max_delay`
+    pub fn delay(&self, attempt: usize) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(u32::try_from(attempt).unwrap_or(u32::MAX)))
+            .min(self.max_delay)
+    }
+}
+
+/// Retry budgets of each `RetryCategory`. By default connection failures & premature closes
+/// are resumed `DEFAULT_MAX_RESUMES` times without waiting, and provider errors aren't retried
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    budgets: HashMap<RetryCategory, RetryBudget>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        let resume = RetryBudget::new(DEFAULT_MAX_RESUMES, Duration::ZERO);
+        Self::none()
+            .with_budget(RetryCategory::Connection, resume)
+            .with_budget(RetryCategory::PrematureClose, resume)
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries
+    pub fn none() -> Self {
+        Self {
+            budgets: HashMap::new(),
+        }
+    }
+
+    pub fn with_budget(mut self, category: RetryCategory, budget: RetryBudget) -> Self {
+        self.set_budget(category, budget);
+        self
+    }
+
+    pub(crate) fn set_budget(&mut self, category: RetryCategory, budget: RetryBudget) {
+        self.budgets.insert(category, budget);
+    }
+
+    pub fn budget(&self, category: RetryCategory) -> RetryBudget {
+        self.budgets
+            .get(&category)
+            .copied()
+            .unwrap_or_else(RetryBudget::none)
+    }
+}
+
+/// A policy along with the attempts made against it for one stream
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RetryState {
+    pub(crate) policy: RetryPolicy,
+    attempts: HashMap<RetryCategory, usize>,
+}
+
+impl RetryState {
+    pub(crate) fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Records a retry of an error of `category`, returning how long to wait before making it.
+    /// `None` once the category's budget is spent
+    pub(crate) fn attempt(&mut self, category: RetryCategory) -> Option<Duration> {
+        let budget = self.policy.budget(category);
+        let attempts = self.attempts.entry(category).or_default();
+        if *attempts >= budget.max_attempts {
+            return None;
+        }
+        let delay = budget.delay(*attempts);
+        *attempts += 1;
+        Some(delay)
+    }
+
+    /// Records a retry of a response of `category` like `attempt`, waiting as long as the
+    /// response's `Retry-After` asks instead of the backoff when it has one. `None` when that
+    /// is longer than the budget's `max_delay`, without spending an attempt
+    pub(crate) fn attempt_after(
+        &mut self,
+        category: RetryCategory,
+        retry_after: Option<Duration>,
+    ) -> Option<Duration> {
+        if retry_after.is_some_and(|after| after > self.policy.budget(category).max_delay) {
+            return None;
+        }
+        let backoff = self.attempt(category)?;
+        Some(retry_after.unwrap_or(backoff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn provider_errors_categorized() {
+        let anthropic = json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}});
+        let openai = json!({"error": {"type": "requests", "code": "rate_limit_exceeded"}});
+        let invalid = json!({"error": {"type": "invalid_request_error"}});
+        assert_eq!(
+            StreamError::from(anthropic).retry_category(),
+            Some(RetryCategory::ServerError)
+        );
+        assert_eq!(
+            StreamError::from(openai).retry_category(),
+            Some(RetryCategory::RateLimited)
+        );
+        assert_eq!(StreamError::from(invalid).retry_category(), None);
+        assert_eq!(
+            StreamError::PrematureClose.retry_category(),
+            Some(RetryCategory::PrematureClose)
+        );
+    }
+
+    #[test]
+    fn attempts_counted_per_category_with_backoff() {
+        let policy = RetryPolicy::default().with_budget(
+            RetryCategory::RateLimited,
+            RetryBudget::new(3, Duration::from_millis(100)),
+        );
+        let mut state = RetryState::new(policy);
+        let delays: Vec<Option<Duration>> = (0..4)
+            .map(|_| state.attempt(RetryCategory::RateLimited))
+            .collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_millis(100)),
+                Some(Duration::from_millis(200)),
+                Some(Duration::from_millis(400)),
+                None
+            ]
+        );
+        // Other categories have their own budgets
+        assert_eq!(
+            state.attempt(RetryCategory::Connection),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(state.attempt(RetryCategory::ServerError), None);
+    }
+
+    #[test]
+    fn error_statuses_categorized_with_retry_after() {
+        assert_eq!(
+            RetryCategory::from_status(StatusCode::TOO_MANY_REQUESTS),
+            Some(RetryCategory::RateLimited)
+        );
+        assert_eq!(
+            RetryCategory::from_status(StatusCode::SERVICE_UNAVAILABLE),
+            Some(RetryCategory::ServerError)
+        );
+        assert_eq!(
+            RetryCategory::from_status(StatusCode::from_u16(529).unwrap()),
+            Some(RetryCategory::ServerError)
+        );
+        assert_eq!(RetryCategory::from_status(StatusCode::BAD_REQUEST), None);
+        assert_eq!(RetryCategory::from_status(StatusCode::OK), None);
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "2".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2026 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn delays_capped_at_max_delay() {
+        let budget = RetryBudget::new(8, Duration::from_secs(1));
+        assert_eq!(budget.delay(5), Duration::from_secs(32));
+        assert_eq!(budget.delay(6), DEFAULT_MAX_RETRY_DELAY);
+        let budget = budget.with_max_delay(Duration::from_secs(10));
+        assert_eq!(budget.delay(7), Duration::from_secs(10));
+
+        let policy = RetryPolicy::none().with_budget(RetryCategory::RateLimited, budget);
+        let mut state = RetryState::new(policy);
+        let asked = |secs| Some(Duration::from_secs(secs));
+        assert_eq!(
+            state.attempt_after(RetryCategory::RateLimited, asked(10)),
+            asked(10)
+        );
+        // Asking for longer than the cap gives up without spending an attempt
+        assert_eq!(
+            state.attempt_after(RetryCategory::RateLimited, asked(86400)),
+            None
+        );
+        assert_eq!(
+            state.attempt_after(RetryCategory::RateLimited, None),
+            asked(2)
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! Forwarding a stream handler to a browser or other http client as server sent events
+use super::{lock_agent, CancelReason, CompletionStreamStatus, ProviderStreamHandler, StreamError};
+use crate::agents::Agent;
+use bytes::Bytes;
+use futures::{Future, Stream};
+use serde_json::{json, Value};
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::{Instant, Sleep},
+};
+use tracing::warn;
+
+/// `Content-Type` of responses with an `SseBody`
+pub const SSE_CONTENT_TYPE: &str = "text/event-stream";
+/// How long an `SseBody` goes without an event before sending a keep-alive comment
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+const KEEP_ALIVE_COMMENT: &[u8] = b": keep-alive\n\n";
+/// Events encoded ahead of the client reading them
+const EVENT_BUFFER: usize = 32;
+
+/// An event with JSON `data`, which never spans more than one line
+fn encode_event(name: Option<&str>, data: Value) -> Bytes {
+    let event = match name {
+        Some(name) => format!("event: {}\ndata: {}\n\n", name, data),
+        None => format!("data: {}\n\n", data),
+    };
+    Bytes::from(event)
+}
+
+/// The event for something received from the handler, and whether it ends the stream. Tokens
+/// are unnamed events, anything that ends the completion is a named event. Empty tokens &
+/// receiver timeouts have no event
+fn received_event(
+    received: Result<Option<CompletionStreamStatus>, StreamError>,
+    handler: &ProviderStreamHandler,
+    index: usize,
+) -> Option<(Bytes, bool)> {
+    let finish_reason = handler.finish_reason();
+    let (name, data) = match received {
+        Ok(Some(CompletionStreamStatus::Working(token))) if token.is_empty() => return None,
+        Ok(Some(CompletionStreamStatus::Working(token))) => {
+            return Some((
+                encode_event(None, json!({"index": index, "token": token})),
+                false,
+            ))
+        }
+        Err(StreamError::ReceiverTimeout) => return None,
+        Ok(Some(CompletionStreamStatus::Finished(content))) => (
+            "finished",
+            json!({"finish_reason": finish_reason, "content": content}),
+        ),
+        Ok(Some(CompletionStreamStatus::Truncated(content))) => (
+            "finished",
+            json!({"finish_reason": "content_limit", "content": content}),
+        ),
+        Ok(Some(CompletionStreamStatus::ToolCalls(calls))) => {
+            let calls: Vec<Value> = calls
+                .iter()
+                .map(|call| json!({"id": call.id, "name": call.name, "arguments": call.arguments}))
+                .collect();
+            (
+                "tool_calls",
+                json!({"finish_reason": finish_reason, "tool_calls": calls}),
+            )
+        }
+        Ok(None) => (
+            "error",
+            json!({"error": StreamError::PrematureClose.to_string()}),
+        ),
+        Err(err) => ("error", json!({"error": err.to_string()})),
+    };
+    Some((encode_event(Some(name), data), true))
+}
+
+/// Receives from `handler` until it ends, sending each event to the body. If the body is
+/// dropped, as when the client disconnects, the stream is cancelled with `CancelReason::User`
+async fn forward(
+    mut handler: ProviderStreamHandler,
+    agent: Arc<Mutex<Agent>>,
+    sender: mpsc::Sender<Bytes>,
+) {
+    let mut index = 0;
+    loop {
+        let received = match lock_agent(&agent, handler.agent_lock_timeout()).await {
+            Ok(mut locked) => tokio::select! {
+                received = handler.receive(&mut locked) => received,
+                () = sender.closed() => {
+                    warn!("Event stream client disconnected, cancelling the stream");
+                    handler.cancel(&mut locked, CancelReason::User);
+                    return;
+                }
+            },
+            Err(err) => Err(err),
+        };
+        let Some((event, last)) = received_event(received, &handler, index) else {
+            continue;
+        };
+        index += 1;
+        if sender.send(event).await.is_err() {
+            warn!("Event stream client disconnected, cancelling the stream");
+            handler.cancel(&mut *agent.lock().await, CancelReason::User);
+            return;
+        }
+        if last {
+            return;
+        }
+    }
+}
+
+/// A stream handler's tokens as a body of server sent events, usable as a streaming http
+/// response body such as with axum's `Body::from_stream`. Tokens are `data:` events holding
+/// their `index` & `token`, the end of the completion is a `finished` or `tool_calls` event
+/// holding its `finish_reason`, and a failed stream ends with an `error` event. Comments are
+/// sent during gaps so proxies don't close the connection. Respond with `SSE_CONTENT_TYPE`
+pub struct SseBody {
+    receiver: mpsc::Receiver<Bytes>,
+    keep_alive: Duration,
+    idle: Pin<Box<Sleep>>,
+}
+
+impl std::fmt::Debug for SseBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SseBody")
+            .field("receiver", &self.receiver)
+            .field("keep_alive", &self.keep_alive)
+            .finish()
+    }
+}
+
+impl SseBody {
+    /// Starts receiving from `handler` right away. Dropping the body cancels the stream
+    pub fn new(handler: ProviderStreamHandler, agent: Arc<Mutex<Agent>>) -> Self {
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+        tokio::spawn(forward(handler, agent, sender));
+        Self {
+            receiver,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            idle: Box::pin(tokio::time::sleep(DEFAULT_KEEP_ALIVE)),
+        }
+    }
+
+    /// How long to go without an event before sending a keep-alive comment
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self.idle.as_mut().reset(Instant::now() + keep_alive);
+        self
+    }
+}
+
+impl Stream for SseBody {
+    type Item = Result<Bytes, Infallible>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let event = match this.receiver.poll_recv(cx) {
+            Poll::Ready(Some(event)) => event,
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => match this.idle.as_mut().poll(cx) {
+                Poll::Ready(()) => Bytes::from_static(KEEP_ALIVE_COMMENT),
+                Poll::Pending => return Poll::Pending,
+            },
+        };
+        let keep_alive = this.keep_alive;
+        this.idle.as_mut().reset(Instant::now() + keep_alive);
+        Poll::Ready(Some(Ok(event)))
+    }
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::{streaming::StreamReplay, CompletionModel};
+    use futures::StreamExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const RECORDED: &str = "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{\"content\":\" world\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n\
+        data: [DONE]\n\n";
+
+    fn replay(recorded: &str, cadence: Duration, agent: &Agent) -> ProviderStreamHandler {
+        StreamReplay::from_reader(std::io::Cursor::new(recorded.as_bytes().to_vec()))
+            .with_cadence(cadence)
+            .into_handler(&agent.completion_model.provider)
+    }
+
+    /// A web handler forwarding a completion, served on a local port. Returns the url
+    async fn serve(recorded: &'static str, cadence: Duration, agent: Arc<Mutex<Agent>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 8192];
+            let _ = socket.read(&mut buf).await.unwrap();
+            let handler = replay(recorded, cadence, &*agent.lock().await);
+            let mut body = SseBody::new(handler, agent).with_keep_alive(Duration::from_millis(30));
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+                SSE_CONTENT_TYPE
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            while let Some(Ok(event)) = body.next().await {
+                socket.write_all(&event).await.unwrap();
+            }
+            socket.shutdown().await.unwrap();
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn completion_forwarded_as_events() {
+        let agent = Arc::new(Mutex::new(Agent::new(
+            None,
+            CompletionModel::default_openai(""),
+        )));
+        let url = serve(RECORDED, Duration::from_millis(50), agent.clone()).await;
+        let response = reqwest::get(url).await.unwrap();
+        assert_eq!(response.headers()["content-type"], SSE_CONTENT_TYPE);
+        let body = response.text().await.unwrap();
+
+        let events: Vec<&str> = body
+            .split("\n\n")
+            .filter(|event| !event.is_empty() && *event != ": keep-alive")
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "data: {\"index\":0,\"token\":\"Hello\"}",
+                "data: {\"index\":1,\"token\":\" world\"}",
+                "event: finished\ndata: {\"content\":\"Hello world\",\"finish_reason\":\"stop\"}",
+            ]
+        );
+        assert!(body.contains(": keep-alive\n\n"));
+        let agent = agent.lock().await;
+        assert_eq!(agent.cache.as_ref().last().unwrap().content, "Hello world");
+
+        let cut = &RECORDED[..RECORDED.find("data: {\"choices\":[{\"delta\":{},").unwrap()];
+        let handler = replay(cut, Duration::ZERO, &agent);
+        let other = Agent::new(None, CompletionModel::default_openai(""));
+        let mut body = SseBody::new(handler, Arc::new(Mutex::new(other)));
+        let mut events = vec![];
+        while let Some(Ok(event)) = body.next().await {
+            events.push(String::from_utf8(event.to_vec()).unwrap());
+        }
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[2],
+            "event: error\ndata: {\"error\":\"Stream closed before completion finished\"}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnecting_cancels_stream() {
+        let tokens: String = (0..20)
+            .map(|i| {
+                format!(
+                    "data: {{\"choices\":[{{\"delta\":{{\"content\":\"{} \"}}}}]}}\n\n",
+                    i
+                )
+            })
+            .collect();
+        let recorded: &'static str = Box::leak(format!("{}{}", tokens, RECORDED).into_boxed_str());
+        let agent = Arc::new(Mutex::new(Agent::new(
+            None,
+            CompletionModel::default_openai(""),
+        )));
+        let handler = replay(recorded, Duration::from_millis(20), &*agent.lock().await);
+        let mut body = SseBody::new(handler, agent.clone());
+        body.next().await.unwrap().unwrap();
+        body.next().await.unwrap().unwrap();
+        drop(body);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let agent = agent.lock().await;
+        let cached = &agent.cache.as_ref().last().unwrap().content;
+        assert!(cached.starts_with("0 1 "), "{:?}", cached);
+        assert!(!cached.contains("world"));
+    }
+}
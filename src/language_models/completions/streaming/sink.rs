@@ -0,0 +1,15 @@
+use super::StreamError;
+
+/// Receives the text of a streamed completion as it comes in, for handing it off to an external
+/// consumer such as a TTS backend. Sinks are called from `receive`, alongside normal consumption
+/// of the returned statuses, so they should be cheap or hand the text off to another task
+pub trait TextSink: Send {
+    /// Called with each non empty token as it is received
+    fn token(&mut self, _token: &str) {}
+    /// Called with the full message once the completion finishes with text. Not called for tool
+    /// call finishes, or streams that fail or are cancelled
+    fn finished(&mut self, _message: &str) {}
+    /// Called with the error ending the stream, including cancels. Receiver timeouts don't end
+    /// the stream & aren't passed. Sinks aren't called again after failing
+    fn failed(&mut self, _err: &StreamError) {}
+}
@@ -0,0 +1,222 @@
+//! Server sent event framing for streamed completions
+use super::{CompletionStream, StreamError, StreamResult};
+use crate::language_models::completions::error::{CompletionError, CompletionResult};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use reqwest_streams::error::{StreamBodyError, StreamBodyKind};
+use serde_json::Value;
+use std::collections::VecDeque;
+
+/// Sent by OpenAi compatible providers as the data of the last event
+const DONE_DATA: &str = "[DONE]";
+const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+/// How many characters of an unexpected body are included in the error
+const SNIPPET_LEN: usize = 200;
+
+/// Splits a byte stream into events, returning the data of each event. Multiple `data:` lines
+/// within one event are joined with newlines, per the SSE spec
+#[derive(Debug, Default)]
+pub(crate) struct SseDecoder {
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    /// Feeds bytes to the decoder, returns the data of every event they complete
+    pub(crate) fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        // Raw carriage returns can't appear inside of JSON, so normalizing CRLF line endings is
+        // safe
+        self.buffer.extend(bytes.iter().filter(|b| **b != b'\r'));
+        let mut events = vec![];
+        while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            if let Some(data) = event_data(&String::from_utf8_lossy(&event)) {
+                events.push(data);
+            }
+        }
+        events
+    }
+
+    /// Data of a trailing event that was never terminated by a blank line
+    pub(crate) fn finish(&mut self) -> Option<String> {
+        let event: Vec<u8> = self.buffer.drain(..).collect();
+        event_data(&String::from_utf8_lossy(&event))
+    }
+}
+
+/// Joins the `data:` fields of a single event, other fields & comments are ignored
+fn event_data(event: &str) -> Option<String> {
+    let data: Vec<&str> = event
+        .lines()
+        .filter_map(|line| {
+            let value = line.strip_prefix("data")?;
+            if value.is_empty() {
+                return Some(value);
+            }
+            let value = value.strip_prefix(':')?;
+            Some(value.strip_prefix(' ').unwrap_or(value))
+        })
+        .collect();
+    if data.is_empty() {
+        return None;
+    }
+    Some(data.join("\n"))
+}
+
+struct JsonEventState<S> {
+    bytes: S,
+    decoder: SseDecoder,
+    pending: VecDeque<String>,
+    exhausted: bool,
+}
+
+/// Parses the data of each event in `bytes` as JSON. The stream ends on a `[DONE]` event or when
+/// `bytes` is exhausted
+pub(crate) fn json_event_stream<S>(bytes: S) -> CompletionStream
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + Unpin + 'static,
+{
+    let state = JsonEventState {
+        bytes,
+        decoder: SseDecoder::default(),
+        pending: VecDeque::new(),
+        exhausted: false,
+    };
+    let stream = futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(data) = state.pending.pop_front() {
+                if data.trim() == DONE_DATA {
+                    return None;
+                }
+                let result: StreamResult<Value> = serde_json::from_str(&data).map_err(|err| {
+                    StreamBodyError::new(StreamBodyKind::CodecError, Some(Box::new(err)), None)
+                        .into()
+                });
+                return Some((result, state));
+            }
+            if state.exhausted {
+                return None;
+            }
+            match state.bytes.next().await {
+                Some(Ok(chunk)) => {
+                    let events = state.decoder.push(&chunk);
+                    state.pending.extend(events);
+                }
+                Some(Err(err)) => {
+                    state.exhausted = true;
+                    return Some((Err(StreamError::Connection(err)), state));
+                }
+                None => {
+                    state.exhausted = true;
+                    state.pending.extend(state.decoder.finish());
+                }
+            }
+        }
+    });
+    Box::new(stream.boxed())
+}
+
+/// Frames the body of a streamed completion response. Responses that aren't an event stream,
+/// such as a proxy's HTML error page, are read and returned as an error instead
+pub(crate) async fn response_event_stream(
+    response: reqwest::Response,
+) -> CompletionResult<CompletionStream> {
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("no content type")
+        .to_owned();
+    if !content_type.starts_with(EVENT_STREAM_CONTENT_TYPE) {
+        let body = response.text().await?;
+        return Err(CompletionError::UnexpectedContentType {
+            content_type,
+            snippet: body.trim().chars().take(SNIPPET_LEN).collect(),
+        });
+    }
+    Ok(json_event_stream(
+        response
+            .bytes_stream()
+            .map(|chunk| chunk.map_err(std::io::Error::other)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_line_data_joined_per_event() {
+        let mut decoder = SseDecoder::default();
+        let events = decoder.push(
+            b"event: message\r\ndata: {\"choices\":\r\ndata: [{\"delta\":{\"content\":\"hi\"}}]}\r\n\r\n: comment\n\ndata: [DONE]\n\n",
+        );
+        assert_eq!(
+            events,
+            vec![
+                "{\"choices\":\n[{\"delta\":{\"content\":\"hi\"}}]}".to_string(),
+                "[DONE]".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn non_event_stream_response_is_an_error() {
+        let body = "<html><body>502 Bad Gateway</body></html>";
+        let url = crate::language_models::completions::testing::serve_once(
+            vec![("Content-Type", "text/html; charset=utf-8")],
+            body.as_bytes().to_vec(),
+        )
+        .await;
+        let response = reqwest::Client::new().post(url).send().await.unwrap();
+        match response_event_stream(response).await {
+            Err(err @ CompletionError::UnexpectedContentType { .. }) => assert_eq!(
+                err.to_string(),
+                format!("Expected SSE, got text/html; charset=utf-8: {}", body)
+            ),
+            _ => panic!("expected an unexpected content type error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn errors_classified_by_recoverability() {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![
+            Ok(Bytes::from_static(b"data: {not json}\n\n")),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "connection reset by peer",
+            )),
+        ];
+        let mut stream = json_event_stream(futures::stream::iter(chunks));
+
+        let decode = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(decode, StreamError::StreamBody(_)));
+        assert!(!decode.is_recoverable());
+
+        let connection = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(connection, StreamError::Connection(_)));
+        assert!(connection.is_recoverable());
+        assert!(format!("{:?}", connection).contains("Caused by:\n\tconnection reset by peer"));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn events_reassembled_across_chunks() {
+        let body =
+            "data: {\"a\":\ndata: 1}\n\ndata: {\"b\": 2}\n\ndata: [DONE]\n\ndata: {\"c\": 3}\n\n";
+        let chunks: Vec<Result<Bytes, std::io::Error>> = body
+            .as_bytes()
+            .chunks(5)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        let mut stream = json_event_stream(futures::stream::iter(chunks));
+
+        let mut values = vec![];
+        while let Some(value) = stream.next().await {
+            values.push(value.unwrap());
+        }
+        assert_eq!(
+            values,
+            vec![serde_json::json!({"a": 1}), serde_json::json!({"b": 2})]
+        );
+    }
+}
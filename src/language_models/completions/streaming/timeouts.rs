@@ -0,0 +1,111 @@
+//! Limits on how long a stream takes to produce its tokens, and the settings streams start with
+use super::{CancelReason, RetryPolicy};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long `ProviderStreamHandler::receive_shared` waits for the agent's lock when
+/// `StreamTimeouts::agent_lock` is unset
+pub const DEFAULT_AGENT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a stream may go without progress before it is cancelled, with the `CancelReason`
+/// of the timeout that passed, caching the content received so far. Each is unset by default.
+/// Time spent resuming after recoverable errors counts towards them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamTimeouts {
+    /// From the first `receive` until the first token
+    pub first_token: Option<Duration>,
+    /// Between tokens, once the first has been received
+    pub inter_token: Option<Duration>,
+    /// From the first `receive` until the completion finishes
+    pub total: Option<Duration>,
+    /// Waiting for a shared agent's lock in `receive_shared`, which returns
+    /// `StreamError::AgentLockTimeout` rather than waiting longer. Doesn't cancel the stream.
+    /// `DEFAULT_AGENT_LOCK_TIMEOUT` when unset
+    pub agent_lock: Option<Duration>,
+}
+
+impl StreamTimeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_first_token(mut self, timeout: Duration) -> Self {
+        self.first_token = Some(timeout);
+        self
+    }
+
+    pub fn with_inter_token(mut self, timeout: Duration) -> Self {
+        self.inter_token = Some(timeout);
+        self
+    }
+
+    pub fn with_total(mut self, timeout: Duration) -> Self {
+        self.total = Some(timeout);
+        self
+    }
+
+    pub fn with_agent_lock(mut self, timeout: Duration) -> Self {
+        self.agent_lock = Some(timeout);
+        self
+    }
+}
+
+/// Timeouts & retry policy every stream requested with a `CompletionModel` starts with, so
+/// they can be set once for all the agents sharing it, see
+/// `CompletionModel::with_stream_defaults`. Setting either on a handler replaces the default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamDefaults {
+    pub timeouts: StreamTimeouts,
+    pub retry_policy: RetryPolicy,
+}
+
+impl StreamDefaults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_timeouts(mut self, timeouts: StreamTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+}
+
+/// Timeouts along with when one stream started & last produced a token
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TimeoutState {
+    pub(crate) timeouts: StreamTimeouts,
+    started: Option<Instant>,
+    last_token: Option<Instant>,
+}
+
+impl TimeoutState {
+    /// When the stream times out & which timeout it is, if any applies. The first call starts
+    /// the clock
+    pub(crate) fn deadline(&mut self) -> Option<(Instant, CancelReason)> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let token = match self.last_token {
+            None => self
+                .timeouts
+                .first_token
+                .map(|timeout| (started + timeout, CancelReason::FirstTokenTimeout)),
+            Some(last) => self
+                .timeouts
+                .inter_token
+                .map(|timeout| (last + timeout, CancelReason::InterTokenTimeout)),
+        };
+        let total = self
+            .timeouts
+            .total
+            .map(|timeout| (started + timeout, CancelReason::TotalTimeout));
+        token.into_iter().chain(total).min_by_key(|(at, _)| *at)
+    }
+
+    pub(crate) fn token_received(&mut self) {
+        self.last_token = Some(Instant::now());
+    }
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A tool call assembled from the deltas of a completion stream
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// Raw JSON arguments as streamed by the provider
+    pub arguments: String,
+}
+
+impl ToolCall {
+    /// Parses the streamed arguments, a call with no arguments parses as an empty object
+    pub fn arguments_json(&self) -> serde_json::Result<Value> {
+        if self.arguments.trim().is_empty() {
+            return Ok(Value::Object(Default::default()));
+        }
+        serde_json::from_str(&self.arguments)
+    }
+}
+
+/// A piece of a tool call from a single stream chunk. Deltas sharing an `index` belong to the
+/// same call
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+/// Accumulates deltas in the order their calls first appear
+#[derive(Debug, Default)]
+pub(super) struct ToolCallAccumulator {
+    calls: Vec<(usize, ToolCall)>,
+}
+
+impl ToolCallAccumulator {
+    pub(super) fn push(&mut self, delta: ToolCallDelta) {
+        let call = match self.calls.iter_mut().find(|(i, _)| *i == delta.index) {
+            Some((_, call)) => call,
+            None => {
+                self.calls.push((delta.index, ToolCall::default()));
+                &mut self.calls.last_mut().unwrap().1
+            }
+        };
+        if let Some(id) = delta.id {
+            call.id = id;
+        }
+        if let Some(name) = delta.name {
+            call.name.push_str(&name);
+        }
+        call.arguments.push_str(&delta.arguments);
+    }
+
+    pub(super) fn calls(&self) -> Vec<ToolCall> {
+        self.calls.iter().map(|(_, call)| call.clone()).collect()
+    }
+}
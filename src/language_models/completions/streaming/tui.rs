@@ -0,0 +1,266 @@
+//! Streamed text kept as lines a terminal UI can render, such as with a ratatui `Paragraph`
+use super::{StreamError, TextSink};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use unicode_width::UnicodeWidthChar;
+
+/// Lines a `TuiBuffer` keeps by default before dropping the oldest
+pub const DEFAULT_MAX_LINES: usize = 1000;
+const FINISHED_MARKER: &str = "── finished ──";
+
+#[derive(Debug)]
+struct Lines {
+    lines: VecDeque<String>,
+    /// Whether text continues the last line, rather than starting a new one
+    open: bool,
+    max_lines: usize,
+}
+
+impl Lines {
+    fn push_str(&mut self, text: &str) {
+        for (i, part) in text.split('\n').enumerate() {
+            if i > 0 || !self.open {
+                self.lines.push_back(String::new());
+                self.open = true;
+            }
+            if let Some(line) = self.lines.back_mut() {
+                line.push_str(part);
+            }
+        }
+        self.trim();
+    }
+
+    /// Puts `marker` on a line of its own, text after it starts a new line
+    fn mark(&mut self, marker: String) {
+        if self.open && self.lines.back().is_some_and(String::is_empty) {
+            self.lines.pop_back();
+        }
+        self.lines.push_back(marker);
+        self.open = false;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.lines.len() > self.max_lines {
+            self.lines.pop_front();
+        }
+    }
+}
+
+/// Text shared between the sinks writing streamed completions & the render loop reading it.
+/// Clones share the same text. Lines are kept as received, up to a maximum, and wrapped to the
+/// width of the area they are rendered in when read, so resizing doesn't lose anything
+#[derive(Debug, Clone)]
+pub struct TuiBuffer {
+    lines: Arc<Mutex<Lines>>,
+}
+
+impl Default for TuiBuffer {
+    fn default() -> Self {
+        Self {
+            lines: Arc::new(Mutex::new(Lines {
+                lines: VecDeque::new(),
+                open: false,
+                max_lines: DEFAULT_MAX_LINES,
+            })),
+        }
+    }
+}
+
+impl TuiBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lines kept before dropping the oldest, counted before wrapping
+    pub fn with_max_lines(self, max_lines: usize) -> Self {
+        self.lines().max_lines = max_lines.max(1);
+        self
+    }
+
+    fn lines(&self) -> MutexGuard<'_, Lines> {
+        self.lines.lock().expect("tui buffer lock poisoned")
+    }
+
+    /// A sink appending streamed tokens to the buffer, followed by a marker line once the
+    /// stream finishes or fails
+    pub fn sink(&self) -> TuiSink {
+        TuiSink {
+            buffer: self.clone(),
+        }
+    }
+
+    /// Appends `text` after what's already buffered, such as the prompt a completion answers
+    pub fn push_str(&self, text: &str) {
+        self.lines().push_str(text);
+    }
+
+    pub fn clear(&self) {
+        let mut lines = self.lines();
+        lines.lines.clear();
+        lines.open = false;
+    }
+
+    /// Every buffered line as the rows they take up `width` columns wide. A width of 0 leaves
+    /// lines unwrapped
+    pub fn wrapped(&self, width: usize) -> Vec<String> {
+        let lines = self.lines();
+        lines
+            .lines
+            .iter()
+            .flat_map(|line| wrap(line, width))
+            .collect()
+    }
+
+    /// The last `height` rows an area `width` columns wide shows, for rendering the buffer
+    /// scrolled to the bottom
+    pub fn tail(&self, width: usize, height: usize) -> Vec<String> {
+        let lines = self.lines();
+        let mut rows = VecDeque::new();
+        for line in lines.lines.iter().rev() {
+            if rows.len() >= height {
+                break;
+            }
+            for row in wrap(line, width).into_iter().rev() {
+                rows.push_front(row);
+            }
+        }
+        let excess = rows.len().saturating_sub(height);
+        rows.drain(..excess);
+        rows.into()
+    }
+}
+
+/// Wraps `line` into rows at most `width` columns wide, breaking after whitespace where it can
+/// and within words that don't fit on a row of their own
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    if width == 0 {
+        return vec![line.to_owned()];
+    }
+    let mut rows = vec![];
+    let mut row = String::new();
+    let mut row_width = 0;
+    // Where the row can be broken, & its width up to there
+    let mut space: Option<(usize, usize)> = None;
+    for c in line.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if row_width + char_width > width && !row.is_empty() {
+            match space.take() {
+                Some((at, at_width)) if !c.is_whitespace() => {
+                    let rest = row.split_off(at);
+                    rows.push(row.trim_end().to_owned());
+                    row = rest;
+                    row_width -= at_width;
+                }
+                _ => {
+                    rows.push(std::mem::take(&mut row).trim_end().to_owned());
+                    row_width = 0;
+                }
+            }
+            if c.is_whitespace() && row.is_empty() {
+                continue;
+            }
+        }
+        row.push(c);
+        row_width += char_width;
+        if c.is_whitespace() {
+            space = Some((row.len(), row_width));
+        }
+    }
+    rows.push(row);
+    rows
+}
+
+/// Appends streamed text to a `TuiBuffer`, see `TuiBuffer::sink`
+#[derive(Debug, Clone)]
+pub struct TuiSink {
+    buffer: TuiBuffer,
+}
+
+impl TextSink for TuiSink {
+    fn token(&mut self, token: &str) {
+        self.buffer.push_str(token);
+    }
+
+    fn finished(&mut self, _message: &str) {
+        self.buffer.lines().mark(FINISHED_MARKER.to_owned());
+    }
+
+    fn failed(&mut self, err: &StreamError) {
+        self.buffer.lines().mark(format!("── error: {} ──", err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::Agent,
+        language_models::completions::{
+            streaming::{StreamReplay, StreamTimeouts},
+            CompletionModel,
+        },
+    };
+    use std::time::Duration;
+
+    const RECORDED: &str = "data: {\"choices\":[{\"delta\":{\"content\":\"Build passed\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{\"content\":\" with warnings:\\nunused import\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n\
+        data: [DONE]\n\n";
+
+    #[test]
+    fn lines_wrap_to_width() {
+        assert_eq!(wrap("build passed", 20), ["build passed"]);
+        assert_eq!(
+            wrap("build passed with warnings", 12),
+            ["build passed", "with", "warnings"]
+        );
+        assert_eq!(wrap("unresolved", 4), ["unre", "solv", "ed"]);
+        assert_eq!(wrap("ビルド成功", 4), ["ビル", "ド成", "功"]);
+        assert_eq!(wrap("", 4), [""]);
+
+        let buffer = TuiBuffer::new().with_max_lines(3);
+        buffer.push_str("one\ntwo\nthree four\nfive");
+        assert_eq!(buffer.wrapped(0), ["two", "three four", "five"]);
+        assert_eq!(buffer.tail(5, 3), ["three", "four", "five"]);
+        assert_eq!(buffer.tail(5, 10), ["two", "three", "four", "five"]);
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn streams_end_with_status_markers() {
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let buffer = TuiBuffer::new();
+        buffer.push_str("> Did the build pass?\n");
+        let replay = || StreamReplay::from_reader(std::io::Cursor::new(RECORDED.as_bytes()));
+
+        let mut handler = replay()
+            .into_handler(&agent.completion_model.provider)
+            .with_sink(buffer.sink());
+        handler.collect(&mut agent).await.unwrap();
+        assert_eq!(
+            buffer.wrapped(0),
+            [
+                "> Did the build pass?",
+                "Build passed with warnings:",
+                "unused import",
+                FINISHED_MARKER
+            ]
+        );
+
+        buffer.clear();
+        let mut handler = replay()
+            .with_cadence(Duration::from_millis(100))
+            .into_handler(&agent.completion_model.provider)
+            .with_timeouts(StreamTimeouts::new().with_first_token(Duration::from_millis(20)))
+            .with_sink(buffer.sink());
+        assert!(handler.collect(&mut agent).await.is_err());
+        assert!(handler.collect(&mut agent).await.is_err());
+        assert_eq!(
+            buffer.wrapped(0),
+            ["── error: Stream cancelled: deadline passed ──"]
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Helpers for unit testing request & response handling against a local server
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+/// Serves a single canned HTTP response on a random local port, returns the url to request
+pub(crate) async fn serve_once(headers: Vec<(&str, &str)>, body: Vec<u8>) -> String {
+    serve_statuses(vec![("200 OK", headers, body)]).await
+}
+
+/// Serves a canned HTTP response for each of `bodies` in order, one per request, on a random
+/// local port. Returns the url to request
+#[cfg(feature = "openai")]
+pub(crate) async fn serve_in_order(headers: Vec<(&str, &str)>, bodies: Vec<Vec<u8>>) -> String {
+    serve_recording(headers, bodies).await.0
+}
+
+/// Serves each of `responses` in order, one per request, on a random local port. Each is a
+/// status line such as `"429 Too Many Requests"`, its headers & its body. Returns the url to
+/// request
+pub(crate) async fn serve_statuses(responses: Vec<(&str, Vec<(&str, &str)>, Vec<u8>)>) -> String {
+    serve_statuses_recording(responses).await.0
+}
+
+/// Serves a canned HTTP response for each of `bodies` in order like `serve_in_order`, also
+/// sending the JSON body of each request it answers
+pub(crate) async fn serve_recording(
+    headers: Vec<(&str, &str)>,
+    bodies: Vec<Vec<u8>>,
+) -> (String, mpsc::UnboundedReceiver<Value>) {
+    let responses = bodies
+        .into_iter()
+        .map(|body| ("200 OK", headers.clone(), body))
+        .collect();
+    serve_statuses_recording(responses).await
+}
+
+/// Serves each of `responses` in order like `serve_statuses`, also sending the JSON body of
+/// each request it answers. Requests without a JSON body aren't sent
+async fn serve_statuses_recording(
+    responses: Vec<(&str, Vec<(&str, &str)>, Vec<u8>)>,
+) -> (String, mpsc::UnboundedReceiver<Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let responses: Vec<(String, Vec<u8>)> = responses
+        .into_iter()
+        .map(|(status, headers, body)| {
+            let mut head = format!("HTTP/1.1 {}\r\n", status);
+            for (k, v) in headers {
+                head.push_str(&format!("{}: {}\r\n", k, v));
+            }
+            head.push_str(&format!(
+                "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            ));
+            (head, body)
+        })
+        .collect();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        for (head, body) in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = vec![0u8; 8192];
+            // Read until the whole body given by the request's content length has arrived
+            let request_body = loop {
+                let read = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                    if read == 0 {
+                        break vec![];
+                    }
+                    continue;
+                };
+                let request_head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                let len: usize = request_head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length:"))
+                    .map_or(0, |len| len.trim().parse().unwrap());
+                if request.len() >= end + 4 + len || read == 0 {
+                    break request[end + 4..].to_vec();
+                }
+            };
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&body).await.unwrap();
+            socket.shutdown().await.unwrap();
+            if let Ok(request_body) = serde_json::from_slice(&request_body) {
+                let _ = tx.send(request_body);
+            }
+        }
+    });
+    (format!("http://{}", addr), rx)
+}
+
+/// Serves every request on a random local port with an OpenAi stream of `tokens`, sending a
+/// chunk every `cadence`, returns the url to request
+#[cfg(all(
+    feature = "openai",
+    any(feature = "ws-relay", all(feature = "ipc", unix))
+))]
+pub(crate) async fn serve_slowly(tokens: &[&str], cadence: std::time::Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut chunks: Vec<_> = tokens
+        .iter()
+        .map(|token| serde_json::json!({"choices": [{"delta": {"content": token}}]}))
+        .collect();
+    chunks.push(serde_json::json!({"choices": [{"delta": {}, "finish_reason": "stop"}]}));
+    let completion_tokens = tokens.len();
+    chunks.push(serde_json::json!({"choices": [], "usage": {"prompt_tokens": 5, "completion_tokens": completion_tokens, "total_tokens": completion_tokens + 5}}));
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let chunks = chunks.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 8192];
+                let _ = socket.read(&mut buf).await;
+                let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                    Connection: close\r\n\r\n";
+                socket.write_all(head.as_bytes()).await?;
+                for chunk in chunks {
+                    tokio::time::sleep(cadence).await;
+                    let event = format!("data: {}\n\n", chunk);
+                    socket.write_all(event.as_bytes()).await?;
+                }
+                socket.write_all(b"data: [DONE]\n\n").await?;
+                socket.shutdown().await
+            });
+        }
+    });
+    format!("http://{}", addr)
+}
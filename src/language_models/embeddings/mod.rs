@@ -1,20 +1,28 @@
-use self::{error::EmbeddingResult, inference::EmbeddingRequest, openai::OpenAiEmbeddingModel};
+#[cfg(feature = "openai")]
+use self::openai::OpenAiEmbeddingModel;
+use self::{error::EmbeddingResult, inference::EmbeddingRequest};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
 pub mod error;
 pub mod inference;
+#[cfg(feature = "openai")]
 pub mod openai;
 
+/// OpenAi is the only embedding provider, without the `openai` feature there are no variants
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EmbeddingProvider {
+    #[cfg(feature = "openai")]
     OpenAi(OpenAiEmbeddingModel),
 }
 
 impl EmbeddingProvider {
     fn inner_request(&self) -> Box<&dyn EmbeddingRequest> {
         match &self {
+            #[cfg(feature = "openai")]
             Self::OpenAi(b) => return Box::new(b),
+            #[cfg(not(feature = "openai"))]
+            _ => unreachable!("no provider features are enabled"),
         }
     }
 }
@@ -28,6 +36,7 @@ pub struct EmbeddingModel {
 }
 
 impl EmbeddingModel {
+    #[cfg(feature = "openai")]
     pub fn default_openai(api_key: &str) -> Self {
         let client = Client::new();
         Self {
@@ -48,6 +57,6 @@ impl EmbeddingModel {
             .json(&request.as_json(text)?)
             .send()
             .await?;
-        Ok(request.process_response(response).await?)
+        request.process_response(response).await
     }
 }
@@ -1,9 +1,23 @@
 pub mod agents;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod errors;
+#[cfg(all(feature = "ipc", unix))]
+pub mod ipc;
 pub mod language_models;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+#[cfg(feature = "ws-relay")]
+pub mod relay;
 pub mod telemetry;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+#[cfg(feature = "tmux")]
+pub mod tmux;
 #[cfg(feature = "tools")]
 pub mod tools;
+pub mod vector;
+pub mod wire;
 
 pub mod prelude {
     pub use crate::{
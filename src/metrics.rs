@@ -0,0 +1,443 @@
+//! Exporting agent, stream & monitor counts as Prometheus metrics, rendered in its text
+//! exposition format for serving at `/metrics`
+use crate::{
+    agents::Agent,
+    language_models::completions::streaming::{StreamError, TextSink, WireStreamError},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex as StdMutex},
+    time::Instant,
+};
+use tokio::sync::Mutex;
+
+#[cfg(feature = "tmux")]
+use crate::tmux::{MonitorHandle, MonitorMetrics, MultiPaneMonitorHandle};
+
+/// Prefix of every series' name
+const NAMESPACE: &str = "espionox";
+/// Upper bounds in seconds of the time to first token histogram's buckets
+const FIRST_TOKEN_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+/// Names & help of the `MonitorMetrics` counts, in the order they're exported
+#[cfg(feature = "tmux")]
+const MONITOR_COUNTS: [(&str, &str); 9] = [
+    ("captures", "Successful captures of the pane"),
+    ("pushed", "Snapshots pushed to the agent's cache"),
+    (
+        "skipped",
+        "Captures skipped because the pane hadn't changed",
+    ),
+    ("completions", "Completions made after pushing snapshots"),
+    (
+        "gaps",
+        "History captures that found output evicted before it was read",
+    ),
+    ("throttled", "Ticks skipped by the throttle"),
+    (
+        "debounced",
+        "Captures put off until the pane's output stopped",
+    ),
+    (
+        "summarized",
+        "Summaries pushed in place of snapshots while degraded",
+    ),
+    (
+        "reselected",
+        "Times a closed pane was replaced by one matching the selector",
+    ),
+];
+
+/// Counts of the streams a sink was attached to
+#[derive(Debug, Default)]
+struct StreamStats {
+    finished: u64,
+    failed: u64,
+    /// By `WireStreamError::kind`
+    errors: BTreeMap<String, u64>,
+    /// Observations in each of `FIRST_TOKEN_BUCKETS`, not cumulative
+    first_token_buckets: [u64; FIRST_TOKEN_BUCKETS.len()],
+    first_token_sum: f64,
+    first_token_count: u64,
+}
+
+impl StreamStats {
+    fn first_token(&mut self, seconds: f64) {
+        if let Some(i) = FIRST_TOKEN_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.first_token_buckets[i] += 1;
+        }
+        self.first_token_sum += seconds;
+        self.first_token_count += 1;
+    }
+}
+
+type SharedStreamStats = Arc<StdMutex<BTreeMap<String, StreamStats>>>;
+
+/// Renders the state of the agents & monitors added to it, and of the streams its sinks are
+/// attached to, as Prometheus metrics. Clones share the same stream counts.
+///
+/// Series are labelled with the name each agent & monitor is added under, which is fine for
+/// tens of them but makes a series per name, per role or error kind, for Prometheus to store.
+/// `without_names` sums every agent's & monitor's series together instead
+#[derive(Debug, Clone, Default)]
+pub struct PrometheusExporter {
+    agents: Vec<(String, Arc<Mutex<Agent>>)>,
+    #[cfg(feature = "tmux")]
+    monitors: Vec<(String, Arc<StdMutex<MonitorMetrics>>)>,
+    streams: SharedStreamStats,
+    aggregate: bool,
+}
+
+impl PrometheusExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exports `agent`'s cached messages by role, token count & spend, labelled `agent="name"`
+    pub fn with_agent(mut self, name: &str, agent: &Arc<Mutex<Agent>>) -> Self {
+        self.agents.push((name.to_owned(), agent.clone()));
+        self
+    }
+
+    /// Exports a monitor's `MonitorMetrics`, labelled `monitor="name"`
+    #[cfg(feature = "tmux")]
+    pub fn with_monitor(mut self, name: &str, monitor: &MonitorHandle) -> Self {
+        self.monitors
+            .push((name.to_owned(), monitor.shared_metrics()));
+        self
+    }
+
+    /// Exports a multi pane monitor's `MonitorMetrics`, labelled `monitor="name"`
+    #[cfg(feature = "tmux")]
+    pub fn with_multi_pane_monitor(mut self, name: &str, monitor: &MultiPaneMonitorHandle) -> Self {
+        self.monitors
+            .push((name.to_owned(), monitor.shared_metrics()));
+        self
+    }
+
+    /// Leaves the agent & monitor labels off, so each series is the total over all of them
+    pub fn without_names(mut self) -> Self {
+        self.aggregate = true;
+        self
+    }
+
+    /// A sink counting how a stream ends & timing its first token, for attaching with
+    /// `ProviderStreamHandler::with_sink`. The time is measured from when the sink is made, so
+    /// it should be made when the stream is requested
+    pub fn stream_sink(&self, agent: &str) -> StreamMetricsSink {
+        StreamMetricsSink {
+            agent: agent.to_owned(),
+            started: Instant::now(),
+            first_token: false,
+            streams: self.streams.clone(),
+        }
+    }
+
+    /// Every series in the text exposition format, with the content type
+    /// `text/plain; version=0.0.4`
+    pub async fn render(&self) -> String {
+        let mut messages = Family::new(
+            "agent_messages",
+            "Messages in each agent's cache, by role",
+            "gauge",
+        );
+        let mut tokens = Family::new(
+            "agent_tokens_total",
+            "Tokens used by each agent's completions",
+            "counter",
+        );
+        let mut spend = Family::new(
+            "agent_spend_dollars_total",
+            "Estimated spend of each agent's completions",
+            "counter",
+        );
+        for (name, agent) in self.agents.iter() {
+            let agent = agent.lock().await;
+            let name = self.name("agent", name);
+            for message in agent.cache.as_ref().iter() {
+                let role = message.role.to_string();
+                messages.add("", &[name, ("role", &role)], 1.0);
+            }
+            let model = &agent.completion_model;
+            tokens.add("", &[name], f64::from(model.params.total_token_count));
+            spend.add("", &[name], model.total_spend);
+        }
+
+        let mut streams = Family::new("streams_total", "Streams that ended, by outcome", "counter");
+        let mut errors = Family::new(
+            "stream_errors_total",
+            "Errors that ended streams, including cancels, by kind",
+            "counter",
+        );
+        let mut first_token = Family::new(
+            "stream_first_token_seconds",
+            "Time from requesting a stream to its first token",
+            "histogram",
+        );
+        for (agent, stats) in self
+            .streams
+            .lock()
+            .expect("stream stats lock poisoned")
+            .iter()
+        {
+            let name = self.name("agent", agent);
+            streams.add("", &[name, ("outcome", "finished")], stats.finished as f64);
+            streams.add("", &[name, ("outcome", "failed")], stats.failed as f64);
+            for (kind, count) in stats.errors.iter() {
+                errors.add("", &[name, ("kind", kind)], *count as f64);
+            }
+            let mut cumulative = 0;
+            for (le, count) in FIRST_TOKEN_BUCKETS.iter().zip(stats.first_token_buckets) {
+                cumulative += count;
+                let le = le.to_string();
+                first_token.add("_bucket", &[name, ("le", &le)], cumulative as f64);
+            }
+            let count = stats.first_token_count as f64;
+            first_token.add("_bucket", &[name, ("le", "+Inf")], count);
+            first_token.add("_sum", &[name], stats.first_token_sum);
+            first_token.add("_count", &[name], count);
+        }
+
+        let mut out = String::new();
+        for family in [messages, tokens, spend, streams, errors, first_token] {
+            family.write(&mut out);
+        }
+        #[cfg(feature = "tmux")]
+        self.render_monitors(&mut out);
+        out
+    }
+
+    #[cfg(feature = "tmux")]
+    fn render_monitors(&self, out: &mut String) {
+        let metrics: Vec<(&str, [u64; MONITOR_COUNTS.len()], bool)> = self
+            .monitors
+            .iter()
+            .map(|(name, metrics)| {
+                let m = *metrics.lock().expect("monitor metrics lock poisoned");
+                let counts = [
+                    m.captures,
+                    m.pushed,
+                    m.skipped,
+                    m.completions,
+                    m.gaps,
+                    m.throttled,
+                    m.debounced,
+                    m.summarized,
+                    m.reselected,
+                ];
+                (name.as_str(), counts, m.degraded)
+            })
+            .collect();
+        for (i, (count, help)) in MONITOR_COUNTS.iter().enumerate() {
+            let mut family = Family::new(&format!("monitor_{}_total", count), help, "counter");
+            for (name, counts, _) in metrics.iter() {
+                family.add("", &[self.name("monitor", name)], counts[i] as f64);
+            }
+            family.write(out);
+        }
+        let mut degraded = Family::new("monitor_degraded", "Monitors in degraded mode", "gauge");
+        for (name, _, is_degraded) in metrics.iter() {
+            let value = if *is_degraded { 1.0 } else { 0.0 };
+            degraded.add("", &[self.name("monitor", name)], value);
+        }
+        degraded.write(out);
+    }
+
+    /// The label naming an agent or monitor, empty when names are left off
+    fn name<'a>(&self, label: &'a str, name: &'a str) -> (&'a str, &'a str) {
+        match self.aggregate {
+            true => ("", ""),
+            false => (label, name),
+        }
+    }
+}
+
+/// Counts the streams it's attached to into a `PrometheusExporter`, see
+/// `PrometheusExporter::stream_sink`
+#[derive(Debug)]
+pub struct StreamMetricsSink {
+    agent: String,
+    started: Instant,
+    first_token: bool,
+    streams: SharedStreamStats,
+}
+
+impl StreamMetricsSink {
+    fn update(&self, update: impl FnOnce(&mut StreamStats)) {
+        let mut streams = self.streams.lock().expect("stream stats lock poisoned");
+        update(streams.entry(self.agent.to_owned()).or_default())
+    }
+}
+
+impl TextSink for StreamMetricsSink {
+    fn token(&mut self, _token: &str) {
+        if !std::mem::replace(&mut self.first_token, true) {
+            let seconds = self.started.elapsed().as_secs_f64();
+            self.update(|stats| stats.first_token(seconds));
+        }
+    }
+
+    fn finished(&mut self, _message: &str) {
+        self.update(|stats| stats.finished += 1);
+    }
+
+    fn failed(&mut self, err: &StreamError) {
+        let kind = WireStreamError::from(err).kind;
+        self.update(|stats| {
+            stats.failed += 1;
+            *stats.errors.entry(kind).or_default() += 1;
+        });
+    }
+}
+
+/// A metric's samples, summing samples with the same labels
+struct Family {
+    name: String,
+    help: String,
+    kind: &'static str,
+    /// Suffix & rendered labels of each sample, in the order they were first added
+    samples: Vec<(String, f64)>,
+}
+
+impl Family {
+    fn new(name: &str, help: &str, kind: &'static str) -> Self {
+        Self {
+            name: format!("{}_{}", NAMESPACE, name),
+            help: help.to_owned(),
+            kind,
+            samples: vec![],
+        }
+    }
+
+    /// Adds `value` to the sample named with `suffix` & labelled `labels`. Labels with empty
+    /// names are left off
+    fn add(&mut self, suffix: &str, labels: &[(&str, &str)], value: f64) {
+        let labels: Vec<String> = labels
+            .iter()
+            .filter(|(label, _)| !label.is_empty())
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        let key = match labels.is_empty() {
+            true => format!("{}{}", self.name, suffix),
+            false => format!("{}{}{{{}}}", self.name, suffix, labels.join(",")),
+        };
+        match self.samples.iter_mut().find(|(sample, _)| *sample == key) {
+            Some((_, total)) => *total += value,
+            None => self.samples.push((key, value)),
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        for (sample, value) in self.samples.iter() {
+            let _ = writeln!(out, "{} {}", sample, value);
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::memory::Message,
+        language_models::completions::{
+            streaming::{CancelReason, StreamReplay},
+            CompletionModel,
+        },
+    };
+
+    const RECORDED: &str = "data: {\"choices\":[{\"delta\":{\"content\":\"Build passed\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n\
+        data: [DONE]\n\n";
+
+    fn agent(prompt: &str) -> Arc<Mutex<Agent>> {
+        let mut agent = Agent::new(
+            Some("You check builds"),
+            CompletionModel::default_openai(""),
+        );
+        agent.cache.push(Message::new_user(prompt));
+        agent.completion_model.params.total_token_count = 12;
+        Arc::new(Mutex::new(agent))
+    }
+
+    #[tokio::test]
+    async fn rendered_series_follow_activity() {
+        let (builds, tests) = (agent("Did the build pass?"), agent("Did the tests pass?"));
+        let exporter = PrometheusExporter::new()
+            .with_agent("builds", &builds)
+            .with_agent("tests", &tests);
+
+        let mut agent = builds.lock().await;
+        let mut handler = StreamReplay::from_reader(std::io::Cursor::new(RECORDED.as_bytes()))
+            .into_handler(&agent.completion_model.provider)
+            .with_sink(exporter.stream_sink("builds"));
+        handler.collect(&mut agent).await.unwrap();
+        drop(agent);
+        let mut sink = exporter.stream_sink("tests");
+        sink.failed(&StreamError::Cancelled(CancelReason::Deadline));
+        #[cfg(feature = "tmux")]
+        let exporter = {
+            let metrics = MonitorMetrics {
+                captures: 4,
+                degraded: true,
+                ..Default::default()
+            };
+            let mut exporter = exporter;
+            exporter
+                .monitors
+                .push(("pane \"1\"".to_owned(), Arc::new(StdMutex::new(metrics))));
+            exporter
+        };
+
+        let rendered = exporter.render().await;
+        for series in [
+            "# TYPE espionox_agent_messages gauge",
+            "espionox_agent_messages{agent=\"builds\",role=\"assistant\"} 1",
+            "espionox_agent_messages{agent=\"tests\",role=\"user\"} 1",
+            "espionox_agent_tokens_total{agent=\"tests\"} 12",
+            "espionox_streams_total{agent=\"builds\",outcome=\"finished\"} 1",
+            "espionox_streams_total{agent=\"tests\",outcome=\"failed\"} 1",
+            "espionox_stream_errors_total{agent=\"tests\",kind=\"cancelled\"} 1",
+            "# TYPE espionox_stream_first_token_seconds histogram",
+            "espionox_stream_first_token_seconds_bucket{agent=\"builds\",le=\"+Inf\"} 1",
+            "espionox_stream_first_token_seconds_count{agent=\"builds\"} 1",
+        ] {
+            assert!(
+                rendered.lines().any(|line| line == series),
+                "{} missing from\n{}",
+                series,
+                rendered
+            );
+        }
+        #[cfg(feature = "tmux")]
+        {
+            assert!(rendered
+                .contains("espionox_monitor_captures_total{monitor=\"pane \\\"1\\\"\"} 4\n"));
+            assert!(rendered.contains("espionox_monitor_degraded{monitor=\"pane \\\"1\\\"\"} 1\n"));
+        }
+
+        let rendered = exporter.without_names().render().await;
+        for series in [
+            "espionox_agent_messages{role=\"user\"} 2",
+            "espionox_agent_messages{role=\"system\"} 2",
+            "espionox_agent_tokens_total 24",
+            "espionox_streams_total{outcome=\"failed\"} 1",
+        ] {
+            assert!(
+                rendered.lines().any(|line| line == series),
+                "{} missing from\n{}",
+                series,
+                rendered
+            );
+        }
+        assert!(!rendered.contains("agent=\""));
+    }
+}
@@ -0,0 +1,604 @@
+//! Relaying an agent over a WebSocket, so a client in another process can prompt it, cancel its
+//! completions & change its parameters, while receiving its tokens as they stream. Frames are
+//! JSON text messages holding `WireEvent`s of `ClientFrame` & `ServerFrame`
+use crate::{
+    agents::{memory::Message, Agent},
+    errors::error_chain_fmt,
+    language_models::completions::{
+        streaming::{
+            CancelReason, CollectedCompletion, CompletionStreamStatus, ProviderStreamHandler,
+            StreamCanceller, StreamError, WireStreamError,
+        },
+        ModelParameters,
+    },
+    wire::WireEvent,
+};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    io,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, Mutex, Notify},
+};
+use tokio_tungstenite::{accept_async, WebSocketStream};
+use tracing::warn;
+use tungstenite::{
+    error::ProtocolError,
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message as WsMessage,
+};
+/// Close frame reasons can't be longer than this many bytes
+const MAX_CLOSE_REASON_BYTES: usize = 123;
+
+pub type RelayResult<T> = Result<T, RelayError>;
+
+#[derive(thiserror::Error)]
+pub enum RelayError {
+    /// The connection didn't open with a valid WebSocket upgrade request, such as one without
+    /// `Connection: Upgrade` or `Sec-WebSocket-Version: 13`
+    Handshake(String),
+    /// The client sent something the protocol doesn't allow, the socket was closed with this
+    /// as the reason
+    Protocol(String),
+    /// Boxed since WebSocket errors are large
+    WebSocket(Box<tungstenite::Error>),
+    Io(#[from] io::Error),
+}
+
+impl From<tungstenite::Error> for RelayError {
+    fn from(err: tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(err))
+    }
+}
+
+impl Debug for RelayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl Display for RelayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let display = match self {
+            Self::Handshake(reason) => format!("WebSocket handshake failed: {}", reason),
+            Self::Protocol(reason) => format!("Relay protocol violation: {}", reason),
+            Self::WebSocket(err) => err.to_string(),
+            Self::Io(err) => err.to_string(),
+        };
+        write!(f, "{}", display)
+    }
+}
+
+/// What clients send
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// Pushes a user message & streams a completion of it. Refused with an error frame while
+    /// another completion is streaming
+    Prompt { content: String },
+    /// Cancels the completion streaming, if any
+    Cancel,
+    /// Parameters set over the agent's current ones, unset fields are kept. Applies from the
+    /// next completion
+    UpdateParams { params: ModelParameters },
+}
+
+/// What the relay sends
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// Tokens received since the last token frame. When the client reads slower than tokens
+    /// stream, those waiting are sent together as one frame
+    Token { token: String },
+    /// The completion ended. Cancelled completions end as `CollectedCompletion::Partial`
+    Finished { completion: CollectedCompletion },
+    /// The completion couldn't be requested or its stream failed, or a frame was refused
+    Error { error: WireStreamError },
+    /// Sent on connecting, and whenever a completion starts or ends or the parameters change
+    StateChanged {
+        streaming: bool,
+        params: ModelParameters,
+    },
+}
+
+/// Tokens the completion task has received that haven't been sent yet
+#[derive(Debug, Default)]
+struct PendingTokens {
+    tokens: std::sync::Mutex<String>,
+    notify: Notify,
+}
+
+impl PendingTokens {
+    fn push(&self, token: &str) {
+        self.tokens
+            .lock()
+            .expect("pending tokens lock poisoned")
+            .push_str(token);
+        self.notify.notify_one();
+    }
+
+    fn take(&self) -> String {
+        std::mem::take(&mut *self.tokens.lock().expect("pending tokens lock poisoned"))
+    }
+}
+
+fn error_frame(kind: &str, message: String) -> ServerFrame {
+    ServerFrame::Error {
+        error: WireStreamError {
+            kind: kind.to_owned(),
+            message,
+            recoverable: false,
+            provider: None,
+            cancel_reason: None,
+        },
+    }
+}
+
+/// Cancels the relay's completion, including one whose stream hasn't been requested yet
+#[derive(Debug, Default)]
+struct RelayCanceller {
+    canceller: StreamCanceller,
+    /// Whether a cancel came before the stream was attached
+    requested: std::sync::Mutex<bool>,
+}
+
+impl RelayCanceller {
+    fn requested(&self) -> std::sync::MutexGuard<'_, bool> {
+        self.requested.lock().expect("relay cancel lock poisoned")
+    }
+
+    /// Returns whether a stream was in flight, otherwise the next one attached is cancelled
+    fn cancel(&self) -> bool {
+        let mut requested = self.requested();
+        let cancelled = self.canceller.cancel(CancelReason::User);
+        *requested = !cancelled;
+        cancelled
+    }
+
+    fn attach(&self, handler: ProviderStreamHandler) -> ProviderStreamHandler {
+        let mut requested = self.requested();
+        let handler = handler.with_canceller(&self.canceller);
+        if std::mem::take(&mut *requested) {
+            self.canceller.cancel(CancelReason::User);
+        }
+        handler
+    }
+
+    /// Forgets cancels that came before a new completion was prompted
+    fn reset(&self) {
+        *self.requested() = false;
+    }
+}
+
+/// Streams a completion of `prompt`, handing tokens to `pending` & sending the frame ending
+/// it to `done`
+async fn complete(
+    agent: Arc<Mutex<Agent>>,
+    prompt: String,
+    canceller: Arc<RelayCanceller>,
+    pending: Arc<PendingTokens>,
+    done: mpsc::Sender<ServerFrame>,
+) {
+    let handler = {
+        let mut agent = agent.lock().await;
+        agent.cache.push(Message::new_user(&prompt));
+        agent.stream_completion().await
+    };
+    let mut handler = match handler {
+        Ok(handler) => canceller.attach(handler),
+        Err(err) => {
+            let _ = done.send(error_frame("request", err.to_string())).await;
+            return;
+        }
+    };
+    let completion = loop {
+        let received = handler.receive_shared(&agent).await;
+        match received {
+            Ok(Some(CompletionStreamStatus::Working(token))) => pending.push(&token),
+            Err(StreamError::ReceiverTimeout) => continue,
+            Ok(Some(CompletionStreamStatus::Finished(content))) => {
+                break Ok(CollectedCompletion::Finished(content))
+            }
+            Ok(Some(CompletionStreamStatus::ToolCalls(calls))) => {
+                break Ok(CollectedCompletion::ToolCalls(calls))
+            }
+            Ok(Some(CompletionStreamStatus::Truncated(content))) => {
+                break Ok(CollectedCompletion::Partial {
+                    content,
+                    reason: CancelReason::ContentLimit,
+                })
+            }
+            Err(StreamError::Cancelled(reason)) => {
+                break Ok(CollectedCompletion::Partial {
+                    content: handler.message_content().to_owned(),
+                    reason,
+                })
+            }
+            Ok(None) => break Err(StreamError::PrematureClose),
+            Err(err) => break Err(err),
+        }
+    };
+    let frame = match completion {
+        Ok(completion) => ServerFrame::Finished { completion },
+        Err(err) => ServerFrame::Error {
+            error: WireStreamError::from(&err),
+        },
+    };
+    // The relay is gone if the client disconnected
+    let _ = done.send(frame).await;
+}
+
+type Socket<S> = WebSocketStream<S>;
+
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut Socket<S>,
+    frame: ServerFrame,
+) -> RelayResult<()> {
+    let text =
+        serde_json::to_string(&WireEvent::new(frame)).expect("server frames always serialize");
+    Ok(socket.send(WsMessage::Text(text)).await?)
+}
+
+/// Closes the socket with `reason`, returning the violation
+async fn violation<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut Socket<S>,
+    code: CloseCode,
+    reason: String,
+) -> RelayError {
+    warn!("Closing relay for protocol violation: {}", reason);
+    let mut end = reason.len().min(MAX_CLOSE_REASON_BYTES);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    let frame = CloseFrame {
+        code,
+        reason: reason[..end].to_owned().into(),
+    };
+    match socket.close(Some(frame)).await {
+        Ok(()) => RelayError::Protocol(reason),
+        Err(err) => err.into(),
+    }
+}
+
+async fn state(agent: &Mutex<Agent>, streaming: bool) -> ServerFrame {
+    let params = agent.lock().await.completion_model.params.clone();
+    ServerFrame::StateChanged { streaming, params }
+}
+
+/// Serves `agent` to the client connecting on `socket`, until it disconnects. `socket` is a
+/// freshly accepted connection, its upgrade request is read & accepted here. Streamed tokens
+/// are forwarded as they arrive, a slow client gets them coalesced into fewer frames rather
+/// than buffered frame by frame. Disconnecting cancels the completion streaming, and frames
+/// that break the protocol close the socket with the reason
+pub async fn ws_relay<S>(agent: Arc<Mutex<Agent>>, socket: S) -> RelayResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut socket = accept_async(socket)
+        .await
+        .map_err(|err| RelayError::Handshake(err.to_string()))?;
+    let canceller = Arc::new(RelayCanceller::default());
+    let result = relay(&agent, &mut socket, &canceller).await;
+    if canceller.cancel() {
+        warn!("Relay client disconnected, cancelling its completion");
+    }
+    result
+}
+
+async fn relay<S: AsyncRead + AsyncWrite + Unpin>(
+    agent: &Arc<Mutex<Agent>>,
+    socket: &mut Socket<S>,
+    canceller: &Arc<RelayCanceller>,
+) -> RelayResult<()> {
+    let pending = Arc::new(PendingTokens::default());
+    let (done_sender, mut done) = mpsc::channel(1);
+    let mut streaming = false;
+    send(socket, state(agent, false).await).await?;
+    loop {
+        tokio::select! {
+            message = socket.next() => {
+                let message = match message {
+                    // The client disconnected, with or without closing the socket first
+                    None
+                    | Some(Err(tungstenite::Error::Protocol(
+                        ProtocolError::ResetWithoutClosingHandshake,
+                    ))) => return Ok(()),
+                    Some(message) => message?,
+                };
+                let text = match message {
+                    WsMessage::Text(text) => text,
+                    WsMessage::Binary(_) => {
+                        let reason = "only text frames are accepted".to_owned();
+                        return Err(violation(socket, CloseCode::Unsupported, reason).await);
+                    }
+                    // The close is answered by the protocol, once the reply is flushed
+                    WsMessage::Close(_) => {
+                        return match socket.flush().await {
+                            Err(tungstenite::Error::ConnectionClosed) => Ok(()),
+                            flushed => Ok(flushed?),
+                        }
+                    }
+                    _ => continue,
+                };
+                let frame = match serde_json::from_str::<WireEvent<ClientFrame>>(&text) {
+                    Ok(event) if event.is_supported() => event.event,
+                    Ok(event) => {
+                        let reason = format!("unsupported wire version {}", event.version);
+                        return Err(violation(socket, CloseCode::Protocol, reason).await);
+                    }
+                    Err(err) => {
+                        let reason = format!("invalid frame: {}", err);
+                        return Err(violation(socket, CloseCode::Protocol, reason).await);
+                    }
+                };
+                match frame {
+                    ClientFrame::Prompt { .. } if streaming => {
+                        let message = "A completion is already streaming".to_owned();
+                        send(socket, error_frame("busy", message)).await?;
+                    }
+                    ClientFrame::Prompt { content } => {
+                        streaming = true;
+                        canceller.reset();
+                        send(socket, state(agent, true).await).await?;
+                        tokio::spawn(complete(
+                            agent.clone(),
+                            content,
+                            canceller.clone(),
+                            pending.clone(),
+                            done_sender.clone(),
+                        ));
+                    }
+                    ClientFrame::Cancel if streaming => {
+                        canceller.cancel();
+                    }
+                    ClientFrame::Cancel => {}
+                    ClientFrame::UpdateParams { params } => {
+                        {
+                            let mut agent = agent.lock().await;
+                            let model = &mut agent.completion_model;
+                            let mut updated = params
+                                .merged_onto(&model.params)
+                                .fitted_to(&model.capabilities());
+                            updated.total_token_count = model.params.total_token_count;
+                            model.params = updated;
+                        }
+                        send(socket, state(agent, streaming).await).await?;
+                    }
+                }
+            }
+            () = pending.notify.notified() => {
+                let token = pending.take();
+                if !token.is_empty() {
+                    send(socket, ServerFrame::Token { token }).await?;
+                }
+            }
+            Some(frame) = done.recv() => {
+                // Tokens are all pending before the completion ends
+                let token = pending.take();
+                if !token.is_empty() {
+                    send(socket, ServerFrame::Token { token }).await?;
+                }
+                send(socket, frame).await?;
+                streaming = false;
+                send(socket, state(agent, false).await).await?;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::{
+        openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+        testing::serve_slowly,
+        CompletionModel,
+    };
+    use std::{net::TcpStream, time::Duration};
+    use tokio::{net::TcpListener, task::JoinHandle};
+    use tungstenite::WebSocket;
+
+    const TOKENS: [&str; 5] = ["Build", " passed", " with", " two", " warnings"];
+
+    async fn served_agent(cadence: Duration) -> Arc<Mutex<Agent>> {
+        let url = serve_slowly(&TOKENS, cadence).await;
+        let deployment =
+            AzureOpenAiDeployment::new(&url, "d", "2024-06-01", OpenAiCompletionModel::Gpt4);
+        let model = CompletionModel::new(deployment, ModelParameters::default(), "");
+        Arc::new(Mutex::new(Agent::new(Some("You check builds"), model)))
+    }
+
+    /// Relays `agent` to the first connection, returns the address to connect to
+    async fn relayed(agent: &Arc<Mutex<Agent>>) -> (String, JoinHandle<RelayResult<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let agent = agent.clone();
+        let relay = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            ws_relay(agent, socket).await
+        });
+        (addr, relay)
+    }
+
+    fn connect(addr: &str) -> WebSocket<TcpStream> {
+        let stream = TcpStream::connect(addr).unwrap();
+        tungstenite::client(format!("ws://{}/", addr), stream)
+            .unwrap()
+            .0
+    }
+
+    fn send(socket: &mut WebSocket<TcpStream>, frame: ClientFrame) {
+        let text = serde_json::to_string(&WireEvent::new(frame)).unwrap();
+        socket.send(WsMessage::Text(text)).unwrap();
+    }
+
+    fn receive(socket: &mut WebSocket<TcpStream>) -> ServerFrame {
+        loop {
+            if let WsMessage::Text(text) = socket.read().unwrap() {
+                let event: WireEvent<ServerFrame> = serde_json::from_str(&text).unwrap();
+                return event.event;
+            }
+        }
+    }
+
+    /// Receives until the completion ends, returning the tokens & the frame ending it
+    fn receive_completion(socket: &mut WebSocket<TcpStream>) -> (String, Vec<ServerFrame>) {
+        let (mut tokens, mut others) = (String::new(), vec![]);
+        loop {
+            match receive(socket) {
+                ServerFrame::Token { token } => tokens.push_str(&token),
+                ServerFrame::StateChanged { streaming, .. } => {
+                    assert!(!streaming);
+                    return (tokens, others);
+                }
+                frame => others.push(frame),
+            }
+        }
+    }
+
+    fn prompt(content: &str) -> ClientFrame {
+        ClientFrame::Prompt {
+            content: content.to_owned(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clients_prompt_cancel_and_update_params() {
+        let agent = served_agent(Duration::from_millis(20)).await;
+        let (addr, relay) = relayed(&agent).await;
+        let client = tokio::task::spawn_blocking(move || {
+            let mut socket = connect(&addr);
+            assert_eq!(
+                receive(&mut socket),
+                ServerFrame::StateChanged {
+                    streaming: false,
+                    params: ModelParameters::default()
+                }
+            );
+
+            send(&mut socket, prompt("Did the build pass?"));
+            assert!(matches!(
+                receive(&mut socket),
+                ServerFrame::StateChanged {
+                    streaming: true,
+                    ..
+                }
+            ));
+            let (tokens, frames) = receive_completion(&mut socket);
+            assert_eq!(tokens, TOKENS.concat());
+            let completion = CollectedCompletion::Finished(TOKENS.concat());
+            assert_eq!(frames, [ServerFrame::Finished { completion }]);
+
+            send(&mut socket, prompt("Did it pass again?"));
+            receive(&mut socket);
+            send(&mut socket, prompt("Well?"));
+            send(&mut socket, ClientFrame::Cancel);
+            let (tokens, frames) = receive_completion(&mut socket);
+            assert!(tokens.len() < TOKENS.concat().len());
+            let [ServerFrame::Error { error }, ServerFrame::Finished { completion }] = &frames[..]
+            else {
+                panic!("unexpected frames {:?}", frames);
+            };
+            assert_eq!(error.kind, "busy");
+            assert_eq!(
+                completion,
+                &CollectedCompletion::Partial {
+                    content: tokens,
+                    reason: CancelReason::User
+                }
+            );
+
+            let update =
+                r#"{"version":1,"event":{"type":"update_params","params":{"temperature":20}}}"#;
+            socket.send(WsMessage::Text(update.to_owned())).unwrap();
+            let ServerFrame::StateChanged { streaming, params } = receive(&mut socket) else {
+                panic!("expected the new parameters");
+            };
+            assert!(!streaming);
+            socket.close(None).unwrap();
+            while socket.read().is_ok() {}
+            params
+        });
+        let params = client.await.unwrap();
+        relay.await.unwrap().unwrap();
+        assert_eq!(params.temperature, Some(20));
+        assert_eq!(params.n, Some(1));
+        assert_eq!(params, agent.lock().await.completion_model.params);
+        assert_eq!(params.total_token_count, 10);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disconnects_cancel_and_violations_close() {
+        let agent = served_agent(Duration::from_millis(50)).await;
+        let (addr, relay) = relayed(&agent).await;
+        tokio::task::spawn_blocking(move || {
+            let mut socket = connect(&addr);
+            send(&mut socket, prompt("Did the build pass?"));
+            while !matches!(receive(&mut socket), ServerFrame::Token { .. }) {}
+        })
+        .await
+        .unwrap();
+        relay.await.unwrap().unwrap();
+        let cached = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let Some(last) = agent.lock().await.cache.as_ref().last() {
+                    if last.content.starts_with(TOKENS[0]) {
+                        return last.content.clone();
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_ne!(cached, TOKENS.concat());
+
+        let (addr, relay) = relayed(&agent).await;
+        let close = tokio::task::spawn_blocking(move || {
+            let mut socket = connect(&addr);
+            receive(&mut socket);
+            socket.send(WsMessage::Text("not json".to_owned())).unwrap();
+            loop {
+                if let WsMessage::Close(frame) = socket.read().unwrap() {
+                    return frame.unwrap();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(close.code, CloseCode::Protocol);
+        assert!(close.reason.starts_with("invalid frame"));
+        assert!(matches!(relay.await.unwrap(), Err(RelayError::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn invalid_upgrades_refused() {
+        use std::io::Write;
+
+        let agent = served_agent(Duration::from_millis(20)).await;
+        for request in [
+            // No Sec-WebSocket-Version
+            "GET / HTTP/1.1\r\nHost: relay\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            // No Connection: Upgrade
+            "GET / HTTP/1.1\r\nHost: relay\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        ] {
+            let (addr, relay) = relayed(&agent).await;
+            tokio::task::spawn_blocking(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream.write_all(request.as_bytes()).unwrap();
+            })
+            .await
+            .unwrap();
+            assert!(matches!(
+                relay.await.unwrap(),
+                Err(RelayError::Handshake(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! `proptest` strategies for the crate's types, for property testing code built on them. Content
+//! is mostly short, sometimes long & occasionally huge, with unicode and blank content mixed in,
+//! since those are the cases example based tests tend to miss
+use crate::{
+    agents::memory::{Attachment, Message, MessageRole, MessageStack, OtherRoleTo},
+    language_models::completions::ModelParameters,
+};
+use proptest::{collection, option, prelude::*};
+
+/// Content of a message, weighted toward a sentence or two. The longest are 20k characters,
+/// around a long pane capture
+pub fn message_content() -> impl Strategy<Value = String> {
+    prop_oneof![
+        1 => Just(String::new()),
+        6 => "[ -~]{1,120}",
+        2 => "[\\PC\n]{1,400}",
+        1 => collection::vec("[ -~]{0,80}\n", 10..120).prop_map(|lines| lines.concat()),
+        1 => "[\\PC\n\t]{1000,20000}",
+    ]
+}
+
+fn other_role_to() -> impl Strategy<Value = OtherRoleTo> {
+    prop_oneof![
+        Just(OtherRoleTo::Assistant),
+        Just(OtherRoleTo::User),
+        Just(OtherRoleTo::System),
+    ]
+}
+
+/// Any role, including aliased roles coerced to each of the others
+pub fn message_role() -> impl Strategy<Value = MessageRole> {
+    prop_oneof![
+        3 => Just(MessageRole::User),
+        3 => Just(MessageRole::Assistant),
+        1 => Just(MessageRole::System),
+        1 => ("[a-z_]{1,12}", other_role_to())
+            .prop_map(|(alias, coerce_to)| MessageRole::Other { alias, coerce_to }),
+    ]
+}
+
+/// A role that isn't coerced to system
+pub fn conversation_role() -> impl Strategy<Value = MessageRole> {
+    message_role().prop_filter("not a system role", |role| {
+        role.actual() != &MessageRole::System
+    })
+}
+
+pub fn attachment() -> impl Strategy<Value = Attachment> {
+    (
+        option::of("[a-z_/]{1,24}\\.(rs|toml|sh|md)"),
+        option::of(message_content()),
+        option::weighted(0.2, "[a-z]{1,8}"),
+    )
+        .prop_map(|(path, snippet, language)| Attachment {
+            path,
+            snippet,
+            language,
+        })
+}
+
+/// A message with `role`, sometimes with metadata & attachments
+pub fn message_with_role(
+    role: impl Strategy<Value = MessageRole>,
+) -> impl Strategy<Value = Message> {
+    (
+        role,
+        message_content(),
+        0.0f32..=1.0,
+        option::weighted(
+            0.3,
+            collection::hash_map("[a-z_]{1,12}", "[ -~]{0,32}", 1..4),
+        ),
+        option::weighted(0.1, collection::vec(attachment(), 1..3)),
+    )
+        .prop_map(
+            |(role, content, importance, metadata, attachments)| Message {
+                role,
+                content,
+                importance,
+                metadata: metadata.unwrap_or_default(),
+                attachments: attachments.unwrap_or_default(),
+            },
+        )
+}
+
+pub fn message() -> impl Strategy<Value = Message> {
+    message_with_role(message_role())
+}
+
+/// A stack as agents keep them, an optional system prompt first followed by non empty
+/// messages of other roles, up to `max_len` of them
+pub fn message_stack(max_len: usize) -> impl Strategy<Value = MessageStack> {
+    (
+        option::weighted(0.8, "[\\PC]{1,400}"),
+        collection::vec(
+            message_with_role(conversation_role())
+                .prop_filter("non empty", |m| !m.content.is_empty()),
+            0..=max_len,
+        ),
+    )
+        .prop_map(|(system, messages)| {
+            let mut stack = system
+                .map(|prompt| MessageStack::new(&prompt))
+                .unwrap_or_else(MessageStack::init);
+            messages.into_iter().for_each(|m| stack.push(m));
+            stack
+        })
+}
+
+pub fn model_parameters() -> impl Strategy<Value = ModelParameters> {
+    (
+        any::<u32>(),
+        option::of(0u8..=200),
+        option::of(-20i8..=20),
+        option::of(1u32..=32_000),
+        option::of(1u32..=8),
+        option::of(-20i8..=20),
+    )
+        .prop_map(
+            |(
+                total_token_count,
+                temperature,
+                frequency_penalty,
+                max_tokens,
+                n,
+                presence_penalty,
+            )| {
+                ModelParameters {
+                    total_token_count,
+                    temperature,
+                    frequency_penalty,
+                    max_tokens,
+                    n,
+                    presence_penalty,
+                }
+            },
+        )
+}
+
+fn capture_line() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => "[ -~]{0,100}",
+        2 => "[a-z]{1,8}@[a-z]{1,8}:~(/[a-z]{1,8}){0,3}\\$ [ -~]{0,40}",
+        1 => ("\x1b\\[[0-9;]{1,5}m", "[ -~]{1,60}").prop_map(|(code, text)| format!("{}{}\x1b[0m", code, text)),
+        1 => "[\\PC]{0,80}[ \t]{0,8}",
+        1 => Just(String::new()),
+    ]
+}
+
+/// Text like a pane capture, prompts, color codes, trailing whitespace & blank lines, with
+/// the blank lines tmux pads the bottom of captures with
+pub fn capture_text() -> impl Strategy<Value = String> {
+    (collection::vec(capture_line(), 0..60), 0usize..8).prop_map(|(lines, padding)| {
+        let mut capture = lines.join("\n");
+        capture.push_str(&"\n".repeat(padding));
+        capture
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same_messages(left: &MessageStack, right: &MessageStack) {
+        assert_eq!(left, right);
+        for (l, r) in left.as_ref().iter().zip(right.as_ref()) {
+            assert_eq!(l.importance, r.importance);
+            assert_eq!(l.metadata, r.metadata);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn stacks_round_trip_through_json(stack in message_stack(24)) {
+            let json = serde_json::to_string(&stack).unwrap();
+            let back: MessageStack = serde_json::from_str(&json).unwrap();
+            assert_same_messages(&stack, &back);
+        }
+
+        #[test]
+        fn messages_round_trip_through_json(message in message()) {
+            let json = serde_json::to_value(&message).unwrap();
+            let back: Message = serde_json::from_value(json).unwrap();
+            prop_assert_eq!(&back, &message);
+            prop_assert_eq!(back.importance, message.importance);
+            prop_assert_eq!(back.metadata, message.metadata);
+        }
+
+        #[test]
+        fn parameters_round_trip_through_json(params in model_parameters()) {
+            let json = serde_json::to_string(&params).unwrap();
+            prop_assert_eq!(serde_json::from_str::<ModelParameters>(&json).unwrap(), params);
+        }
+
+        #[test]
+        fn trim_keeps_system_prompt_and_order(stack in message_stack(24), max_len in 0usize..30) {
+            let mut trimmed = stack.clone();
+            trimmed.trim_to(max_len);
+
+            let system = stack.ref_system_prompt_content();
+            prop_assert_eq!(trimmed.ref_system_prompt_content(), system);
+            prop_assert!(trimmed.len() <= max_len.max(system.is_some() as usize));
+            prop_assert!(trimmed.len() <= stack.len());
+            // What's kept is in its original order
+            let mut original = stack.as_ref().iter();
+            for kept in trimmed.as_ref() {
+                prop_assert!(original.any(|m| m == kept && m.importance == kept.importance));
+            }
+        }
+    }
+
+    #[cfg(feature = "tmux")]
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn identical_captures_are_unchanged(capture in capture_text()) {
+            let differ = crate::tmux::PaneDiffer::new();
+            prop_assert_eq!(differ.delta(&capture, &capture), crate::tmux::PaneDelta::Unchanged);
+        }
+    }
+}
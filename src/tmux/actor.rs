@@ -0,0 +1,474 @@
+//! Sending keys into panes, so an agent can act on what it observes. Every send goes through a
+//! confirmation hook
+use super::{
+    select::Matcher,
+    server::list_panes,
+    tool::{PaneTool, PaneToolFuture},
+    KeysBatch, KeysInput, Pane, PaneMatch, TmuxError, TmuxResult,
+};
+use crate::language_models::completions::streaming::ToolCall;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Name of the tool a tool calling model uses to propose a command, see
+/// `PaneActor::execute_tool_call`
+pub const SEND_COMMAND_TOOL: &str = "send_command";
+/// Name of the tool handled by `SendKeysTool`
+pub const SEND_KEYS_TOOL: &str = "send_keys";
+
+/// Decides whether keys proposed for a pane are sent. This is where destructive commands should
+/// be refused, or a user asked for approval
+pub trait ConfirmSend: Send + Sync {
+    fn confirm(&self, pane: &Pane, keys: &str, enter: bool) -> bool;
+}
+
+impl<F> ConfirmSend for F
+where
+    F: Fn(&Pane, &str, bool) -> bool + Send + Sync,
+{
+    fn confirm(&self, pane: &Pane, keys: &str, enter: bool) -> bool {
+        self(pane, keys, enter)
+    }
+}
+
+/// Whether proposed keys were sent to the pane
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    Sent,
+    /// The confirmation hook refused the keys, nothing was sent
+    Refused,
+}
+
+impl SendOutcome {
+    /// The outcome as returned to the model
+    pub fn render(&self) -> &'static str {
+        match self {
+            Self::Sent => "The keys were sent",
+            Self::Refused => "Sending the keys was refused, nothing was sent",
+        }
+    }
+}
+
+/// Arguments of a `SEND_COMMAND_TOOL` call
+#[derive(Debug, Deserialize)]
+pub struct SendCommandArgs {
+    command: String,
+    #[serde(default = "default_enter")]
+    enter: bool,
+}
+
+fn default_enter() -> bool {
+    true
+}
+
+/// Sends keys to a pane, but only those its confirmation hook accepts. Keys go to the pane the
+/// match picks when they are sent
+pub struct PaneActor {
+    pane: PaneMatch,
+    socket: Option<String>,
+    confirm: Box<dyn ConfirmSend>,
+}
+
+impl std::fmt::Debug for PaneActor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PaneActor")
+            .field("pane", &self.pane)
+            .field("socket", &self.socket)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PaneActor {
+    pub fn new(pane: impl Into<PaneMatch>, confirm: impl ConfirmSend + 'static) -> Self {
+        Self {
+            pane: pane.into(),
+            socket: None,
+            confirm: Box::new(confirm),
+        }
+    }
+
+    /// Pick the pane on the server with the given socket name rather than the default server
+    pub fn on_socket(mut self, socket: &str) -> Self {
+        self.socket = Some(socket.to_owned());
+        self
+    }
+
+    pub fn pane(&self) -> &PaneMatch {
+        &self.pane
+    }
+
+    /// Types `keys` into the pane literally, so key names like `C-c` aren't interpreted, then
+    /// presses enter if `enter` is set. Nothing is sent unless the confirmation hook accepts
+    pub async fn send_keys(&self, keys: &str, enter: bool) -> TmuxResult<SendOutcome> {
+        let pane = self.pane.resolve_first(self.socket.as_deref()).await?;
+        if !self.confirm.confirm(&pane, keys, enter) {
+            tracing::info!("Refused sending {:?} to pane {}", keys, pane.id);
+            return Ok(SendOutcome::Refused);
+        }
+        let mut input = KeysInput::text(keys);
+        input.enter = enter;
+        pane.send_keys(input).await?;
+        Ok(SendOutcome::Sent)
+    }
+
+    /// Executes a command proposed by a tool calling model, the same as `PaneTool::execute`
+    pub async fn execute_tool_call(&self, call: &ToolCall) -> TmuxResult<SendOutcome> {
+        self.execute(call).await
+    }
+}
+
+/// Handles `SEND_COMMAND_TOOL` calls, with a `command` string argument and an optional `enter`
+/// bool that defaults to true
+impl PaneTool for PaneActor {
+    type Args = SendCommandArgs;
+    type Output = SendOutcome;
+
+    fn name(&self) -> &'static str {
+        SEND_COMMAND_TOOL
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "command": {
+                    "type": "string",
+                    "description": "Command to type into the pane"
+                },
+                "enter": {
+                    "type": "boolean",
+                    "description": "Press enter after the command, defaults to true"
+                }
+            },
+            "required": ["command"]
+        })
+    }
+
+    fn run_call(&self, args: SendCommandArgs) -> PaneToolFuture<'_, SendOutcome> {
+        Box::pin(async move { self.send_keys(&args.command, args.enter).await })
+    }
+
+    fn render(&self, output: &SendOutcome) -> String {
+        output.render().to_owned()
+    }
+}
+
+/// Arguments of a `SEND_KEYS_TOOL` call
+#[derive(Debug, Deserialize)]
+pub struct SendKeysArgs {
+    target: String,
+    input: Vec<KeysBatch>,
+    #[serde(default = "default_enter")]
+    enter: bool,
+}
+
+/// Lets a tool calling model type into panes, but only panes in its allowlist, and only input
+/// its confirmation hook accepts
+pub struct SendKeysTool {
+    allowed: Vec<PaneMatch>,
+    socket: Option<String>,
+    confirm: Box<dyn ConfirmSend>,
+    batch_delay: Duration,
+}
+
+impl std::fmt::Debug for SendKeysTool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendKeysTool")
+            .field("allowed", &self.allowed)
+            .field("socket", &self.socket)
+            .field("batch_delay", &self.batch_delay)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SendKeysTool {
+    /// A tool that can't type anywhere until panes are allowed with `with_pane`
+    pub fn new(confirm: impl ConfirmSend + 'static) -> Self {
+        Self {
+            allowed: vec![],
+            socket: None,
+            confirm: Box::new(confirm),
+            batch_delay: Duration::ZERO,
+        }
+    }
+
+    /// Allow typing into the panes `pane` picks, checked against the target of each call
+    pub fn with_pane(mut self, pane: impl Into<PaneMatch>) -> Self {
+        self.allowed.push(pane.into());
+        self
+    }
+
+    /// Match allowed panes on the server with the given socket name rather than the default
+    /// server. Resolved panes are on their own server
+    pub fn on_socket(mut self, socket: &str) -> Self {
+        self.socket = Some(socket.to_owned());
+        self
+    }
+
+    /// Pause between keystroke batches, see `KeysInput::batch_delay`
+    pub fn with_batch_delay(mut self, delay: Duration) -> Self {
+        self.batch_delay = delay;
+        self
+    }
+
+    /// The pane `target` names, if an allowed match picks it. Panes are only listed when a
+    /// match other than a resolved pane is allowed
+    async fn allowed_pane(&self, target: &str) -> TmuxResult<Pane> {
+        let not_allowed = || TmuxError::TargetNotAllowed {
+            target: target.to_owned(),
+        };
+        let resolved = self.allowed.iter().find_map(|allowed| match allowed {
+            PaneMatch::Pane(pane) if pane.id == target || pane.target() == target => Some(pane),
+            _ => None,
+        });
+        if let Some(pane) = resolved {
+            return Ok(pane.clone());
+        }
+        let matchers = self
+            .allowed
+            .iter()
+            .filter(|allowed| !matches!(allowed, PaneMatch::Pane(_)))
+            .map(PaneMatch::matcher)
+            .collect::<TmuxResult<Vec<Matcher>>>()?;
+        if matchers.is_empty() {
+            return Err(not_allowed());
+        }
+        list_panes(self.socket.as_deref())
+            .await?
+            .into_iter()
+            .find(|listed| {
+                (listed.pane.id == target || listed.pane.target() == target)
+                    && matchers.iter().any(|matcher| matcher.matches(listed))
+            })
+            .map(|listed| listed.pane)
+            .ok_or_else(not_allowed)
+    }
+}
+
+/// Handles `SEND_KEYS_TOOL` calls. The target, a pane id or `session:window.pane` target, must
+/// be a pane an allowed match picks. A resolved pane is matched by its id or by its target when
+/// it was allowed
+impl PaneTool for SendKeysTool {
+    type Args = SendKeysArgs;
+    type Output = SendOutcome;
+
+    fn name(&self) -> &'static str {
+        SEND_KEYS_TOOL
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "target": {
+                    "type": "string",
+                    "description": "Pane to type into, as session:window.pane or a pane id like %3"
+                },
+                "input": {
+                    "type": "array",
+                    "description": "Batches sent in order, either literal text or a key name like Enter, Escape or C-c",
+                    "items": {
+                        "oneOf": [
+                            {"type": "object", "properties": {"text": {"type": "string"}}, "required": ["text"]},
+                            {"type": "object", "properties": {"key": {"type": "string"}}, "required": ["key"]}
+                        ]
+                    }
+                },
+                "enter": {
+                    "type": "boolean",
+                    "description": "Press enter after the input, defaults to true"
+                }
+            },
+            "required": ["target", "input"]
+        })
+    }
+
+    fn run_call(&self, args: SendKeysArgs) -> PaneToolFuture<'_, SendOutcome> {
+        Box::pin(async move {
+            let pane = self.allowed_pane(&args.target).await?;
+            let input = KeysInput {
+                batches: args.input,
+                enter: args.enter,
+                batch_delay: self.batch_delay,
+            };
+            let keys = describe(&input.batches);
+            if !self.confirm.confirm(&pane, &keys, input.enter) {
+                tracing::info!("Refused sending {:?} to pane {}", keys, pane.id);
+                return Ok(SendOutcome::Refused);
+            }
+            pane.send_keys(input).await?;
+            Ok(SendOutcome::Sent)
+        })
+    }
+
+    fn render(&self, output: &SendOutcome) -> String {
+        output.render().to_owned()
+    }
+}
+
+/// Batches as one string for the confirmation hook, with key names in angle brackets
+fn describe(batches: &[KeysBatch]) -> String {
+    batches
+        .iter()
+        .map(|batch| match batch {
+            KeysBatch::Text(text) => text.to_owned(),
+            KeysBatch::Key(key) => format!("<{}>", key.tmux_name()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmux::{run, tests::TestServer, CaptureOpts};
+    use std::time::Duration;
+
+    /// Captures the pane until it contains `expected`, gives up after a second
+    async fn wait_for(pane: &Pane, expected: &str) -> String {
+        let mut content = String::new();
+        for _ in 0..50 {
+            content = pane.capture(CaptureOpts::default()).await.unwrap();
+            if content.contains(expected) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        content
+    }
+
+    #[tokio::test]
+    async fn only_confirmed_keys_sent() {
+        let Some(server) = TestServer::start("sh").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let actor = PaneActor::new(pane.clone(), |_: &Pane, keys: &str, _enter: bool| {
+            !keys.contains("rm ")
+        });
+
+        let outcome = actor.send_keys("rm -rf scratch", true).await.unwrap();
+        assert_eq!(outcome, SendOutcome::Refused);
+        let outcome = actor.send_keys("echo sent-$((1 + 1))", true).await.unwrap();
+        assert_eq!(outcome, SendOutcome::Sent);
+        let content = wait_for(&pane, "sent-2").await;
+        assert!(content.contains("sent-2"));
+        assert!(!content.contains("rm -rf"));
+
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            name: SEND_COMMAND_TOOL.to_string(),
+            arguments: r#"{"command": "echo tool-$((2 + 2))"}"#.to_string(),
+        };
+        assert_eq!(
+            actor.execute_tool_call(&call).await.unwrap(),
+            SendOutcome::Sent
+        );
+        assert!(wait_for(&pane, "tool-4").await.contains("tool-4"));
+
+        let wrong_tool = ToolCall {
+            name: "search".to_string(),
+            ..call.clone()
+        };
+        assert!(matches!(
+            actor.execute_tool_call(&wrong_tool).await,
+            Err(TmuxError::InvalidToolCall(_))
+        ));
+        let missing_command = ToolCall {
+            arguments: r#"{"enter": false}"#.to_string(),
+            ..call
+        };
+        assert!(matches!(
+            actor.execute_tool_call(&missing_command).await,
+            Err(TmuxError::InvalidToolCall(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_keys_tool_limited_to_allowlist() {
+        let Some(server) = TestServer::start("sh").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let tool = SendKeysTool::new(|_: &Pane, keys: &str, _enter: bool| !keys.contains("<C-d>"))
+            .with_pane(pane.clone());
+        let call = |arguments: Value| ToolCall {
+            id: "call_1".to_string(),
+            name: SEND_KEYS_TOOL.to_string(),
+            arguments: arguments.to_string(),
+        };
+
+        let sent = tool
+            .execute(&call(json!({
+                "target": "test:0.0",
+                "input": [{"text": "echo typed-$((3 + 3));"}]
+            })))
+            .await
+            .unwrap();
+        assert_eq!(sent, SendOutcome::Sent);
+        assert!(wait_for(&pane, "typed-6").await.contains("typed-6"));
+
+        let refused = tool
+            .execute(&call(json!({"target": pane.id, "input": [{"key": "C-d"}]})))
+            .await
+            .unwrap();
+        assert_eq!(refused, SendOutcome::Refused);
+        let refused_call = call(json!({}));
+        assert_eq!(
+            tool.result_message(&refused_call, &refused).content,
+            "Sending the keys was refused, nothing was sent"
+        );
+        assert!(matches!(
+            tool.execute(&call(json!({"target": "test:1.0", "input": []})))
+                .await,
+            Err(TmuxError::TargetNotAllowed { .. })
+        ));
+        assert!(matches!(
+            tool.execute(&call(
+                json!({"target": "test:0.0", "input": [{"key": "Hyper"}]})
+            ))
+            .await,
+            Err(TmuxError::InvalidToolCall(_))
+        ));
+
+        // Panes a match picks are allowed by their target or id
+        let socket = Some(server.socket.as_str());
+        let window = ["new-window", "-d", "-t", "test", "-n", "logs-1", "sh"];
+        assert!(run(socket, &window).await.is_ok());
+        let logs = Pane::resolve_on(socket, "test:logs-1.0").await.unwrap();
+        let by_window = SendKeysTool::new(|_: &Pane, _: &str, _: bool| true)
+            .with_pane(PaneMatch::WindowGlob("logs-*".to_string()))
+            .on_socket(&server.socket);
+        let sent = by_window
+            .execute(&call(json!({
+                "target": logs.id,
+                "input": [{"text": "echo logs-$((4 + 4))"}]
+            })))
+            .await
+            .unwrap();
+        assert_eq!(sent, SendOutcome::Sent);
+        assert!(wait_for(&logs, "logs-8").await.contains("logs-8"));
+        assert!(matches!(
+            by_window
+                .execute(&call(json!({"target": "test:0.0", "input": []})))
+                .await,
+            Err(TmuxError::TargetNotAllowed { .. })
+        ));
+
+        let actor = PaneActor::new(
+            PaneMatch::WindowGlob("logs-*".to_string()),
+            |_: &Pane, _: &str, _: bool| true,
+        )
+        .on_socket(&server.socket);
+        let outcome = actor
+            .send_keys("echo actor-$((5 + 5))", true)
+            .await
+            .unwrap();
+        assert_eq!(outcome, SendOutcome::Sent);
+        assert!(wait_for(&logs, "actor-10").await.contains("actor-10"));
+    }
+}
@@ -0,0 +1,343 @@
+//! Normalizing terminal output, with its escape sequences & overwritten lines, into plain text
+use std::mem;
+
+pub(super) const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// What `normalize` strips or resolves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NormalizeOpts {
+    /// Strip SGR sequences, which set colors & text attributes
+    pub strip_colors: bool,
+    /// Apply carriage returns & backspaces to their line like a terminal would, so progress bars
+    /// and spinners leave only their final state. Otherwise they are kept as written
+    pub resolve_overwrites: bool,
+    /// Drop cursor movement, screen clearing, alternate screen & other non SGR sequences.
+    /// Movement & erasing within a line is applied to it, and moving to another row starts a
+    /// new line
+    pub drop_cursor_sequences: bool,
+    /// Runs of blank lines longer than this are collapsed to this many
+    pub max_blank_lines: Option<usize>,
+}
+
+impl Default for NormalizeOpts {
+    fn default() -> Self {
+        Self {
+            strip_colors: true,
+            resolve_overwrites: true,
+            drop_cursor_sequences: true,
+            max_blank_lines: Some(1),
+        }
+    }
+}
+
+/// Length of the escape sequence at the start of `bytes`, `None` if it is incomplete
+pub(super) fn escape_len(bytes: &[u8]) -> Option<usize> {
+    match bytes.get(1)? {
+        // CSI, parameters & intermediates followed by a final byte
+        b'[' => bytes[2..]
+            .iter()
+            .position(|b| (0x40..=0x7e).contains(b))
+            .map(|i| i + 3),
+        // Strings, like OSC & tmux's window title, ended by BEL or ST
+        b']' | b'P' | b'_' | b'^' | b'k' => {
+            let mut i = 2;
+            while i < bytes.len() {
+                match bytes[i] {
+                    BEL => return Some(i + 1),
+                    ESC if bytes.get(i + 1) == Some(&b'\\') => return Some(i + 2),
+                    ESC if i + 1 == bytes.len() => return None,
+                    _ => i += 1,
+                }
+            }
+            None
+        }
+        // Character set designation
+        b'(' | b')' | b'*' | b'+' => bytes.get(2).map(|_| 3),
+        _ => Some(2),
+    }
+}
+
+/// What an escape sequence does, as far as `normalize` cares
+#[derive(Debug, PartialEq, Eq)]
+enum Escape {
+    Sgr,
+    Forward(usize),
+    Back(usize),
+    Column(usize),
+    /// Erase in line, 0 from the cursor to the end, 1 from the start to the cursor, 2 all of it
+    EraseLine(usize),
+    /// Moves the cursor to another row, and to a column if given
+    Row(Option<usize>),
+    Other,
+}
+
+impl Escape {
+    fn classify(sequence: &str) -> Self {
+        let Some((params, end)) = sequence
+            .strip_prefix("\x1b[")
+            .and_then(|csi| Some((&csi[..csi.len().checked_sub(1)?], csi.chars().last()?)))
+        else {
+            return Self::Other;
+        };
+        if end == 'm'
+            && params
+                .bytes()
+                .all(|b| b.is_ascii_digit() || b == b';' || b == b':')
+        {
+            return Self::Sgr;
+        }
+        // Private modes, like the alternate screen, and sequences with intermediates
+        if !params.bytes().all(|b| b.is_ascii_digit() || b == b';') {
+            return Self::Other;
+        }
+        let param = params.split(';').next().and_then(|p| p.parse().ok());
+        let count = param.filter(|n| *n > 0).unwrap_or(1);
+        match end {
+            'C' => Self::Forward(count),
+            'D' => Self::Back(count),
+            'G' => Self::Column(count),
+            'K' => Self::EraseLine(param.unwrap_or(0)),
+            'H' | 'f' => Self::Row(Some(
+                params
+                    .split(';')
+                    .nth(1)
+                    .and_then(|p| p.parse().ok())
+                    .filter(|n| *n > 0)
+                    .unwrap_or(1),
+            )),
+            'E' | 'F' => Self::Row(Some(1)),
+            'A' | 'B' | 'd' => Self::Row(None),
+            _ => Self::Other,
+        }
+    }
+}
+
+/// A character on a line, after the sequences kept before it
+#[derive(Debug, Clone)]
+struct Cell {
+    codes: String,
+    ch: char,
+}
+
+/// The line being written, with the cursor's column on it
+#[derive(Debug, Default)]
+struct Line {
+    cells: Vec<Cell>,
+    col: usize,
+    /// Kept sequences not yet followed by a character
+    codes: String,
+}
+
+impl Line {
+    fn write(&mut self, ch: char) {
+        let cell = Cell {
+            codes: mem::take(&mut self.codes),
+            ch,
+        };
+        if self.col < self.cells.len() {
+            self.cells[self.col] = cell;
+        } else {
+            let blank = Cell {
+                codes: String::new(),
+                ch: ' ',
+            };
+            self.cells.resize(self.col, blank);
+            self.cells.push(cell);
+        }
+        self.col += 1;
+    }
+
+    fn erase(&mut self, mode: usize) {
+        match mode {
+            0 => self.cells.truncate(self.col),
+            1 => {
+                let end = (self.col + 1).min(self.cells.len());
+                self.cells[..end].iter_mut().for_each(|cell| cell.ch = ' ');
+            }
+            _ => self.cells.clear(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cells.is_empty() && self.codes.is_empty()
+    }
+
+    /// The line's text without trailing spaces, which erasing & moving the cursor leave
+    fn render(self) -> String {
+        let mut text: String = self
+            .cells
+            .into_iter()
+            .flat_map(|cell| cell.codes.chars().chain([cell.ch]).collect::<Vec<_>>())
+            .collect();
+        text.truncate(text.trim_end_matches(' ').len());
+        text.push_str(&self.codes);
+        text
+    }
+}
+
+/// Turns captured or piped terminal output into text for an agent, see `NormalizeOpts`. Control
+/// characters other than newlines & tabs are always removed, and line endings become `\n`
+pub fn normalize(text: &str, opts: NormalizeOpts) -> String {
+    let bytes = text.as_bytes();
+    let mut lines = vec![];
+    let mut line = Line::default();
+    let mut i = 0;
+    while let Some(ch) = text[i..].chars().next() {
+        match ch {
+            '\x1b' => {
+                // Incomplete sequences are dropped with the rest of the text
+                let Some(len) = escape_len(&bytes[i..]) else {
+                    break;
+                };
+                let mut end = i + len;
+                while !text.is_char_boundary(end) {
+                    end += 1;
+                }
+                let sequence = &text[i..end];
+                match Escape::classify(sequence) {
+                    Escape::Sgr if opts.strip_colors => {}
+                    Escape::Sgr => line.codes.push_str(sequence),
+                    _ if !opts.drop_cursor_sequences => line.codes.push_str(sequence),
+                    Escape::Forward(n) => line.col += n,
+                    Escape::Back(n) => line.col = line.col.saturating_sub(n),
+                    Escape::Column(n) => line.col = n - 1,
+                    Escape::EraseLine(mode) => line.erase(mode),
+                    Escape::Row(col) => {
+                        let col = col.map_or(line.col, |col| col - 1);
+                        if !line.is_empty() {
+                            lines.push(mem::take(&mut line).render());
+                        }
+                        line.col = col;
+                    }
+                    Escape::Other => {}
+                }
+                i = end;
+                continue;
+            }
+            '\n' => lines.push(mem::take(&mut line).render()),
+            '\r' if bytes.get(i + 1) == Some(&b'\n') => {}
+            '\r' if opts.resolve_overwrites => line.col = 0,
+            '\x08' if opts.resolve_overwrites => line.col = line.col.saturating_sub(1),
+            '\r' | '\x08' | '\t' => line.write(ch),
+            ch if ch.is_control() => {}
+            ch => line.write(ch),
+        }
+        i += ch.len_utf8();
+    }
+    lines.push(line.render());
+
+    let mut blank_run = 0;
+    lines.retain(|line| {
+        if !line.trim().is_empty() {
+            blank_run = 0;
+            return true;
+        }
+        blank_run += 1;
+        opts.max_blank_lines.is_none_or(|max| blank_run <= max)
+    });
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `cargo build` in a terminal, with its colored status & the progress bar it redraws
+    const CARGO_BUILD: &str = "\x1b[1m\x1b[32m   Compiling\x1b[0m libc v0.2.155\n\
+        \x1b[1m\x1b[32m   Compiling\x1b[0m serde v1.0.203\n\
+        \x1b[1m\x1b[36m    Building\x1b[0m [=====>                  ] 12/51: serde, libc\r\
+        \x1b[K\x1b[1m\x1b[36m    Building\x1b[0m [=================>      ] 38/51: tokio\r\
+        \x1b[K\x1b[1m\x1b[32m   Compiling\x1b[0m app v0.1.0 (/home/dev/app)\n\
+        \x1b[0m\x1b[1m\x1b[33mwarning\x1b[0m\x1b[0m\x1b[1m: unused variable: `x`\x1b[0m\n\
+        \x1b[0m \x1b[0m\x1b[0m\x1b[1m\x1b[38;5;12m--> \x1b[0m\x1b[0msrc/main.rs:2:9\x1b[0m\n\
+        \n\n\n\
+        \x1b[1m\x1b[32m    Finished\x1b[0m `dev` profile [unoptimized + debuginfo] target(s) in 4.21s\n";
+
+    /// `npm install`, hiding the cursor while its spinner & progress line redraw in place
+    const NPM_INSTALL: &str = "\x1b[?25l\x1b[1G\x1b[0K⠙ idealTree:app: \x1b[32;40mtiming\x1b[0m \
+        idealTree Completed in 35ms\x1b[1G\x1b[0K⠹ reify:lodash: \x1b[32;40mhttp\x1b[0m fetch GET \
+        200 https://registry.npmjs.org/lodash 120ms\x1b[1G\x1b[0K⠸\x1b[1G\x1b[0K\x1b[?25h\r\n\
+        added 120 packages, and audited 121 packages in 3s\r\n\r\n\r\n\
+        14 packages are looking for funding\r\n  run `npm fund` for details\r\n\r\n\
+        found \x1b[32m\x1b[1m0\x1b[22m\x1b[39m vulnerabilities\r\n";
+
+    /// The start of htop drawing its first screen, onto the alternate screen
+    const HTOP: &str = "\x1b]0;htop\x07\x1b[?1049h\x1b[22;0;0t\x1b[1;24r\x1b(B\x1b[m\x1b[4l\
+        \x1b[?7h\x1b[?1h\x1b=\x1b[?25l\x1b[39;49m\x1b[H\x1b[2J\x1b[1B  \x1b[36m1\x1b[39m\
+        \x1b[1m[\x1b[32m||||\x1b[31m|\x1b[30m\x1b[22m\x1b[16X\x1b[1m\x1b[37m12.5%\x1b[39m]\
+        \x1b[3;3H\x1b[36mMem\x1b[1m\x1b[39m[\x1b[32m|||||\x1b[34m||\x1b[30m\x1b[14C\
+        \x1b[39m1.21G/7.63G]\x1b[5;1H\x1b[30m\x1b[42m    PID USER      PRI  NI  VIRT   RES \
+        S CPU%\x1b[K\x1b[6;1H\x1b[39;49m\x1b[m   1204 dev        20   0 25.1G  412M S  3.9\r\
+        \x1b[24;1H\x1b[30m\x1b[46mF1\x1b[39;49mHelp  \x1b[30m\x1b[46mF10\x1b[39;49mQuit\x1b[K";
+
+    #[test]
+    fn progress_bars_resolved_to_final_state() {
+        assert_eq!(
+            normalize(CARGO_BUILD, NormalizeOpts::default()),
+            "   Compiling libc v0.2.155\n\
+             \x20  Compiling serde v1.0.203\n\
+             \x20  Compiling app v0.1.0 (/home/dev/app)\n\
+             warning: unused variable: `x`\n\
+             \x20--> src/main.rs:2:9\n\
+             \n\
+             \x20   Finished `dev` profile [unoptimized + debuginfo] target(s) in 4.21s\n"
+        );
+        assert_eq!(
+            normalize(NPM_INSTALL, NormalizeOpts::default()),
+            "\n\
+             added 120 packages, and audited 121 packages in 3s\n\
+             \n\
+             14 packages are looking for funding\n\
+             \x20 run `npm fund` for details\n\
+             \n\
+             found 0 vulnerabilities\n"
+        );
+        // A bar overwritten by a shorter one keeps the end of the longer
+        assert_eq!(
+            normalize("50% done\r100%", NormalizeOpts::default()),
+            "100%done"
+        );
+        assert_eq!(normalize("ab\x08c", NormalizeOpts::default()), "ac");
+    }
+
+    #[test]
+    fn full_screen_programs_flattened_to_rows() {
+        assert_eq!(
+            normalize(HTOP, NormalizeOpts::default()),
+            "  1[|||||12.5%]\n\
+             \x20 Mem[|||||||              1.21G/7.63G]\n\
+             \x20   PID USER      PRI  NI  VIRT   RES S CPU%\n\
+             \x20  1204 dev        20   0 25.1G  412M S  3.9\n\
+             F1Help  F10Quit"
+        );
+    }
+
+    #[test]
+    fn stripping_configurable() {
+        let colored = "\x1b[31merror\x1b[0m:\x1b[Cfailed\n\n\n\nagain";
+        let keep_colors = NormalizeOpts {
+            strip_colors: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalize(colored, keep_colors),
+            "\x1b[31merror\x1b[0m: failed\n\nagain"
+        );
+        let keep_all = NormalizeOpts {
+            strip_colors: false,
+            resolve_overwrites: false,
+            drop_cursor_sequences: false,
+            max_blank_lines: None,
+        };
+        assert_eq!(normalize(colored, keep_all), colored);
+        assert_eq!(normalize("a\rb\r\nc", keep_all), "a\rb\nc");
+        // Control characters & incomplete sequences are removed regardless
+        assert_eq!(normalize("bell\x07 é\x1b[3", keep_all), "bell é");
+        assert_eq!(
+            Escape::classify("\x1b[?1049h"),
+            Escape::Other,
+            "the alternate screen isn't movement"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Streaming completion text live into a pane split off another
+use super::{run, Failure, Pane, TmuxError, TmuxResult, PANE_FORMAT, TMUX_BIN};
+use crate::language_models::completions::streaming::TextSink;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+};
+use tracing::warn;
+
+/// Command run in commentary panes. It keeps the pane open & discards anything typed into it,
+/// text is written straight to the pane's tty instead
+const COMMENTARY_COMMAND: &str = "cat > /dev/null";
+
+/// Options for `Pane::split`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SplitOpts {
+    /// Split into left & right panes rather than top & bottom
+    pub horizontal: bool,
+    /// Size of the new pane as a percentage of the split pane, defaults to half
+    pub percent: Option<u8>,
+}
+
+impl SplitOpts {
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if self.horizontal {
+            args.push("-h".to_string());
+        }
+        if let Some(percent) = self.percent {
+            args.push("-l".to_string());
+            args.push(format!("{}%", percent));
+        }
+        args
+    }
+}
+
+impl Pane {
+    /// Splits a new pane running `command` off this one with `tmux split-window`, without
+    /// moving focus to it
+    pub async fn split(&self, opts: SplitOpts, command: &str) -> TmuxResult<Pane> {
+        let opt_args = opts.args();
+        let mut args = vec![
+            "split-window",
+            "-d",
+            "-P",
+            "-F",
+            PANE_FORMAT,
+            "-t",
+            &self.id,
+        ];
+        args.extend(opt_args.iter().map(|a| a.as_str()));
+        args.push(command);
+        let creation = |message: String| TmuxError::PaneCreation {
+            target: self.target(),
+            message,
+        };
+        let output = run(self.socket.as_deref(), &args)
+            .await
+            .map_err(|failure| match failure {
+                Failure::CantFind(message) | Failure::Other(TmuxError::Command(message)) => {
+                    creation(message)
+                }
+                Failure::Other(err) => err,
+            })?;
+        Self::parse_format(self.socket.as_deref(), &output)
+            .ok_or_else(|| creation(format!("unexpected split-window output: {}", output.trim())))
+    }
+
+    /// Kills the pane with `tmux kill-pane`
+    pub async fn kill(&self) -> TmuxResult<()> {
+        run(self.socket.as_deref(), &["kill-pane", "-t", &self.id])
+            .await
+            .map(|_| ())
+            .map_err(|failure| self.gone(failure))
+    }
+
+    /// Path of the pane's tty
+    async fn tty(&self) -> TmuxResult<String> {
+        let output = run(
+            self.socket.as_deref(),
+            &["display-message", "-p", "-t", &self.id, "#{pane_tty}"],
+        )
+        .await
+        .map_err(|failure| self.gone(failure))?;
+        match output.trim() {
+            "" => Err(TmuxError::PaneGone {
+                id: self.id.to_owned(),
+            }),
+            tty => Ok(tty.to_owned()),
+        }
+    }
+}
+
+/// A pane split off another that streamed text is shown in. The pane is killed when this is
+/// dropped, so keep it for as long as the text should stay on screen
+#[derive(Debug)]
+pub struct CommentaryPane {
+    pane: Pane,
+    tty: File,
+}
+
+impl CommentaryPane {
+    /// Splits a commentary pane off `beside`. Focus stays on `beside`
+    pub async fn open(beside: &Pane, opts: SplitOpts) -> TmuxResult<Self> {
+        let pane = beside.split(opts, COMMENTARY_COMMAND).await?;
+        let tty = match pane.tty().await {
+            Ok(path) => OpenOptions::new()
+                .write(true)
+                .open(path)
+                .map_err(TmuxError::from),
+            Err(err) => Err(err),
+        };
+        match tty {
+            Ok(tty) => Ok(Self { pane, tty }),
+            Err(err) => {
+                let _ = pane.kill().await;
+                Err(err)
+            }
+        }
+    }
+
+    pub fn pane(&self) -> &Pane {
+        &self.pane
+    }
+
+    /// Writes `text` to the pane
+    pub fn write(&mut self, text: &str) -> TmuxResult<()> {
+        self.tty.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    /// A sink writing a streamed completion's tokens to the pane, for
+    /// `ProviderStreamHandler::with_sink`
+    pub fn sink(&self) -> TmuxResult<PaneSink> {
+        Ok(PaneSink {
+            tty: self.tty.try_clone()?,
+        })
+    }
+}
+
+impl Drop for CommentaryPane {
+    fn drop(&mut self) {
+        let mut command = std::process::Command::new(TMUX_BIN);
+        if let Some(socket) = &self.pane.socket {
+            command.args(["-L", socket]);
+        }
+        let _ = command
+            .args(["kill-pane", "-t", &self.pane.id])
+            .stderr(std::process::Stdio::null())
+            .status();
+    }
+}
+
+/// Writes streamed tokens to a `CommentaryPane`, ending the message with a newline once it
+/// finishes. Writes after the pane is closed are dropped
+#[derive(Debug)]
+pub struct PaneSink {
+    tty: File,
+}
+
+impl PaneSink {
+    fn write(&mut self, text: &str) {
+        if let Err(err) = self.tty.write_all(text.as_bytes()) {
+            warn!("Failed to write to commentary pane: {:?}", err);
+        }
+    }
+}
+
+impl TextSink for PaneSink {
+    fn token(&mut self, token: &str) {
+        self.write(token);
+    }
+
+    fn finished(&mut self, _message: &str) {
+        self.write("\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmux::{tests::TestServer, CaptureOpts};
+
+    #[test]
+    fn split_opts_map_to_flags() {
+        let opts = SplitOpts {
+            horizontal: true,
+            percent: Some(30),
+        };
+        assert_eq!(opts.args(), vec!["-h", "-l", "30%"]);
+        assert!(SplitOpts::default().args().is_empty());
+    }
+
+    #[tokio::test]
+    async fn commentary_written_to_split_pane_until_dropped() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let opts = SplitOpts {
+            horizontal: true,
+            percent: Some(40),
+        };
+        let commentary = CommentaryPane::open(&pane, opts.clone()).await.unwrap();
+        let split = commentary.pane().clone();
+        assert_ne!(split.id, pane.id);
+        assert_eq!(split.window, pane.window);
+
+        let mut sink = commentary.sink().unwrap();
+        sink.token("streamed ");
+        sink.token("commentary");
+        sink.finished("streamed commentary");
+        let mut content = String::new();
+        for _ in 0..50 {
+            content = split.capture(CaptureOpts::default()).await.unwrap();
+            if content.contains("streamed commentary") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(content.contains("streamed commentary"));
+
+        drop(commentary);
+        assert!(!split.exists().await.unwrap());
+        assert!(pane.exists().await.unwrap());
+        // Writing after the pane is gone doesn't panic
+        sink.token("late");
+
+        let gone = Pane {
+            id: "%99".to_string(),
+            ..pane
+        };
+        assert!(matches!(
+            CommentaryPane::open(&gone, opts).await,
+            Err(TmuxError::PaneCreation { .. })
+        ));
+    }
+}
@@ -0,0 +1,494 @@
+//! A tmux control mode client, `tmux -C attach`, turning the protocol's notifications into
+//! events so panes & windows can be followed without polling
+use super::{classify_stderr, Failure, TmuxError, TmuxResult, TMUX_BIN};
+use futures::stream::BoxStream;
+use std::{collections::VecDeque, process::Stdio, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, BufReader},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::warn;
+
+/// Bytes read from the client's stdout at a time
+const READ_SIZE: usize = 8192;
+/// Sent before the first line by `tmux -CC`, which is for terminals. It is ignored
+const DCS_PREFIX: &[u8] = b"\x1bP1000p";
+
+pub type ControlStream = BoxStream<'static, ControlEvent>;
+
+/// A notification from a control mode client, or from a `ControlConnection` about its client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlEvent {
+    /// Output written by a pane, unescaped, as `pipe-pane` would give it
+    Output {
+        pane_id: String,
+        data: Vec<u8>,
+    },
+    /// A window was created, in the attached session or any other
+    WindowAdd {
+        window_id: String,
+    },
+    /// A window was closed, in the attached session or any other
+    WindowClose {
+        window_id: String,
+    },
+    WindowRenamed {
+        window_id: String,
+        name: String,
+    },
+    /// The client was attached to another session
+    SessionChanged {
+        session_id: String,
+        name: String,
+    },
+    /// A session was created or destroyed
+    SessionsChanged,
+    /// The output of a command, including the `attach` the client starts with. `ok` is false
+    /// when tmux reported an error
+    Reply {
+        ok: bool,
+        output: Vec<String>,
+    },
+    /// The client is exiting, with tmux's reason if it gave one
+    Exit {
+        reason: Option<String>,
+    },
+    /// A notification that isn't handled, as it was sent
+    Other(String),
+    /// The client died, events are lost until `ControlEvent::Reconnected`
+    Disconnected,
+    /// A new client attached after `ControlEvent::Disconnected`
+    Reconnected,
+}
+
+/// Undoes the escaping of `%output` data, where control characters & backslashes are written
+/// as a backslash then three octal digits
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let octal = data
+            .get(i + 1..i + 4)
+            .filter(|digits| data[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match octal {
+            Some(digits) => {
+                let byte = digits.iter().fold(0u32, |n, d| n * 8 + u32::from(d - b'0'));
+                out.push(byte as u8);
+                i += 4;
+            }
+            None => {
+                out.push(data[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Splits the protocol into lines & parses them. A line split across reads is held until the
+/// rest of it arrives
+#[derive(Debug, Default)]
+struct ControlParser {
+    partial: Vec<u8>,
+    /// The time, number & flags of the command whose reply is being read, and its output so far.
+    /// Its `%end` or `%error` repeats them, so output lines that look like one aren't mistaken
+    reply: Option<(String, Vec<String>)>,
+}
+
+impl ControlParser {
+    fn push(&mut self, bytes: &[u8]) -> Vec<ControlEvent> {
+        self.partial.extend_from_slice(bytes);
+        let mut events = vec![];
+        while let Some(end) = self.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let line = line.strip_prefix(DCS_PREFIX).unwrap_or(&line);
+            events.extend(self.line(&line[..line.len() - 1]));
+        }
+        events
+    }
+
+    fn line(&mut self, line: &[u8]) -> Option<ControlEvent> {
+        let text = String::from_utf8_lossy(line);
+        let (keyword, rest) = text.split_once(' ').unwrap_or((&text, ""));
+        if let Some((guard, output)) = self.reply.as_mut() {
+            let ok = match keyword {
+                "%end" if rest == guard => true,
+                "%error" if rest == guard => false,
+                _ => {
+                    output.push(text.into_owned());
+                    return None;
+                }
+            };
+            let (_, output) = self.reply.take().expect("reply checked above");
+            return Some(ControlEvent::Reply { ok, output });
+        }
+        let (id, name) = rest.split_once(' ').unwrap_or((rest, ""));
+        let event = match keyword {
+            "%begin" => {
+                self.reply = Some((rest.to_owned(), vec![]));
+                return None;
+            }
+            "%output" => {
+                // Output may not be UTF-8, so it is taken from the line's bytes
+                let data = &line[keyword.len()..];
+                let data = data.strip_prefix(b" ").unwrap_or(data);
+                let data = data.get(id.len() + 1..).unwrap_or_default();
+                ControlEvent::Output {
+                    pane_id: id.to_owned(),
+                    data: unescape(data),
+                }
+            }
+            "%window-add" | "%unlinked-window-add" => ControlEvent::WindowAdd {
+                window_id: id.to_owned(),
+            },
+            "%window-close" | "%unlinked-window-close" => ControlEvent::WindowClose {
+                window_id: id.to_owned(),
+            },
+            "%window-renamed" | "%unlinked-window-renamed" => ControlEvent::WindowRenamed {
+                window_id: id.to_owned(),
+                name: name.to_owned(),
+            },
+            "%session-changed" => ControlEvent::SessionChanged {
+                session_id: id.to_owned(),
+                name: name.to_owned(),
+            },
+            "%sessions-changed" => ControlEvent::SessionsChanged,
+            "%exit" => ControlEvent::Exit {
+                reason: Some(rest.to_owned()).filter(|reason| !reason.is_empty()),
+            },
+            _ => ControlEvent::Other(text.into_owned()),
+        };
+        Some(event)
+    }
+}
+
+/// A running `tmux -C attach`
+#[derive(Debug)]
+pub struct ControlClient {
+    child: Child,
+    /// The client exits when its stdin is closed, so it is held open
+    _stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    parser: ControlParser,
+    events: VecDeque<ControlEvent>,
+}
+
+impl ControlClient {
+    /// Attaches a control client to `session`, or the most recently used session when `None`,
+    /// on the server with the given socket name. Returns once tmux has replied to the attach
+    pub async fn connect(socket: Option<&str>, session: Option<&str>) -> TmuxResult<Self> {
+        let mut command = Command::new(TMUX_BIN);
+        if let Some(socket) = socket {
+            command.args(["-L", socket]);
+        }
+        command.args(["-C", "attach"]);
+        if let Some(session) = session {
+            command.args(["-t", session]);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::NotFound => TmuxError::BinaryNotFound,
+                _ => TmuxError::Io(err),
+            })?;
+        let mut client = Self {
+            _stdin: child.stdin.take().expect("stdin is piped"),
+            stdout: BufReader::new(child.stdout.take().expect("stdout is piped")),
+            child,
+            parser: ControlParser::default(),
+            events: VecDeque::new(),
+        };
+        let failure = loop {
+            match client.next_event().await? {
+                Some(ControlEvent::Reply { ok: true, .. }) => return Ok(client),
+                Some(ControlEvent::Reply { output, .. }) => break output.join("\n"),
+                Some(_) => continue,
+                None => {
+                    let mut stderr = String::new();
+                    if let Some(mut pipe) = client.child.stderr.take() {
+                        pipe.read_to_string(&mut stderr).await?;
+                    }
+                    break stderr.trim().to_owned();
+                }
+            }
+        };
+        Err(match classify_stderr(failure) {
+            Failure::Other(TmuxError::Command(message)) if message == "no sessions" => {
+                TmuxError::NoServer
+            }
+            Failure::CantFind(message) => TmuxError::InvalidTarget {
+                target: session.unwrap_or_default().to_owned(),
+                message,
+            },
+            Failure::Other(err) => err,
+        })
+    }
+
+    /// The next event, `None` once the client has exited
+    pub async fn next_event(&mut self) -> TmuxResult<Option<ControlEvent>> {
+        let mut buf = [0; READ_SIZE];
+        while self.events.is_empty() {
+            let read = self.stdout.read(&mut buf).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.events.extend(self.parser.push(&buf[..read]));
+        }
+        Ok(self.events.pop_front())
+    }
+}
+
+/// How a `ControlConnection` attaches & reconnects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlOpts {
+    /// Session to attach to, the most recently used one when `None`. Output is only sent for
+    /// panes in the attached session
+    pub session: Option<String>,
+    /// Wait before the first reconnect, doubled after each failed one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Failed reconnects in a row before the connection gives up
+    pub max_attempts: usize,
+}
+
+impl Default for ControlOpts {
+    fn default() -> Self {
+        Self {
+            session: None,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            max_attempts: 5,
+        }
+    }
+}
+
+impl ControlOpts {
+    pub fn for_session(session: &str) -> Self {
+        Self {
+            session: Some(session.to_owned()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A control client that is reconnected with backoff when it dies
+#[derive(Debug)]
+pub struct ControlConnection {
+    receiver: UnboundedReceiver<ControlEvent>,
+    task: JoinHandle<()>,
+}
+
+impl ControlConnection {
+    /// Attaches a client, failing if control mode isn't available, then reads its events on a
+    /// new task
+    pub async fn connect(socket: Option<&str>, opts: ControlOpts) -> TmuxResult<Self> {
+        let client = ControlClient::connect(socket, opts.session.as_deref()).await?;
+        let (sender, receiver) = unbounded_channel();
+        let socket = socket.map(str::to_owned);
+        let task = tokio::spawn(Self::run(client, socket, opts, sender));
+        Ok(Self { receiver, task })
+    }
+
+    async fn run(
+        mut client: ControlClient,
+        socket: Option<String>,
+        opts: ControlOpts,
+        sender: UnboundedSender<ControlEvent>,
+    ) {
+        loop {
+            loop {
+                match client.next_event().await {
+                    Ok(Some(event)) => {
+                        if sender.send(event).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(err) => {
+                        warn!("Reading from the tmux control client failed: {:?}", err);
+                        break;
+                    }
+                }
+            }
+            if sender.send(ControlEvent::Disconnected).is_err() {
+                return;
+            }
+            let mut backoff = opts.initial_backoff;
+            let mut attempts = 0;
+            client = loop {
+                tokio::time::sleep(backoff).await;
+                match ControlClient::connect(socket.as_deref(), opts.session.as_deref()).await {
+                    Ok(client) => break client,
+                    Err(err) if attempts + 1 >= opts.max_attempts => {
+                        warn!("Gave up reconnecting the tmux control client: {:?}", err);
+                        return;
+                    }
+                    Err(_) => {
+                        attempts += 1;
+                        backoff = (backoff * 2).min(opts.max_backoff);
+                    }
+                }
+            };
+            if sender.send(ControlEvent::Reconnected).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// The next event, `None` once the connection has given up reconnecting
+    pub async fn next_event(&mut self) -> Option<ControlEvent> {
+        self.receiver.recv().await
+    }
+
+    pub fn into_stream(self) -> ControlStream {
+        Box::pin(futures::stream::unfold(self, |mut connection| async move {
+            let event = connection.next_event().await?;
+            Some((event, connection))
+        }))
+    }
+}
+
+impl Drop for ControlConnection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmux::{run, tests::TestServer};
+
+    const LIFECYCLE: &[u8] = include_bytes!("fixtures/control-lifecycle.txt");
+    const REPLIES: &[u8] = include_bytes!("fixtures/control-replies.txt");
+    const NO_SERVER: &[u8] = include_bytes!("fixtures/control-no-server.txt");
+
+    /// Parses a transcript fed in small reads, so lines are split across them
+    fn parse(transcript: &[u8]) -> Vec<ControlEvent> {
+        let mut parser = ControlParser::default();
+        transcript
+            .chunks(7)
+            .flat_map(|chunk| parser.push(chunk))
+            .collect()
+    }
+
+    fn reply(ok: bool, output: &[&str]) -> ControlEvent {
+        ControlEvent::Reply {
+            ok,
+            output: output.iter().map(|line| line.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn output_unescaped() {
+        assert_eq!(unescape(br"a\134b\011c\015\012"), b"a\\b\tc\r\n");
+        // Backslashes not followed by three octal digits are kept
+        assert_eq!(unescape(br"\9\01"), br"\9\01");
+    }
+
+    #[test]
+    fn lifecycle_transcript_parsed() {
+        let window = |id: &str| id.to_string();
+        assert_eq!(
+            parse(LIFECYCLE),
+            vec![
+                reply(true, &[]),
+                ControlEvent::SessionChanged {
+                    session_id: "$0".to_string(),
+                    name: "work".to_string()
+                },
+                reply(true, &["@0 sleep"]),
+                ControlEvent::WindowAdd {
+                    window_id: window("@1")
+                },
+                ControlEvent::Output {
+                    pane_id: "%1".to_string(),
+                    data: "a\\b\tc\x1b[1mé\x1b[0m\r\n".as_bytes().to_vec()
+                },
+                ControlEvent::WindowAdd {
+                    window_id: window("@2")
+                },
+                ControlEvent::SessionsChanged,
+                ControlEvent::WindowRenamed {
+                    window_id: window("@1"),
+                    name: "build logs".to_string()
+                },
+                ControlEvent::WindowClose {
+                    window_id: window("@1")
+                },
+                ControlEvent::SessionsChanged,
+                ControlEvent::SessionsChanged,
+                ControlEvent::Exit { reason: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn replies_read_to_their_own_end() {
+        let events = parse(REPLIES);
+        // Output that looks like the end of a reply doesn't end it
+        assert_eq!(events[2], reply(true, &["%end 1 2 3"]));
+        assert_eq!(
+            events[3],
+            reply(false, &["parse error: unknown command: bogus-command"])
+        );
+        assert_eq!(parse(NO_SERVER)[0], reply(false, &["no sessions"]));
+
+        let mut prefixed = DCS_PREFIX.to_vec();
+        prefixed.extend_from_slice(b"%exit detached\n%layout-change @0 b25d\n");
+        assert_eq!(
+            parse(&prefixed),
+            vec![
+                ControlEvent::Exit {
+                    reason: Some("detached".to_string())
+                },
+                ControlEvent::Other("%layout-change @0 b25d".to_string())
+            ]
+        );
+    }
+
+    async fn next(connection: &mut ControlConnection) -> Option<ControlEvent> {
+        tokio::time::timeout(Duration::from_secs(5), connection.next_event())
+            .await
+            .expect("no control event within 5s")
+    }
+
+    #[tokio::test]
+    async fn connection_reconnects_after_client_dies() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        let failed = ControlClient::connect(Some(&server.socket), Some("missing")).await;
+        assert!(matches!(failed, Err(TmuxError::InvalidTarget { .. })));
+        let opts = ControlOpts {
+            initial_backoff: Duration::from_millis(20),
+            ..ControlOpts::for_session("test")
+        };
+        let mut connection = ControlConnection::connect(Some(&server.socket), opts)
+            .await
+            .unwrap();
+        let detach = ["detach-client", "-s", "test"];
+        assert!(run(Some(&server.socket), &detach).await.is_ok());
+        let mut events = vec![];
+        while let Some(event) = next(&mut connection).await {
+            events.push(event.clone());
+            if event == ControlEvent::Reconnected {
+                break;
+            }
+        }
+        assert!(events.contains(&ControlEvent::Disconnected));
+        assert!(events.ends_with(&[ControlEvent::Reconnected]));
+
+        // Once the server is gone reconnecting fails, until the connection gives up
+        server.kill().await;
+        while let Some(event) = next(&mut connection).await {
+            assert_ne!(event, ControlEvent::Reconnected);
+        }
+    }
+}
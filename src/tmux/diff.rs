@@ -0,0 +1,392 @@
+//! Differences between successive captures of a pane
+use std::collections::HashMap;
+
+/// Lines that must carry over from one capture to the next for the next to count as appended
+/// to. Fewer could match by chance, like a bare prompt before & after `clear`
+const MIN_OVERLAP_LINES: usize = 2;
+
+/// How a capture differs from the one before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaneDelta {
+    Unchanged,
+    /// Lines written below the previous capture's content, which may have scrolled up. When the
+    /// previous capture's last line was written to, such as a command typed at a prompt, the
+    /// whole line is included
+    Appended(String),
+    /// The screen was cleared or redrawn, such as by a full screen program opening or closing.
+    /// Contains the whole new capture
+    Replaced(String),
+}
+
+impl PaneDelta {
+    /// The appended lines or new capture, empty when unchanged
+    pub fn content(&self) -> &str {
+        match self {
+            Self::Unchanged => "",
+            Self::Appended(content) | Self::Replaced(content) => content,
+        }
+    }
+
+    /// Text telling an agent about the delta, `since` being when the previous capture was taken
+    /// in milliseconds since the epoch. `None` when unchanged
+    pub fn render(&self, since: u64) -> Option<String> {
+        match self {
+            Self::Unchanged => None,
+            Self::Appended(lines) => Some(format!(
+                "New output since {}:\n{}",
+                clock_time(since),
+                lines
+            )),
+            Self::Replaced(content) => Some(format!(
+                "Screen replaced since {}:\n{}",
+                clock_time(since),
+                content
+            )),
+        }
+    }
+}
+
+/// `HH:MM:SS UTC` of a time in milliseconds since the epoch
+pub(super) fn clock_time(ms: u64) -> String {
+    let secs = ms / 1000 % 86_400;
+    format!(
+        "{:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Lines of a capture without trailing whitespace, or the blank lines tmux pads captures with
+fn lines(capture: &str) -> Vec<&str> {
+    capture.trim_end().lines().map(str::trim_end).collect()
+}
+
+/// Diffs captures against the previous capture of the same pane
+#[derive(Debug, Clone)]
+pub struct PaneDiffer {
+    min_overlap: usize,
+    previous: HashMap<String, String>,
+}
+
+impl Default for PaneDiffer {
+    fn default() -> Self {
+        Self {
+            min_overlap: MIN_OVERLAP_LINES,
+            previous: HashMap::new(),
+        }
+    }
+}
+
+impl PaneDiffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lines that must carry over between captures for output to count as appended rather
+    /// than the screen being replaced. Captures of fewer lines only need all of theirs to carry
+    pub fn with_min_overlap(mut self, lines: usize) -> Self {
+        self.min_overlap = lines.max(1);
+        self
+    }
+
+    /// How `next` differs from `prev`. Output is appended when the end of `prev` starts `next`,
+    /// otherwise the screen was replaced
+    pub fn delta(&self, prev: &str, next: &str) -> PaneDelta {
+        let prev = lines(prev);
+        let next = lines(next);
+        if prev == next {
+            return PaneDelta::Unchanged;
+        }
+        let Some((last, kept)) = prev.split_last() else {
+            return PaneDelta::Appended(next.join("\n"));
+        };
+        let min_overlap = self.min_overlap.min(prev.len());
+        // The earliest start keeps the most lines, which is the smallest scroll
+        for start in 0..=prev.len() - min_overlap {
+            let overlap = prev.len() - start;
+            if overlap > next.len() || next[..overlap - 1] != kept[start..] {
+                continue;
+            }
+            let line = next[overlap - 1];
+            let appended = if line == *last {
+                &next[overlap..]
+            } else if line.starts_with(last) {
+                &next[overlap - 1..]
+            } else {
+                continue;
+            };
+            if !appended.is_empty() {
+                return PaneDelta::Appended(appended.join("\n"));
+            }
+        }
+        PaneDelta::Replaced(next.join("\n"))
+    }
+
+    /// Diffs a capture of the pane with id `pane_id` against its last one, and keeps it for the
+    /// next. The first capture of a pane is all appended
+    pub fn push(&mut self, pane_id: &str, capture: &str) -> PaneDelta {
+        let delta = self.delta(
+            self.previous.get(pane_id).map(String::as_str).unwrap_or(""),
+            capture,
+        );
+        self.previous.insert(pane_id.to_owned(), capture.to_owned());
+        delta
+    }
+
+    /// Drops the last capture of a pane, returns false if there was none
+    pub fn forget(&mut self, pane_id: &str) -> bool {
+        self.previous.remove(pane_id).is_some()
+    }
+}
+
+/// Unchanged lines kept on either side of a changed region by default
+pub const DEFAULT_CONTEXT_LINES: usize = 3;
+/// Lines sent of a pane's first capture by default
+pub const DEFAULT_INITIAL_TAIL_LINES: usize = 40;
+
+/// Turns a pair of captures into a compact prompt holding only the lines that changed, with a
+/// few unchanged lines around them for context, to keep continuous monitoring cheap. Lines are
+/// marked `+` when changed & indented when context
+#[derive(Debug, Clone)]
+pub struct DiffPrompt {
+    differ: PaneDiffer,
+    context_lines: usize,
+    initial_tail_lines: usize,
+}
+
+impl Default for DiffPrompt {
+    fn default() -> Self {
+        Self {
+            differ: PaneDiffer::new(),
+            context_lines: DEFAULT_CONTEXT_LINES,
+            initial_tail_lines: DEFAULT_INITIAL_TAIL_LINES,
+        }
+    }
+}
+
+impl DiffPrompt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Unchanged lines to keep on either side of the changed lines
+    pub fn with_context_lines(mut self, lines: usize) -> Self {
+        self.context_lines = lines;
+        self
+    }
+
+    /// How many of the last lines of a first capture to send, since there's nothing to diff
+    pub fn with_initial_tail_lines(mut self, lines: usize) -> Self {
+        self.initial_tail_lines = lines.max(1);
+        self
+    }
+
+    /// Diffs captures the way `differ` does, see `PaneDiffer::with_min_overlap`
+    pub fn with_differ(mut self, differ: PaneDiffer) -> Self {
+        self.differ = differ;
+        self
+    }
+
+    /// The prompt for `next`, diffed against `prev` when there is an earlier capture. `None`
+    /// when nothing changed
+    pub fn prompt(&self, prev: Option<&str>, next: &str) -> Option<String> {
+        let next_lines = lines(next);
+        let Some(prev) = prev else {
+            if next_lines.is_empty() {
+                return None;
+            }
+            let start = next_lines.len().saturating_sub(self.initial_tail_lines);
+            return Some(format!(
+                "First capture of a tmux pane, its last {} of {} lines:\n{}",
+                next_lines.len() - start,
+                next_lines.len(),
+                next_lines[start..].join("\n")
+            ));
+        };
+        if next_lines.is_empty() {
+            return Some("Terminal diff of a tmux pane, the screen was cleared".to_owned());
+        }
+        let (start, end, change) = match self.differ.delta(prev, next) {
+            PaneDelta::Unchanged => return None,
+            PaneDelta::Appended(appended) => (
+                next_lines.len() - appended.lines().count(),
+                next_lines.len(),
+                "are new output",
+            ),
+            PaneDelta::Replaced(_) => {
+                let prev_lines = lines(prev);
+                let same = |(a, b): &(&&str, &&str)| a == b;
+                let prefix = prev_lines.iter().zip(&next_lines).take_while(same).count();
+                let suffix = prev_lines[prefix..]
+                    .iter()
+                    .rev()
+                    .zip(next_lines[prefix..].iter().rev())
+                    .take_while(same)
+                    .count();
+                // Only lines of the previous screen were removed, the line after them shows where
+                let end = (next_lines.len() - suffix).max((prefix + 1).min(next_lines.len()));
+                (prefix, end, "changed as the screen was redrawn")
+            }
+        };
+        let start = start.min(end - 1);
+        let context_start = start.saturating_sub(self.context_lines);
+        let context_end = (end + self.context_lines).min(next_lines.len());
+        let marked = next_lines[context_start..context_end]
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let marker = if (start..end).contains(&(context_start + i)) {
+                    "+"
+                } else {
+                    " "
+                };
+                format!("{} {}", marker, line).trim_end().to_owned()
+            })
+            .collect::<Vec<_>>();
+        Some(format!(
+            "Terminal diff of a tmux pane, lines {}-{} of {} {} since the last capture. They're \
+             marked `+`, with unchanged lines around them:\n{}",
+            start + 1,
+            end,
+            next_lines.len(),
+            change,
+            marked.join("\n")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn appended(lines: &str) -> PaneDelta {
+        PaneDelta::Appended(lines.to_string())
+    }
+
+    #[test]
+    fn scrolled_prompts_appended() {
+        let differ = PaneDiffer::new();
+        let prev = "$ echo one\none\n$\n\n\n";
+        // Typing at the prompt extends its line
+        assert_eq!(
+            differ.delta(prev, "$ echo one\none\n$ echo two\ntwo\n$"),
+            appended("$ echo two\ntwo\n$")
+        );
+        // The pane is full, so earlier lines scroll out of view
+        assert_eq!(
+            differ.delta(prev, "one\n$ echo two\ntwo\n$"),
+            appended("$ echo two\ntwo\n$")
+        );
+        assert_eq!(differ.delta("a\nb\nc", "b\nc\nd"), appended("d"));
+        assert_eq!(
+            differ.delta(prev, "$ echo one  \none\n$"),
+            PaneDelta::Unchanged
+        );
+        // Scrolled past everything that was on screen
+        assert_eq!(
+            differ.delta(prev, "three\nfour\n$"),
+            PaneDelta::Replaced("three\nfour\n$".to_string())
+        );
+    }
+
+    #[test]
+    fn full_screen_programs_replace_the_screen() {
+        let differ = PaneDiffer::new();
+        let shell = "$ ls\nnotes.txt\n$ vim notes.txt";
+        let vim = "hello\n~\n~\n~\n\"notes.txt\" 1L, 6B";
+        assert_eq!(
+            differ.delta(shell, vim),
+            PaneDelta::Replaced(vim.to_string())
+        );
+        let edited = "hello world\n~\n~\n~\n-- INSERT --";
+        assert_eq!(
+            differ.delta(vim, edited),
+            PaneDelta::Replaced(edited.to_string())
+        );
+        // Closing vim restores the shell's screen, with the next prompt below it
+        let closed = format!("{}\n$", shell);
+        assert_eq!(
+            differ.delta(edited, &closed),
+            PaneDelta::Replaced(closed.clone())
+        );
+        assert_eq!(differ.delta(shell, &closed), appended("$"));
+    }
+
+    #[test]
+    fn clear_replaces_the_screen() {
+        let differ = PaneDiffer::new();
+        assert_eq!(
+            differ.delta("$ ls\nnotes.txt\n$ clear", "$"),
+            PaneDelta::Replaced("$".to_string())
+        );
+        // When `clear` ran between captures, the bare prompt on both isn't enough overlap
+        assert_eq!(
+            differ.delta("$ ls\nnotes.txt\n$", "$ echo hi\nhi\n$"),
+            PaneDelta::Replaced("$ echo hi\nhi\n$".to_string())
+        );
+        assert_eq!(
+            differ.delta("$ ls\nnotes.txt\n$", "$"),
+            PaneDelta::Replaced("$".to_string())
+        );
+    }
+
+    #[test]
+    fn captures_kept_per_pane() {
+        let mut differ = PaneDiffer::new();
+        assert_eq!(differ.push("%0", "a\nb"), appended("a\nb"));
+        assert_eq!(differ.push("%1", "x"), appended("x"));
+        assert_eq!(differ.push("%0", "a\nb\nc"), appended("c"));
+        assert_eq!(differ.push("%0", "a\nb\nc"), PaneDelta::Unchanged);
+        assert!(differ.forget("%0"));
+        assert_eq!(differ.push("%0", "a\nb\nc"), appended("a\nb\nc"));
+
+        assert_eq!(
+            appended("c").render(45_296_000).as_deref(),
+            Some("New output since 12:34:56 UTC:\nc")
+        );
+        assert_eq!(PaneDelta::Unchanged.render(0), None);
+    }
+
+    #[test]
+    fn diff_prompts_keep_changed_lines_and_context() {
+        let prompt = DiffPrompt::new().with_context_lines(1);
+        let prev = "$ cargo build\nCompiling a\nCompiling b\nFinished\n$";
+        let next = format!(
+            "{}\n$ cargo test\ntest result: ok\n$",
+            &prev[..prev.len() - 2]
+        );
+        assert_eq!(
+            prompt.prompt(Some(prev), &next).unwrap(),
+            "Terminal diff of a tmux pane, lines 5-7 of 7 are new output since the last \
+             capture. They're marked `+`, with unchanged lines around them:\n  Finished\n\
+             + $ cargo test\n+ test result: ok\n+ $"
+        );
+        assert_eq!(prompt.prompt(Some(&next), &next), None);
+
+        // An edit in a full screen program only sends the edited line
+        let vim = "one\ntwo\nthree\nfour\nfive\n-- INSERT --";
+        let edited = vim.replace("three", "THREE");
+        assert_eq!(
+            prompt.prompt(Some(vim), &edited).unwrap(),
+            "Terminal diff of a tmux pane, lines 3-3 of 6 changed as the screen was redrawn \
+             since the last capture. They're marked `+`, with unchanged lines around them:\n\
+             \x20 two\n+ THREE\n  four"
+        );
+        assert_eq!(
+            prompt.prompt(Some(vim), "").unwrap(),
+            "Terminal diff of a tmux pane, the screen was cleared"
+        );
+
+        let scrollback: Vec<String> = (1..=100).map(|i| format!("line {}", i)).collect();
+        let first = DiffPrompt::new()
+            .with_initial_tail_lines(2)
+            .prompt(None, &scrollback.join("\n"))
+            .unwrap();
+        assert_eq!(
+            first,
+            "First capture of a tmux pane, its last 2 of 100 lines:\nline 99\nline 100"
+        );
+        assert_eq!(prompt.prompt(None, "\n\n"), None);
+    }
+}
@@ -0,0 +1,509 @@
+//! Showing a streamed completion inside tmux, in a popup or on the status line
+use super::{run, shell_quote, Pane, TmuxError, TmuxResult, TMUX_BIN};
+use crate::{
+    agents::Agent,
+    language_models::completions::streaming::{
+        CancelReason, CollectedCompletion, ProviderStreamHandler, StreamResult, TextSink,
+    },
+};
+use std::{path::PathBuf, process::Stdio, time::Duration};
+use tokio::{
+    io::AsyncWriteExt,
+    net::unix::pipe,
+    process::{Child, Command},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    time::MissedTickBehavior,
+};
+use tracing::warn;
+
+/// Default for `DisplayOpts::status_option`. Put `#{@espionox_stream}` in a `status-format`,
+/// `status-left` or `status-right` to show it
+pub const DEFAULT_STATUS_OPTION: &str = "@espionox_stream";
+const DEFAULT_REPAINT_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_DWELL: Duration = Duration::from_secs(3);
+/// Marks the start of a status line that was cut to its end
+const ELLIPSIS: char = '…';
+
+/// Where a `StreamDisplay` shows the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayTarget {
+    /// A `display-popup` on the client attached to the pane's session, with the text fed to
+    /// it over a pipe. Closing the popup cancels the stream
+    Popup,
+    /// A user option of the pane's session, set to the end of the text so far on one line
+    StatusLine,
+}
+
+/// Options for `StreamDisplay::open`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayOpts {
+    pub target: DisplayTarget,
+    /// Text received in between repaints is shown together, however fast it arrives
+    pub repaint_interval: Duration,
+    /// How long the final text stays up once the stream ends, before the popup closes or the
+    /// status option is unset
+    pub dwell: Duration,
+    /// Columns of the status line, defaults to the width of the pane's window
+    pub width: Option<usize>,
+    /// Name of the user option the status line is set in
+    pub status_option: String,
+}
+
+impl DisplayOpts {
+    fn new(target: DisplayTarget) -> Self {
+        Self {
+            target,
+            repaint_interval: DEFAULT_REPAINT_INTERVAL,
+            dwell: DEFAULT_DWELL,
+            width: None,
+            status_option: DEFAULT_STATUS_OPTION.to_string(),
+        }
+    }
+
+    pub fn popup() -> Self {
+        Self::new(DisplayTarget::Popup)
+    }
+
+    pub fn status_line() -> Self {
+        Self::new(DisplayTarget::StatusLine)
+    }
+
+    pub fn with_repaint_interval(mut self, interval: Duration) -> Self {
+        self.repaint_interval = interval;
+        self
+    }
+
+    pub fn with_dwell(mut self, dwell: Duration) -> Self {
+        self.dwell = dwell;
+        self
+    }
+
+    pub fn with_width(mut self, width: usize) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    pub fn with_status_option(mut self, option: &str) -> Self {
+        self.status_option = option.to_owned();
+        self
+    }
+}
+
+/// The end of `text` on one line of at most `width` characters, runs of whitespace such as
+/// newlines collapsed to single spaces
+fn rolling_line(text: &str, width: usize) -> String {
+    let line = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let len = line.chars().count();
+    if len <= width {
+        return line;
+    }
+    let tail: String = line.chars().skip(len - width.saturating_sub(1)).collect();
+    match width {
+        0 => String::new(),
+        _ => format!("{}{}", ELLIPSIS, tail),
+    }
+}
+
+/// Sent from the stream to the painting task
+#[derive(Debug)]
+enum Paint {
+    Text(String),
+    /// The stream ended, with an error to show if it failed
+    End(Option<String>),
+}
+
+/// Hands streamed tokens to the painting task
+struct DisplaySink {
+    sender: UnboundedSender<Paint>,
+}
+
+impl TextSink for DisplaySink {
+    fn token(&mut self, token: &str) {
+        let _ = self.sender.send(Paint::Text(token.to_owned()));
+    }
+}
+
+/// Waits for the next paint, after which anything received is returned. `None` once the
+/// stream ended, along with whether that was by an error to show
+async fn next_paint(
+    receiver: &mut UnboundedReceiver<Paint>,
+    interval: &mut tokio::time::Interval,
+    text: &mut String,
+) -> Option<Option<String>> {
+    interval.tick().await;
+    while let Ok(paint) = receiver.try_recv() {
+        match paint {
+            Paint::Text(token) => text.push_str(&token),
+            Paint::End(error) => return Some(error),
+        }
+    }
+    if receiver.is_closed() && receiver.is_empty() {
+        return Some(None);
+    }
+    None
+}
+
+/// Paints into a popup's pipe until the stream ends, telling `closed` if the popup closes first
+async fn paint_popup(
+    mut popup: Child,
+    mut writer: pipe::Sender,
+    fifo: PathBuf,
+    mut receiver: UnboundedReceiver<Paint>,
+    repaint_interval: Duration,
+    closed: oneshot::Sender<()>,
+) {
+    let mut interval = tokio::time::interval(repaint_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut text = String::new();
+    loop {
+        tokio::select! {
+            exited = popup.wait() => {
+                if let Err(err) = exited {
+                    warn!("Failed to wait on stream popup: {:?}", err);
+                }
+                let _ = closed.send(());
+                break;
+            }
+            end = next_paint(&mut receiver, &mut interval, &mut text) => {
+                if let Err(err) = writer.write_all(text.as_bytes()).await {
+                    warn!("Failed to write to stream popup: {:?}", err);
+                }
+                text.clear();
+                let Some(error) = end else {
+                    continue;
+                };
+                if let Some(error) = error {
+                    let error = format!("\n\nStream failed: {}\n", error);
+                    let _ = writer.write_all(error.as_bytes()).await;
+                }
+                // Closing the only writer ends the pager, and the popup closes after its dwell
+                drop(writer);
+                let _ = popup.wait().await;
+                break;
+            }
+        }
+    }
+    let _ = std::fs::remove_file(fifo);
+}
+
+/// Paints the status option until the stream ends, then unsets it after the dwell
+async fn paint_status(
+    pane: Pane,
+    opts: DisplayOpts,
+    width: usize,
+    mut receiver: UnboundedReceiver<Paint>,
+) {
+    let socket = pane.socket.as_deref();
+    let mut interval = tokio::time::interval(opts.repaint_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut text = String::new();
+    let mut painted = String::new();
+    loop {
+        let end = next_paint(&mut receiver, &mut interval, &mut text).await;
+        let line = match end.as_ref() {
+            Some(Some(error)) => rolling_line(&format!("Stream failed: {}", error), width),
+            _ => rolling_line(&text, width),
+        };
+        if line != painted {
+            let set = [
+                "set-option",
+                "-t",
+                &pane.session,
+                &opts.status_option,
+                &line,
+            ];
+            if let Err(failure) = run(socket, &set).await {
+                warn!("Failed to set status line: {:?}", pane.gone(failure));
+            }
+            // Clients only redraw their status line on an interval otherwise
+            let _ = run(socket, &["refresh-client", "-S"]).await;
+            painted = line;
+        }
+        if end.is_some() {
+            break;
+        }
+    }
+    tokio::time::sleep(opts.dwell).await;
+    let unset = ["set-option", "-u", "-t", &pane.session, &opts.status_option];
+    let _ = run(socket, &unset).await;
+    let _ = run(socket, &["refresh-client", "-S"]).await;
+}
+
+/// A popup or status line a stream is shown in, see `StreamDisplay::show`
+#[derive(Debug)]
+pub struct StreamDisplay {
+    sender: UnboundedSender<Paint>,
+    /// Told when the popup closes before the stream ends
+    closed: Option<oneshot::Receiver<()>>,
+}
+
+impl StreamDisplay {
+    /// Opens a display on the client attached to `pane`'s session. Popups fail with
+    /// `TmuxError::Command` when no client is attached, and a client shows one popup at a time
+    pub async fn open(pane: &Pane, opts: DisplayOpts) -> TmuxResult<Self> {
+        let (sender, receiver) = unbounded_channel();
+        let socket = pane.socket.as_deref();
+        match opts.target {
+            DisplayTarget::Popup => {
+                let client = attached_client(pane).await?;
+                let fifo = std::env::temp_dir()
+                    .join(format!("espionox-popup-{}.fifo", uuid::Uuid::new_v4()));
+                let made = Command::new("mkfifo").arg(&fifo).status().await?;
+                if !made.success() {
+                    return Err(TmuxError::Command(format!(
+                        "mkfifo {} failed",
+                        fifo.display()
+                    )));
+                }
+                // Opened for reading too, so this doesn't wait for the pager to open it
+                let writer = match pipe::OpenOptions::new().read_write(true).open_sender(&fifo) {
+                    Ok(writer) => writer,
+                    Err(err) => {
+                        let _ = std::fs::remove_file(&fifo);
+                        return Err(err.into());
+                    }
+                };
+                let pager = format!(
+                    "cat {}; sleep {:.3}",
+                    shell_quote(&fifo.display().to_string()),
+                    opts.dwell.as_secs_f64()
+                );
+                let mut command = Command::new(TMUX_BIN);
+                if let Some(socket) = socket {
+                    command.args(["-L", socket]);
+                }
+                let popup = command
+                    .args([
+                        "display-popup",
+                        "-E",
+                        "-c",
+                        &client,
+                        "-w",
+                        "80%",
+                        "-h",
+                        "50%",
+                    ])
+                    .arg(pager)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .spawn();
+                let popup = match popup {
+                    Ok(popup) => popup,
+                    Err(err) => {
+                        let _ = std::fs::remove_file(&fifo);
+                        return Err(err.into());
+                    }
+                };
+                let (closed_sender, closed) = oneshot::channel();
+                tokio::spawn(paint_popup(
+                    popup,
+                    writer,
+                    fifo,
+                    receiver,
+                    opts.repaint_interval,
+                    closed_sender,
+                ));
+                Ok(Self {
+                    sender,
+                    closed: Some(closed),
+                })
+            }
+            DisplayTarget::StatusLine => {
+                let width = match opts.width {
+                    Some(width) => width,
+                    None => window_width(pane).await?,
+                };
+                tokio::spawn(paint_status(pane.clone(), opts, width, receiver));
+                Ok(Self {
+                    sender,
+                    closed: None,
+                })
+            }
+        }
+    }
+
+    /// Receives `handler`'s stream until it finishes like `ProviderStreamHandler::collect`,
+    /// showing its text as it arrives. If the popup is closed first, the stream is cancelled
+    /// with `CancelReason::User` & the content so far returned as partial
+    pub async fn show(
+        mut self,
+        handler: ProviderStreamHandler,
+        agent: &mut Agent,
+    ) -> StreamResult<CollectedCompletion> {
+        let mut handler = handler.with_sink(DisplaySink {
+            sender: self.sender.clone(),
+        });
+        let collected = match self.closed.take() {
+            Some(closed) => {
+                tokio::select! {
+                    collected = handler.collect(agent) => Some(collected),
+                    Ok(()) = closed => None,
+                }
+            }
+            None => Some(handler.collect(agent).await),
+        };
+        let Some(collected) = collected else {
+            warn!("Stream display was closed, cancelling the stream");
+            handler.cancel(agent, CancelReason::User);
+            return Ok(CollectedCompletion::Partial {
+                content: handler.message_content().to_owned(),
+                reason: CancelReason::User,
+            });
+        };
+        let error = collected.as_ref().err().map(|err| err.to_string());
+        let _ = self.sender.send(Paint::End(error));
+        collected
+    }
+}
+
+/// Name of the client attached to `pane`'s session that was most recently active
+async fn attached_client(pane: &Pane) -> TmuxResult<String> {
+    let format = "#{client_activity}\t#{client_name}";
+    let args = ["list-clients", "-t", &pane.session, "-F", format];
+    let output = run(pane.socket.as_deref(), &args)
+        .await
+        .map_err(|failure| pane.gone(failure))?;
+    output
+        .lines()
+        .filter_map(|line| {
+            let (activity, name) = line.split_once('\t')?;
+            Some((activity.parse::<u64>().unwrap_or_default(), name.to_owned()))
+        })
+        .max()
+        .map(|(_, name)| name)
+        .ok_or_else(|| {
+            TmuxError::Command(format!("no client is attached to session {}", pane.session))
+        })
+}
+
+async fn window_width(pane: &Pane) -> TmuxResult<usize> {
+    let args = ["display-message", "-p", "-t", &pane.id, "#{window_width}"];
+    let output = run(pane.socket.as_deref(), &args)
+        .await
+        .map_err(|failure| pane.gone(failure))?;
+    output
+        .trim()
+        .parse()
+        .map_err(|_| TmuxError::Command(format!("unexpected window width: {}", output.trim())))
+}
+
+#[cfg(all(test, feature = "openai"))]
+mod tests {
+    use super::*;
+    use crate::{
+        language_models::completions::{streaming::StreamReplay, CompletionModel},
+        tmux::{tests::TestServer, ControlClient},
+    };
+
+    /// A recorded OpenAi stream of `tokens`, replayed with `cadence` between events
+    fn replay(tokens: &[&str], cadence: Duration, agent: &Agent) -> ProviderStreamHandler {
+        let mut recorded = String::new();
+        for token in tokens {
+            let chunk = serde_json::json!({"choices": [{"delta": {"content": token}}]});
+            recorded.push_str(&format!("data: {}\n\n", chunk));
+        }
+        let finish = serde_json::json!({"choices": [{"delta": {}, "finish_reason": "stop"}]});
+        recorded.push_str(&format!("data: {}\n\ndata: [DONE]\n\n", finish));
+        StreamReplay::from_reader(std::io::Cursor::new(recorded.into_bytes()))
+            .with_cadence(cadence)
+            .into_handler(&agent.completion_model.provider)
+    }
+
+    async fn status(server: &TestServer) -> String {
+        let show = ["show-options", "-v", "-t", "test", DEFAULT_STATUS_OPTION];
+        run(Some(&server.socket), &show).await.unwrap_or_default()
+    }
+
+    #[test]
+    fn status_lines_roll_to_width() {
+        assert_eq!(
+            rolling_line("checking\n\n  the build", 40),
+            "checking the build"
+        );
+        assert_eq!(rolling_line("checking the build", 10), "…the build");
+        assert_eq!(rolling_line("checking", 0), "");
+    }
+
+    #[tokio::test]
+    async fn stream_shown_on_status_line() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let handler = replay(
+            &["The build ", "is failing\n", "on lint"],
+            Duration::ZERO,
+            &agent,
+        );
+        let opts = DisplayOpts::status_line()
+            .with_width(16)
+            .with_dwell(Duration::from_millis(200));
+        let display = StreamDisplay::open(&pane, opts).await.unwrap();
+
+        let collected = display.show(handler, &mut agent).await.unwrap();
+        assert!(matches!(
+            collected,
+            CollectedCompletion::Finished(ref content) if content == "The build is failing\non lint"
+        ));
+        let mut shown = String::new();
+        for _ in 0..50 {
+            shown = status(&server).await;
+            if !shown.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(shown.trim_end(), "…failing on lint");
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(status(&server).await, "");
+    }
+
+    #[tokio::test]
+    async fn popups_need_a_client_and_cancel_when_closed() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        let socket = Some(server.socket.as_str());
+        let pane = Pane::resolve_on(socket, "test:0.0").await.unwrap();
+        assert!(matches!(
+            StreamDisplay::open(&pane, DisplayOpts::popup()).await,
+            Err(TmuxError::Command(_))
+        ));
+
+        let _client = ControlClient::connect(socket, Some("test")).await.unwrap();
+        let client = attached_client(&pane).await.unwrap();
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let opts = DisplayOpts::popup().with_dwell(Duration::ZERO);
+        let display = StreamDisplay::open(&pane, opts.clone()).await.unwrap();
+        let handler = replay(&["Quick ", "check"], Duration::ZERO, &agent);
+        let collected = display.show(handler, &mut agent).await.unwrap();
+        assert!(matches!(collected, CollectedCompletion::Finished(ref c) if c == "Quick check"));
+        // A client shows one popup at a time, so let the first close after the pager ends
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let display = StreamDisplay::open(&pane, opts).await.unwrap();
+        let tokens = ["still "; 50];
+        let handler = replay(&tokens, Duration::from_millis(50), &agent);
+        let close = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert!(run(socket, &["display-popup", "-C", "-c", &client])
+                .await
+                .is_ok());
+        };
+        let (collected, ()) = tokio::join!(display.show(handler, &mut agent), close);
+        match collected.unwrap() {
+            CollectedCompletion::Partial { content, reason } => {
+                assert_eq!(reason, CancelReason::User);
+                assert!(content.starts_with("still "), "{:?}", content);
+                assert!(content.len() < tokens.concat().len());
+            }
+            other => panic!("expected the stream to be cancelled, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::errors::error_chain_fmt;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+
+pub type TmuxResult<T> = Result<T, TmuxError>;
+
+#[derive(thiserror::Error)]
+pub enum TmuxError {
+    /// The tmux binary couldn't be found on the `PATH`
+    BinaryNotFound,
+    /// No tmux server is running on the socket
+    NoServer,
+    /// The target didn't resolve to a pane
+    InvalidTarget {
+        target: String,
+        message: String,
+    },
+    /// A pane that was resolved earlier no longer exists
+    PaneGone {
+        id: String,
+    },
+    /// A new pane couldn't be split from the target pane
+    PaneCreation {
+        target: String,
+        message: String,
+    },
+    /// A tool call with the wrong name or arguments
+    InvalidToolCall(String),
+    /// A tool call targeted a pane that isn't in the tool's allowlist
+    TargetNotAllowed {
+        target: String,
+    },
+    /// A command refused by a tool's allowlist or denylist
+    CommandNotAllowed {
+        command: String,
+        reason: String,
+    },
+    /// A key name that isn't one of `SpecialKey`
+    UnknownKey(String),
+    /// A watch pattern that isn't a valid regex
+    InvalidPattern(#[from] regex::Error),
+    /// tmux exited unsuccessfully for another reason, contains its stderr
+    Command(String),
+    Io(#[from] std::io::Error),
+}
+
+impl Debug for TmuxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl Display for TmuxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let display = match self {
+            Self::BinaryNotFound => "tmux binary not found".to_string(),
+            Self::NoServer => "No tmux server running".to_string(),
+            Self::InvalidTarget { target, message } => {
+                format!("Invalid tmux target {}: {}", target, message)
+            }
+            Self::PaneGone { id } => format!("Pane {} no longer exists", id),
+            Self::PaneCreation { target, message } => {
+                format!("Failed to split a pane from {}: {}", target, message)
+            }
+            Self::InvalidToolCall(reason) => format!("Invalid tool call: {}", reason),
+            Self::TargetNotAllowed { target } => {
+                format!("Pane {} is not in the allowlist", target)
+            }
+            Self::CommandNotAllowed { command, reason } => {
+                format!("Command {:?} not allowed: {}", command, reason)
+            }
+            Self::UnknownKey(name) => format!("Unknown key: {}", name),
+            Self::InvalidPattern(err) => format!("Invalid watch pattern: {}", err),
+            Self::Command(stderr) => format!("tmux command failed: {}", stderr),
+            Self::Io(err) => err.to_string(),
+        };
+        write!(f, "{}", display)
+    }
+}
@@ -0,0 +1,403 @@
+//! Running shell commands proposed by a model in a dedicated pane, so they stay visible &
+//! interactive in tmux
+use super::{
+    tool::{tool_result_message, PaneTool, PaneToolFuture},
+    CaptureOpts, KeysInput, PaneMatch, SpecialKey, TmuxError, TmuxResult,
+};
+use crate::{agents::memory::Message, language_models::completions::streaming::ToolCall};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::{sync::Mutex, time::Instant};
+
+/// Name of the tool handled by `ExecTool`
+pub const EXEC_TOOL: &str = "exec_command";
+
+pub const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes of output kept by default, the end of longer output is kept
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 16 * 1024;
+
+/// Markers echoed before & after a command. They are typed with quotes splitting them, so the
+/// typed command line never contains them, only the command's output does
+const START_MARKER: &str = "__espionox_start_";
+const DONE_MARKER: &str = "__espionox_done_";
+const SPLIT_AT: usize = "__espionox_".len();
+/// Far enough back to capture the pane's whole history, tmux clamps it to the oldest line
+const HISTORY_START: i32 = -1_000_000;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Characters that chain, substitute or redirect commands, refused when there is an allowlist
+const SHELL_METACHARACTERS: [&str; 7] = [";", "&", "|", "`", "$(", ">", "<"];
+
+/// Arguments of an `EXEC_TOOL` call
+#[derive(Debug, Deserialize)]
+pub struct ExecArgs {
+    command: String,
+}
+
+/// What a command run by `ExecTool` printed, and how it ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecOutput {
+    pub command: String,
+    /// Text the command wrote to the pane, both stdout & stderr
+    pub output: String,
+    /// `None` when the command timed out
+    pub exit_code: Option<i32>,
+    /// The command ran past the timeout & was interrupted with C-c
+    pub timed_out: bool,
+    /// The start of the output was cut to fit the maximum output size
+    pub truncated: bool,
+}
+
+impl ExecOutput {
+    /// The output along with how the command ended, for returning to the model
+    pub fn render(&self) -> String {
+        let status = match (self.exit_code, self.timed_out) {
+            (_, true) => "timed out and was interrupted".to_string(),
+            (Some(code), false) => format!("exited with code {}", code),
+            (None, false) => "exit code unknown".to_string(),
+        };
+        let truncated = match self.truncated {
+            true => "[earlier output truncated]\n",
+            false => "",
+        };
+        format!(
+            "$ {}\n{}{}\n[command {}]",
+            self.command, truncated, self.output, status
+        )
+    }
+
+    /// A tool result message answering `call`, with the `TOOL_ROLE_ALIAS` role
+    pub fn to_message(&self, call: &ToolCall) -> Message {
+        tool_result_message(self.render(), call)
+    }
+}
+
+/// The last `max_bytes` of `output`, cut at a character boundary, and whether it was cut
+fn truncate_start(output: &str, max_bytes: usize) -> (&str, bool) {
+    if output.len() <= max_bytes {
+        return (output, false);
+    }
+    let mut start = output.len() - max_bytes;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    (&output[start..], true)
+}
+
+/// Finds the output of the command with `id` in a capture. Returns the lines between its start
+/// & done markers, with the exit code if the done marker has been printed
+fn parse_capture(capture: &str, id: &str) -> Option<(String, Option<i32>)> {
+    let start = format!("{}{}", START_MARKER, id);
+    let done = format!("{}{}:", DONE_MARKER, id);
+    let lines: Vec<&str> = capture.lines().collect();
+    let first = lines.iter().rposition(|line| line.trim_end() == start)? + 1;
+    let mut output = vec![];
+    for line in &lines[first..] {
+        if let Some(code) = line.trim_end().strip_prefix(&done) {
+            return Some((output.join("\n"), code.parse().ok()));
+        }
+        output.push(*line);
+    }
+    // Blank lines tmux pads the capture to the pane's height with
+    while output.last().is_some_and(|line| line.trim().is_empty()) {
+        output.pop();
+    }
+    Some((output.join("\n"), None))
+}
+
+/// `marker` with a pair of quotes inside it, which the shell removes when echoing it
+fn split_marker(marker: &str, id: &str) -> String {
+    format!("{}''{}{}", &marker[..SPLIT_AT], &marker[SPLIT_AT..], id)
+}
+
+/// Lets a tool calling model run shell commands in a dedicated pane. The pane should be
+/// running a POSIX shell & not be used for anything else, since commands are typed into it
+/// with send-keys & their output is read back with capture-pane. Commands run one at a time,
+/// each in the pane the match picks when it starts
+#[derive(Debug)]
+pub struct ExecTool {
+    pane: PaneMatch,
+    socket: Option<String>,
+    allowed: Vec<String>,
+    denied: Vec<String>,
+    timeout: Duration,
+    max_output_bytes: usize,
+    running: Mutex<()>,
+}
+
+impl ExecTool {
+    /// A tool running any command in `pane`. Restrict it with `with_allowed` or `with_denied`
+    pub fn new(pane: impl Into<PaneMatch>) -> Self {
+        Self {
+            pane: pane.into(),
+            socket: None,
+            allowed: vec![],
+            denied: vec![],
+            timeout: DEFAULT_EXEC_TIMEOUT,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            running: Mutex::new(()),
+        }
+    }
+
+    /// Only run commands whose program is one of `programs`. Commands that chain, substitute
+    /// or redirect with shell metacharacters are refused, since they could run other programs
+    pub fn with_allowed<S: AsRef<str>>(mut self, programs: &[S]) -> Self {
+        self.allowed
+            .extend(programs.iter().map(|p| p.as_ref().to_owned()));
+        self
+    }
+
+    /// Refuse commands that contain any of `programs` as a word. This is best effort & not a
+    /// security boundary, a denied program can still be reached through a path, an alias or a
+    /// variable. Use `with_allowed` to restrict what runs
+    pub fn with_denied<S: AsRef<str>>(mut self, programs: &[S]) -> Self {
+        self.denied
+            .extend(programs.iter().map(|p| p.as_ref().to_owned()));
+        self
+    }
+
+    /// How long a command can run before it is interrupted with C-c
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_max_output_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_output_bytes = max_bytes;
+        self
+    }
+
+    /// Pick the pane on the server with the given socket name rather than the default server
+    pub fn on_socket(mut self, socket: &str) -> Self {
+        self.socket = Some(socket.to_owned());
+        self
+    }
+
+    pub fn pane(&self) -> &PaneMatch {
+        &self.pane
+    }
+
+    /// Checks `command` against the allowlist & denylist. Control characters are refused
+    /// since a carriage return or newline would submit the line early, running whatever
+    /// follows it unchecked
+    fn check(&self, command: &str) -> TmuxResult<()> {
+        let not_allowed = |reason: String| TmuxError::CommandNotAllowed {
+            command: command.to_owned(),
+            reason,
+        };
+        if let Some(c) = command.chars().find(|c| c.is_ascii_control()) {
+            return Err(not_allowed(format!(
+                "control character {:?} isn't allowed",
+                c
+            )));
+        }
+        let words: Vec<&str> = command
+            .split(|c: char| c.is_whitespace() || ";&|()`<>".contains(c))
+            .filter(|w| !w.is_empty())
+            .collect();
+        if let Some(denied) = words.iter().find(|w| self.denied.iter().any(|d| d == *w)) {
+            return Err(not_allowed(format!("{} is denied", denied)));
+        }
+        if self.allowed.is_empty() {
+            return Ok(());
+        }
+        if let Some(meta) = SHELL_METACHARACTERS.iter().find(|m| command.contains(**m)) {
+            return Err(not_allowed(format!("{} isn't allowed", meta)));
+        }
+        match words.first() {
+            Some(program) if self.allowed.iter().any(|a| a == program) => Ok(()),
+            Some(program) => Err(not_allowed(format!("{} isn't allowed", program))),
+            None => Err(not_allowed("empty command".to_string())),
+        }
+    }
+
+    /// Types `command` into the pane, then waits for it to finish or time out and returns its
+    /// output
+    pub async fn run(&self, command: &str) -> TmuxResult<ExecOutput> {
+        self.check(command)?;
+        let _running = self.running.lock().await;
+        let pane = self.pane.resolve_first(self.socket.as_deref()).await?;
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let line = format!(
+            "echo {}; {}; echo {}:$?",
+            split_marker(START_MARKER, &id),
+            command,
+            split_marker(DONE_MARKER, &id)
+        );
+        pane.send_keys(KeysInput::text(&line)).await?;
+
+        let opts = CaptureOpts {
+            join_wrapped: true,
+            start: Some(HISTORY_START),
+            ..Default::default()
+        };
+        let deadline = Instant::now() + self.timeout;
+        let (output, exit_code, timed_out) = loop {
+            let capture = pane.capture(opts.clone()).await?;
+            let parsed = parse_capture(&capture, &id);
+            if let Some((output, Some(code))) = parsed {
+                break (output, Some(code), false);
+            }
+            if Instant::now() >= deadline {
+                let interrupt = KeysInput::key(SpecialKey::Ctrl('c')).without_enter();
+                pane.send_keys(interrupt).await?;
+                let output = parsed.map(|(output, _)| output).unwrap_or_default();
+                break (output, None, true);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+        let (output, truncated) = truncate_start(&output, self.max_output_bytes);
+        Ok(ExecOutput {
+            command: command.to_owned(),
+            output: output.to_owned(),
+            exit_code,
+            timed_out,
+            truncated,
+        })
+    }
+}
+
+/// Handles `EXEC_TOOL` calls with a `command` string argument
+impl PaneTool for ExecTool {
+    type Args = ExecArgs;
+    type Output = ExecOutput;
+
+    fn name(&self) -> &'static str {
+        EXEC_TOOL
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "command": {
+                    "type": "string",
+                    "description": "Shell command to run, its output and exit code are returned"
+                }
+            },
+            "required": ["command"]
+        })
+    }
+
+    fn run_call(&self, args: ExecArgs) -> PaneToolFuture<'_, ExecOutput> {
+        Box::pin(async move { self.run(&args.command).await })
+    }
+
+    fn render(&self, output: &ExecOutput) -> String {
+        output.render()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmux::{tests::TestServer, Pane, TOOL_CALL_ID_METADATA_KEY};
+
+    #[test]
+    fn commands_checked_against_lists() {
+        let pane = Pane {
+            session: "test".to_string(),
+            window: 0,
+            index: 0,
+            id: "%0".to_string(),
+            socket: None,
+            history_limit: 0,
+        };
+        let open = ExecTool::new(pane.clone()).with_denied(&["rm"]);
+        assert!(open.check("ls -la | wc -l").is_ok());
+        assert!(open.check("ls; rm -rf /").is_err());
+        assert!(open.check("ls\necho").is_err());
+
+        let allowed = ExecTool::new(pane.clone()).with_allowed(&["ls", "cargo"]);
+        assert!(allowed.check("cargo test --workspace").is_ok());
+        assert!(allowed.check("python -c 1").is_err());
+        assert!(allowed.check("ls $(rm -rf /)").is_err());
+        assert!(allowed.check("cargo test > out").is_err());
+        assert!(allowed.check("   ").is_err());
+        for command in [
+            "echo ok\rrm -rf ~",
+            "echo ok\x1b",
+            "echo\tok",
+            "echo ok\x7f",
+        ] {
+            assert!(matches!(
+                ExecTool::new(pane.clone())
+                    .with_allowed(&["echo"])
+                    .check(command),
+                Err(TmuxError::CommandNotAllowed { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn output_found_between_markers() {
+        let capture = "$ echo __espionox_''start_1; ls; echo __espionox_''done_1:$?\n__espionox_start_1\na\nb\n__espionox_done_1:2\n$ \n\n";
+        assert_eq!(
+            parse_capture(capture, "1"),
+            Some(("a\nb".to_string(), Some(2)))
+        );
+        let running = "__espionox_start_1\npartial\n\n\n";
+        assert_eq!(
+            parse_capture(running, "1"),
+            Some(("partial".to_string(), None))
+        );
+        assert_eq!(parse_capture(capture, "2"), None);
+        assert_eq!(truncate_start("héllo", 4), ("llo", true));
+        assert_eq!(truncate_start("hello", 5), ("hello", false));
+    }
+
+    #[tokio::test]
+    async fn commands_run_in_pane_with_exit_codes() {
+        let Some(server) = TestServer::start("sh").await else {
+            return;
+        };
+        // Resolved on each run, rather than up front
+        let tool = ExecTool::new("test:0.0")
+            .on_socket(&server.socket)
+            .with_allowed(&["printf", "ls", "sleep", "echo"])
+            .with_timeout(Duration::from_millis(500))
+            .with_max_output_bytes(12);
+
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            name: EXEC_TOOL.to_string(),
+            arguments: json!({"command": "printf 'one\\ntwo\\n'"}).to_string(),
+        };
+        let result = tool.execute(&call).await.unwrap();
+        assert_eq!(result.output, "one\ntwo");
+        assert_eq!(result.exit_code, Some(0));
+        let message = result.to_message(&call);
+        assert_eq!(
+            message.content,
+            "$ printf 'one\\ntwo\\n'\none\ntwo\n[command exited with code 0]"
+        );
+        assert_eq!(message.metadata[TOOL_CALL_ID_METADATA_KEY], "call_1");
+        assert_eq!(tool.result_message(&call, &result), message);
+        assert_eq!(tool.parameters_schema()["required"], json!(["command"]));
+        let wrong_tool = ToolCall {
+            name: crate::tmux::SEND_KEYS_TOOL.to_string(),
+            ..call.clone()
+        };
+        assert!(matches!(
+            tool.execute(&wrong_tool).await,
+            Err(TmuxError::InvalidToolCall(_))
+        ));
+
+        let missing = tool.run("ls /does-not-exist").await.unwrap();
+        assert_eq!(missing.exit_code, Some(2));
+        assert!(missing.truncated);
+        assert_eq!(missing.output.len(), 12);
+
+        let slow = tool.run("sleep 5").await.unwrap();
+        assert!(slow.timed_out);
+        assert_eq!(slow.exit_code, None);
+        // The pane is usable again after the interrupt
+        let after = tool.run("echo after").await.unwrap();
+        assert_eq!(after.output, "after");
+
+        assert!(matches!(
+            tool.run("echo hi; rm -rf scratch").await,
+            Err(TmuxError::CommandNotAllowed { .. })
+        ));
+    }
+}
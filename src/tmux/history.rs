@@ -0,0 +1,364 @@
+//! Reading what a pane has written since a remembered line, through its history, so output that
+//! scrolls off the screen between reads isn't missed
+use super::{run, CaptureLimit, Pane, TmuxError, TmuxResult};
+
+/// Lines before a cursor's row that must still be there for nothing to have been missed
+const ANCHOR_LINES: usize = 3;
+/// Extra lines captured before a cursor, for output written between finding where the cursor is
+/// and capturing from it
+const RACE_MARGIN_LINES: usize = 100;
+/// Fields are tab separated
+const HISTORY_FORMAT: &str = "#{history_size}\t#{cursor_y}\t#{alternate_on}";
+/// Put before lines read after a gap by `HistoryCapture::render`
+pub const HISTORY_GAP_MARKER: &str =
+    "[Output was lost here, it left the pane's history before it could be read]";
+
+/// Where a pane's history & cursor were when it was captured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct HistoryState {
+    size: usize,
+    cursor_y: usize,
+    /// A full screen program is showing the alternate screen, which has no history
+    alternate: bool,
+}
+
+impl HistoryState {
+    fn parse(output: &str) -> Option<Self> {
+        let fields: Vec<&str> = output.trim_end_matches('\n').split('\t').collect();
+        match fields.as_slice() {
+            [size, cursor_y, alternate] => Some(Self {
+                size: size.parse().ok()?,
+                cursor_y: cursor_y.parse().ok()?,
+                alternate: *alternate == "1",
+            }),
+            _ => None,
+        }
+    }
+
+    /// Row of the cursor's line, counting from the oldest line of history. Lines above it are
+    /// complete, its own line may still be written to
+    fn end(&self) -> usize {
+        self.size + self.cursor_y
+    }
+}
+
+/// Rows captured from `first` on, counting from the oldest line of history
+struct CapturedRows {
+    first: usize,
+    rows: Vec<String>,
+    state: HistoryState,
+}
+
+impl CapturedRows {
+    /// The complete rows, those above the cursor's line
+    fn complete(&self) -> &[String] {
+        let len = self.state.end().saturating_sub(self.first);
+        &self.rows[..len.min(self.rows.len())]
+    }
+}
+
+/// What has been read of a pane's history, see `Pane::capture_since`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryCursor {
+    /// Row of the first unread line, counting from the oldest line of history when it was read
+    next_row: usize,
+    /// The last lines read, which have to still be right before `next_row`
+    anchor: Vec<String>,
+    limit: Option<CaptureLimit>,
+}
+
+impl HistoryCursor {
+    /// A cursor at the start of the history, so the first read returns all of it
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    /// Cut what each read returns down to `limit`
+    pub fn with_limit(mut self, limit: CaptureLimit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Moves past `rows`, the last of which ends before `end`
+    fn advance(&mut self, rows: &[String], end: usize) {
+        let anchor = &rows[rows.len().saturating_sub(ANCHOR_LINES)..];
+        self.anchor = anchor.to_vec();
+        self.next_row = end;
+    }
+}
+
+/// Lines read from a pane's history
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryCapture {
+    /// Complete lines written since the last read. The line the pane's cursor is on is left
+    /// for the next read, since it may still be written to
+    pub content: String,
+    /// Lines were evicted from the history, by tmux's `history-limit`, before they were read.
+    /// `content` is what was left
+    pub gap: bool,
+}
+
+impl HistoryCapture {
+    pub fn is_empty(&self) -> bool {
+        self.content.is_empty() && !self.gap
+    }
+
+    /// The content, after `HISTORY_GAP_MARKER` if there was a gap
+    pub fn render(&self) -> String {
+        match self.gap {
+            true if self.content.is_empty() => HISTORY_GAP_MARKER.to_string(),
+            true => format!("{}\n{}", HISTORY_GAP_MARKER, self.content),
+            false => self.content.to_owned(),
+        }
+    }
+}
+
+impl Pane {
+    /// A cursor at the line the pane's cursor is on, so reads return what is written after now
+    pub async fn history_cursor(&self) -> TmuxResult<HistoryCursor> {
+        let state = self.history_state().await?;
+        let start = state.end().saturating_sub(ANCHOR_LINES + RACE_MARGIN_LINES);
+        let captured = self
+            .capture_rows(Some(start as i64 - state.size as i64))
+            .await?;
+        let mut cursor = HistoryCursor::start();
+        cursor.advance(captured.complete(), captured.state.end());
+        Ok(cursor)
+    }
+
+    /// Complete lines written since `cursor`, which is moved past them. Lines that scrolled off
+    /// the screen are read from the history, and if some were evicted from it first, the
+    /// capture reports a gap. Nothing is read while a full screen program is showing
+    pub async fn capture_since(&self, cursor: &mut HistoryCursor) -> TmuxResult<HistoryCapture> {
+        let state = self.history_state().await?;
+        if state.alternate {
+            return Ok(HistoryCapture::default());
+        }
+        let anchor_start = cursor.next_row.saturating_sub(cursor.anchor.len());
+        let start = anchor_start.saturating_sub(RACE_MARGIN_LINES);
+        let captured = self
+            .capture_rows(Some(start as i64 - state.size as i64))
+            .await?;
+        let (captured, new_from, gap) = match self.find_cursor(cursor, &captured) {
+            Some(row) => (captured, row, false),
+            // Lines were evicted, or the history was cleared, so the cursor has moved up or is
+            // gone. The whole history is searched for it
+            None => {
+                let captured = self.capture_rows(None).await?;
+                match self.find_cursor(cursor, &captured) {
+                    Some(row) => (captured, row, false),
+                    None => (captured, 0, true),
+                }
+            }
+        };
+        let rows = captured.complete();
+        let content = rows[new_from.min(rows.len())..].join("\n");
+        let content = match cursor.limit {
+            Some(limit) => limit.apply(&content).to_owned(),
+            None => content,
+        };
+        cursor.advance(rows, captured.state.end());
+        Ok(HistoryCapture { content, gap })
+    }
+
+    /// Index in `captured`'s rows of the first unread line, the latest at or before the
+    /// cursor's row that follows its anchor
+    fn find_cursor(&self, cursor: &HistoryCursor, captured: &CapturedRows) -> Option<usize> {
+        let rows = captured.complete();
+        let k = cursor.anchor.len();
+        let latest = cursor.next_row.checked_sub(captured.first)?.min(rows.len());
+        (k..=latest)
+            .rev()
+            .find(|end| rows[end - k..*end] == cursor.anchor[..])
+    }
+
+    async fn history_state(&self) -> TmuxResult<HistoryState> {
+        let output = run(
+            self.socket.as_deref(),
+            &["display-message", "-p", "-t", &self.id, HISTORY_FORMAT],
+        )
+        .await
+        .map_err(|failure| self.gone(failure))?;
+        HistoryState::parse(&output).ok_or_else(|| {
+            TmuxError::Command(format!(
+                "unexpected display-message output: {}",
+                output.trim()
+            ))
+        })
+    }
+
+    /// Captures from `start`, relative to the first visible line, to the bottom of the screen,
+    /// with the history's state in the same command so the rows can't move in between. `None`
+    /// starts from the oldest line of history
+    async fn capture_rows(&self, start: Option<i64>) -> TmuxResult<CapturedRows> {
+        let start_arg = start.map_or("-".to_string(), |start| start.to_string());
+        let output = run(
+            self.socket.as_deref(),
+            &[
+                "capture-pane",
+                "-p",
+                "-t",
+                &self.id,
+                "-S",
+                &start_arg,
+                "-E",
+                "-",
+                ";",
+                "display-message",
+                "-p",
+                "-t",
+                &self.id,
+                HISTORY_FORMAT,
+            ],
+        )
+        .await
+        .map_err(|failure| self.gone(failure))?;
+        let unexpected = || {
+            TmuxError::Command(format!(
+                "unexpected capture-pane output ending: {}",
+                output.lines().last().unwrap_or_default()
+            ))
+        };
+        let (capture, state) = output
+            .trim_end_matches('\n')
+            .rsplit_once('\n')
+            .ok_or_else(unexpected)?;
+        let state = HistoryState::parse(state).ok_or_else(unexpected)?;
+        // Starts before the oldest line of history are clamped to it
+        let first = start.map_or(0, |start| (state.size as i64 + start).max(0) as usize);
+        Ok(CapturedRows {
+            first,
+            rows: capture.split('\n').map(str::to_owned).collect(),
+            state,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmux::{tests::TestServer, KeepEnd, KeysInput, SpecialKey};
+
+    #[test]
+    fn gaps_marked() {
+        let capture = |content: &str, gap| HistoryCapture {
+            content: content.to_string(),
+            gap,
+        };
+        assert_eq!(capture("a\nb", false).render(), "a\nb");
+        assert_eq!(
+            capture("b", true).render(),
+            format!("{}\nb", HISTORY_GAP_MARKER)
+        );
+        assert!(capture("", false).is_empty());
+        assert!(!capture("", true).is_empty());
+        assert_eq!(
+            HistoryState::parse("2000\t23\t0\n"),
+            Some(HistoryState {
+                size: 2000,
+                cursor_y: 23,
+                alternate: false
+            })
+        );
+    }
+
+    /// Waits for the pane's history to end with a line containing `line`
+    async fn wait_for(pane: &Pane, line: &str) {
+        for _ in 0..250 {
+            if pane.capture_tail(2).await.unwrap().contains(line) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("{} never appeared", line);
+    }
+
+    #[tokio::test]
+    async fn reads_scrolled_output_incrementally() {
+        let Some(server) =
+            TestServer::start("seq 1 50; read; seq 51 150; read; seq 151 155; sleep 30").await
+        else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        assert_eq!(pane.history_limit, 2000);
+        wait_for(&pane, "50").await;
+        let mut cursor = HistoryCursor::start();
+        let first = pane.capture_since(&mut cursor).await.unwrap();
+        let expected: Vec<String> = (1..=50).map(|n| n.to_string()).collect();
+        assert_eq!(first.content, expected.join("\n"));
+        assert!(!first.gap);
+        assert!(pane.capture_since(&mut cursor).await.unwrap().is_empty());
+
+        // Far more than a screen is written between reads
+        let mut limited = pane
+            .history_cursor()
+            .await
+            .unwrap()
+            .with_limit(CaptureLimit::bytes(8, KeepEnd::Tail));
+        pane.send_keys(KeysInput::key(SpecialKey::Enter))
+            .await
+            .unwrap();
+        wait_for(&pane, "150").await;
+        let second = pane.capture_since(&mut cursor).await.unwrap();
+        let expected: Vec<String> = std::iter::once(String::new())
+            .chain((51..=150).map(|n| n.to_string()))
+            .collect();
+        assert_eq!(second.content, expected.join("\n"));
+        assert_eq!(
+            pane.capture_since(&mut limited).await.unwrap().content,
+            "149\n150"
+        );
+
+        pane.send_keys(KeysInput::key(SpecialKey::Enter))
+            .await
+            .unwrap();
+        wait_for(&pane, "155").await;
+        assert_eq!(
+            pane.capture_since(&mut cursor).await.unwrap().content,
+            "\n151\n152\n153\n154\n155"
+        );
+    }
+
+    #[tokio::test]
+    async fn evicted_cursor_reported_as_gap() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        // The option only applies to panes created after it is set
+        run(
+            Some(&server.socket),
+            &["set-option", "-g", "history-limit", "100"],
+        )
+        .await
+        .map_err(|_| "set-option failed")
+        .unwrap();
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap()
+            .split(
+                Default::default(),
+                "read; seq 1 30; read; seq 31 400; sleep 30",
+            )
+            .await
+            .unwrap();
+        assert_eq!(pane.history_limit, 100);
+        pane.send_keys(KeysInput::key(SpecialKey::Enter))
+            .await
+            .unwrap();
+        wait_for(&pane, "30").await;
+        let mut cursor = pane.history_cursor().await.unwrap();
+
+        pane.send_keys(KeysInput::key(SpecialKey::Enter))
+            .await
+            .unwrap();
+        wait_for(&pane, "400").await;
+        let capture = pane.capture_since(&mut cursor).await.unwrap();
+        assert!(capture.gap);
+        assert!(capture.content.ends_with("399\n400"));
+        assert!(!capture.content.contains("\n31\n"));
+        assert!(capture.render().starts_with(HISTORY_GAP_MARKER));
+    }
+}
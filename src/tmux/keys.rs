@@ -0,0 +1,273 @@
+//! Typing into panes with `tmux send-keys`
+use super::{run, Pane, TmuxError, TmuxResult};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, time::Duration};
+
+/// A key that is sent by name rather than typed as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SpecialKey {
+    Enter,
+    Escape,
+    Tab,
+    Backspace,
+    Up,
+    Down,
+    Left,
+    Right,
+    /// Control plus a key, like `Ctrl('c')`
+    Ctrl(char),
+}
+
+impl SpecialKey {
+    /// The key's name as understood by `tmux send-keys`
+    pub fn tmux_name(&self) -> String {
+        match self {
+            Self::Enter => "Enter".to_string(),
+            Self::Escape => "Escape".to_string(),
+            Self::Tab => "Tab".to_string(),
+            Self::Backspace => "BSpace".to_string(),
+            Self::Up => "Up".to_string(),
+            Self::Down => "Down".to_string(),
+            Self::Left => "Left".to_string(),
+            Self::Right => "Right".to_string(),
+            Self::Ctrl(c) => format!("C-{}", c),
+        }
+    }
+}
+
+impl FromStr for SpecialKey {
+    type Err = TmuxError;
+    /// Parses tmux key names, like `Enter`, `BSpace` or `C-c`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = match s {
+            "Enter" => Self::Enter,
+            "Escape" => Self::Escape,
+            "Tab" => Self::Tab,
+            "BSpace" => Self::Backspace,
+            "Up" => Self::Up,
+            "Down" => Self::Down,
+            "Left" => Self::Left,
+            "Right" => Self::Right,
+            _ => {
+                let mut chars = s.strip_prefix("C-").unwrap_or_default().chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Self::Ctrl(c),
+                    _ => return Err(TmuxError::UnknownKey(s.to_owned())),
+                }
+            }
+        };
+        Ok(key)
+    }
+}
+
+impl TryFrom<String> for SpecialKey {
+    type Error = TmuxError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<SpecialKey> for String {
+    fn from(value: SpecialKey) -> Self {
+        value.tmux_name()
+    }
+}
+
+/// One `send-keys` invocation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeysBatch {
+    /// Typed as is, key names in it aren't interpreted
+    Text(String),
+    Key(SpecialKey),
+}
+
+/// Input for `Pane::send_keys`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeysInput {
+    pub batches: Vec<KeysBatch>,
+    /// Press enter after the last batch
+    pub enter: bool,
+    /// Pause between batches, for programs that drop input when flooded. Zero by default
+    pub batch_delay: Duration,
+}
+
+impl KeysInput {
+    /// Literal text followed by enter
+    pub fn text(text: &str) -> Self {
+        Self {
+            batches: vec![KeysBatch::Text(text.to_owned())],
+            enter: true,
+            batch_delay: Duration::ZERO,
+        }
+    }
+
+    /// A single key, without enter
+    pub fn key(key: SpecialKey) -> Self {
+        Self {
+            batches: vec![KeysBatch::Key(key)],
+            enter: false,
+            batch_delay: Duration::ZERO,
+        }
+    }
+
+    pub fn then_text(mut self, text: &str) -> Self {
+        self.batches.push(KeysBatch::Text(text.to_owned()));
+        self
+    }
+
+    pub fn then_key(mut self, key: SpecialKey) -> Self {
+        self.batches.push(KeysBatch::Key(key));
+        self
+    }
+
+    /// Don't press enter after the last batch
+    pub fn without_enter(mut self) -> Self {
+        self.enter = false;
+        self
+    }
+
+    pub fn with_batch_delay(mut self, delay: Duration) -> Self {
+        self.batch_delay = delay;
+        self
+    }
+
+    /// Every batch to send, including the trailing enter
+    fn all_batches(&self) -> Vec<KeysBatch> {
+        let mut batches = self.batches.clone();
+        if self.enter {
+            batches.push(KeysBatch::Key(SpecialKey::Enter));
+        }
+        batches
+    }
+}
+
+/// tmux treats an argument ending in `;` as a command separator, even when it is passed as its
+/// own argv entry, so a trailing semicolon is escaped. tmux removes the backslash
+fn escape_literal(text: &str) -> String {
+    match text.strip_suffix(';') {
+        Some(rest) => format!("{}\\;", rest),
+        None => text.to_owned(),
+    }
+}
+
+impl Pane {
+    /// Sends `input` to the pane. Text is typed literally, `--` keeps text starting with a dash
+    /// from being read as a flag. This doesn't ask for confirmation, see `PaneActor` &
+    /// `SendKeysTool` for gated sending
+    pub async fn send_keys(&self, input: KeysInput) -> TmuxResult<()> {
+        let socket = self.socket.as_deref();
+        for (i, batch) in input.all_batches().into_iter().enumerate() {
+            if i > 0 && !input.batch_delay.is_zero() {
+                tokio::time::sleep(input.batch_delay).await;
+            }
+            let result = match batch {
+                KeysBatch::Text(text) if text.is_empty() => continue,
+                KeysBatch::Text(text) => {
+                    let text = escape_literal(&text);
+                    run(socket, &["send-keys", "-t", &self.id, "-l", "--", &text]).await
+                }
+                KeysBatch::Key(key) => {
+                    run(socket, &["send-keys", "-t", &self.id, &key.tmux_name()]).await
+                }
+            };
+            result.map_err(|failure| self.gone(failure))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmux::{tests::TestServer, CaptureOpts};
+    use std::time::Instant;
+
+    /// Non empty lines of the pane, once there are at least `count` of them or a second passes
+    async fn wait_for_lines(pane: &Pane, count: usize) -> Vec<String> {
+        let mut lines = vec![];
+        for _ in 0..50 {
+            let content = pane.capture(CaptureOpts::default()).await.unwrap();
+            lines = content
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(|l| l.to_owned())
+                .collect();
+            if lines.len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        lines
+    }
+
+    #[test]
+    fn trailing_semicolons_escaped() {
+        assert_eq!(escape_literal("ls; pwd"), "ls; pwd");
+        assert_eq!(escape_literal("ls;"), "ls\\;");
+        assert_eq!(escape_literal(";"), "\\;");
+        assert_eq!(escape_literal("a\\;"), "a\\\\;");
+    }
+
+    #[test]
+    fn special_keys_round_trip_through_tmux_names() {
+        for key in [
+            SpecialKey::Backspace,
+            SpecialKey::Ctrl('c'),
+            SpecialKey::Enter,
+        ] {
+            assert_eq!(key.tmux_name().parse::<SpecialKey>().unwrap(), key);
+        }
+        assert!(matches!(
+            "C-".parse::<SpecialKey>(),
+            Err(TmuxError::UnknownKey(_))
+        ));
+        let batch: KeysBatch = serde_json::from_str(r#"{"key": "C-d"}"#).unwrap();
+        assert_eq!(batch, KeysBatch::Key(SpecialKey::Ctrl('d')));
+    }
+
+    #[tokio::test]
+    async fn footgun_text_typed_literally() {
+        let Some(server) = TestServer::start("cat").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let lines = [
+            "ends with;",
+            ";",
+            "a; b",
+            "it's \"quoted\"",
+            "-n leading dash",
+            "--",
+            "back\\;",
+            "C-c",
+        ];
+        let delay = Duration::from_millis(50);
+        let start = Instant::now();
+        let mut input = KeysInput::text(lines[0]).with_batch_delay(delay);
+        for line in &lines[1..] {
+            input = input.then_key(SpecialKey::Enter).then_text(line);
+        }
+        pane.send_keys(input).await.unwrap();
+        assert!(start.elapsed() >= delay * (lines.len() as u32 * 2 - 1));
+
+        // cat echoes each line back after the terminal's echo, so every line appears twice
+        let expected: Vec<&str> = lines.iter().flat_map(|l| [*l, *l]).collect();
+        assert_eq!(wait_for_lines(&pane, expected.len()).await, expected);
+
+        // Without enter the text is left on the line, where C-u erases it
+        pane.send_keys(KeysInput::text("unsent").without_enter())
+            .await
+            .unwrap();
+        let content = wait_for_lines(&pane, expected.len() + 1).await;
+        assert_eq!(content.last().map(|l| l.as_str()), Some("unsent"));
+        pane.send_keys(KeysInput::key(SpecialKey::Ctrl('u')))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(wait_for_lines(&pane, expected.len()).await, expected);
+    }
+}
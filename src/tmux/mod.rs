@@ -0,0 +1,622 @@
+//! Bridge from tmux panes into agent memory, by shelling out to the `tmux` binary
+mod actor;
+pub mod ansi;
+mod commentary;
+mod control;
+mod diff;
+mod display;
+pub mod error;
+mod exec;
+mod history;
+mod keys;
+mod monitor;
+mod multi;
+mod output;
+pub mod recording;
+mod select;
+mod server;
+mod silence;
+mod throttle;
+mod tool;
+mod watcher;
+mod window;
+use crate::agents::{
+    memory::{Message, MessageRole, ToMessage, CHARS_PER_TOKEN},
+    Agent,
+};
+pub use actor::{
+    ConfirmSend, PaneActor, SendCommandArgs, SendKeysArgs, SendKeysTool, SendOutcome,
+    SEND_COMMAND_TOOL, SEND_KEYS_TOOL,
+};
+use ansi::NormalizeOpts;
+pub use commentary::{CommentaryPane, PaneSink, SplitOpts};
+pub use control::{ControlClient, ControlConnection, ControlEvent, ControlOpts, ControlStream};
+pub use diff::{
+    DiffPrompt, PaneDelta, PaneDiffer, DEFAULT_CONTEXT_LINES, DEFAULT_INITIAL_TAIL_LINES,
+};
+pub use display::{DisplayOpts, DisplayTarget, StreamDisplay, DEFAULT_STATUS_OPTION};
+pub use error::{TmuxError, TmuxResult};
+pub use exec::{
+    ExecArgs, ExecOutput, ExecTool, DEFAULT_EXEC_TIMEOUT, DEFAULT_MAX_OUTPUT_BYTES, EXEC_TOOL,
+};
+pub use history::{HistoryCapture, HistoryCursor, HISTORY_GAP_MARKER};
+pub use keys::{KeysBatch, KeysInput, SpecialKey};
+pub use monitor::{
+    Monitor, MonitorConfig, MonitorHandle, MonitorInfo, MonitorMetrics, Monitors, SnapshotMode,
+};
+pub use multi::{MonitoredPane, MonitoredPaneOpts, MultiPaneMonitor, MultiPaneMonitorHandle};
+pub use output::{OutputStreamOpts, PaneChunk, PaneOutputStream};
+pub use select::{PaneMatch, ResolvePolicy};
+use serde::{Deserialize, Serialize};
+pub use server::{ListedPane, ServerState, Session, Tmux, Window};
+pub use silence::{
+    SilenceAction, SilenceAlert, SilenceDetector, SilenceDetectorHandle, SilenceRule, SilenceState,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+pub use throttle::{Debounce, MonitorThrottle, ThrottleEvent};
+use tokio::process::Command;
+pub use tokio_util::sync::CancellationToken;
+pub use tool::{PaneTool, PaneToolFuture, TOOL_CALL_ID_METADATA_KEY, TOOL_ROLE_ALIAS};
+pub use watcher::{
+    SharedAgent, WatchAction, WatchMatch, WatchPattern, WatchThrottle, Watcher, WatcherHandle,
+    WatcherInfo, Watchers, DEFAULT_WATCH_TEMPLATE,
+};
+pub use window::{
+    BindingEvent, BoundWindow, WindowBinding, WindowBindingHandle, WindowBindingOpts,
+};
+
+const TMUX_BIN: &str = "tmux";
+/// Format passed to `display-message` to resolve a target, fields are tab separated
+const PANE_FORMAT: &str =
+    "#{session_name}\t#{window_index}\t#{pane_index}\t#{pane_id}\t#{history_limit}";
+
+/// Metadata keys set on messages made from pane captures
+pub const PANE_TARGET_METADATA_KEY: &str = "tmux_target";
+pub const PANE_ID_METADATA_KEY: &str = "tmux_pane_id";
+pub const CAPTURED_AT_METADATA_KEY: &str = "captured_at_ms";
+/// Set on messages from `MultiPaneMonitor`. Combined messages join the labels, targets & ids of
+/// their panes with commas
+pub const PANE_LABEL_METADATA_KEY: &str = "tmux_pane_label";
+/// Set on monitor snapshots to the capture before normalizing, see
+/// `MonitorConfig::with_raw_capture`
+pub const RAW_CAPTURE_METADATA_KEY: &str = "tmux_raw_capture";
+
+/// A resolved tmux pane
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pane {
+    pub session: String,
+    pub window: u32,
+    pub index: u32,
+    /// tmux's unique id for the pane, like `%3`. Panes are addressed by it after resolving, so
+    /// a pane that closes is reported as gone rather than another pane being captured
+    pub id: String,
+    /// Name of the tmux server socket, as passed to `tmux -L`. `None` is the default server
+    #[serde(default)]
+    pub socket: Option<String>,
+    /// Lines of history the pane keeps, its `history-limit` when it was created. Older lines are
+    /// evicted as new ones scroll off the screen
+    #[serde(default)]
+    pub history_limit: usize,
+}
+
+/// Options for `Pane::capture`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaptureOpts {
+    /// Include escape sequences for text & background attributes
+    pub escape_sequences: bool,
+    /// Join wrapped lines, preserving trailing spaces
+    pub join_wrapped: bool,
+    /// First line to capture. 0 is the first visible line, negative numbers are lines of
+    /// history. Defaults to the first visible line
+    pub start: Option<i32>,
+    /// Last line to capture, defaults to the last visible line
+    pub end: Option<i32>,
+    /// Normalize captures with `ansi::normalize`, most useful with `escape_sequences`
+    pub normalize: Option<NormalizeOpts>,
+    /// Cut captures down to a number of bytes or tokens, after normalizing
+    pub limit: Option<CaptureLimit>,
+}
+
+/// Which end of a capture is kept when it is cut down to its `CaptureLimit`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeepEnd {
+    Head,
+    /// The most recent output, the default
+    #[default]
+    Tail,
+}
+
+/// A hard cap on the size of a capture. Captures are cut at a line boundary where there is one,
+/// otherwise mid line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureLimit {
+    pub max_bytes: Option<usize>,
+    /// Estimated like `MonitorConfig::max_snapshot_tokens`
+    pub max_tokens: Option<usize>,
+    pub keep: KeepEnd,
+}
+
+impl CaptureLimit {
+    pub fn bytes(max_bytes: usize, keep: KeepEnd) -> Self {
+        Self {
+            max_bytes: Some(max_bytes),
+            max_tokens: None,
+            keep,
+        }
+    }
+
+    pub fn tokens(max_tokens: usize, keep: KeepEnd) -> Self {
+        Self {
+            max_bytes: None,
+            max_tokens: Some(max_tokens),
+            keep,
+        }
+    }
+
+    /// `content` cut down to the limit
+    pub fn apply<'c>(&self, content: &'c str) -> &'c str {
+        let max_chars = self.max_tokens.map(|tokens| tokens * CHARS_PER_TOKEN);
+        let chars = content.chars().count();
+        match self.keep {
+            KeepEnd::Head => {
+                let mut end = content.len();
+                if let Some(max_bytes) = self.max_bytes.filter(|max| *max < end) {
+                    end = (0..=max_bytes)
+                        .rev()
+                        .find(|i| content.is_char_boundary(*i))
+                        .unwrap_or(0);
+                }
+                if let Some(max_chars) = max_chars.filter(|max| *max < chars) {
+                    let by_chars = content.char_indices().nth(max_chars).map(|(i, _)| i);
+                    end = end.min(by_chars.unwrap_or(content.len()));
+                }
+                if end == content.len() || content[end..].starts_with('\n') {
+                    return &content[..end];
+                }
+                match content[..end].rfind('\n') {
+                    Some(line_end) => &content[..line_end],
+                    None => &content[..end],
+                }
+            }
+            KeepEnd::Tail => {
+                let mut start = 0;
+                if let Some(max_bytes) = self.max_bytes.filter(|max| *max < content.len()) {
+                    start = (content.len() - max_bytes..=content.len())
+                        .find(|i| content.is_char_boundary(*i))
+                        .unwrap_or(content.len());
+                }
+                if let Some(max_chars) = max_chars.filter(|max| *max < chars) {
+                    let by_chars = content
+                        .char_indices()
+                        .nth(chars - max_chars)
+                        .map(|(i, _)| i);
+                    start = start.max(by_chars.unwrap_or(content.len()));
+                }
+                if start == 0 || content[..start].ends_with('\n') {
+                    return &content[start..];
+                }
+                let tail = &content[start..];
+                match tail.find('\n') {
+                    Some(line_start) if line_start + 1 < tail.len() => &tail[line_start + 1..],
+                    _ => tail,
+                }
+            }
+        }
+    }
+}
+
+impl CaptureOpts {
+    /// The visible screen & the last `lines` lines of history above it
+    pub fn history(lines: usize) -> Self {
+        Self {
+            start: Some(-i32::try_from(lines).unwrap_or(i32::MAX)),
+            ..Default::default()
+        }
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if self.escape_sequences {
+            args.push("-e".to_string());
+        }
+        if self.join_wrapped {
+            args.push("-J".to_string());
+        }
+        if let Some(start) = self.start {
+            args.push("-S".to_string());
+            args.push(start.to_string());
+        }
+        if let Some(end) = self.end {
+            args.push("-E".to_string());
+            args.push(end.to_string());
+        }
+        args
+    }
+
+    /// Applies `normalize` & `limit` to a capture taken with these options
+    fn finish(&self, capture: String) -> String {
+        let capture = match self.normalize {
+            Some(opts) => ansi::normalize(&capture, opts),
+            None => capture,
+        };
+        match self.limit {
+            Some(limit) => limit.apply(&capture).to_owned(),
+            None => capture,
+        }
+    }
+}
+
+/// Why a tmux command failed
+enum Failure {
+    CantFind(String),
+    Other(TmuxError),
+}
+
+/// Runs tmux with `args`, returning its stdout
+async fn run(socket: Option<&str>, args: &[&str]) -> Result<String, Failure> {
+    let mut command = Command::new(TMUX_BIN);
+    if let Some(socket) = socket {
+        command.args(["-L", socket]);
+    }
+    let output = command.args(args).output().await.map_err(|err| {
+        Failure::Other(match err.kind() {
+            std::io::ErrorKind::NotFound => TmuxError::BinaryNotFound,
+            _ => TmuxError::Io(err),
+        })
+    })?;
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    Err(classify_stderr(stderr))
+}
+
+/// `text` quoted for `sh`
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+/// Milliseconds since the unix epoch
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn classify_stderr(stderr: String) -> Failure {
+    // A server that is shutting down as the command runs reports that it exited unexpectedly
+    const NO_SERVER_PREFIXES: [&str; 3] = [
+        "no server running",
+        "error connecting to",
+        "server exited unexpectedly",
+    ];
+    if NO_SERVER_PREFIXES.iter().any(|p| stderr.starts_with(p)) {
+        return Failure::Other(TmuxError::NoServer);
+    }
+    // Reported instead of "can't find" when the server has no sessions left
+    if stderr.starts_with("can't find") || stderr.starts_with("no current target") {
+        return Failure::CantFind(stderr);
+    }
+    Failure::Other(TmuxError::Command(stderr))
+}
+
+impl Pane {
+    /// Resolves a tmux target, like `session:window.pane` or `%3`, on the default server
+    pub async fn resolve(target: &str) -> TmuxResult<Self> {
+        Self::resolve_on(None, target).await
+    }
+
+    /// Resolves a tmux target on the server with the given socket name
+    pub async fn resolve_on(socket: Option<&str>, target: &str) -> TmuxResult<Self> {
+        let invalid_target = |failure| match failure {
+            Failure::CantFind(message) => TmuxError::InvalidTarget {
+                target: target.to_owned(),
+                message,
+            },
+            Failure::Other(err) => err,
+        };
+        // display-message falls back to the current pane when a target doesn't exist, so the
+        // target is checked first with a command that doesn't
+        run(
+            socket,
+            &["capture-pane", "-p", "-t", target, "-S", "0", "-E", "0"],
+        )
+        .await
+        .map_err(invalid_target)?;
+        let output = run(
+            socket,
+            &["display-message", "-p", "-t", target, PANE_FORMAT],
+        )
+        .await
+        .map_err(invalid_target)?;
+        Self::parse_format(socket, &output).ok_or_else(|| TmuxError::InvalidTarget {
+            target: target.to_owned(),
+            message: format!("unexpected display-message output: {}", output.trim()),
+        })
+    }
+
+    /// Parses a pane printed with `PANE_FORMAT`
+    fn parse_format(socket: Option<&str>, output: &str) -> Option<Self> {
+        let fields: Vec<&str> = output.trim_end_matches('\n').split('\t').collect();
+        match fields.as_slice() {
+            [session, window, index, id, history_limit] => Some(Self {
+                session: session.to_string(),
+                window: window.parse().ok()?,
+                index: index.parse().ok()?,
+                id: id.to_string(),
+                socket: socket.map(|s| s.to_owned()),
+                history_limit: history_limit.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The `session:window.pane` target of this pane
+    pub fn target(&self) -> String {
+        format!("{}:{}.{}", self.session, self.window, self.index)
+    }
+
+    /// Captures the pane's contents with `tmux capture-pane -p`
+    pub async fn capture(&self, opts: CaptureOpts) -> TmuxResult<String> {
+        let opt_args = opts.args();
+        let mut args = vec!["capture-pane", "-p", "-t", &self.id];
+        args.extend(opt_args.iter().map(|a| a.as_str()));
+        run(self.socket.as_deref(), &args)
+            .await
+            .map(|capture| opts.finish(capture))
+            .map_err(|failure| self.gone(failure))
+    }
+
+    /// The last `lines` lines of the pane's content, reaching into its history when the screen
+    /// holds fewer. Blank lines below the content aren't counted, and panes with fewer lines
+    /// return all they have
+    pub async fn capture_tail(&self, lines: usize) -> TmuxResult<String> {
+        if lines == 0 {
+            return Ok(String::new());
+        }
+        let capture = self.capture(CaptureOpts::history(lines)).await?;
+        Ok(tail_lines(&capture, lines).to_owned())
+    }
+
+    /// Maps a failure to find the pane, or its server, to `TmuxError::PaneGone`
+    fn gone(&self, failure: Failure) -> TmuxError {
+        match failure {
+            Failure::CantFind(_) | Failure::Other(TmuxError::NoServer) => TmuxError::PaneGone {
+                id: self.id.to_owned(),
+            },
+            Failure::Other(err) => err,
+        }
+    }
+
+    /// Captures the pane into a message, with metadata recording the pane & capture time
+    pub async fn capture_message(
+        &self,
+        role: MessageRole,
+        opts: CaptureOpts,
+    ) -> TmuxResult<Message> {
+        let content = self.capture(opts).await?;
+        Ok(self.message(&content, role, now_ms()))
+    }
+
+    /// A message of `content` from this pane, with metadata recording the pane & capture time
+    fn message(&self, content: &str, role: MessageRole, captured_at: u64) -> Message {
+        content
+            .to_owned()
+            .to_message(role)
+            .with_metadata(PANE_TARGET_METADATA_KEY, &self.target())
+            .with_metadata(PANE_ID_METADATA_KEY, &self.id)
+            .with_metadata(CAPTURED_AT_METADATA_KEY, &captured_at.to_string())
+    }
+}
+
+/// Resolves `target` on the default server & captures its last `lines` lines, see
+/// `Pane::capture_tail`
+pub async fn capture_pane_tail(target: &str, lines: usize) -> TmuxResult<String> {
+    Pane::resolve(target).await?.capture_tail(lines).await
+}
+
+/// The last `lines` lines of `content`, ignoring trailing whitespace
+fn tail_lines(content: &str, lines: usize) -> &str {
+    if lines == 0 {
+        return "";
+    }
+    let content = content.trim_end();
+    match content.rmatch_indices('\n').nth(lines - 1) {
+        Some((i, _)) => &content[i + 1..],
+        None => content,
+    }
+}
+
+impl Agent {
+    /// Captures `pane` and pushes it to the cache as a message with the given role
+    pub async fn push_pane_capture(
+        &mut self,
+        pane: &Pane,
+        role: MessageRole,
+        opts: CaptureOpts,
+    ) -> TmuxResult<()> {
+        let message = pane.capture_message(role, opts).await?;
+        self.cache.push(message);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::language_models::completions::CompletionModel;
+
+    /// A tmux server on its own socket, killed on drop
+    pub(crate) struct TestServer {
+        pub(crate) socket: String,
+    }
+
+    impl TestServer {
+        /// Starts a server with a detached session running `command`, `None` if tmux isn't
+        /// installed
+        pub(crate) async fn start(command: &str) -> Option<Self> {
+            let socket = format!("espionox-test-{}", uuid::Uuid::new_v4());
+            let started = Command::new(TMUX_BIN)
+                .args(["-L", &socket, "-f", "/dev/null", "new-session", "-d"])
+                .args(["-s", "test", "-x", "80", "-y", "24", command])
+                .status()
+                .await
+                .ok()?;
+            assert!(started.success(), "failed to start tmux server");
+            Some(Self { socket })
+        }
+
+        pub(crate) async fn kill(&self) {
+            let _ = Command::new(TMUX_BIN)
+                .args(["-L", &self.socket, "kill-server"])
+                .stderr(std::process::Stdio::null())
+                .status()
+                .await;
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::process::Command::new(TMUX_BIN)
+                .args(["-L", &self.socket, "kill-server"])
+                .stderr(std::process::Stdio::null())
+                .status();
+        }
+    }
+
+    #[test]
+    fn capture_opts_map_to_flags() {
+        let opts = CaptureOpts {
+            escape_sequences: true,
+            join_wrapped: true,
+            start: Some(-100),
+            end: Some(5),
+            normalize: Some(NormalizeOpts::default()),
+            limit: Some(CaptureLimit::bytes(10, KeepEnd::Tail)),
+        };
+        // Normalizing & limiting happen after capturing, there is no flag for it
+        assert_eq!(opts.args(), vec!["-e", "-J", "-S", "-100", "-E", "5"]);
+        assert!(CaptureOpts::default().args().is_empty());
+    }
+
+    #[test]
+    fn captures_cut_at_line_boundaries() {
+        let content = "one\ntwo\nthree";
+        assert_eq!(
+            CaptureLimit::bytes(9, KeepEnd::Tail).apply(content),
+            "two\nthree"
+        );
+        assert_eq!(
+            CaptureLimit::bytes(9, KeepEnd::Head).apply(content),
+            "one\ntwo"
+        );
+        assert_eq!(
+            CaptureLimit::bytes(7, KeepEnd::Head).apply(content),
+            "one\ntwo"
+        );
+        assert_eq!(CaptureLimit::bytes(3, KeepEnd::Tail).apply(content), "ree");
+        assert_eq!(
+            CaptureLimit::bytes(100, KeepEnd::Tail).apply(content),
+            content
+        );
+        assert_eq!(CaptureLimit::tokens(1, KeepEnd::Head).apply(content), "one");
+    }
+
+    #[test]
+    fn tail_taken_without_padding() {
+        assert_eq!(tail_lines("a\nb\nc\n\n\n", 2), "b\nc");
+        assert_eq!(tail_lines("a\nb\nc\n\n\n", 3), "a\nb\nc");
+        assert_eq!(tail_lines("a\nb", 10), "a\nb");
+        assert_eq!(tail_lines("a\nb", 0), "");
+        assert_eq!(tail_lines("\n\n", 2), "");
+    }
+
+    #[test]
+    fn quoted_text_passed_through_shell() {
+        let text = "/tmp/it's $HOME `id` \"x\"";
+        let output = std::process::Command::new("sh")
+            .args(["-c", &format!("printf %s {}", shell_quote(text))])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), text);
+    }
+
+    #[tokio::test]
+    async fn tail_captured_from_history() {
+        let Some(server) = TestServer::start("seq 1 50; sleep 30").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let mut tail = String::new();
+        for _ in 0..50 {
+            tail = pane.capture_tail(3).await.unwrap();
+            if tail == "48\n49\n50" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(tail, "48\n49\n50");
+        // More lines than the 24 row pane shows come from history, and asking for more than
+        // there are returns them all
+        let expected: Vec<String> = (1..=50).map(|n| n.to_string()).collect();
+        assert_eq!(
+            pane.capture_tail(40).await.unwrap(),
+            expected[10..].join("\n")
+        );
+        assert_eq!(pane.capture_tail(500).await.unwrap(), expected.join("\n"));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn pane_captured_into_agent_cache() {
+        let Some(server) = TestServer::start("echo hello from tmux; sleep 30").await else {
+            return;
+        };
+        let socket = Some(server.socket.as_str());
+        let pane = Pane::resolve_on(socket, "test:0.0").await.unwrap();
+        assert_eq!(pane.target(), "test:0.0");
+        assert!(pane.id.starts_with('%'));
+
+        let mut content = String::new();
+        for _ in 0..50 {
+            content = pane.capture(CaptureOpts::default()).await.unwrap();
+            if content.contains("hello from tmux") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(content.contains("hello from tmux"));
+
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        agent
+            .push_pane_capture(&pane, MessageRole::User, CaptureOpts::default())
+            .await
+            .unwrap();
+        let message = &agent.cache.as_ref()[0];
+        assert!(message.content.contains("hello from tmux"));
+        assert_eq!(message.metadata[PANE_TARGET_METADATA_KEY], "test:0.0");
+        assert_eq!(message.metadata[PANE_ID_METADATA_KEY], pane.id);
+        assert!(message.metadata.contains_key(CAPTURED_AT_METADATA_KEY));
+
+        assert!(matches!(
+            Pane::resolve_on(socket, "test:7").await,
+            Err(TmuxError::InvalidTarget { .. })
+        ));
+        assert!(matches!(
+            Pane::resolve_on(socket, "test:0.5").await,
+            Err(TmuxError::InvalidTarget { .. })
+        ));
+        server.kill().await;
+        assert!(matches!(
+            pane.capture(CaptureOpts::default()).await,
+            Err(TmuxError::PaneGone { .. })
+        ));
+        assert!(matches!(
+            Pane::resolve_on(socket, "test:0.0").await,
+            Err(TmuxError::NoServer)
+        ));
+    }
+}
@@ -0,0 +1,1031 @@
+//! Periodic pane snapshots pushed into an agent's cache
+use super::{
+    now_ms,
+    recording::{Recorder, RecordingOpts, TimestampedChunk},
+    throttle::{MonitorThrottle, ThrottleState, TickAction},
+    CaptureOpts, HistoryCursor, MessageRole, Pane, PaneDelta, PaneDiffer, PaneMatch, ResolvePolicy,
+    SharedAgent, TmuxError, TmuxResult, RAW_CAPTURE_METADATA_KEY,
+};
+use crate::agents::memory::{Message, CHARS_PER_TOKEN};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+use tracing::warn;
+
+/// Starts the message history is backfilled with
+const BACKFILL_HEADER: &str = "Scrollback of the pane from before it was monitored:";
+/// Tokens kept free beyond the backfill header, for the message's formatting
+const BACKFILL_MARGIN_TOKENS: usize = 8;
+
+/// What monitors push for each capture of a pane
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotMode {
+    /// The whole capture
+    #[default]
+    FullSnapshot,
+    /// Only what changed since the last capture, see `PaneDelta`. The first capture is pushed
+    /// whole
+    DeltaOnly,
+    /// Deltas, with the whole capture pushed in place of every this many deltas
+    DeltaWithPeriodicFull(usize),
+    /// The lines written since the last capture, read through the pane's history so output that
+    /// scrolled off the screen in between is kept, see `Pane::capture_since`. Captures are cut to
+    /// `CaptureOpts::limit` rather than taken with the rest of `capture_opts`
+    History,
+}
+
+/// What to snapshot, how often, and what to do with the snapshots
+#[derive(Debug, Clone)]
+pub struct MonitorConfig {
+    pub pane: Pane,
+    pub interval: Duration,
+    pub capture_opts: CaptureOpts,
+    /// Snapshots estimated to be longer than this many tokens are cut down to their end
+    pub max_snapshot_tokens: Option<usize>,
+    /// Skip snapshots that are the same as the last one pushed
+    pub dedup: bool,
+    /// Get an io completion after every this many pushed snapshots, and push the response
+    pub complete_every: Option<usize>,
+    pub mode: SnapshotMode,
+    /// Lines of history pushed with one completion before snapshotting starts
+    pub backfill_lines: Option<usize>,
+    /// Keep each snapshot's capture from before `CaptureOpts::normalize` in its metadata
+    pub raw_capture: bool,
+    /// Append every capture that differs from the last, before normalizing, to a file
+    pub recording: Option<RecordingOpts>,
+    pub throttle: Option<MonitorThrottle>,
+    /// Panes to monitor in place of `pane` once it closes
+    pub selector: Option<PaneMatch>,
+}
+
+impl MonitorConfig {
+    /// Snapshots the visible pane every `interval`, deduplicated, untruncated, and without
+    /// completions
+    pub fn new(pane: Pane, interval: Duration) -> Self {
+        Self {
+            pane,
+            interval,
+            capture_opts: CaptureOpts::default(),
+            max_snapshot_tokens: None,
+            dedup: true,
+            complete_every: None,
+            mode: SnapshotMode::default(),
+            backfill_lines: None,
+            raw_capture: false,
+            recording: None,
+            throttle: None,
+            selector: None,
+        }
+    }
+
+    pub fn with_capture_opts(mut self, opts: CaptureOpts) -> Self {
+        self.capture_opts = opts;
+        self
+    }
+
+    pub fn with_max_snapshot_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_snapshot_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Push every snapshot, even if the pane hasn't changed
+    pub fn without_dedup(mut self) -> Self {
+        self.dedup = false;
+        self
+    }
+
+    pub fn with_complete_every(mut self, snapshots: usize) -> Self {
+        self.complete_every = Some(snapshots.max(1));
+        self
+    }
+
+    pub fn with_mode(mut self, mode: SnapshotMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Before the first snapshot, push the pane's last `lines` lines, reaching into its history,
+    /// and get a completion so the agent starts out with what already happened. They are cut to
+    /// fit in what is left of the model's context window, and to `max_snapshot_tokens`. Snapshots
+    /// then only push what changes after it
+    pub fn with_backfill_scrollback(mut self, lines: usize) -> Self {
+        self.backfill_lines = Some(lines);
+        self
+    }
+
+    /// Set `RAW_CAPTURE_METADATA_KEY` on snapshots to the capture they came from, before it was
+    /// normalized, for debugging what the agent was shown
+    pub fn with_raw_capture(mut self) -> Self {
+        self.raw_capture = true;
+        self
+    }
+
+    /// Record the pane's raw output to disk as it is captured, whatever is pushed to the agent.
+    /// See `recording::read` to read it back
+    pub fn with_recording(mut self, opts: RecordingOpts) -> Self {
+        self.recording = Some(opts);
+        self
+    }
+
+    /// Limit how often the pane is captured & pushed, for panes whose output would flood the
+    /// agent. Throttles that debounce or limit the byte rate follow the pane's output through a
+    /// control mode client while the monitor runs
+    pub fn with_throttle(mut self, throttle: MonitorThrottle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Once the pane closes, monitor the first pane matching `selector` in its place rather
+    /// than stopping, waiting for one to open if none has. Keeps a monitor going when the
+    /// program it watches is restarted in a new pane
+    pub fn with_selector(mut self, selector: impl Into<PaneMatch>) -> Self {
+        self.selector = Some(selector.into());
+        self
+    }
+}
+
+/// Counts of what a monitor has done. Counts missing when deserialized are zero
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MonitorMetrics {
+    /// Successful captures of the pane
+    pub captures: u64,
+    /// Snapshots pushed to the agent's cache
+    pub pushed: u64,
+    /// Captures that weren't pushed because the pane hadn't changed
+    pub skipped: u64,
+    /// Completions made after pushing snapshots
+    pub completions: u64,
+    /// `SnapshotMode::History` captures that found output was evicted from the pane's history
+    /// before it was read
+    pub gaps: u64,
+    /// Ticks skipped by the throttle's `min_push_interval`
+    pub throttled: u64,
+    /// Captures the throttle put off until the pane's output stopped
+    pub debounced: u64,
+    /// Summaries of the output pushed in place of snapshots while degraded
+    pub summarized: u64,
+    /// Whether the throttle is in degraded mode
+    pub degraded: bool,
+    /// Times the pane closed & one matching `MonitorConfig::selector` was monitored instead
+    pub reselected: u64,
+}
+
+/// The last `max_tokens` estimated tokens of `content`. When it is cut, it is cut to start at a
+/// whole line if there is more than one line left
+pub(super) fn truncate_to_tokens(content: &str, max_tokens: usize) -> &str {
+    let max_chars = max_tokens * CHARS_PER_TOKEN;
+    let len = content.chars().count();
+    if len <= max_chars {
+        return content;
+    }
+    let start = content
+        .char_indices()
+        .nth(len - max_chars)
+        .map(|(i, _)| i)
+        .unwrap_or(content.len());
+    let tail = &content[start..];
+    if content[..start].ends_with('\n') {
+        return tail;
+    }
+    match tail.find('\n') {
+        Some(end) if end + 1 < tail.len() => &tail[end + 1..],
+        _ => tail,
+    }
+}
+
+/// The backfill message for `history`, its end cut to fit in `budget` tokens along with the
+/// header, and to `max_tokens`. `None` when there is no room for any of it
+fn backfill_content(history: &str, budget: i64, max_tokens: Option<usize>) -> Option<String> {
+    let header_tokens = BACKFILL_HEADER.len().div_ceil(CHARS_PER_TOKEN) + BACKFILL_MARGIN_TOKENS;
+    let tokens = usize::try_from(budget)
+        .ok()?
+        .checked_sub(header_tokens)
+        .filter(|tokens| *tokens > 0)?;
+    let tokens = max_tokens.map_or(tokens, |max| max.min(tokens));
+    Some(format!(
+        "{}\n{}",
+        BACKFILL_HEADER,
+        truncate_to_tokens(history.trim_end(), tokens)
+    ))
+}
+
+/// Pushes a pane's history & gets a completion for `MonitorConfig::with_backfill_scrollback`,
+/// returning whether the history was pushed & whether the completion succeeded. The visible
+/// screen is given to `snapshots` as though it was snapshotted, since it is in the history
+async fn backfill(
+    config: &MonitorConfig,
+    agent: &SharedAgent,
+    lines: usize,
+    snapshots: &mut Snapshots,
+) -> TmuxResult<(bool, bool)> {
+    let history = config.pane.capture_tail(lines).await?;
+    let (visible, _) = capture(config).await?;
+    let captured_at = now_ms();
+    snapshots.next(config, &visible, captured_at);
+    if history.trim().is_empty() {
+        return Ok((false, false));
+    }
+    let mut agent = agent.lock().await;
+    let response_tokens = agent.completion_model.params.max_tokens.unwrap_or_default();
+    let budget = agent.remaining_budget(response_tokens);
+    let Some(content) = backfill_content(&history, budget, config.max_snapshot_tokens) else {
+        warn!(
+            "Not backfilling {}, the context window is full",
+            config.pane.target()
+        );
+        return Ok((false, false));
+    };
+    let message = config
+        .pane
+        .message(&content, MessageRole::User, captured_at);
+    agent.cache.push(message);
+    match agent.io_completion().await {
+        Ok(response) => {
+            agent.cache.push(Message::new_assistant(&response));
+            Ok((true, true))
+        }
+        Err(err) => {
+            warn!("Monitor backfill completion failed: {:?}", err);
+            Ok((true, false))
+        }
+    }
+}
+
+/// Captures the monitored pane, returning the capture & the capture before normalizing
+async fn capture(config: &MonitorConfig) -> TmuxResult<(String, String)> {
+    let opts = CaptureOpts {
+        normalize: None,
+        ..config.capture_opts.clone()
+    };
+    let raw = config.pane.capture(opts).await?;
+    let kept = raw.trim_end().to_owned();
+    Ok((config.capture_opts.finish(raw), kept))
+}
+
+/// The history cursor & throttle state a monitor of `config.pane` starts with
+async fn follow(
+    config: &MonitorConfig,
+) -> TmuxResult<(Option<HistoryCursor>, Option<ThrottleState>)> {
+    let history = match config.mode {
+        SnapshotMode::History => {
+            let cursor = config.pane.history_cursor().await?;
+            Some(match config.capture_opts.limit {
+                Some(limit) => cursor.with_limit(limit),
+                None => cursor,
+            })
+        }
+        _ => None,
+    };
+    let throttle = match config.throttle.clone() {
+        Some(throttle) => Some(ThrottleState::start(&config.pane, throttle).await?),
+        None => None,
+    };
+    Ok((history, throttle))
+}
+
+/// The pane to monitor in place of `config.pane` after it closed, if one matching the selector
+/// is open
+async fn reselect(config: &MonitorConfig) -> TmuxResult<Option<Pane>> {
+    let Some(selector) = config.selector.as_ref() else {
+        return Ok(None);
+    };
+    let socket = config.pane.socket.as_deref();
+    let panes = Pane::select_on(socket, selector, ResolvePolicy::First).await?;
+    Ok(panes.into_iter().next())
+}
+
+/// Turns captures into snapshots, remembering the last ones for deduplication & diffing
+#[derive(Debug, Default)]
+struct Snapshots {
+    /// Last full snapshot pushed, cleared once a delta is pushed after it
+    last: Option<String>,
+    differ: PaneDiffer,
+    /// When the last capture was taken
+    last_at: Option<u64>,
+    /// Deltas pushed since the last full snapshot
+    deltas: usize,
+}
+
+impl Snapshots {
+    /// The snapshot to push for a capture taken at `at`, `None` if it should be skipped.
+    /// Trailing blank lines, which tmux pads captures to the pane's height with, are removed
+    fn next(&mut self, config: &MonitorConfig, capture: &str, at: u64) -> Option<String> {
+        let content = capture.trim_end();
+        let since = self.last_at.replace(at);
+        let truncate = |content: &str| match config.max_snapshot_tokens {
+            Some(max_tokens) => truncate_to_tokens(content, max_tokens).to_owned(),
+            None => content.to_owned(),
+        };
+        if config.mode == SnapshotMode::History {
+            return (!content.is_empty()).then(|| truncate(content));
+        }
+        let full_due = match config.mode {
+            SnapshotMode::FullSnapshot | SnapshotMode::History => true,
+            SnapshotMode::DeltaOnly => false,
+            SnapshotMode::DeltaWithPeriodicFull(every) => self.deltas + 1 >= every,
+        };
+        let delta = match config.mode {
+            SnapshotMode::FullSnapshot | SnapshotMode::History => None,
+            _ => Some(self.differ.push(&config.pane.id, content)),
+        };
+        if let (Some(delta), Some(since)) = (delta, since) {
+            let delta = match delta {
+                PaneDelta::Unchanged if config.dedup => return None,
+                PaneDelta::Unchanged => None,
+                PaneDelta::Appended(lines) => Some(PaneDelta::Appended(truncate(&lines))),
+                PaneDelta::Replaced(content) => Some(PaneDelta::Replaced(truncate(&content))),
+            };
+            if let Some(text) = delta.filter(|_| !full_due).and_then(|d| d.render(since)) {
+                self.last = None;
+                self.deltas += 1;
+                return Some(text);
+            }
+        }
+        let content = truncate(content);
+        if config.dedup && self.last.as_deref() == Some(&content) {
+            return None;
+        }
+        self.last = Some(content.clone());
+        self.deltas = 0;
+        Some(content)
+    }
+}
+
+/// Snapshots a pane into an agent's cache on an interval
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    config: MonitorConfig,
+}
+
+/// A monitor running on its own task
+#[derive(Debug)]
+pub struct MonitorHandle {
+    target: String,
+    metrics: Arc<Mutex<MonitorMetrics>>,
+    task: JoinHandle<TmuxResult<()>>,
+}
+
+impl MonitorHandle {
+    /// False once the pane has closed, the agent was dropped, or the monitor failed
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+
+    pub fn metrics(&self) -> MonitorMetrics {
+        *self.metrics.lock().expect("monitor metrics lock poisoned")
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn shared_metrics(&self) -> Arc<Mutex<MonitorMetrics>> {
+        Arc::clone(&self.metrics)
+    }
+}
+
+impl Drop for MonitorHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Monitor {
+    pub fn new(config: MonitorConfig) -> Self {
+        Self { config }
+    }
+
+    /// Starts snapshotting on a new task, the first snapshot is taken immediately. The monitor
+    /// only holds a weak reference to `agent`, and stops by itself once the agent is dropped or
+    /// the pane closes
+    pub fn spawn(self, agent: &SharedAgent) -> MonitorHandle {
+        let config = self.config;
+        let target = config.pane.target();
+        let agent = Arc::downgrade(agent);
+        let metrics = Arc::new(Mutex::new(MonitorMetrics::default()));
+        let task_metrics = Arc::clone(&metrics);
+        let record = move |update: fn(&mut MonitorMetrics)| {
+            update(&mut task_metrics.lock().expect("monitor metrics lock poisoned"))
+        };
+        let task = tokio::spawn(async move {
+            let mut config = config;
+            let mut ticks = tokio::time::interval(config.interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut snapshots = Snapshots::default();
+            if let (Some(lines), Some(agent)) = (config.backfill_lines, agent.upgrade()) {
+                match backfill(&config, &agent, lines, &mut snapshots).await {
+                    Ok((pushed, completed)) => {
+                        if pushed {
+                            record(|m| m.pushed += 1);
+                        }
+                        if completed {
+                            record(|m| m.completions += 1);
+                        }
+                    }
+                    Err(TmuxError::PaneGone { .. }) => return Ok(()),
+                    Err(err) => return Err(err),
+                }
+            }
+            // Started after the backfill, which already pushed what came before
+            let (mut history, mut throttle) = match follow(&config).await {
+                Ok(followed) => followed,
+                Err(TmuxError::PaneGone { .. }) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let recorder = config
+                .recording
+                .as_ref()
+                .map(|opts| Recorder::spawn(&config.pane, opts.clone()));
+            let mut last_recorded = None;
+            let mut since_completion = 0;
+            loop {
+                ticks.tick().await;
+                let Some(agent) = agent.upgrade() else {
+                    return Ok(());
+                };
+                if let Some(throttle) = throttle.as_mut() {
+                    let tick = throttle.tick(&config.pane.target()).await;
+                    match throttle.degraded() {
+                        true => record(|m| m.degraded = true),
+                        false => record(|m| m.degraded = false),
+                    }
+                    if tick.debounced {
+                        record(|m| m.debounced += 1);
+                    }
+                    match tick.action {
+                        TickAction::Capture => {}
+                        TickAction::Skip => {
+                            record(|m| m.throttled += 1);
+                            continue;
+                        }
+                        TickAction::Summarize(summary) => {
+                            let message =
+                                config.pane.message(&summary, MessageRole::User, now_ms());
+                            agent.lock().await.cache.push(message);
+                            throttle.pushed();
+                            record(|m| m.pushed += 1);
+                            record(|m| m.summarized += 1);
+                            continue;
+                        }
+                    }
+                }
+                let mut gap = false;
+                let captured = match history.as_mut() {
+                    Some(cursor) => config.pane.capture_since(cursor).await.map(|history| {
+                        gap = history.gap;
+                        let content = history.render();
+                        (content.to_owned(), content)
+                    }),
+                    None => capture(&config).await,
+                };
+                let (capture, raw) = match captured {
+                    Ok(captured) => captured,
+                    Err(TmuxError::PaneGone { .. }) if config.selector.is_some() => {
+                        let Some(pane) = reselect(&config).await? else {
+                            continue;
+                        };
+                        warn!(
+                            "{} closed, monitoring {} in its place",
+                            config.pane.target(),
+                            pane.target()
+                        );
+                        config.pane = pane;
+                        (history, throttle) = match follow(&config).await {
+                            Ok(followed) => followed,
+                            Err(TmuxError::PaneGone { .. }) => (None, None),
+                            Err(err) => return Err(err),
+                        };
+                        record(|m| m.reselected += 1);
+                        continue;
+                    }
+                    Err(TmuxError::PaneGone { .. }) => return Ok(()),
+                    Err(err) => return Err(err),
+                };
+                record(|m| m.captures += 1);
+                let captured_at = now_ms();
+                if let Some(recorder) = recorder.as_ref() {
+                    let repeated = config.mode != SnapshotMode::History
+                        && last_recorded.as_ref() == Some(&raw);
+                    if !repeated && !raw.is_empty() {
+                        recorder.record(TimestampedChunk {
+                            timestamp: captured_at,
+                            target: config.pane.target(),
+                            data: raw.to_owned(),
+                        });
+                        last_recorded = Some(raw.to_owned());
+                    }
+                }
+                if gap {
+                    record(|m| m.gaps += 1);
+                    warn!(
+                        "Output of {} left its history before it was captured, its history-limit \
+                         of {} lines is too small for an interval of {:?}",
+                        config.pane.target(),
+                        config.pane.history_limit,
+                        config.interval
+                    );
+                }
+                let Some(snapshot) = snapshots.next(&config, &capture, captured_at) else {
+                    record(|m| m.skipped += 1);
+                    continue;
+                };
+                let mut message = config
+                    .pane
+                    .message(&snapshot, MessageRole::User, captured_at);
+                if config.raw_capture {
+                    message = message.with_metadata(RAW_CAPTURE_METADATA_KEY, &raw);
+                }
+                let mut agent = agent.lock().await;
+                agent.cache.push(message);
+                record(|m| m.pushed += 1);
+                if let Some(throttle) = throttle.as_mut() {
+                    throttle.pushed();
+                }
+                since_completion += 1;
+                if config
+                    .complete_every
+                    .is_some_and(|every| since_completion >= every)
+                {
+                    since_completion = 0;
+                    match agent.io_completion().await {
+                        Ok(response) => {
+                            agent.cache.push(Message::new_assistant(&response));
+                            record(|m| m.completions += 1);
+                        }
+                        Err(err) => warn!("Monitor completion failed: {:?}", err),
+                    }
+                }
+            }
+        });
+        MonitorHandle {
+            target,
+            metrics,
+            task,
+        }
+    }
+}
+
+/// Description of a monitor, see `Monitors::list`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonitorInfo {
+    pub name: String,
+    pub target: String,
+    pub running: bool,
+    pub metrics: MonitorMetrics,
+}
+
+/// Named monitors that can be added & removed while they run
+#[derive(Debug, Default)]
+pub struct Monitors {
+    handles: HashMap<String, MonitorHandle>,
+}
+
+impl Monitors {
+    /// Spawns a monitor of `config` into `agent` under `name`, stopping any monitor already
+    /// using the name
+    pub fn add(&mut self, name: &str, config: MonitorConfig, agent: &SharedAgent) {
+        let handle = Monitor::new(config).spawn(agent);
+        self.handles.insert(name.to_owned(), handle);
+    }
+
+    /// Stops & removes a monitor, returns false if there was none with the name
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.handles.remove(name).is_some()
+    }
+
+    /// Removes monitors that have stopped because their pane closed or their agent was
+    /// dropped, returns their names sorted
+    pub fn prune(&mut self) -> Vec<String> {
+        let mut stopped: Vec<String> = self
+            .handles
+            .iter()
+            .filter(|(_, handle)| !handle.is_running())
+            .map(|(name, _)| name.to_owned())
+            .collect();
+        stopped.sort();
+        for name in stopped.iter() {
+            self.handles.remove(name);
+        }
+        stopped
+    }
+
+    /// Every monitor, sorted by name
+    pub fn list(&self) -> Vec<MonitorInfo> {
+        let mut infos: Vec<MonitorInfo> = self
+            .handles
+            .iter()
+            .map(|(name, handle)| MonitorInfo {
+                name: name.to_owned(),
+                target: handle.target.to_owned(),
+                running: handle.is_running(),
+                metrics: handle.metrics(),
+            })
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::Agent,
+        language_models::completions::CompletionModel,
+        tmux::{ansi::NormalizeOpts, run, tests::TestServer, ThrottleEvent, PANE_ID_METADATA_KEY},
+    };
+
+    fn pane() -> Pane {
+        Pane {
+            session: "test".to_string(),
+            window: 0,
+            index: 0,
+            id: "%0".to_string(),
+            socket: None,
+            history_limit: 0,
+        }
+    }
+
+    #[test]
+    fn snapshots_truncated_and_deduplicated() {
+        let config = MonitorConfig::new(pane(), Duration::from_secs(1)).with_max_snapshot_tokens(2);
+        let mut snapshots = Snapshots::default();
+        // Eight characters are kept, which starts mid line so the partial line is dropped
+        assert_eq!(
+            snapshots
+                .next(&config, "first line\nab\ncd\n\n\n", 0)
+                .as_deref(),
+            Some("ab\ncd")
+        );
+        assert_eq!(snapshots.next(&config, "other\nab\ncd\n", 0), None);
+        assert_eq!(
+            snapshots.next(&config, "abcdefghij", 0).as_deref(),
+            Some("cdefghij")
+        );
+
+        let mut snapshots = Snapshots::default();
+        let config = config.without_dedup();
+        assert!(snapshots.next(&config, "same", 0).is_some());
+        assert!(snapshots.next(&config, "same", 0).is_some());
+    }
+
+    #[test]
+    fn deltas_pushed_with_periodic_full_snapshots() {
+        let config = MonitorConfig::new(pane(), Duration::from_secs(1))
+            .with_mode(SnapshotMode::DeltaWithPeriodicFull(3));
+        let mut snapshots = Snapshots::default();
+        let mut next = |capture: &str, at: u64| snapshots.next(&config, capture, at);
+        assert_eq!(next("$ ls\n", 0).as_deref(), Some("$ ls"));
+        assert_eq!(
+            next("$ ls\nnotes.txt\n$", 1000).as_deref(),
+            Some("New output since 00:00:00 UTC:\nnotes.txt\n$")
+        );
+        assert_eq!(next("$ ls\nnotes.txt\n$", 2000), None);
+        assert_eq!(
+            next("$", 3000).as_deref(),
+            Some("Screen replaced since 00:00:02 UTC:\n$")
+        );
+        // The third push since the last full snapshot is full
+        assert_eq!(next("$ pwd\n/\n$", 4000).as_deref(), Some("$ pwd\n/\n$"));
+        assert_eq!(
+            next("$ pwd\n/\n$ ls", 5000).as_deref(),
+            Some("New output since 00:00:04 UTC:\n$ ls")
+        );
+
+        let config = MonitorConfig::new(pane(), Duration::from_secs(1))
+            .with_mode(SnapshotMode::DeltaOnly)
+            .with_max_snapshot_tokens(1);
+        let mut snapshots = Snapshots::default();
+        assert_eq!(snapshots.next(&config, "a", 0).as_deref(), Some("a"));
+        // Deltas are truncated, without cutting their header
+        assert_eq!(
+            snapshots.next(&config, "a\nlong line\nb", 1000).as_deref(),
+            Some("New output since 00:00:00 UTC:\nb")
+        );
+    }
+
+    #[test]
+    fn history_snapshots_pushed_whole() {
+        let config = MonitorConfig::new(pane(), Duration::from_secs(1))
+            .with_mode(SnapshotMode::History)
+            .with_max_snapshot_tokens(1);
+        let mut snapshots = Snapshots::default();
+        assert_eq!(snapshots.next(&config, "ab\n", 0).as_deref(), Some("ab"));
+        // Each capture is only new output, so repeats are pushed again
+        assert_eq!(snapshots.next(&config, "ab", 1000).as_deref(), Some("ab"));
+        assert_eq!(snapshots.next(&config, "", 2000), None);
+        assert_eq!(
+            snapshots.next(&config, "long line\nxyz", 3000).as_deref(),
+            Some("xyz")
+        );
+    }
+
+    #[test]
+    fn backfill_cut_to_budget() {
+        let history = "old line\n".repeat(10) + "latest line\n\n";
+        let content = backfill_content(&history, 10_000, None).unwrap();
+        assert!(content.starts_with(BACKFILL_HEADER));
+        assert!(content.ends_with("old line\nlatest line"));
+        assert_eq!(content.matches("old line").count(), 10);
+
+        let header_tokens =
+            BACKFILL_HEADER.len().div_ceil(CHARS_PER_TOKEN) + BACKFILL_MARGIN_TOKENS;
+        let content = backfill_content(&history, header_tokens as i64 + 3, None).unwrap();
+        assert_eq!(content, format!("{}\nlatest line", BACKFILL_HEADER));
+        let content = backfill_content(&history, 10_000, Some(3)).unwrap();
+        assert_eq!(content, format!("{}\nlatest line", BACKFILL_HEADER));
+        assert_eq!(backfill_content(&history, header_tokens as i64, None), None);
+        assert_eq!(backfill_content(&history, -100, None), None);
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn scrollback_backfilled_before_snapshots() {
+        use crate::language_models::completions::{
+            openai::{azure::AzureOpenAiDeployment, builder::OpenAiCompletionModel},
+            testing::serve_once,
+            ModelParameters,
+        };
+        let Some(server) = TestServer::start("seq 1 100; sleep 30").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if pane.capture_tail(1).await.unwrap() == "100" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let body = serde_json::json!({
+            "usage": {"prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12},
+            "choices": [{"message": {"role": "assistant", "content": "counted to 100"}}]
+        });
+        let url = serve_once(
+            vec![("Content-Type", "application/json")],
+            body.to_string().into_bytes(),
+        )
+        .await;
+        let deployment =
+            AzureOpenAiDeployment::new(&url, "d", "2024-06-01", OpenAiCompletionModel::Gpt3);
+        let agent: SharedAgent = Arc::new(tokio::sync::Mutex::new(Agent::new(
+            None,
+            CompletionModel::new(deployment, ModelParameters::default(), ""),
+        )));
+        let config = MonitorConfig::new(pane, Duration::from_millis(20))
+            .with_mode(SnapshotMode::DeltaOnly)
+            .with_backfill_scrollback(500);
+        let handle = Monitor::new(config).spawn(&agent);
+
+        for _ in 0..100 {
+            if handle.metrics().skipped >= 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let metrics = handle.metrics();
+        assert_eq!(metrics.pushed, 1);
+        assert_eq!(metrics.completions, 1);
+        assert!(metrics.skipped >= 3);
+        let agent = agent.lock().await;
+        let messages = agent.cache.as_ref();
+        assert_eq!(messages.len(), 2);
+        let expected: Vec<String> = (1..=100).map(|n| n.to_string()).collect();
+        assert_eq!(
+            messages[0].content,
+            format!("{}\n{}", BACKFILL_HEADER, expected.join("\n"))
+        );
+        assert_eq!(messages[1].content, "counted to 100");
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn monitor_pushes_changed_snapshots_until_agent_dropped() {
+        let Some(server) = TestServer::start("echo monitored; sleep 30").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let agent: SharedAgent = Arc::new(tokio::sync::Mutex::new(Agent::new(
+            None,
+            CompletionModel::default_openai(""),
+        )));
+        let mut monitors = Monitors::default();
+        let config = MonitorConfig::new(pane.clone(), Duration::from_millis(20));
+        monitors.add("session", config, &agent);
+
+        for _ in 0..250 {
+            let metrics = monitors.list()[0].metrics;
+            if metrics.skipped >= 3 && metrics.pushed > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let info = &monitors.list()[0];
+        assert_eq!(info.target, "test:0.0");
+        assert!(info.running);
+        assert!(info.metrics.skipped >= 3);
+        {
+            let agent = agent.lock().await;
+            let last = agent.cache.as_ref().last().unwrap();
+            assert!(last.content.contains("monitored"));
+            assert_eq!(last.metadata[PANE_ID_METADATA_KEY], pane.id);
+            assert_eq!(agent.cache.len() as u64, info.metrics.pushed);
+        }
+
+        drop(agent);
+        for _ in 0..50 {
+            if !monitors.list()[0].running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(monitors.prune(), vec!["session"]);
+        assert!(monitors.list().is_empty());
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn normalized_snapshots_keep_raw_capture() {
+        let Some(server) =
+            TestServer::start("printf '\\033[31mred\\033[0m alert\\n'; sleep 30").await
+        else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let agent: SharedAgent = Arc::new(tokio::sync::Mutex::new(Agent::new(
+            None,
+            CompletionModel::default_openai(""),
+        )));
+        let opts = CaptureOpts {
+            escape_sequences: true,
+            normalize: Some(NormalizeOpts::default()),
+            ..Default::default()
+        };
+        let dir = std::env::temp_dir().join(format!("espionox-monitor-{}", uuid::Uuid::new_v4()));
+        let config = MonitorConfig::new(pane, Duration::from_millis(20))
+            .with_capture_opts(opts)
+            .with_raw_capture()
+            .with_recording(RecordingOpts::new(&dir).with_template("{session}.jsonl"));
+        let handle = Monitor::new(config).spawn(&agent);
+
+        for _ in 0..250 {
+            if agent
+                .lock()
+                .await
+                .cache
+                .as_ref()
+                .last()
+                .is_some_and(|m| m.content.contains("alert"))
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        handle.stop();
+        let agent = agent.lock().await;
+        let last = agent.cache.as_ref().last().unwrap();
+        assert!(last.content.contains("red alert"));
+        assert!(!last.content.contains('\x1b'));
+        assert!(last.metadata[RAW_CAPTURE_METADATA_KEY].contains("\x1b[31mred"));
+
+        // The screen didn't change, so the capture was recorded once
+        let mut recorded: Vec<TimestampedChunk> = vec![];
+        for _ in 0..50 {
+            if let Ok(chunks) = crate::tmux::recording::read(dir.join("test.jsonl")) {
+                recorded = chunks.collect();
+                if !recorded.is_empty() {
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].target, "test:0.0");
+        assert!(recorded[0].data.contains("\x1b[31mred"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn heavy_output_summarized_while_degraded() {
+        let Some(server) = TestServer::start("sleep 1; seq 1 200000; sleep 30").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let agent: SharedAgent = Arc::new(tokio::sync::Mutex::new(Agent::new(
+            None,
+            CompletionModel::default_openai(""),
+        )));
+        let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+        let throttle = MonitorThrottle::new()
+            .with_max_bytes_per_sec(20_000)
+            .with_notifications(sender);
+        let config = MonitorConfig::new(pane, Duration::from_millis(50)).with_throttle(throttle);
+        let handle = Monitor::new(config).spawn(&agent);
+
+        let wait = Duration::from_secs(10);
+        let degraded = tokio::time::timeout(wait, events.recv()).await.unwrap();
+        assert!(matches!(
+            degraded,
+            Some(ThrottleEvent::Degraded { ref target, bytes_per_sec })
+                if target == "test:0.0" && bytes_per_sec > 20_000
+        ));
+        let recovered = tokio::time::timeout(wait, events.recv()).await.unwrap();
+        assert!(matches!(recovered, Some(ThrottleEvent::Recovered { .. })));
+
+        for _ in 0..100 {
+            if agent.lock().await.cache.as_ref().last().is_some_and(|m| {
+                m.content.contains("200000") && !m.content.starts_with("High output volume")
+            }) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        handle.stop();
+        let metrics = handle.metrics();
+        assert!(metrics.summarized >= 1);
+        assert!(!metrics.degraded);
+        let agent = agent.lock().await;
+        let summary = agent
+            .cache
+            .as_ref()
+            .iter()
+            .find(|m| m.content.starts_with("High output volume: "))
+            .unwrap();
+        assert!(summary.content.contains(" lines, sample:\n"));
+        let last = agent.cache.as_ref().last().unwrap();
+        assert!(last.content.contains("200000"));
+        assert!(!last.content.contains("High output volume"));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn restarted_program_monitored_through_selector() {
+        let Some(server) = TestServer::start("printf 'first run\n'; sleep 30").await else {
+            return;
+        };
+        let socket = Some(server.socket.as_str());
+        let rename = ["rename-window", "-t", "test:0", "app"];
+        assert!(run(socket, &rename).await.is_ok());
+        let other = ["new-window", "-d", "-t", "test:1", "sleep 30"];
+        assert!(run(socket, &other).await.is_ok());
+        let pane = Pane::resolve_on(socket, "test:0.0").await.unwrap();
+        let agent: SharedAgent = Arc::new(tokio::sync::Mutex::new(Agent::new(
+            None,
+            CompletionModel::default_openai(""),
+        )));
+        let config = MonitorConfig::new(pane, Duration::from_millis(20))
+            .with_selector(PaneMatch::WindowGlob("app".to_string()));
+        let handle = Monitor::new(config).spawn(&agent);
+
+        let pushed = |text: &'static str| {
+            let agent = Arc::clone(&agent);
+            async move {
+                for _ in 0..250 {
+                    if agent
+                        .lock()
+                        .await
+                        .cache
+                        .as_ref()
+                        .iter()
+                        .any(|m| m.content.contains(text))
+                    {
+                        return true;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                false
+            }
+        };
+        assert!(pushed("first run").await);
+        assert!(run(socket, &["kill-window", "-t", "test:0"]).await.is_ok());
+        let restart = [
+            "new-window",
+            "-d",
+            "-t",
+            "test:",
+            "-n",
+            "app",
+            "printf 'second run\\n'; sleep 30",
+        ];
+        assert!(run(socket, &restart).await.is_ok());
+        assert!(pushed("second run").await);
+        assert!(handle.is_running());
+        assert_eq!(handle.metrics().reselected, 1);
+        let last = agent.lock().await.cache.as_ref().last().cloned().unwrap();
+        assert_ne!(last.metadata[PANE_ID_METADATA_KEY], "%0");
+    }
+}
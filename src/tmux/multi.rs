@@ -0,0 +1,721 @@
+//! One agent monitoring several panes, with each pane's output labeled
+use super::{
+    diff::clock_time, monitor::truncate_to_tokens, now_ms, select::Matcher, server::list_panes,
+    CaptureOpts, Message, MessageRole, MonitorMetrics, Pane, PaneDelta, PaneDiffer, PaneMatch,
+    SharedAgent, TmuxError, TmuxResult, ToMessage, CAPTURED_AT_METADATA_KEY, PANE_ID_METADATA_KEY,
+    PANE_LABEL_METADATA_KEY, PANE_TARGET_METADATA_KEY,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::Instant};
+
+/// How often selectors are matched against the server's panes by default
+const DEFAULT_RESOLVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How a `MultiPaneMonitor` captures the panes one `PaneMatch` picks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonitoredPaneOpts {
+    /// Prefixes the output of matched panes. Defaults to the target, for window globs the
+    /// window's name, and for other matches each pane's target. Labels of any match but a target
+    /// or pane are followed by the pane's index, like `logs.0`
+    pub label: Option<String>,
+    /// How often each matched pane is captured
+    pub interval: Duration,
+    /// Output of a matched pane estimated to be longer than this many tokens is cut down to its
+    /// end, so it can't crowd out other panes in combined messages
+    pub max_tokens: Option<usize>,
+}
+
+impl MonitoredPaneOpts {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            label: None,
+            interval,
+            max_tokens: None,
+        }
+    }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_owned());
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+}
+
+/// A pane being monitored, see `MultiPaneMonitorHandle::members`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonitoredPane {
+    pub label: String,
+    pub pane: Pane,
+}
+
+/// A monitored pane & when it is next captured
+#[derive(Debug, Clone)]
+struct Member {
+    label: String,
+    pane: Pane,
+    /// Index of the match that picked the pane
+    selector: usize,
+    next_capture: Instant,
+    /// When the pane was last captured
+    last_at: Option<u64>,
+}
+
+/// Output of a pane that hasn't been pushed yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pending {
+    label: String,
+    pane: Pane,
+    delta: PaneDelta,
+    /// When the capture before the output was taken, `None` for a pane's first capture
+    since: Option<u64>,
+    /// When the latest output was captured
+    at: u64,
+    max_tokens: Option<usize>,
+}
+
+impl Pending {
+    /// Adds later output of the same pane. A replaced screen supersedes everything before it
+    fn merge(&mut self, delta: PaneDelta, at: u64) {
+        self.delta = match (&self.delta, delta) {
+            (_, PaneDelta::Unchanged) => return,
+            (_, PaneDelta::Replaced(content)) => PaneDelta::Replaced(content),
+            (PaneDelta::Replaced(content), PaneDelta::Appended(lines)) => {
+                PaneDelta::Replaced(format!("{}\n{}", content, lines))
+            }
+            (_, PaneDelta::Appended(lines)) => match self.delta.content() {
+                "" => PaneDelta::Appended(lines),
+                content => PaneDelta::Appended(format!("{}\n{}", content, lines)),
+            },
+        };
+        self.at = at;
+    }
+
+    /// The output prefixed with the pane's label
+    fn render(&self) -> String {
+        let content = self.delta.content();
+        let content = match self.max_tokens {
+            Some(max_tokens) => truncate_to_tokens(content, max_tokens),
+            None => content,
+        };
+        let body = match (&self.delta, self.since) {
+            (PaneDelta::Appended(_), Some(since)) => {
+                PaneDelta::Appended(content.to_owned()).render(since)
+            }
+            (PaneDelta::Replaced(_), Some(since)) => {
+                PaneDelta::Replaced(content.to_owned()).render(since)
+            }
+            _ => None,
+        };
+        let body =
+            body.unwrap_or_else(|| format!("Screen at {}:\n{}", clock_time(self.at), content));
+        format!("[{}] {}", self.label, body)
+    }
+}
+
+/// One message of pending output from one or more panes, sorted by label
+fn combined_message(mut outputs: Vec<Pending>) -> Message {
+    outputs.sort_by(|a, b| a.label.cmp(&b.label));
+    let content = outputs
+        .iter()
+        .map(Pending::render)
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    let join = |field: fn(&Pending) -> String| {
+        outputs.iter().map(field).collect::<Vec<String>>().join(",")
+    };
+    let at = outputs.iter().map(|o| o.at).max().unwrap_or_default();
+    content
+        .to_message(MessageRole::User)
+        .with_metadata(PANE_LABEL_METADATA_KEY, &join(|o| o.label.to_owned()))
+        .with_metadata(PANE_TARGET_METADATA_KEY, &join(|o| o.pane.target()))
+        .with_metadata(PANE_ID_METADATA_KEY, &join(|o| o.pane.id.to_owned()))
+        .with_metadata(CAPTURED_AT_METADATA_KEY, &at.to_string())
+}
+
+/// Panes picked by matches, along with their captures & pending output
+#[derive(Debug)]
+struct Panes {
+    selectors: Vec<(PaneMatch, MonitoredPaneOpts)>,
+    /// Matcher of each selector, `None` for targets & panes. Compiled once the monitor starts
+    matchers: Vec<Option<Matcher>>,
+    socket: Option<String>,
+    aggregation_window: Option<Duration>,
+    members: HashMap<String, Member>,
+    differ: PaneDiffer,
+    pending: HashMap<String, Pending>,
+    /// When the first output still pending was captured
+    pending_since: Option<Instant>,
+}
+
+impl Panes {
+    /// Adds panes that newly match a selector. A pane matched by more than one selector is
+    /// monitored under the first
+    async fn resolve(&mut self, now: Instant) -> TmuxResult<()> {
+        let listed = match self.matchers.iter().any(Option::is_some) {
+            true => list_panes(self.socket.as_deref()).await?,
+            false => vec![],
+        };
+        let selectors = self
+            .selectors
+            .clone()
+            .into_iter()
+            .zip(self.matchers.clone());
+        for (index, ((matches, opts), matcher)) in selectors.enumerate() {
+            match (matches.tmux_target(self.socket.as_deref()), matcher) {
+                (Some((socket, target)), _) => {
+                    if self.members.values().any(|m| m.selector == index) {
+                        continue;
+                    }
+                    match Pane::resolve_on(socket, target).await {
+                        Ok(pane) => {
+                            let label = opts.label.unwrap_or_else(|| matches.describe());
+                            self.join(label, pane, index, now);
+                        }
+                        Err(TmuxError::InvalidTarget { .. })
+                        | Err(TmuxError::PaneGone { .. })
+                        | Err(TmuxError::NoServer) => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+                (None, Some(matcher)) => {
+                    for listing in listed.iter().filter(|l| matcher.matches(l)) {
+                        let label = match (opts.label.as_deref(), &matches) {
+                            (Some(label), _) => format!("{}.{}", label, listing.pane.index),
+                            (None, PaneMatch::WindowGlob(_)) => {
+                                format!("{}.{}", listing.window_name, listing.pane.index)
+                            }
+                            (None, _) => listing.pane.target(),
+                        };
+                        self.join(label, listing.pane.clone(), index, now);
+                    }
+                }
+                (_, None) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a pane unless it is already monitored. A label already in use is followed by the
+    /// pane's id to keep it unique
+    fn join(&mut self, label: String, pane: Pane, selector: usize, now: Instant) {
+        if self.members.contains_key(&pane.id) {
+            return;
+        }
+        let label = match self.members.values().any(|m| m.label == label) {
+            true => format!("{}-{}", label, pane.id.trim_start_matches('%')),
+            false => label,
+        };
+        self.members.insert(
+            pane.id.to_owned(),
+            Member {
+                label,
+                pane,
+                selector,
+                next_capture: now,
+                last_at: None,
+            },
+        );
+    }
+
+    /// Captures & diffs every pane that is due, keeping any output as pending. Panes that have
+    /// closed are removed
+    async fn capture_due(
+        &mut self,
+        now: Instant,
+        metrics: &Mutex<MonitorMetrics>,
+    ) -> TmuxResult<()> {
+        let mut due: Vec<String> = self
+            .members
+            .iter()
+            .filter(|(_, m)| m.next_capture <= now)
+            .map(|(id, _)| id.to_owned())
+            .collect();
+        due.sort();
+        for id in due {
+            let pane = self.members[&id].pane.clone();
+            let capture = match pane.capture(CaptureOpts::default()).await {
+                Ok(capture) => capture,
+                Err(TmuxError::PaneGone { .. }) => {
+                    self.members.remove(&id);
+                    self.differ.forget(&id);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let at = now_ms();
+            let delta = self.differ.push(&id, capture.trim_end());
+            let member = self.members.get_mut(&id).expect("due pane is a member");
+            let since = member.last_at.replace(at);
+            member.next_capture = now + self.selectors[member.selector].1.interval;
+            let mut metrics = metrics.lock().expect("monitor metrics lock poisoned");
+            metrics.captures += 1;
+            if delta == PaneDelta::Unchanged {
+                metrics.skipped += 1;
+                continue;
+            }
+            match self.pending.get_mut(&id) {
+                Some(pending) => pending.merge(delta, at),
+                None => {
+                    self.pending.insert(
+                        id,
+                        Pending {
+                            label: member.label.to_owned(),
+                            pane,
+                            delta,
+                            since,
+                            at,
+                            max_tokens: self.selectors[member.selector].1.max_tokens,
+                        },
+                    );
+                    self.pending_since.get_or_insert(now);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Messages of the pending output to push. Without an aggregation window each pane's
+    /// output is its own message, with one it is combined once the window has passed
+    fn flush(&mut self, now: Instant) -> Vec<Message> {
+        let Some(since) = self.pending_since else {
+            return vec![];
+        };
+        let outputs: Vec<Pending> = match self.aggregation_window {
+            Some(window) if now < since + window => return vec![],
+            _ => self.pending.drain().map(|(_, p)| p).collect(),
+        };
+        self.pending_since = None;
+        match self.aggregation_window {
+            Some(_) => vec![combined_message(outputs)],
+            None => {
+                let mut outputs = outputs;
+                outputs.sort_by(|a, b| a.label.cmp(&b.label));
+                outputs
+                    .into_iter()
+                    .map(|output| combined_message(vec![output]))
+                    .collect()
+            }
+        }
+    }
+
+    /// The next time a pane is due, pending output is due to be flushed, or `next_resolve`
+    fn next_wake(&self, next_resolve: Instant) -> Instant {
+        let flush = self
+            .pending_since
+            .zip(self.aggregation_window)
+            .map(|(since, window)| since + window);
+        self.members
+            .values()
+            .map(|m| m.next_capture)
+            .chain(flush)
+            .fold(next_resolve, Instant::min)
+    }
+
+    fn monitored(&self) -> Vec<MonitoredPane> {
+        let mut monitored: Vec<MonitoredPane> = self
+            .members
+            .values()
+            .map(|m| MonitoredPane {
+                label: m.label.to_owned(),
+                pane: m.pane.clone(),
+            })
+            .collect();
+        monitored.sort_by(|a, b| a.label.cmp(&b.label));
+        monitored
+    }
+}
+
+/// Captures several panes into one agent's cache, each on its own interval, pushing only what
+/// changed prefixed with the pane's label. Matches are matched again every resolve interval,
+/// so panes that open & match later join, and panes that close leave. A target is resolved
+/// again after its pane closes, so a pane that takes its place joins
+#[derive(Debug, Clone)]
+pub struct MultiPaneMonitor {
+    selectors: Vec<(PaneMatch, MonitoredPaneOpts)>,
+    socket: Option<String>,
+    aggregation_window: Option<Duration>,
+    resolve_interval: Duration,
+}
+
+impl Default for MultiPaneMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultiPaneMonitor {
+    /// A monitor without panes, add them with `with_panes`
+    pub fn new() -> Self {
+        Self {
+            selectors: vec![],
+            socket: None,
+            aggregation_window: None,
+            resolve_interval: DEFAULT_RESOLVE_INTERVAL,
+        }
+    }
+
+    /// Monitor every pane `matches` picks. A pane picked by more than one match is monitored
+    /// with the options of the first
+    pub fn with_panes(mut self, matches: impl Into<PaneMatch>, opts: MonitoredPaneOpts) -> Self {
+        self.selectors.push((matches.into(), opts));
+        self
+    }
+
+    /// Monitor the server with the given socket name rather than the default server
+    pub fn on_socket(mut self, socket: &str) -> Self {
+        self.socket = Some(socket.to_owned());
+        self
+    }
+
+    /// Combine output captured from any of the panes within `window` of the first into a
+    /// single message, rather than pushing a message per pane
+    pub fn with_aggregation_window(mut self, window: Duration) -> Self {
+        self.aggregation_window = Some(window);
+        self
+    }
+
+    /// How often matches are matched against the server's panes
+    pub fn with_resolve_interval(mut self, interval: Duration) -> Self {
+        self.resolve_interval = interval;
+        self
+    }
+
+    /// Starts monitoring on a new task. Like `Monitor::spawn`, only a weak reference to `agent`
+    /// is held & the monitor stops once the agent is dropped. Its metrics count captures &
+    /// pushed messages across every pane
+    pub fn spawn(self, agent: &SharedAgent) -> MultiPaneMonitorHandle {
+        let agent = Arc::downgrade(agent);
+        let members = Arc::new(Mutex::new(vec![]));
+        let metrics = Arc::new(Mutex::new(MonitorMetrics::default()));
+        let task_members = Arc::clone(&members);
+        let task_metrics = Arc::clone(&metrics);
+        let resolve_interval = self.resolve_interval;
+        let mut panes = Panes {
+            matchers: vec![],
+            selectors: self.selectors,
+            socket: self.socket,
+            aggregation_window: self.aggregation_window,
+            members: HashMap::new(),
+            differ: PaneDiffer::new(),
+            pending: HashMap::new(),
+            pending_since: None,
+        };
+        let task = tokio::spawn(async move {
+            panes.matchers = panes
+                .selectors
+                .iter()
+                .map(|(matches, _)| match matches {
+                    PaneMatch::Target(_) | PaneMatch::Pane(_) => Ok(None),
+                    matches => matches.matcher().map(Some),
+                })
+                .collect::<TmuxResult<_>>()?;
+            let mut next_resolve = Instant::now();
+            loop {
+                let Some(agent) = agent.upgrade() else {
+                    return Ok(());
+                };
+                let now = Instant::now();
+                if now >= next_resolve {
+                    panes.resolve(now).await?;
+                    next_resolve = now + resolve_interval;
+                }
+                panes.capture_due(now, &task_metrics).await?;
+                *task_members.lock().expect("members lock poisoned") = panes.monitored();
+                let messages = panes.flush(now);
+                if !messages.is_empty() {
+                    let pushed = messages.len() as u64;
+                    let mut agent = agent.lock().await;
+                    for message in messages {
+                        agent.cache.push(message);
+                    }
+                    task_metrics
+                        .lock()
+                        .expect("monitor metrics lock poisoned")
+                        .pushed += pushed;
+                }
+                drop(agent);
+                tokio::time::sleep_until(panes.next_wake(next_resolve)).await;
+            }
+        });
+        MultiPaneMonitorHandle {
+            members,
+            metrics,
+            task,
+        }
+    }
+}
+
+/// A multi pane monitor running on its own task
+#[derive(Debug)]
+pub struct MultiPaneMonitorHandle {
+    members: Arc<Mutex<Vec<MonitoredPane>>>,
+    metrics: Arc<Mutex<MonitorMetrics>>,
+    task: JoinHandle<TmuxResult<()>>,
+}
+
+impl MultiPaneMonitorHandle {
+    /// False once stopped, the agent was dropped, or the monitor failed
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+
+    /// Panes being monitored, sorted by label
+    pub fn members(&self) -> Vec<MonitoredPane> {
+        self.members.lock().expect("members lock poisoned").clone()
+    }
+
+    pub fn metrics(&self) -> MonitorMetrics {
+        *self.metrics.lock().expect("monitor metrics lock poisoned")
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn shared_metrics(&self) -> Arc<Mutex<MonitorMetrics>> {
+        Arc::clone(&self.metrics)
+    }
+}
+
+impl Drop for MultiPaneMonitorHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::Agent,
+        language_models::completions::CompletionModel,
+        tmux::{run, tests::TestServer},
+    };
+
+    fn pending(label: &str, id: &str, delta: PaneDelta, since: Option<u64>) -> Pending {
+        Pending {
+            label: label.to_string(),
+            pane: Pane {
+                session: "dev".to_string(),
+                window: 0,
+                index: 0,
+                id: id.to_string(),
+                socket: None,
+                history_limit: 0,
+            },
+            delta,
+            since,
+            at: 2000,
+            max_tokens: None,
+        }
+    }
+
+    #[test]
+    fn outputs_merged_and_combined_within_budgets() {
+        let mut server = pending(
+            "server",
+            "%1",
+            PaneDelta::Appended("GET /".to_string()),
+            Some(1000),
+        );
+        server.merge(PaneDelta::Appended("GET /health".to_string()), 3000);
+        assert_eq!(
+            server.delta,
+            PaneDelta::Appended("GET /\nGET /health".to_string())
+        );
+        server.merge(PaneDelta::Replaced("restarted".to_string()), 4000);
+        server.merge(PaneDelta::Appended("listening".to_string()), 5000);
+        assert_eq!(
+            server.delta,
+            PaneDelta::Replaced("restarted\nlistening".to_string())
+        );
+
+        let mut logs = pending(
+            "logs",
+            "%2",
+            PaneDelta::Appended("noise\n".repeat(50) + "latest error"),
+            Some(1000),
+        );
+        logs.max_tokens = Some(3);
+        let first = pending("tests", "%3", PaneDelta::Appended("ok".to_string()), None);
+        let message = combined_message(vec![server, first, logs]);
+        assert_eq!(
+            message.content,
+            "[logs] New output since 00:00:01 UTC:\nlatest error\n\n\
+             [server] Screen replaced since 00:00:01 UTC:\nrestarted\nlistening\n\n\
+             [tests] Screen at 00:00:02 UTC:\nok"
+        );
+        assert_eq!(
+            message.metadata[PANE_LABEL_METADATA_KEY],
+            "logs,server,tests"
+        );
+        assert_eq!(message.metadata[PANE_ID_METADATA_KEY], "%2,%1,%3");
+        assert_eq!(message.metadata[CAPTURED_AT_METADATA_KEY], "5000");
+    }
+
+    async fn wait_for_cache(agent: &SharedAgent, len: usize) {
+        for _ in 0..100 {
+            if agent.lock().await.cache.len() >= len {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn panes_monitored_as_they_open_and_close() {
+        let Some(server) = TestServer::start("echo server up; sleep 30").await else {
+            return;
+        };
+        let socket = Some(server.socket.as_str());
+        let server_pane = Pane::resolve_on(socket, "test:0.0").await.unwrap();
+        for _ in 0..50 {
+            let content = server_pane.capture(CaptureOpts::default()).await.unwrap();
+            if content.contains("server up") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let interval = Duration::from_millis(20);
+        let agent: SharedAgent = Arc::new(tokio::sync::Mutex::new(Agent::new(
+            None,
+            CompletionModel::default_openai(""),
+        )));
+        let monitor = MultiPaneMonitor::new()
+            .with_panes(
+                "test:0.0",
+                MonitoredPaneOpts::new(interval).with_label("server"),
+            )
+            .with_panes(
+                PaneMatch::WindowGlob("logs-*".to_string()),
+                MonitoredPaneOpts::new(interval),
+            )
+            .on_socket(&server.socket)
+            .with_resolve_interval(Duration::from_millis(50));
+        let handle = monitor.spawn(&agent);
+
+        wait_for_cache(&agent, 1).await;
+        {
+            let agent = agent.lock().await;
+            let message = &agent.cache.as_ref()[0];
+            assert!(message.content.starts_with("[server] Screen at"));
+            assert!(message.content.contains("server up"));
+            assert_eq!(message.metadata[PANE_LABEL_METADATA_KEY], "server");
+        }
+
+        // A window opened later joins once it matches
+        let args = [
+            "new-window",
+            "-d",
+            "-n",
+            "logs-app",
+            "-t",
+            "test:",
+            "echo first log; sleep 0.3; echo second log; sleep 0.3",
+        ];
+        assert!(run(socket, &args).await.is_ok());
+        wait_for_cache(&agent, 3).await;
+        {
+            let agent = agent.lock().await;
+            let contents: Vec<&str> = agent
+                .cache
+                .as_ref()
+                .iter()
+                .map(|m| m.content.as_str())
+                .collect();
+            // Depending on when the window is first captured, its first output is either on
+            // screen already or new output
+            assert!(contents[1].starts_with("[logs-app.0] "));
+            assert!(contents[1].ends_with("first log"));
+            assert!(contents[2].starts_with("[logs-app.0] New output since"));
+            assert!(contents[2].ends_with("second log"));
+        }
+        assert_eq!(
+            handle
+                .members()
+                .iter()
+                .map(|m| m.label.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["logs-app.0", "server"]
+        );
+
+        // The window's command exits, closing it
+        for _ in 0..100 {
+            if handle.members().len() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(handle.members()[0].label, "server");
+        let metrics = handle.metrics();
+        assert_eq!(metrics.pushed, 3);
+        assert!(metrics.skipped > 0);
+
+        drop(agent);
+        for _ in 0..50 {
+            if !handle.is_running() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!handle.is_running());
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn output_within_window_combined() {
+        let Some(server) = TestServer::start("sleep 0.2; echo from server; sleep 30").await else {
+            return;
+        };
+        let socket = Some(server.socket.as_str());
+        let args = [
+            "new-window",
+            "-d",
+            "-n",
+            "logs",
+            "-t",
+            "test:",
+            "sleep 0.2; echo from logs; sleep 30",
+        ];
+        assert!(run(socket, &args).await.is_ok());
+        let interval = Duration::from_millis(20);
+        let agent: SharedAgent = Arc::new(tokio::sync::Mutex::new(Agent::new(
+            None,
+            CompletionModel::default_openai(""),
+        )));
+        let _handle = MultiPaneMonitor::new()
+            .with_panes(
+                "test:0.0",
+                MonitoredPaneOpts::new(interval).with_label("server"),
+            )
+            .with_panes(
+                "test:logs.0",
+                MonitoredPaneOpts::new(interval).with_label("logs"),
+            )
+            .on_socket(&server.socket)
+            .with_aggregation_window(Duration::from_millis(600))
+            .spawn(&agent);
+
+        wait_for_cache(&agent, 1).await;
+        let agent = agent.lock().await;
+        assert_eq!(agent.cache.len(), 1);
+        let message = &agent.cache.as_ref()[0];
+        let sections: Vec<&str> = message.content.split("\n\n").collect();
+        assert_eq!(sections.len(), 2);
+        assert!(sections[0].starts_with("[logs] "));
+        assert!(sections[0].ends_with("from logs"));
+        assert!(sections[1].starts_with("[server] "));
+        assert!(sections[1].ends_with("from server"));
+        assert_eq!(message.metadata[PANE_LABEL_METADATA_KEY], "logs,server");
+    }
+}
@@ -0,0 +1,560 @@
+//! Live pane output, piped out of tmux with `pipe-pane` through a fifo, or read from a control
+//! mode client
+use super::{
+    ansi::{escape_len, ESC},
+    control::{ControlConnection, ControlEvent, ControlOpts},
+    now_ms, run, shell_quote, Debounce, Failure, Pane, TmuxError, TmuxResult, TMUX_BIN,
+};
+use crate::agents::{memory::MessageRole, Agent};
+use futures::{stream::BoxStream, StreamExt};
+use std::{path::PathBuf, time::Duration};
+use tokio::{io::AsyncReadExt, net::unix::pipe, process::Command, time::Instant};
+use tracing::warn;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long output still in the fifo has to arrive after the pane closes
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(50);
+const READ_BUFFER_BYTES: usize = 8 * 1024;
+
+pub type PaneOutputStream = BoxStream<'static, TmuxResult<PaneChunk>>;
+
+/// Output read from a pane. Chunks of a stream are in order & never overlap
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaneChunk {
+    /// The `session:window.pane` target of the pane
+    pub target: String,
+    pub text: String,
+    /// Milliseconds since the unix epoch when the chunk was read
+    pub timestamp: u64,
+}
+
+/// Options for `Pane::output_stream`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputStreamOpts {
+    /// How long a piped pane can be quiet before checking it is still open. Output is read as
+    /// it is written, the stream ends at most this long after the pane closes
+    pub poll_interval: Duration,
+    /// Keep escape sequences & control characters instead of stripping them
+    pub escape_sequences: bool,
+    /// Read output from a tmux control mode client as it is written, rather than piping it.
+    /// Falls back to piping when control mode is unavailable
+    pub control_mode: bool,
+}
+
+impl Default for OutputStreamOpts {
+    fn default() -> Self {
+        Self {
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            escape_sequences: false,
+            control_mode: false,
+        }
+    }
+}
+
+/// Turns raw pane output into text. Escape sequences & multibyte characters split across reads
+/// are held back until the rest of them arrives
+#[derive(Debug, Default)]
+struct OutputDecoder {
+    pending: Vec<u8>,
+    escape_sequences: bool,
+}
+
+impl OutputDecoder {
+    fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let (mut out, consumed) = match self.escape_sequences {
+            true => (self.pending.clone(), self.pending.len()),
+            false => strip_controls(&self.pending),
+        };
+        let mut rest = self.pending.split_off(consumed);
+        if let Err(err) = std::str::from_utf8(&out) {
+            // The output ends part way through a character
+            if err.error_len().is_none() {
+                let tail = out.split_off(err.valid_up_to());
+                rest = [tail, rest].concat();
+            }
+        }
+        self.pending = rest;
+        String::from_utf8_lossy(&out).into_owned()
+    }
+}
+
+/// Strips escape sequences & control characters other than newlines & tabs, returns the output
+/// and how many bytes were consumed
+fn strip_controls(bytes: &[u8]) -> (Vec<u8>, usize) {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            ESC => match escape_len(&bytes[i..]) {
+                Some(len) => i += len,
+                None => break,
+            },
+            b'\n' | b'\t' => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+            b if b < 0x20 || b == 0x7f => i += 1,
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    (out, i)
+}
+
+/// Stops piping the pane & removes the fifo when the stream is dropped
+struct PipeGuard {
+    socket: Option<String>,
+    id: String,
+    path: PathBuf,
+}
+
+impl Drop for PipeGuard {
+    fn drop(&mut self) {
+        let mut command = std::process::Command::new(TMUX_BIN);
+        if let Some(socket) = &self.socket {
+            command.args(["-L", socket]);
+        }
+        // pipe-pane without a command closes the pane's pipe
+        let _ = command
+            .args(["pipe-pane", "-t", &self.id])
+            .stderr(std::process::Stdio::null())
+            .status();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+struct OutputState {
+    pane: Pane,
+    /// Held until the stream is dropped
+    _guard: PipeGuard,
+    /// Opened for writing too, so reads wait for output rather than ending while the piped
+    /// command hasn't opened the fifo yet
+    fifo: pipe::Receiver,
+    decoder: OutputDecoder,
+    poll_interval: Duration,
+    done: bool,
+}
+
+impl OutputState {
+    /// Waits up to `quiet` for output, `None` if there was none
+    async fn read_new(&mut self, quiet: Duration) -> TmuxResult<Option<String>> {
+        let mut bytes = [0u8; READ_BUFFER_BYTES];
+        match tokio::time::timeout(quiet, self.fifo.read(&mut bytes)).await {
+            Ok(read) => Ok(Some(self.decoder.push(&bytes[..read?]))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Output the piped command wrote before the pane closed
+    async fn drain(&mut self) -> TmuxResult<String> {
+        let mut text = String::new();
+        while let Some(more) = self.read_new(DRAIN_TIMEOUT).await? {
+            text.push_str(&more);
+        }
+        Ok(text)
+    }
+
+    fn chunk(&self, text: String) -> PaneChunk {
+        PaneChunk {
+            target: self.pane.target(),
+            text,
+            timestamp: now_ms(),
+        }
+    }
+}
+
+/// A pane's output read from a control client's `%output` events
+struct ControlOutputState {
+    pane: Pane,
+    connection: ControlConnection,
+    decoder: OutputDecoder,
+    opts: OutputStreamOpts,
+    /// Piped output, once the connection has given up reconnecting
+    fallback: Option<PaneOutputStream>,
+    done: bool,
+}
+
+impl ControlOutputState {
+    /// The next chunk, `None` once the pane has closed
+    async fn next(&mut self) -> Option<TmuxResult<PaneChunk>> {
+        loop {
+            if let Some(fallback) = self.fallback.as_mut() {
+                return fallback.next().await;
+            }
+            let gave_up = match self.connection.next_event().await {
+                Some(ControlEvent::Output { pane_id, data }) if pane_id == self.pane.id => {
+                    let text = self.decoder.push(&data);
+                    if text.is_empty() {
+                        continue;
+                    }
+                    return Some(Ok(PaneChunk {
+                        target: self.pane.target(),
+                        text,
+                        timestamp: now_ms(),
+                    }));
+                }
+                Some(ControlEvent::Output { .. }) | Some(ControlEvent::Reconnected) => continue,
+                Some(ControlEvent::Disconnected) => {
+                    warn!(
+                        "Control client for {} died, output is lost until it reconnects",
+                        self.pane.target()
+                    );
+                    false
+                }
+                // Windows closing & layouts changing are how a pane closing shows up
+                Some(_) => false,
+                None => true,
+            };
+            match self.pane.exists().await {
+                Ok(true) if gave_up => {
+                    match self.pane.pipe_output_stream(self.opts.clone()).await {
+                        Ok(stream) => self.fallback = Some(stream),
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+                Ok(true) => continue,
+                Ok(false) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+impl Pane {
+    /// Whether the pane still exists
+    pub(super) async fn exists(&self) -> TmuxResult<bool> {
+        let args = ["capture-pane", "-p", "-t", &self.id, "-S", "0", "-E", "0"];
+        match run(self.socket.as_deref(), &args).await {
+            Ok(_) => Ok(true),
+            Err(Failure::CantFind(_)) | Err(Failure::Other(TmuxError::NoServer)) => Ok(false),
+            Err(Failure::Other(err)) => Err(err),
+        }
+    }
+
+    /// Streams the pane's output as it is written, using `tmux pipe-pane`, or a control client
+    /// with `OutputStreamOpts::control_mode`. Piping replaces any pipe the pane already has. The
+    /// stream ends once the pane is killed, or after the first error
+    pub async fn output_stream(&self, opts: OutputStreamOpts) -> TmuxResult<PaneOutputStream> {
+        if opts.control_mode {
+            match self.control_output_stream(&opts).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => warn!(
+                    "Control mode unavailable for {}, polling its output: {}",
+                    self.target(),
+                    err
+                ),
+            }
+        }
+        self.pipe_output_stream(opts).await
+    }
+
+    /// Streams output from a control client attached to the pane's session. If the client
+    /// can't be reconnected after dying, output is piped instead
+    async fn control_output_stream(&self, opts: &OutputStreamOpts) -> TmuxResult<PaneOutputStream> {
+        let connection = ControlConnection::connect(
+            self.socket.as_deref(),
+            ControlOpts::for_session(&self.session),
+        )
+        .await?;
+        let state = ControlOutputState {
+            pane: self.clone(),
+            connection,
+            decoder: OutputDecoder {
+                pending: vec![],
+                escape_sequences: opts.escape_sequences,
+            },
+            opts: OutputStreamOpts {
+                control_mode: false,
+                ..opts.clone()
+            },
+            fallback: None,
+            done: false,
+        };
+        let stream = futures::stream::unfold(state, |mut state| async move {
+            if state.done {
+                return None;
+            }
+            let next = state.next().await;
+            state.done = matches!(next, None | Some(Err(_)));
+            Some((next?, state))
+        });
+        Ok(stream.boxed())
+    }
+
+    async fn pipe_output_stream(&self, opts: OutputStreamOpts) -> TmuxResult<PaneOutputStream> {
+        // Read from a fifo rather than a file, so output doesn't pile up on disk
+        let path =
+            std::env::temp_dir().join(format!("espionox-pane-{}.fifo", uuid::Uuid::new_v4()));
+        let made = Command::new("mkfifo").arg(&path).status().await?;
+        if !made.success() {
+            return Err(TmuxError::Command(format!(
+                "mkfifo {} failed",
+                path.display()
+            )));
+        }
+        let guard = PipeGuard {
+            socket: self.socket.to_owned(),
+            id: self.id.to_owned(),
+            path,
+        };
+        let fifo = pipe::OpenOptions::new()
+            .read_write(true)
+            .open_receiver(&guard.path)?;
+        let command = format!(
+            "exec cat > {}",
+            shell_quote(&guard.path.display().to_string())
+        );
+        run(
+            self.socket.as_deref(),
+            &["pipe-pane", "-t", &self.id, &command],
+        )
+        .await
+        .map_err(|failure| self.gone(failure))?;
+        let state = OutputState {
+            pane: self.clone(),
+            _guard: guard,
+            fifo,
+            decoder: OutputDecoder {
+                pending: vec![],
+                escape_sequences: opts.escape_sequences,
+            },
+            poll_interval: opts.poll_interval,
+            done: false,
+        };
+        let stream = futures::stream::unfold(state, |mut state| async move {
+            if state.done {
+                return None;
+            }
+            loop {
+                match state.read_new(state.poll_interval).await {
+                    Ok(Some(text)) if text.is_empty() => continue,
+                    Ok(Some(text)) => {
+                        let chunk = state.chunk(text);
+                        return Some((Ok(chunk), state));
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        state.done = true;
+                        return Some((Err(err), state));
+                    }
+                }
+                // Only checked once the pane has been quiet, output shows it is still open
+                match state.pane.exists().await {
+                    Ok(true) => continue,
+                    Ok(false) => {
+                        state.done = true;
+                        return match state.drain().await {
+                            Ok(text) if text.is_empty() => None,
+                            result => {
+                                let result = result.map(|text| state.chunk(text));
+                                Some((result, state))
+                            }
+                        };
+                    }
+                    Err(err) => {
+                        state.done = true;
+                        return Some((Err(err), state));
+                    }
+                }
+            }
+        });
+        Ok(stream.boxed())
+    }
+}
+
+impl Agent {
+    /// Pushes a pane's output to the cache until the stream ends. Chunks arriving within
+    /// `debounce.quiet` of each other are pushed together as one message, which is pushed once
+    /// it has waited `debounce.max_wait` so output that never stops still reaches the cache
+    pub async fn forward_pane_output(
+        &mut self,
+        pane: &Pane,
+        mut stream: PaneOutputStream,
+        role: MessageRole,
+        debounce: Debounce,
+    ) -> TmuxResult<()> {
+        let mut pending: Option<(String, u64, Instant)> = None;
+        loop {
+            let next = match &pending {
+                Some((_, _, started)) => {
+                    let left = debounce.max_wait.saturating_sub(started.elapsed());
+                    match tokio::time::timeout(debounce.quiet.min(left), stream.next()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            let (text, timestamp, _) =
+                                pending.take().expect("pending checked above");
+                            self.cache
+                                .push(pane.message(&text, role.clone(), timestamp));
+                            continue;
+                        }
+                    }
+                }
+                None => stream.next().await,
+            };
+            match next {
+                Some(chunk) => {
+                    let chunk = chunk?;
+                    match pending.as_mut() {
+                        Some((text, _, _)) => text.push_str(&chunk.text),
+                        None => pending = Some((chunk.text, chunk.timestamp, Instant::now())),
+                    }
+                }
+                None => break,
+            }
+        }
+        if let Some((text, timestamp, _)) = pending {
+            self.cache.push(pane.message(&text, role, timestamp));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        language_models::completions::CompletionModel, tmux::tests::TestServer,
+        tmux::CAPTURED_AT_METADATA_KEY,
+    };
+    use tokio::process::Command;
+
+    /// Prints three numbered lines after giving the stream time to start piping
+    const LINES_COMMAND: &str = "sleep 0.5; for i in 1 2 3; do echo \"line $i\"; sleep 0.2; done";
+
+    #[test]
+    fn split_escapes_and_characters_held_back() {
+        let mut decoder = OutputDecoder::default();
+        assert_eq!(decoder.push(b"\x1b[1;3"), "");
+        assert_eq!(decoder.push(b"2mred\x1b[0m\r\n\x1b]0;ti"), "red\n");
+        assert_eq!(decoder.push(b"tle\x07caf\xc3"), "caf");
+        assert_eq!(decoder.push(b"\xa9\x1b(B!"), "é!");
+
+        let mut raw = OutputDecoder {
+            pending: vec![],
+            escape_sequences: true,
+        };
+        assert_eq!(raw.push(b"\x1b[1m\xe2\x9c"), "\x1b[1m");
+        assert_eq!(raw.push(b"\x93\r\n"), "✓\r\n");
+    }
+
+    #[tokio::test]
+    async fn output_streamed_until_pane_killed() {
+        let Some(server) = TestServer::start(LINES_COMMAND).await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let mut stream = pane
+            .output_stream(OutputStreamOpts::default())
+            .await
+            .unwrap();
+
+        let mut text = String::new();
+        let mut last_timestamp = 0;
+        let mut resized = false;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.unwrap();
+            assert_eq!(chunk.target, "test:0.0");
+            assert!(chunk.timestamp >= last_timestamp);
+            last_timestamp = chunk.timestamp;
+            text.push_str(&chunk.text);
+            if !resized {
+                resized = true;
+                let status = Command::new(TMUX_BIN)
+                    .args([
+                        "-L",
+                        &server.socket,
+                        "resize-window",
+                        "-t",
+                        "test",
+                        "-x",
+                        "120",
+                    ])
+                    .status()
+                    .await
+                    .unwrap();
+                assert!(status.success());
+            }
+        }
+        // The pane closes once its command exits, ending the stream
+        assert_eq!(text, "line 1\nline 2\nline 3\n");
+    }
+
+    #[tokio::test]
+    async fn output_streamed_from_control_client() {
+        let Some(server) = TestServer::start(LINES_COMMAND).await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let opts = OutputStreamOpts {
+            control_mode: true,
+            poll_interval: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let stream = pane.output_stream(opts).await.unwrap();
+        let chunks: Vec<PaneChunk> = stream.map(|chunk| chunk.unwrap()).collect().await;
+        let text: String = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(text, "line 1\nline 2\nline 3\n");
+        assert!(chunks.iter().all(|chunk| chunk.target == "test:0.0"));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn output_forwarded_into_cache_debounced() {
+        let Some(server) = TestServer::start(LINES_COMMAND).await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let stream = pane
+            .output_stream(OutputStreamOpts::default())
+            .await
+            .unwrap();
+
+        let debounce = Debounce {
+            quiet: Duration::from_secs(1),
+            max_wait: Duration::from_secs(5),
+        };
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        agent
+            .forward_pane_output(&pane, stream, MessageRole::User, debounce)
+            .await
+            .unwrap();
+        let messages = agent.cache.as_ref();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "line 1\nline 2\nline 3\n");
+        assert!(messages[0].metadata.contains_key(CAPTURED_AT_METADATA_KEY));
+
+        // Output that keeps arriving is pushed once it has waited max_wait
+        let Some(server) = TestServer::start(LINES_COMMAND).await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let stream = pane
+            .output_stream(OutputStreamOpts::default())
+            .await
+            .unwrap();
+        let capped = Debounce {
+            max_wait: Duration::from_millis(100),
+            ..debounce
+        };
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        agent
+            .forward_pane_output(&pane, stream, MessageRole::User, capped)
+            .await
+            .unwrap();
+        let messages = agent.cache.as_ref();
+        assert!(messages.len() > 1);
+        let content: String = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(content, "line 1\nline 2\nline 3\n");
+    }
+}
@@ -0,0 +1,321 @@
+//! Raw, timestamped logs of what monitored panes produced, written as JSON lines so a write cut
+//! short by a crash only loses its last line
+use super::Pane;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+use tracing::warn;
+
+/// Default for `RecordingOpts::template`
+pub const DEFAULT_RECORDING_TEMPLATE: &str = "{session}-{window}-{pane}-{date}.jsonl";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const MS_PER_DAY: u64 = 86_400_000;
+
+/// When a recording's file is gzipped & a new one started. Rotated files are named after the
+/// file with `.1.gz`, `.2.gz` and so on appended
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate before a write would take the file past this many bytes
+    pub max_bytes: Option<u64>,
+    /// Rotate on the first write of each day, UTC
+    pub daily: bool,
+}
+
+/// Where & how a monitor records its pane, see `MonitorConfig::with_recording`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordingOpts {
+    pub dir: PathBuf,
+    /// File name, with `{session}`, `{window}`, `{pane}` & `{date}` replaced by the pane's
+    /// session name, window & pane index, and the `YYYY-MM-DD` UTC date of the write
+    pub template: String,
+    pub rotation: Option<Rotation>,
+}
+
+impl RecordingOpts {
+    /// Records to `DEFAULT_RECORDING_TEMPLATE` in `dir`, without rotation
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            template: DEFAULT_RECORDING_TEMPLATE.to_string(),
+            rotation: None,
+        }
+    }
+
+    pub fn with_template(mut self, template: &str) -> Self {
+        self.template = template.to_owned();
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = Some(rotation);
+        self
+    }
+
+    /// The file `pane` is recorded to at `at`, milliseconds since the unix epoch
+    pub fn path(&self, pane: &Pane, at: u64) -> PathBuf {
+        let name = self
+            .template
+            .replace("{session}", &pane.session.replace('/', "_"))
+            .replace("{window}", &pane.window.to_string())
+            .replace("{pane}", &pane.index.to_string())
+            .replace("{date}", &utc_date(at));
+        self.dir.join(name)
+    }
+}
+
+/// A capture of a pane as it was recorded, before any normalizing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimestampedChunk {
+    /// Milliseconds since the unix epoch when the capture was taken
+    pub timestamp: u64,
+    /// The `session:window.pane` target of the pane
+    pub target: String,
+    pub data: String,
+}
+
+/// `YYYY-MM-DD` of a time in milliseconds since the unix epoch, UTC
+fn utc_date(ms: u64) -> String {
+    // Converts days since the epoch to a proleptic Gregorian date, in 400 year eras
+    let days = (ms / MS_PER_DAY) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// The chunks recorded in a file, plain or gzipped. Reading stops at the end of what was
+/// written intact, a truncated last line or gzip member is skipped
+pub fn read(path: impl AsRef<Path>) -> std::io::Result<impl Iterator<Item = TimestampedChunk>> {
+    let path = path.as_ref();
+    let mut magic = [0; 2];
+    let gzipped = std::fs::File::open(path)?.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    let file = std::fs::File::open(path)?;
+    let reader: Box<dyn Read> = match gzipped {
+        true => Box::new(MultiGzDecoder::new(file)),
+        false => Box::new(file),
+    };
+    Ok(BufReader::new(reader)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok()))
+}
+
+/// Writes a pane's chunks on a task of its own, so capturing never waits on the disk. Chunks
+/// that arrive while a write is in progress are written together in the next one
+#[derive(Debug)]
+pub(super) struct Recorder {
+    sender: UnboundedSender<TimestampedChunk>,
+}
+
+impl Recorder {
+    /// The task finishes writing what it was sent once the recorder is dropped
+    pub(super) fn spawn(pane: &Pane, opts: RecordingOpts) -> Self {
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(write_chunks(pane.clone(), opts, receiver));
+        Self { sender }
+    }
+
+    pub(super) fn record(&self, chunk: TimestampedChunk) {
+        // The task only stops once this is dropped
+        let _ = self.sender.send(chunk);
+    }
+}
+
+/// The file being written to
+struct OpenRecording {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    day: u64,
+}
+
+async fn write_chunks(
+    pane: Pane,
+    opts: RecordingOpts,
+    mut receiver: UnboundedReceiver<TimestampedChunk>,
+) {
+    let mut open: Option<OpenRecording> = None;
+    while let Some(first) = receiver.recv().await {
+        let mut batch = vec![first];
+        while let Ok(chunk) = receiver.try_recv() {
+            batch.push(chunk);
+        }
+        let at = batch[0].timestamp;
+        let mut bytes = vec![];
+        for chunk in batch {
+            serde_json::to_writer(&mut bytes, &chunk).expect("chunks serialize");
+            bytes.push(b'\n');
+        }
+        match write_batch(&pane, &opts, &mut open, at, &bytes).await {
+            Ok(()) => {}
+            Err(err) => {
+                warn!("Failed to record {}: {:?}", pane.target(), err);
+                open = None;
+            }
+        }
+    }
+}
+
+async fn write_batch(
+    pane: &Pane,
+    opts: &RecordingOpts,
+    open: &mut Option<OpenRecording>,
+    at: u64,
+    bytes: &[u8],
+) -> std::io::Result<()> {
+    let path = opts.path(pane, at);
+    let day = at / MS_PER_DAY;
+    let rotation = opts.rotation.unwrap_or_default();
+    if let Some(current) = open.take() {
+        let oversized = rotation
+            .max_bytes
+            .is_some_and(|max| current.len > 0 && current.len + bytes.len() as u64 > max);
+        let new_day = rotation.daily && current.day != day;
+        match oversized || new_day {
+            true => {
+                drop(current.file);
+                tokio::task::spawn_blocking(move || rotate(&current.path))
+                    .await
+                    .map_err(std::io::Error::other)??;
+            }
+            false if current.path == path => *open = Some(current),
+            false => {}
+        }
+    }
+    if open.is_none() {
+        tokio::fs::create_dir_all(&opts.dir).await?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let len = file.metadata().await?.len();
+        *open = Some(OpenRecording {
+            path,
+            file,
+            len,
+            day,
+        });
+    }
+    let current = open.as_mut().expect("opened above");
+    current.file.write_all(bytes).await?;
+    current.file.flush().await?;
+    current.len += bytes.len() as u64;
+    Ok(())
+}
+
+/// Gzips `path` to the first free `path.N.gz` & removes it
+fn rotate(path: &Path) -> std::io::Result<()> {
+    let rotated = (1..)
+        .map(|n| PathBuf::from(format!("{}.{}.gz", path.display(), n)))
+        .find(|rotated| !rotated.exists())
+        .expect("some rotated name is free");
+    let mut encoder = GzEncoder::new(std::fs::File::create(&rotated)?, Compression::default());
+    std::io::copy(&mut std::fs::File::open(path)?, &mut encoder)?;
+    encoder.finish()?;
+    std::fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pane() -> Pane {
+        Pane {
+            session: "dev/api".to_string(),
+            window: 1,
+            index: 2,
+            id: "%4".to_string(),
+            socket: None,
+            history_limit: 0,
+        }
+    }
+
+    fn chunk(timestamp: u64, data: &str) -> TimestampedChunk {
+        TimestampedChunk {
+            timestamp,
+            target: "dev/api:1.2".to_string(),
+            data: data.to_string(),
+        }
+    }
+
+    #[test]
+    fn paths_rendered_from_template() {
+        let opts = RecordingOpts::new("/logs");
+        // 2024-02-29T12:00:00Z
+        let at = 1_709_208_000_000;
+        assert_eq!(
+            opts.path(&pane(), at),
+            PathBuf::from("/logs/dev_api-1-2-2024-02-29.jsonl")
+        );
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(951_782_400_000), "2000-02-29");
+        assert_eq!(utc_date(1_767_225_599_000), "2025-12-31");
+    }
+
+    #[tokio::test]
+    async fn rotated_recordings_read_back() {
+        let dir = std::env::temp_dir().join(format!("espionox-recording-{}", uuid::Uuid::new_v4()));
+        let opts = RecordingOpts::new(&dir)
+            .with_template("{pane}.jsonl")
+            .with_rotation(Rotation {
+                max_bytes: Some(100),
+                daily: false,
+            });
+        let recorder = Recorder::spawn(&pane(), opts);
+        let chunks: Vec<TimestampedChunk> = (0..6)
+            .map(|i| chunk(i, &format!("\x1b[1mline {}\x1b[0m", i)))
+            .collect();
+        for chunk in chunks.iter() {
+            recorder.record(chunk.clone());
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        drop(recorder);
+
+        let current = dir.join("2.jsonl");
+        let mut read_back = vec![];
+        for _ in 0..100 {
+            let rotated: Vec<PathBuf> = (1..)
+                .map(|n| dir.join(format!("2.jsonl.{}.gz", n)))
+                .take_while(|path| path.exists())
+                .collect();
+            read_back = rotated
+                .iter()
+                .chain([&current])
+                .filter_map(|path| read(path).ok())
+                .flatten()
+                .collect();
+            if read_back.len() == chunks.len() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(read_back, chunks);
+        assert!(dir.join("2.jsonl.2.gz").exists());
+
+        // A line cut short by a crash is skipped
+        let mut contents = std::fs::read(&current).unwrap();
+        contents.extend_from_slice(b"{\"timestamp\":9,\"tar");
+        std::fs::write(&current, contents).unwrap();
+        assert_eq!(read(&current).unwrap().last(), chunks.last().cloned());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,387 @@
+//! Picking panes by what they run & where, so targets survive rearranging windows
+use super::{
+    server::{list_panes, ListedPane},
+    window::glob_regex,
+    Pane, TmuxError, TmuxResult,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Which panes to pick. Every API targeting a pane takes one, a resolved `Pane` or a target
+/// string converts into it. In a config, variants are written in snake case as a table with one
+/// key, like `{ command = "cargo*" }` or `{ all_of = [...] }`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaneMatch {
+    /// One target, like `dev:1.0` or `%3`. Within `AllOf` & `AnyOf` it matches a pane by its
+    /// id, its `session:window.pane` target, its `session:window` or its session
+    Target(String),
+    /// Every pane of each window whose name matches a glob, like `test-*`
+    WindowGlob(String),
+    /// Every pane of each session whose name matches a glob
+    SessionGlob(String),
+    /// Panes whose title matches a regex
+    TitleRegex(String),
+    /// Panes whose foreground program's name matches a glob, like `cargo*` for `cargo watch`
+    Command(String),
+    /// Panes whose working directory starts with a path, like `/home/me/api`
+    PathPrefix(String),
+    /// Panes matching every one of these
+    AllOf(Vec<PaneMatch>),
+    /// Panes matching any one of these
+    AnyOf(Vec<PaneMatch>),
+    /// A resolved pane, on its own server whatever server the match is used with. Matches the
+    /// pane by its id
+    Pane(Pane),
+}
+
+impl From<Pane> for PaneMatch {
+    fn from(pane: Pane) -> Self {
+        Self::Pane(pane)
+    }
+}
+
+impl From<&Pane> for PaneMatch {
+    fn from(pane: &Pane) -> Self {
+        Self::Pane(pane.clone())
+    }
+}
+
+impl From<&PaneMatch> for PaneMatch {
+    fn from(matches: &PaneMatch) -> Self {
+        matches.clone()
+    }
+}
+
+impl From<&str> for PaneMatch {
+    fn from(target: &str) -> Self {
+        Self::Target(target.to_owned())
+    }
+}
+
+impl From<String> for PaneMatch {
+    fn from(target: String) -> Self {
+        Self::Target(target)
+    }
+}
+
+/// What `Pane::select` returns when more than one pane matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolvePolicy {
+    /// The first in the order tmux lists panes, by session, window & index
+    #[default]
+    First,
+    All,
+    /// Fail with `TmuxError::InvalidTarget` if there is more than one
+    Unique,
+}
+
+/// A `PaneMatch` with its patterns compiled
+#[derive(Debug, Clone)]
+pub(super) enum Matcher {
+    Target(String),
+    Window(Regex),
+    Session(Regex),
+    Title(Regex),
+    Command(Regex),
+    PathPrefix(String),
+    AllOf(Vec<Matcher>),
+    AnyOf(Vec<Matcher>),
+}
+
+impl Matcher {
+    pub(super) fn matches(&self, listing: &ListedPane) -> bool {
+        let pane = &listing.pane;
+        match self {
+            Self::Target(target) => {
+                *target == pane.id
+                    || *target == pane.target()
+                    || *target == format!("{}:{}", pane.session, pane.window)
+                    || *target == pane.session
+            }
+            Self::Window(pattern) => pattern.is_match(&listing.window_name),
+            Self::Session(pattern) => pattern.is_match(&pane.session),
+            Self::Title(pattern) => pattern.is_match(&listing.title),
+            Self::Command(pattern) => pattern.is_match(&listing.command),
+            Self::PathPrefix(prefix) => listing.path.starts_with(prefix.as_str()),
+            Self::AllOf(matchers) => matchers.iter().all(|m| m.matches(listing)),
+            Self::AnyOf(matchers) => matchers.iter().any(|m| m.matches(listing)),
+        }
+    }
+}
+
+impl PaneMatch {
+    /// Fails with `TmuxError::InvalidPattern` for a title regex that doesn't parse
+    pub(super) fn matcher(&self) -> TmuxResult<Matcher> {
+        let all = |matches: &[PaneMatch]| -> TmuxResult<Vec<Matcher>> {
+            matches.iter().map(Self::matcher).collect()
+        };
+        Ok(match self {
+            Self::Target(target) => Matcher::Target(target.to_owned()),
+            Self::WindowGlob(glob) => Matcher::Window(glob_regex(glob)),
+            Self::SessionGlob(glob) => Matcher::Session(glob_regex(glob)),
+            Self::TitleRegex(pattern) => Matcher::Title(Regex::new(pattern)?),
+            Self::Command(glob) => Matcher::Command(glob_regex(glob)),
+            Self::PathPrefix(prefix) => Matcher::PathPrefix(prefix.to_owned()),
+            Self::AllOf(matches) => Matcher::AllOf(all(matches)?),
+            Self::AnyOf(matches) => Matcher::AnyOf(all(matches)?),
+            Self::Pane(pane) => Matcher::Target(pane.id.to_owned()),
+        })
+    }
+
+    /// For a target or a pane, the server & target for tmux to resolve, since tmux understands
+    /// every form of target
+    pub(super) fn tmux_target<'m>(
+        &'m self,
+        socket: Option<&'m str>,
+    ) -> Option<(Option<&'m str>, &'m str)> {
+        match self {
+            Self::Target(target) => Some((socket, target)),
+            Self::Pane(pane) => Some((pane.socket.as_deref(), &pane.id)),
+            _ => None,
+        }
+    }
+
+    /// The pane this picks on the server with the given socket name, the first when several
+    /// match. A `Pane` is returned as is, without checking it is still open. Fails with
+    /// `TmuxError::InvalidTarget` when no pane matches
+    pub(super) async fn resolve_first(&self, socket: Option<&str>) -> TmuxResult<Pane> {
+        match self {
+            Self::Pane(pane) => Ok(pane.clone()),
+            Self::Target(target) => Pane::resolve_on(socket, target).await,
+            _ => Pane::select_on(socket, self, ResolvePolicy::First)
+                .await?
+                .pop()
+                .ok_or_else(|| TmuxError::InvalidTarget {
+                    target: self.to_string(),
+                    message: "no pane matches".to_string(),
+                }),
+        }
+    }
+
+    /// The pane's target for a target or pane, otherwise a description of what is matched
+    pub(super) fn describe(&self) -> String {
+        match self {
+            Self::Target(target) => target.to_owned(),
+            Self::Pane(pane) => pane.target(),
+            matches => matches.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for PaneMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |matches: &[PaneMatch]| {
+            matches
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        match self {
+            Self::Target(target) => write!(f, "target {}", target),
+            Self::WindowGlob(glob) => write!(f, "window {}", glob),
+            Self::SessionGlob(glob) => write!(f, "session {}", glob),
+            Self::TitleRegex(pattern) => write!(f, "title /{}/", pattern),
+            Self::Command(glob) => write!(f, "command {}", glob),
+            Self::PathPrefix(prefix) => write!(f, "path {}*", prefix),
+            Self::AllOf(matches) => write!(f, "all of ({})", join(matches)),
+            Self::AnyOf(matches) => write!(f, "any of ({})", join(matches)),
+            Self::Pane(pane) => write!(f, "pane {}", pane.id),
+        }
+    }
+}
+
+impl Pane {
+    /// The panes on the default server matching `matches`, by `policy`
+    pub async fn select(
+        matches: impl Into<PaneMatch>,
+        policy: ResolvePolicy,
+    ) -> TmuxResult<Vec<Self>> {
+        Self::select_on(None, matches, policy).await
+    }
+
+    /// The panes matching `matches` on the server with the given socket name. No panes match
+    /// when there is no server
+    pub async fn select_on(
+        socket: Option<&str>,
+        matches: impl Into<PaneMatch>,
+        policy: ResolvePolicy,
+    ) -> TmuxResult<Vec<Self>> {
+        let matches = matches.into();
+        let panes = match matches.tmux_target(socket) {
+            Some((socket, target)) => match Self::resolve_on(socket, target).await {
+                Ok(pane) => vec![pane],
+                Err(TmuxError::InvalidTarget { .. })
+                | Err(TmuxError::PaneGone { .. })
+                | Err(TmuxError::NoServer) => vec![],
+                Err(err) => return Err(err),
+            },
+            None => {
+                let matcher = matches.matcher()?;
+                list_panes(socket)
+                    .await?
+                    .into_iter()
+                    .filter(|listing| matcher.matches(listing))
+                    .map(|listing| listing.pane)
+                    .collect()
+            }
+        };
+        match policy {
+            ResolvePolicy::First => Ok(panes.into_iter().take(1).collect()),
+            ResolvePolicy::All => Ok(panes),
+            ResolvePolicy::Unique if panes.len() > 1 => Err(TmuxError::InvalidTarget {
+                target: matches.to_string(),
+                message: format!(
+                    "matched {} panes: {}",
+                    panes.len(),
+                    panes
+                        .iter()
+                        .map(Pane::target)
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+            }),
+            ResolvePolicy::Unique => Ok(panes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmux::{run, tests::TestServer};
+
+    fn listing(command: &str, path: &str, title: &str) -> ListedPane {
+        ListedPane {
+            pane: Pane {
+                session: "dev".to_string(),
+                window: 1,
+                index: 0,
+                id: "%4".to_string(),
+                socket: None,
+                history_limit: 2000,
+            },
+            window_name: "api".to_string(),
+            command: command.to_string(),
+            path: path.to_string(),
+            title: title.to_string(),
+            width: 80,
+            height: 24,
+            active: true,
+        }
+    }
+
+    #[test]
+    fn matchers_combined() {
+        let matches = PaneMatch::AllOf(vec![
+            PaneMatch::SessionGlob("d*".to_string()),
+            PaneMatch::AnyOf(vec![
+                PaneMatch::Command("cargo*".to_string()),
+                PaneMatch::TitleRegex("^build (ok|failed)$".to_string()),
+            ]),
+            PaneMatch::PathPrefix("/home/me/api".to_string()),
+        ]);
+        let matcher = matches.matcher().unwrap();
+        assert!(matcher.matches(&listing("cargo-watch", "/home/me/api/src", "host")));
+        assert!(matcher.matches(&listing("zsh", "/home/me/api", "build failed")));
+        assert!(!matcher.matches(&listing("zsh", "/home/me/api", "host")));
+        assert!(!matcher.matches(&listing("cargo", "/home/me/web", "host")));
+        for target in ["%4", "dev:1.0", "dev:1", "dev"] {
+            assert!(PaneMatch::Target(target.to_string())
+                .matcher()
+                .unwrap()
+                .matches(&listing("zsh", "/", "")));
+        }
+        assert!(PaneMatch::TitleRegex("(".to_string()).matcher().is_err());
+        assert_eq!(
+            matches.to_string(),
+            "all of (session d*, any of (command cargo*, title /^build (ok|failed)$/), path /home/me/api*)"
+        );
+    }
+
+    #[test]
+    fn selectors_deserialized() {
+        let matches: PaneMatch = serde_json::from_str(
+            r#"{"any_of": [{"command": "cargo*"}, {"window_glob": "test-*"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            matches,
+            PaneMatch::AnyOf(vec![
+                PaneMatch::Command("cargo*".to_string()),
+                PaneMatch::WindowGlob("test-*".to_string()),
+            ])
+        );
+        let policy: ResolvePolicy = serde_json::from_str(r#""unique""#).unwrap();
+        assert_eq!(policy, ResolvePolicy::Unique);
+    }
+
+    #[test]
+    fn panes_and_targets_converted_to_matches() {
+        let pane = listing("zsh", "/", "").pane;
+        assert_eq!(
+            PaneMatch::from("dev:1"),
+            PaneMatch::Target("dev:1".to_string())
+        );
+        let matches = PaneMatch::from(&pane);
+        assert_eq!(matches.to_string(), "pane %4");
+        assert_eq!(matches.describe(), "dev:1.0");
+        assert_eq!(matches.tmux_target(Some("other")), Some((None, "%4")));
+        assert!(matches.matcher().unwrap().matches(&listing("zsh", "/", "")));
+        let glob = PaneMatch::WindowGlob("ap*".to_string());
+        assert_eq!(glob.describe(), "window ap*");
+        assert_eq!(glob.tmux_target(None), None);
+    }
+
+    #[tokio::test]
+    async fn panes_selected_by_policy() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        let socket = Some(server.socket.as_str());
+        let split = ["split-window", "-d", "-t", "test:0", "sleep 30"];
+        assert!(run(socket, &split).await.is_ok());
+        let sleeping = PaneMatch::Command("sleep".to_string());
+
+        let all = Pane::select_on(socket, &sleeping, ResolvePolicy::All)
+            .await
+            .unwrap();
+        let targets: Vec<String> = all.iter().map(Pane::target).collect();
+        assert_eq!(targets, ["test:0.0", "test:0.1"]);
+        let first = Pane::select_on(socket, &sleeping, ResolvePolicy::First)
+            .await
+            .unwrap();
+        assert_eq!(first, all[..1]);
+        match Pane::select_on(socket, &sleeping, ResolvePolicy::Unique).await {
+            Err(TmuxError::InvalidTarget { target, message }) => {
+                assert_eq!(target, "command sleep");
+                assert_eq!(message, "matched 2 panes: test:0.0, test:0.1");
+            }
+            other => panic!("expected an ambiguous selector error, got {:?}", other),
+        }
+
+        let target = PaneMatch::Target("test:0.1".to_string());
+        let selected = Pane::select_on(socket, &target, ResolvePolicy::Unique)
+            .await
+            .unwrap();
+        assert_eq!(selected, all[1..]);
+        let missing = PaneMatch::Command("vim".to_string());
+        assert!(Pane::select_on(socket, &missing, ResolvePolicy::All)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            sleeping.resolve_first(socket).await.unwrap().target(),
+            "test:0.0"
+        );
+        match missing.resolve_first(socket).await {
+            Err(TmuxError::InvalidTarget { target, message }) => {
+                assert_eq!(target, "command vim");
+                assert_eq!(message, "no pane matches");
+            }
+            other => panic!("expected no pane to match, got {:?}", other),
+        }
+    }
+}
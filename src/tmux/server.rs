@@ -0,0 +1,383 @@
+//! Listing what a tmux server runs, its sessions along with their windows & panes
+use super::{run, Failure, Pane, PaneMatch, TmuxError, TmuxResult, PANE_FORMAT};
+use serde::{Deserialize, Serialize};
+
+/// Fields of `list-sessions` output, with the name last since it is the field most likely to
+/// contain a tab
+const SESSION_FORMAT: &str = "#{session_id}\t#{session_attached}\t#{session_name}";
+const SESSION_FIELDS: usize = 3;
+/// Fields of `list-windows` output, with the name last
+const WINDOW_FORMAT: &str = "#{session_id}\t#{window_id}\t#{window_index}\t#{window_active}\t#{window_width}\t#{window_height}\t#{window_name}";
+const WINDOW_FIELDS: usize = 7;
+/// Listed ahead of `PANE_FORMAT` in `list-panes` output, which is followed by the title
+const PANE_FORMAT_PREFIX: &str = "#{session_id}\t#{window_id}\t#{pane_active}\t#{pane_width}\t#{pane_height}\t#{window_name}\t#{pane_current_command}\t#{pane_current_path}";
+/// Tab separated fields of a pane in `list-panes` output, those of `PANE_FORMAT` included
+const PANE_FIELDS: usize = 14;
+
+/// Lists the sessions, windows & panes of a tmux server. Formats are passed explicitly, so
+/// listing doesn't depend on the user's tmux config
+#[derive(Debug, Clone, Copy)]
+pub struct Tmux;
+
+/// Whether a server is running, and what it runs if it is
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerState {
+    NotRunning,
+    Running(Vec<Session>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    /// tmux's unique id for the session, like `$1`
+    pub id: String,
+    pub name: String,
+    /// A client is attached to the session
+    pub attached: bool,
+    pub windows: Vec<Window>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Window {
+    /// tmux's unique id for the window, like `@3`
+    pub id: String,
+    pub index: u32,
+    pub name: String,
+    /// The window is its session's current window
+    pub active: bool,
+    pub width: u32,
+    pub height: u32,
+    pub panes: Vec<ListedPane>,
+}
+
+/// A pane along with what it is running
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListedPane {
+    pub pane: Pane,
+    pub window_name: String,
+    /// Name of the pane's foreground program, like `cargo` or `zsh`
+    pub command: String,
+    /// The foreground program's working directory
+    pub path: String,
+    pub title: String,
+    pub width: u32,
+    pub height: u32,
+    /// The pane is its window's current pane
+    pub active: bool,
+}
+
+impl ServerState {
+    /// The server's sessions, none when it isn't running
+    pub fn sessions(self) -> Vec<Session> {
+        match self {
+            Self::NotRunning => vec![],
+            Self::Running(sessions) => sessions,
+        }
+    }
+}
+
+impl Session {
+    pub fn panes(&self) -> impl Iterator<Item = &ListedPane> {
+        self.windows.iter().flat_map(|window| window.panes.iter())
+    }
+}
+
+/// `1` & counts of clients are set, empty fields of formats an older server doesn't know aren't
+fn flag(field: &str) -> bool {
+    !field.is_empty() && field != "0"
+}
+
+/// Dimensions are `0` when the server doesn't know their format
+fn dimension(field: &str) -> u32 {
+    field.parse().unwrap_or_default()
+}
+
+/// Ids of the session and window a listed window or pane belongs to, for assembling the tree
+type Parent = (String, String);
+
+fn parse_session(line: &str) -> Option<Session> {
+    let fields: Vec<&str> = line.splitn(SESSION_FIELDS, '\t').collect();
+    let [id, attached, name] = fields.as_slice() else {
+        return None;
+    };
+    Some(Session {
+        id: id.to_string(),
+        name: name.to_string(),
+        attached: flag(attached),
+        windows: vec![],
+    })
+}
+
+/// A window along with the id of its session
+fn parse_window(line: &str) -> Option<(String, Window)> {
+    let fields: Vec<&str> = line.splitn(WINDOW_FIELDS, '\t').collect();
+    let [session_id, id, index, active, width, height, name] = fields.as_slice() else {
+        return None;
+    };
+    let window = Window {
+        id: id.to_string(),
+        index: index.parse().ok()?,
+        name: name.to_string(),
+        active: flag(active),
+        width: dimension(width),
+        height: dimension(height),
+        panes: vec![],
+    };
+    Some((session_id.to_string(), window))
+}
+
+impl ListedPane {
+    /// Parses a line of `list-panes` output, along with the ids of the pane's session & window
+    fn parse(socket: Option<&str>, line: &str) -> Option<(Parent, Self)> {
+        let fields: Vec<&str> = line.splitn(PANE_FIELDS, '\t').collect();
+        let [session_id, window_id, active, width, height, window_name, command, path, pane @ .., title] =
+            fields.as_slice()
+        else {
+            return None;
+        };
+        if fields.len() != PANE_FIELDS {
+            return None;
+        }
+        let listed = Self {
+            pane: Pane::parse_format(socket, &pane.join("\t"))?,
+            window_name: window_name.to_string(),
+            command: command.to_string(),
+            path: path.to_string(),
+            title: title.to_string(),
+            width: dimension(width),
+            height: dimension(height),
+            active: flag(active),
+        };
+        Some(((session_id.to_string(), window_id.to_string()), listed))
+    }
+}
+
+/// The `-F` argument listing panes with `ListedPane::parse`
+fn pane_format() -> String {
+    format!("{}\t{}\t#{{pane_title}}", PANE_FORMAT_PREFIX, PANE_FORMAT)
+}
+
+/// Builds the tree of sessions from the output of each list command. Windows & panes of a
+/// session or window that wasn't listed, because it was created between commands, are left out
+fn assemble(socket: Option<&str>, sessions: &str, windows: &str, panes: &str) -> Vec<Session> {
+    let mut sessions: Vec<Session> = sessions.lines().filter_map(parse_session).collect();
+    for (session_id, window) in windows.lines().filter_map(parse_window) {
+        if let Some(session) = sessions.iter_mut().find(|s| s.id == session_id) {
+            session.windows.push(window);
+        }
+    }
+    let listed = panes
+        .lines()
+        .filter_map(|line| ListedPane::parse(socket, line));
+    for ((session_id, window_id), pane) in listed {
+        let window = sessions
+            .iter_mut()
+            .filter(|s| s.id == session_id)
+            .flat_map(|s| s.windows.iter_mut())
+            .find(|w| w.id == window_id);
+        if let Some(window) = window {
+            window.panes.push(pane);
+        }
+    }
+    sessions
+}
+
+/// Every pane on the server, none if there is no server
+pub(super) async fn list_panes(socket: Option<&str>) -> TmuxResult<Vec<ListedPane>> {
+    let output = match run(socket, &["list-panes", "-a", "-F", &pane_format()]).await {
+        Ok(output) => output,
+        Err(Failure::Other(TmuxError::NoServer)) | Err(Failure::CantFind(_)) => return Ok(vec![]),
+        Err(Failure::Other(err)) => return Err(err),
+    };
+    Ok(output
+        .lines()
+        .filter_map(|line| ListedPane::parse(socket, line))
+        .map(|(_, pane)| pane)
+        .collect())
+}
+
+impl Tmux {
+    /// Sessions of the default server, none when no server is running. Use `Tmux::state` to
+    /// tell a server without sessions apart from no server
+    pub async fn list() -> TmuxResult<Vec<Session>> {
+        Self::list_on(None).await
+    }
+
+    /// Sessions of the server with the given socket name
+    pub async fn list_on(socket: Option<&str>) -> TmuxResult<Vec<Session>> {
+        Ok(Self::state_on(socket).await?.sessions())
+    }
+
+    /// Whether the default server is running, with its sessions if it is
+    pub async fn state() -> TmuxResult<ServerState> {
+        Self::state_on(None).await
+    }
+
+    /// Whether the server with the given socket name is running, with its sessions if it is.
+    /// A server that exits between listing sessions, windows & panes isn't running
+    pub async fn state_on(socket: Option<&str>) -> TmuxResult<ServerState> {
+        let pane_format = pane_format();
+        let commands = [
+            vec!["list-sessions", "-F", SESSION_FORMAT],
+            vec!["list-windows", "-a", "-F", WINDOW_FORMAT],
+            vec!["list-panes", "-a", "-F", &pane_format],
+        ];
+        let mut outputs = vec![];
+        for args in commands.iter() {
+            match run(socket, args).await {
+                Ok(output) => outputs.push(output),
+                Err(Failure::Other(TmuxError::NoServer)) => return Ok(ServerState::NotRunning),
+                // Reported when the server has no sessions left
+                Err(Failure::CantFind(_)) => outputs.push(String::new()),
+                Err(Failure::Other(err)) => return Err(err),
+            }
+        }
+        Ok(ServerState::Running(assemble(
+            socket,
+            &outputs[0],
+            &outputs[1],
+            &outputs[2],
+        )))
+    }
+
+    /// The first pane on the default server matching `selector`, in the order tmux lists panes
+    pub async fn find_pane(selector: impl Into<PaneMatch>) -> TmuxResult<Option<ListedPane>> {
+        Self::find_pane_on(None, selector).await
+    }
+
+    /// The first pane matching `selector` on the server with the given socket name, or the
+    /// pane's own server for a `PaneMatch::Pane`. No pane matches when there is no server
+    pub async fn find_pane_on(
+        socket: Option<&str>,
+        selector: impl Into<PaneMatch>,
+    ) -> TmuxResult<Option<ListedPane>> {
+        let selector = selector.into();
+        let socket = match &selector {
+            PaneMatch::Pane(pane) => pane.socket.as_deref(),
+            _ => socket,
+        };
+        let matcher = selector.matcher()?;
+        Ok(list_panes(socket)
+            .await?
+            .into_iter()
+            .find(|pane| matcher.matches(pane)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmux::{run, tests::TestServer};
+
+    /// Captured from tmux 3.3a, with a session `dev` holding a split `api` window and a `logs
+    /// view` window, and a session `ops`
+    const SESSIONS_3_3: &str = include_str!("fixtures/list-sessions-3.3a.txt");
+    const WINDOWS_3_3: &str = include_str!("fixtures/list-windows-3.3a.txt");
+    const PANES_3_3: &str = include_str!("fixtures/list-panes-3.3a.txt");
+    /// In the shape a server older than the formats it's given prints them, with those it
+    /// doesn't know empty, here dimensions & `session_attached`. Also has a window linked into
+    /// two sessions, names with spaces and a title with a tab
+    const SESSIONS_OLD: &str = include_str!("fixtures/list-sessions-old.txt");
+    const WINDOWS_OLD: &str = include_str!("fixtures/list-windows-old.txt");
+    const PANES_OLD: &str = include_str!("fixtures/list-panes-old.txt");
+
+    #[test]
+    fn listings_parsed_from_3_3_fixtures() {
+        let sessions = assemble(Some("fx"), SESSIONS_3_3, WINDOWS_3_3, PANES_3_3);
+        let names: Vec<&str> = sessions.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["dev", "ops"]);
+        let dev = &sessions[0];
+        assert!(!dev.attached);
+        let windows: Vec<(u32, &str, bool)> = dev
+            .windows
+            .iter()
+            .map(|w| (w.index, w.name.as_str(), w.active))
+            .collect();
+        assert_eq!(windows, [(0, "api", true), (1, "logs view", false)]);
+        assert_eq!((dev.windows[0].width, dev.windows[0].height), (120, 40));
+
+        let api = &dev.windows[0].panes;
+        assert_eq!(api.len(), 2);
+        assert_eq!(api[0].pane.target(), "dev:0.0");
+        assert_eq!(api[0].pane.id, "%0");
+        assert_eq!(api[0].pane.socket.as_deref(), Some("fx"));
+        assert_eq!(api[0].pane.history_limit, 2000);
+        assert_eq!(
+            (api[0].width, api[0].height, api[0].active),
+            (120, 20, true)
+        );
+        assert_eq!((api[1].height, api[1].active), (19, false));
+        assert_eq!(api[1].command, "sleep");
+        assert_eq!(api[1].path, "/tmp");
+        assert_eq!(api[1].window_name, "api");
+        assert_eq!(dev.windows[1].panes[0].command, "tail");
+
+        let ops = &sessions[1];
+        assert_eq!(ops.panes().count(), 1);
+        assert_eq!(ops.windows[0].panes[0].pane.target(), "ops:0.0");
+    }
+
+    #[test]
+    fn listings_parsed_from_old_fixtures() {
+        let sessions = assemble(None, SESSIONS_OLD, WINDOWS_OLD, PANES_OLD);
+        let names: Vec<&str> = sessions.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["main", "side job"]);
+        assert!(sessions.iter().all(|s| !s.attached));
+
+        let main = &sessions[0];
+        assert_eq!(main.windows.len(), 2);
+        assert_eq!(main.windows[1].name, "build logs");
+        assert_eq!((main.windows[0].width, main.windows[0].height), (0, 0));
+        let pane = &main.windows[0].panes[0];
+        assert_eq!(pane.title, "host: ~/src\tvim");
+        assert_eq!((pane.width, pane.height), (0, 0));
+        assert_eq!(pane.command, "vim");
+        // The linked window is listed under both sessions, each with its own target
+        let linked: Vec<String> = sessions
+            .iter()
+            .flat_map(|s| s.panes())
+            .filter(|p| p.pane.id == "%2")
+            .map(|p| p.pane.target())
+            .collect();
+        assert_eq!(linked, ["main:1.0", "side job:3.0"]);
+        // A pane line cut short is skipped, as is a pane of a window that wasn't listed
+        assert_eq!(sessions.iter().flat_map(|s| s.panes()).count(), 3);
+    }
+
+    #[tokio::test]
+    async fn servers_listed_and_panes_found() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        let socket = Some(server.socket.as_str());
+        let window = ["new-window", "-d", "-t", "test", "-n", "logs-1", "cat"];
+        assert!(run(socket, &window).await.is_ok());
+
+        let sessions = Tmux::list_on(socket).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].name, "test");
+        let commands: Vec<&str> = sessions[0].panes().map(|p| p.command.as_str()).collect();
+        assert_eq!(commands, ["sleep", "cat"]);
+        assert_eq!(
+            (sessions[0].windows[0].width, sessions[0].windows[0].height),
+            (80, 24)
+        );
+
+        let by_window = PaneMatch::WindowGlob("logs-*".to_string());
+        let found = Tmux::find_pane_on(socket, &by_window).await.unwrap();
+        assert_eq!(found.unwrap().pane.target(), "test:1.0");
+        let by_command = PaneMatch::Command("sle*".to_string());
+        let found = Tmux::find_pane_on(socket, &by_command).await.unwrap();
+        assert_eq!(found.unwrap().pane.target(), "test:0.0");
+        let missing = PaneMatch::SessionGlob("prod*".to_string());
+        assert_eq!(Tmux::find_pane_on(socket, &missing).await.unwrap(), None);
+
+        server.kill().await;
+        assert_eq!(
+            Tmux::state_on(socket).await.unwrap(),
+            ServerState::NotRunning
+        );
+        assert!(Tmux::list_on(socket).await.unwrap().is_empty());
+        assert_eq!(Tmux::find_pane_on(socket, &by_command).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,526 @@
+//! Alerting when a pane goes quiet for too long
+use super::{
+    now_ms, run, CaptureOpts, MessageRole, Pane, PaneMatch, SharedAgent, TmuxError, TmuxResult,
+};
+use crate::agents::memory::Message;
+use regex::Regex;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle, time::MissedTickBehavior};
+use tracing::warn;
+
+/// How often panes are captured to check for output by default
+const DEFAULT_SILENCE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a silence detector does when a pane has been quiet for too long
+#[derive(Debug, Clone)]
+pub enum SilenceAction {
+    /// Send the alert over a channel
+    Notify(UnboundedSender<SilenceAlert>),
+    /// Push the rendered alert to the agent's cache as a user message
+    PushMessage(SharedAgent),
+    /// Push the rendered alert with the pane's last `lines` lines as a user message, then get
+    /// an io completion and push the response
+    Assess { agent: SharedAgent, lines: usize },
+}
+
+/// A pane that has been quiet for longer than its rule allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SilenceAlert {
+    /// The `session:window.pane` target of the pane
+    pub target: String,
+    pub quiet_for: Duration,
+    /// The pane's `pane_current_command`, when the rule checks it
+    pub command: Option<String>,
+    /// Alerts fired since the pane last had output, including this one
+    pub repeat: u64,
+    /// The pane's last lines, for `SilenceAction::Assess`
+    pub tail: Option<String>,
+    /// Milliseconds since the unix epoch when the alert fired
+    pub timestamp: u64,
+}
+
+impl SilenceAlert {
+    pub fn render(&self) -> String {
+        let mut rendered = format!(
+            "Pane {} has had no output for {}s",
+            self.target,
+            self.quiet_for.as_secs()
+        );
+        if let Some(command) = &self.command {
+            rendered.push_str(&format!(" while running `{}`", command));
+        }
+        if let Some(tail) = &self.tail {
+            rendered.push_str(&format!(", its last lines are:\n{}", tail));
+        }
+        rendered
+    }
+}
+
+impl SilenceAction {
+    async fn run(&self, pane: &Pane, mut alert: SilenceAlert) -> TmuxResult<()> {
+        match self {
+            Self::Notify(sender) => {
+                if sender.send(alert).is_err() {
+                    warn!("Silence notification receiver was dropped");
+                }
+            }
+            Self::PushMessage(agent) => {
+                let message = pane.message(&alert.render(), MessageRole::User, alert.timestamp);
+                agent.lock().await.cache.push(message);
+            }
+            Self::Assess { agent, lines } => {
+                alert.tail = Some(pane.capture_tail(*lines).await?);
+                let message = pane.message(&alert.render(), MessageRole::User, alert.timestamp);
+                let mut agent = agent.lock().await;
+                agent.cache.push(message);
+                match agent.io_completion().await {
+                    Ok(response) => agent.cache.push(Message::new_assistant(&response)),
+                    Err(err) => warn!("Silence assessment completion failed: {:?}", err),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How long a pane may go without output, and what to do when it does
+#[derive(Debug, Clone)]
+pub struct SilenceRule {
+    /// Resolved once the detector starts. A rule whose match picks no pane is treated as if its
+    /// pane had closed
+    pub pane: PaneMatch,
+    /// Name of the server socket the pane is picked on, `None` is the default server
+    pub socket: Option<String>,
+    pub max_quiet: Duration,
+    /// Only alert while the pane's `pane_current_command` matches, so a pane that is back at an
+    /// idle shell doesn't alert
+    pub command: Option<Regex>,
+    /// Alert again after this long while the pane stays quiet. `None` alerts once per silence
+    pub realert: Option<Duration>,
+    pub action: SilenceAction,
+}
+
+impl SilenceRule {
+    /// A rule for any command that alerts once per silence
+    pub fn new(pane: impl Into<PaneMatch>, max_quiet: Duration, action: SilenceAction) -> Self {
+        Self {
+            pane: pane.into(),
+            socket: None,
+            max_quiet,
+            command: None,
+            realert: None,
+            action,
+        }
+    }
+
+    /// Pick the pane on the server with the given socket name rather than the default server
+    pub fn on_socket(mut self, socket: &str) -> Self {
+        self.socket = Some(socket.to_owned());
+        self
+    }
+
+    pub fn when_command(mut self, command: &str) -> TmuxResult<Self> {
+        self.command = Some(Regex::new(command)?);
+        Ok(self)
+    }
+
+    pub fn with_realert(mut self, interval: Duration) -> Self {
+        self.realert = Some(interval);
+        self
+    }
+}
+
+/// Silence state of a pane, see `SilenceDetectorHandle::states`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SilenceState {
+    pub target: String,
+    /// Milliseconds since the unix epoch when the pane's content last changed, or when the
+    /// detector started if it hasn't
+    pub last_output: u64,
+    /// Alerts fired for the pane
+    pub alerts: u64,
+    /// Whether the pane has alerted since its last output
+    pub silent: bool,
+    /// False once the pane has closed
+    pub open: bool,
+}
+
+/// Tracks a pane's captures, deciding when it has been quiet long enough to alert
+#[derive(Debug)]
+struct SilenceTracker {
+    last_capture: Option<String>,
+    last_output: Instant,
+    last_output_ms: u64,
+    last_alert: Option<Instant>,
+    repeat: u64,
+    alerts: u64,
+}
+
+impl SilenceTracker {
+    fn new(now: Instant, now_ms: u64) -> Self {
+        Self {
+            last_capture: None,
+            last_output: now,
+            last_output_ms: now_ms,
+            last_alert: None,
+            repeat: 0,
+            alerts: 0,
+        }
+    }
+
+    /// Records a capture, returning how long the pane has been quiet if an alert is due. A
+    /// changed capture resets the silence. `command_matches` is whether the pane's command is
+    /// one the rule alerts for
+    fn observe(
+        &mut self,
+        rule: &SilenceRule,
+        capture: &str,
+        command_matches: bool,
+        now: Instant,
+        now_ms: u64,
+    ) -> Option<Duration> {
+        if self.last_capture.as_deref() != Some(capture) {
+            if self.last_capture.is_some() {
+                self.last_output = now;
+                self.last_output_ms = now_ms;
+                self.last_alert = None;
+                self.repeat = 0;
+            }
+            self.last_capture = Some(capture.to_owned());
+            return None;
+        }
+        let quiet_for = now.duration_since(self.last_output);
+        if !command_matches || quiet_for < rule.max_quiet {
+            return None;
+        }
+        let due = match (self.last_alert, rule.realert) {
+            (None, _) => true,
+            (Some(last), Some(interval)) => now.duration_since(last) >= interval,
+            (Some(_), None) => false,
+        };
+        if !due {
+            return None;
+        }
+        self.last_alert = Some(now);
+        self.repeat += 1;
+        self.alerts += 1;
+        Some(quiet_for)
+    }
+
+    fn state(&self, target: &str, open: bool) -> SilenceState {
+        SilenceState {
+            target: target.to_owned(),
+            last_output: self.last_output_ms,
+            alerts: self.alerts,
+            silent: self.last_alert.is_some(),
+            open,
+        }
+    }
+}
+
+impl Pane {
+    /// Name of the command running in the pane, tmux's `pane_current_command`
+    async fn current_command(&self) -> TmuxResult<String> {
+        run(
+            self.socket.as_deref(),
+            &[
+                "display-message",
+                "-p",
+                "-t",
+                &self.id,
+                "#{pane_current_command}",
+            ],
+        )
+        .await
+        .map(|output| output.trim().to_owned())
+        .map_err(|failure| self.gone(failure))
+    }
+}
+
+/// Captures panes on an interval & fires each rule's action once its pane has gone without
+/// output for too long
+#[derive(Debug, Clone)]
+pub struct SilenceDetector {
+    rules: Vec<SilenceRule>,
+    poll_interval: Duration,
+}
+
+impl SilenceDetector {
+    pub fn new(rules: Vec<SilenceRule>) -> Self {
+        Self {
+            rules,
+            poll_interval: DEFAULT_SILENCE_POLL_INTERVAL,
+        }
+    }
+
+    /// How often panes are captured, which is also how late output & alerts can be noticed
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Starts detecting on a new task, which runs until every pane has closed, an action
+    /// fails, or the handle is stopped or dropped
+    pub fn spawn(self) -> SilenceDetectorHandle {
+        let start = (Instant::now(), now_ms());
+        let states = Arc::new(Mutex::new(
+            self.rules
+                .iter()
+                .map(|rule| {
+                    SilenceTracker::new(start.0, start.1).state(&rule.pane.describe(), true)
+                })
+                .collect::<Vec<SilenceState>>(),
+        ));
+        let task_states = Arc::clone(&states);
+        let task = tokio::spawn(async move {
+            // Each rule's pane & tracker, `None` once the pane has closed
+            let mut trackers: Vec<Option<(Pane, SilenceTracker)>> = vec![];
+            for (i, rule) in self.rules.iter().enumerate() {
+                let tracker = SilenceTracker::new(start.0, start.1);
+                match rule.pane.resolve_first(rule.socket.as_deref()).await {
+                    Ok(pane) => trackers.push(Some((pane, tracker))),
+                    Err(TmuxError::InvalidTarget { .. }) | Err(TmuxError::NoServer) => {
+                        task_states.lock().expect("silence state lock poisoned")[i] =
+                            tracker.state(&rule.pane.describe(), false);
+                        trackers.push(None);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            let mut ticks = tokio::time::interval(self.poll_interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            while trackers.iter().any(Option::is_some) {
+                ticks.tick().await;
+                for (i, rule) in self.rules.iter().enumerate() {
+                    let Some((pane, tracker)) = trackers[i].as_mut() else {
+                        continue;
+                    };
+                    let target = pane.target();
+                    match Self::check(rule, pane, tracker).await {
+                        Ok(Some(alert)) => {
+                            rule.action.run(pane, alert).await?;
+                        }
+                        Ok(None) => {}
+                        Err(TmuxError::PaneGone { .. }) => {
+                            let state = tracker.state(&target, false);
+                            trackers[i] = None;
+                            task_states.lock().expect("silence state lock poisoned")[i] = state;
+                            continue;
+                        }
+                        Err(err) => return Err(err),
+                    }
+                    task_states.lock().expect("silence state lock poisoned")[i] =
+                        tracker.state(&target, true);
+                }
+            }
+            Ok(())
+        });
+        SilenceDetectorHandle { states, task }
+    }
+
+    /// Captures a rule's pane, returning an alert if one is due
+    async fn check(
+        rule: &SilenceRule,
+        pane: &Pane,
+        tracker: &mut SilenceTracker,
+    ) -> TmuxResult<Option<SilenceAlert>> {
+        let capture = pane.capture(CaptureOpts::default()).await?;
+        let command = match &rule.command {
+            Some(_) => Some(pane.current_command().await?),
+            None => None,
+        };
+        let command_matches = match (&rule.command, &command) {
+            (Some(pattern), Some(command)) => pattern.is_match(command),
+            _ => true,
+        };
+        let timestamp = now_ms();
+        let quiet_for = tracker.observe(
+            rule,
+            capture.trim_end(),
+            command_matches,
+            Instant::now(),
+            timestamp,
+        );
+        Ok(quiet_for.map(|quiet_for| SilenceAlert {
+            target: pane.target(),
+            quiet_for,
+            command,
+            repeat: tracker.repeat,
+            tail: None,
+            timestamp,
+        }))
+    }
+}
+
+/// A silence detector running on its own task
+#[derive(Debug)]
+pub struct SilenceDetectorHandle {
+    states: Arc<Mutex<Vec<SilenceState>>>,
+    task: JoinHandle<TmuxResult<()>>,
+}
+
+impl SilenceDetectorHandle {
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+
+    /// State of each rule's pane, in the order the rules were given
+    pub fn states(&self) -> Vec<SilenceState> {
+        self.states
+            .lock()
+            .expect("silence state lock poisoned")
+            .clone()
+    }
+}
+
+impl Drop for SilenceDetectorHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmux::tests::TestServer;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn pane() -> Pane {
+        Pane {
+            session: "test".to_string(),
+            window: 0,
+            index: 0,
+            id: "%0".to_string(),
+            socket: None,
+            history_limit: 0,
+        }
+    }
+
+    #[test]
+    fn alerts_reset_by_output_and_repeated() {
+        let (sender, _receiver) = unbounded_channel();
+        let rule = SilenceRule::new(
+            pane(),
+            Duration::from_secs(30),
+            SilenceAction::Notify(sender),
+        )
+        .with_realert(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut tracker = SilenceTracker::new(start, 0);
+
+        assert_eq!(tracker.observe(&rule, "building", true, at(0), 0), None);
+        assert_eq!(tracker.observe(&rule, "building", true, at(29), 0), None);
+        assert_eq!(
+            tracker.observe(&rule, "building", true, at(30), 0),
+            Some(Duration::from_secs(30))
+        );
+        assert!(tracker.state("t", true).silent);
+        // Quiet, but not for another re-alert interval
+        assert_eq!(tracker.observe(&rule, "building", true, at(60), 0), None);
+        assert_eq!(
+            tracker.observe(&rule, "building", true, at(90), 0),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(tracker.repeat, 2);
+
+        // Output resets the silence & the count of repeated alerts
+        assert_eq!(
+            tracker.observe(&rule, "built", true, at(100), 100_000),
+            None
+        );
+        let state = tracker.state("t", true);
+        assert_eq!(state.last_output, 100_000);
+        assert_eq!(state.alerts, 2);
+        assert!(!state.silent);
+        assert_eq!(tracker.observe(&rule, "built", true, at(120), 0), None);
+        // An idle shell doesn't alert
+        assert_eq!(tracker.observe(&rule, "built", false, at(200), 0), None);
+        assert_eq!(
+            tracker.observe(&rule, "built", true, at(201), 0),
+            Some(Duration::from_secs(101))
+        );
+        assert_eq!(tracker.repeat, 1);
+
+        let once = SilenceRule {
+            realert: None,
+            ..rule
+        };
+        assert_eq!(tracker.observe(&once, "built", true, at(1000), 0), None);
+    }
+
+    #[test]
+    fn alert_rendered_with_command_and_tail() {
+        let alert = SilenceAlert {
+            target: "dev:1.0".to_string(),
+            quiet_for: Duration::from_secs(600),
+            command: Some("cargo".to_string()),
+            repeat: 1,
+            tail: Some("Compiling app".to_string()),
+            timestamp: 0,
+        };
+        assert_eq!(
+            alert.render(),
+            "Pane dev:1.0 has had no output for 600s while running `cargo`, its last lines are:\nCompiling app"
+        );
+    }
+
+    #[tokio::test]
+    async fn quiet_panes_alerted_while_command_matches() {
+        let Some(server) = TestServer::start("echo waiting; sleep 30").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let (sender, mut receiver) = unbounded_channel();
+        let quiet = Duration::from_millis(100);
+        let rules = vec![
+            SilenceRule::new(pane.clone(), quiet, SilenceAction::Notify(sender.clone()))
+                .when_command("^sleep$")
+                .unwrap()
+                .with_realert(Duration::from_millis(100)),
+            SilenceRule::new(pane, quiet, SilenceAction::Notify(sender))
+                .when_command("^cargo$")
+                .unwrap(),
+        ];
+        let handle = SilenceDetector::new(rules)
+            .with_poll_interval(Duration::from_millis(20))
+            .spawn();
+
+        let first = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.target, "test:0.0");
+        assert_eq!(first.command.as_deref(), Some("sleep"));
+        assert!(first.quiet_for >= quiet);
+        let second = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.repeat, 2);
+
+        let states = handle.states();
+        assert!(states[0].alerts >= 2);
+        assert!(states[0].silent);
+        assert_eq!(states[1].alerts, 0);
+
+        server.kill().await;
+        for _ in 0..50 {
+            if !handle.is_running() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!handle.is_running());
+        assert!(handle.states().iter().all(|state| !state.open));
+    }
+}
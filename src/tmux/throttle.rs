@@ -0,0 +1,358 @@
+//! Keeping monitors of panes with heavy output from flooding their agents
+use super::{OutputStreamOpts, Pane, TmuxResult};
+use futures::StreamExt;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+use tracing::warn;
+
+/// Last lines of output kept as a sample for degraded mode summaries
+const SAMPLE_LINES: usize = 3;
+/// Characters of an unfinished line kept, output without newlines is cut to its end
+const MAX_PARTIAL_LINE: usize = 1024;
+
+/// Waiting for a pane's output to stop before capturing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Debounce {
+    /// How long the pane has to be quiet for
+    pub quiet: Duration,
+    /// The longest a capture is put off, so output that never stops doesn't starve it
+    pub max_wait: Duration,
+}
+
+/// Limits on how often a monitor captures & pushes, see `MonitorConfig::with_throttle`
+#[derive(Debug, Clone, Default)]
+pub struct MonitorThrottle {
+    /// Ticks sooner than this after the last push are skipped
+    pub min_push_interval: Option<Duration>,
+    pub debounce: Option<Debounce>,
+    /// Output faster than this many bytes a second switches the monitor to degraded mode, where
+    /// it pushes a summary of the output's volume & a sample of it instead of snapshots
+    pub max_bytes_per_sec: Option<u64>,
+    pub notifications: Option<UnboundedSender<ThrottleEvent>>,
+}
+
+impl MonitorThrottle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_min_push_interval(mut self, interval: Duration) -> Self {
+        self.min_push_interval = Some(interval);
+        self
+    }
+
+    pub fn with_debounce(mut self, quiet: Duration, max_wait: Duration) -> Self {
+        self.debounce = Some(Debounce { quiet, max_wait });
+        self
+    }
+
+    pub fn with_max_bytes_per_sec(mut self, bytes: u64) -> Self {
+        self.max_bytes_per_sec = Some(bytes);
+        self
+    }
+
+    /// Send a `ThrottleEvent` whenever the monitor goes into or out of degraded mode
+    pub fn with_notifications(mut self, sender: UnboundedSender<ThrottleEvent>) -> Self {
+        self.notifications = Some(sender);
+        self
+    }
+
+    /// Debouncing & the byte rate need the pane's output, which is followed as it is written
+    fn follows_output(&self) -> bool {
+        self.debounce.is_some() || self.max_bytes_per_sec.is_some()
+    }
+}
+
+/// Sent when a throttled monitor goes into or out of degraded mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThrottleEvent {
+    Degraded {
+        /// The `session:window.pane` target of the pane
+        target: String,
+        bytes_per_sec: u64,
+    },
+    Recovered {
+        target: String,
+        bytes_per_sec: u64,
+    },
+}
+
+/// Totals of a pane's output, and a sample of its last lines
+#[derive(Debug, Default)]
+struct Activity {
+    bytes: u64,
+    lines: u64,
+    last_output: Option<Instant>,
+    partial: String,
+    sample: VecDeque<String>,
+}
+
+impl Activity {
+    fn push(&mut self, text: &str, now: Instant) {
+        self.bytes += text.len() as u64;
+        self.lines += text.matches('\n').count() as u64;
+        self.last_output = Some(now);
+        self.partial.push_str(text);
+        while let Some(end) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=end).collect();
+            let line = line.trim_end();
+            if !line.trim().is_empty() {
+                self.sample.push_back(line.to_owned());
+            }
+            if self.sample.len() > SAMPLE_LINES {
+                self.sample.pop_front();
+            }
+        }
+        if self.partial.len() > MAX_PARTIAL_LINE {
+            let cut = self.partial.len() - MAX_PARTIAL_LINE;
+            let cut = (cut..self.partial.len())
+                .find(|i| self.partial.is_char_boundary(*i))
+                .unwrap_or(self.partial.len());
+            self.partial.drain(..cut);
+        }
+    }
+}
+
+/// Follows a pane's output on a task of its own until it is dropped
+#[derive(Debug)]
+struct OutputTracker {
+    activity: Arc<Mutex<Activity>>,
+    task: JoinHandle<()>,
+}
+
+impl OutputTracker {
+    /// Follows output with a control mode client, so any pipe the pane has is left alone
+    async fn start(pane: &Pane) -> TmuxResult<Self> {
+        let opts = OutputStreamOpts {
+            control_mode: true,
+            ..Default::default()
+        };
+        let mut stream = pane.output_stream(opts).await?;
+        let activity = Arc::new(Mutex::new(Activity::default()));
+        let task_activity = Arc::clone(&activity);
+        let target = pane.target();
+        let task = tokio::spawn(async move {
+            while let Some(chunk) = stream.next().await {
+                match chunk {
+                    Ok(chunk) => task_activity
+                        .lock()
+                        .expect("activity lock poisoned")
+                        .push(&chunk.text, Instant::now()),
+                    Err(err) => {
+                        warn!("Stopped following output of {}: {:?}", target, err);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Self { activity, task })
+    }
+
+    fn activity<R>(&self, read: impl FnOnce(&Activity) -> R) -> R {
+        read(&self.activity.lock().expect("activity lock poisoned"))
+    }
+}
+
+impl Drop for OutputTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// What a throttled monitor does on a tick
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum TickAction {
+    /// Too soon after the last push
+    Skip,
+    Capture,
+    /// Push this summary of the output instead of a snapshot
+    Summarize(String),
+}
+
+#[derive(Debug)]
+pub(super) struct Tick {
+    pub(super) action: TickAction,
+    /// The capture was put off for output to stop
+    pub(super) debounced: bool,
+}
+
+/// A monitor's throttle & what it has seen so far
+#[derive(Debug)]
+pub(super) struct ThrottleState {
+    throttle: MonitorThrottle,
+    tracker: Option<OutputTracker>,
+    last_push: Option<Instant>,
+    /// Output totals when the rate was last checked, and when that was
+    checked: (u64, Instant),
+    /// Lines of output when the last summary was made
+    summarized_lines: u64,
+    degraded: bool,
+}
+
+impl ThrottleState {
+    pub(super) async fn start(pane: &Pane, throttle: MonitorThrottle) -> TmuxResult<Self> {
+        let tracker = match throttle.follows_output() {
+            true => Some(OutputTracker::start(pane).await?),
+            false => None,
+        };
+        Ok(Self {
+            throttle,
+            tracker,
+            last_push: None,
+            checked: (0, Instant::now()),
+            summarized_lines: 0,
+            degraded: false,
+        })
+    }
+
+    pub(super) fn degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Call after each push to the agent
+    pub(super) fn pushed(&mut self) {
+        self.last_push = Some(Instant::now());
+    }
+
+    /// Decides what to do this tick. When debouncing, waits for the output to stop first
+    pub(super) async fn tick(&mut self, target: &str) -> Tick {
+        let now = Instant::now();
+        self.check_rate(target, now);
+        let push_due = match (self.throttle.min_push_interval, self.last_push) {
+            (Some(interval), Some(last)) => now.duration_since(last) >= interval,
+            _ => true,
+        };
+        let action = match (push_due, self.degraded) {
+            (false, _) => TickAction::Skip,
+            (true, true) => TickAction::Summarize(self.summary()),
+            (true, false) => TickAction::Capture,
+        };
+        let debounced = action == TickAction::Capture && self.debounce().await;
+        Tick { action, debounced }
+    }
+
+    /// Measures the output's byte rate since the last check, going into or out of degraded
+    /// mode when it passes the ceiling
+    fn check_rate(&mut self, target: &str, now: Instant) {
+        let (Some(max), Some(tracker)) = (self.throttle.max_bytes_per_sec, &self.tracker) else {
+            return;
+        };
+        let bytes = tracker.activity(|activity| activity.bytes);
+        let (checked_bytes, checked_at) = self.checked;
+        let secs = now.duration_since(checked_at).as_secs_f64().max(0.001);
+        let bytes_per_sec = ((bytes - checked_bytes) as f64 / secs) as u64;
+        self.checked = (bytes, now);
+        let event = match (self.degraded, bytes_per_sec > max) {
+            (false, true) => {
+                warn!(
+                    "Output of {} is {} bytes a second, summarizing it instead of snapshotting",
+                    target, bytes_per_sec
+                );
+                ThrottleEvent::Degraded {
+                    target: target.to_owned(),
+                    bytes_per_sec,
+                }
+            }
+            (true, false) => ThrottleEvent::Recovered {
+                target: target.to_owned(),
+                bytes_per_sec,
+            },
+            _ => return,
+        };
+        self.degraded = !self.degraded;
+        if !self.degraded {
+            // Output from while it was degraded isn't summarized after it recovers
+            self.summarized_lines = tracker.activity(|activity| activity.lines);
+        }
+        if let Some(sender) = &self.throttle.notifications {
+            if sender.send(event).is_err() {
+                warn!("Monitor throttle notification receiver was dropped");
+            }
+        }
+    }
+
+    /// The volume of output since the last summary, with a sample of its last lines
+    fn summary(&mut self) -> String {
+        let Some(tracker) = &self.tracker else {
+            return String::new();
+        };
+        let (lines, sample) = tracker.activity(|activity| {
+            (
+                activity.lines,
+                Vec::from(activity.sample.clone()).join("\n"),
+            )
+        });
+        let new_lines = lines - self.summarized_lines;
+        self.summarized_lines = lines;
+        format!(
+            "High output volume: {} lines, sample:\n{}",
+            new_lines, sample
+        )
+    }
+
+    /// Waits until the output has been quiet for the debounce's `quiet`, or for its `max_wait`.
+    /// Returns whether it had to wait
+    async fn debounce(&self) -> bool {
+        let (Some(debounce), Some(tracker)) = (self.throttle.debounce, &self.tracker) else {
+            return false;
+        };
+        let start = Instant::now();
+        let mut waited = false;
+        loop {
+            let quiet_for = tracker
+                .activity(|activity| activity.last_output)
+                .map_or(Duration::MAX, |at| at.elapsed());
+            let left = debounce.max_wait.saturating_sub(start.elapsed());
+            if quiet_for >= debounce.quiet || left.is_zero() {
+                return waited;
+            }
+            waited = true;
+            tokio::time::sleep((debounce.quiet - quiet_for).min(left)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activity_counted_and_sampled() {
+        let mut activity = Activity::default();
+        let now = Instant::now();
+        activity.push("one\ntwo\n\nth", now);
+        activity.push("ree\nfour\nfive", now);
+        assert_eq!(activity.bytes, 24);
+        assert_eq!(activity.lines, 5);
+        assert_eq!(activity.last_output, Some(now));
+        // Blank lines aren't kept in the sample, nor the unfinished last line
+        assert_eq!(activity.sample, ["two", "three", "four"]);
+
+        activity.push(&"x".repeat(5000), now);
+        assert_eq!(activity.partial.len(), MAX_PARTIAL_LINE);
+    }
+
+    #[tokio::test]
+    async fn pushes_skipped_within_min_interval() {
+        let pane = Pane {
+            session: "test".to_string(),
+            window: 0,
+            index: 0,
+            id: "%0".to_string(),
+            socket: None,
+            history_limit: 0,
+        };
+        let throttle = MonitorThrottle::new().with_min_push_interval(Duration::from_secs(60));
+        let mut state = ThrottleState::start(&pane, throttle).await.unwrap();
+        assert_eq!(state.tick("test:0.0").await.action, TickAction::Capture);
+        state.pushed();
+        let tick = state.tick("test:0.0").await;
+        assert_eq!(tick.action, TickAction::Skip);
+        assert!(!tick.debounced);
+        assert!(!state.degraded());
+    }
+}
@@ -0,0 +1,72 @@
+//! What the tools a model calls to act on panes have in common
+use super::{TmuxError, TmuxResult};
+use crate::{
+    agents::memory::{Message, MessageRole, OtherRoleTo, ToMessage},
+    language_models::completions::streaming::ToolCall,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::{future::Future, pin::Pin};
+
+/// Metadata key of the id of the tool call a result message answers
+pub const TOOL_CALL_ID_METADATA_KEY: &str = "tool_call_id";
+/// Role alias of tool result messages, they are sent as user messages
+pub const TOOL_ROLE_ALIAS: &str = "tool";
+
+pub type PaneToolFuture<'t, T> = Pin<Box<dyn Future<Output = TmuxResult<T>> + Send + 't>>;
+
+/// A tool a tool calling model uses to act on panes, such as `SendKeysTool` & `ExecTool`.
+/// `execute` checks a call is for the tool & parses its arguments before running it
+pub trait PaneTool: Send + Sync {
+    /// Arguments of a call, parsed from its JSON
+    type Args: DeserializeOwned + Send;
+    type Output: Send;
+
+    /// Name the model calls the tool by
+    fn name(&self) -> &'static str;
+
+    /// JSON schema of the tool's parameters, for describing the tool to a model
+    fn parameters_schema(&self) -> Value;
+
+    /// Runs the tool with a call's parsed arguments
+    fn run_call(&self, args: Self::Args) -> PaneToolFuture<'_, Self::Output>;
+
+    /// What `output` is returned to the model as
+    fn render(&self, output: &Self::Output) -> String;
+
+    /// Executes a call of this tool, a call of another tool or with arguments that don't parse
+    /// is `TmuxError::InvalidToolCall`
+    fn execute(&self, call: &ToolCall) -> PaneToolFuture<'_, Self::Output> {
+        if call.name != self.name() {
+            let err =
+                TmuxError::InvalidToolCall(format!("expected {}, got {}", self.name(), call.name));
+            return Box::pin(std::future::ready(Err(err)));
+        }
+        match parse_arguments(call) {
+            Ok(args) => self.run_call(args),
+            Err(err) => Box::pin(std::future::ready(Err(err))),
+        }
+    }
+
+    /// A tool result message answering `call` with `output`, with the `TOOL_ROLE_ALIAS` role
+    fn result_message(&self, call: &ToolCall, output: &Self::Output) -> Message {
+        tool_result_message(self.render(output), call)
+    }
+}
+
+/// A tool result message answering `call` with `content`, with the `TOOL_ROLE_ALIAS` role
+pub(super) fn tool_result_message(content: String, call: &ToolCall) -> Message {
+    let role = MessageRole::Other {
+        alias: TOOL_ROLE_ALIAS.to_string(),
+        coerce_to: OtherRoleTo::User,
+    };
+    content
+        .to_message(role)
+        .with_metadata(TOOL_CALL_ID_METADATA_KEY, &call.id)
+}
+
+pub(super) fn parse_arguments<T: DeserializeOwned>(call: &ToolCall) -> TmuxResult<T> {
+    call.arguments_json()
+        .and_then(serde_json::from_value)
+        .map_err(|err| TmuxError::InvalidToolCall(err.to_string()))
+}
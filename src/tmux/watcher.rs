@@ -0,0 +1,855 @@
+//! Watching pane output for regex matches, and acting on them
+use super::{now_ms, MessageRole, OutputStreamOpts, Pane, PaneMatch, TmuxResult};
+use crate::{
+    agents::{memory::Message, Agent},
+    language_models::completions::streaming::{CancelReason, StreamCanceller},
+};
+use futures::StreamExt;
+use regex::Regex;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc::UnboundedSender, Mutex},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Template used by `WatchPattern::new` for pushed messages & completion prompts
+pub const DEFAULT_WATCH_TEMPLATE: &str = "Matched `{match}` in pane {target}:\n{context}";
+
+/// An agent shared between a watcher's task & the rest of a program
+pub type SharedAgent = Arc<Mutex<Agent>>;
+
+/// What a watcher does when a pattern matches
+#[derive(Debug, Clone)]
+pub enum WatchAction {
+    /// Push the rendered template to the agent's cache
+    PushMessage {
+        agent: SharedAgent,
+        role: MessageRole,
+        template: String,
+    },
+    /// Push the rendered template to the agent's cache as a user message, then get an io
+    /// completion and push the response
+    Complete {
+        agent: SharedAgent,
+        template: String,
+    },
+    /// Send the match over a channel
+    Notify(UnboundedSender<WatchMatch>),
+    /// Cancel the stream in flight on the canceller with `CancelReason::Superseded`, such as
+    /// when the command being analysed is run again. Runs as soon as the pattern matches,
+    /// without waiting on the watcher's throttle
+    CancelStream(StreamCanceller),
+}
+
+/// A regex to watch a pane's output for, and what to do when a line matches it
+#[derive(Debug, Clone)]
+pub struct WatchPattern {
+    pub regex: Regex,
+    /// Lines before the matching line included in the match's context
+    pub context_before: usize,
+    /// Lines after the matching line included in the match's context. The action waits for
+    /// them, or for the pane's output to end
+    pub context_after: usize,
+    /// Matches within this long of the last time the pattern fired are ignored
+    pub cooldown: Duration,
+    pub action: WatchAction,
+}
+
+impl WatchPattern {
+    /// A pattern with no context & no cooldown
+    pub fn new(regex: &str, action: WatchAction) -> TmuxResult<Self> {
+        Ok(Self {
+            regex: Regex::new(regex)?,
+            context_before: 0,
+            context_after: 0,
+            cooldown: Duration::ZERO,
+            action,
+        })
+    }
+
+    /// A pattern cancelling the stream in flight on `canceller` when it matches
+    pub fn cancel_trigger(regex: &str, canceller: &StreamCanceller) -> TmuxResult<Self> {
+        Self::new(regex, WatchAction::CancelStream(canceller.clone()))
+    }
+
+    pub fn with_context(mut self, before: usize, after: usize) -> Self {
+        self.context_before = before;
+        self.context_after = after;
+        self
+    }
+
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+}
+
+/// Paces how often a watcher's actions run, whichever of its patterns matched. Matches made
+/// sooner than `min_interval` after the last dispatch wait their turn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchThrottle {
+    pub min_interval: Duration,
+    /// Matches kept waiting at most, the oldest are dropped past this
+    pub max_queued: usize,
+}
+
+impl WatchThrottle {
+    /// At most one dispatch every `min_interval`, with up to 16 matches waiting
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            max_queued: 16,
+        }
+    }
+
+    pub fn with_max_queued(mut self, max_queued: usize) -> Self {
+        self.max_queued = max_queued.max(1);
+        self
+    }
+}
+
+/// A line of pane output that matched a `WatchPattern`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchMatch {
+    /// The regex that matched
+    pub pattern: String,
+    /// The `session:window.pane` target of the pane
+    pub target: String,
+    /// The text the regex matched
+    pub matched: String,
+    /// Lines before the match, the matching line, then lines after it
+    pub context: Vec<String>,
+    /// Milliseconds since the unix epoch when the matching line was read
+    pub timestamp: u64,
+}
+
+impl WatchMatch {
+    /// Replaces `{match}`, `{target}` & `{context}` in a template. Context lines are joined
+    /// with newlines
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{match}", &self.matched)
+            .replace("{target}", &self.target)
+            .replace("{context}", &self.context.join("\n"))
+    }
+}
+
+impl WatchAction {
+    async fn run(&self, pane: &Pane, watch_match: WatchMatch) {
+        match self {
+            Self::PushMessage {
+                agent,
+                role,
+                template,
+            } => {
+                let content = watch_match.render(template);
+                let message = pane.message(&content, role.clone(), watch_match.timestamp);
+                agent.lock().await.cache.push(message);
+            }
+            Self::Complete { agent, template } => {
+                let content = watch_match.render(template);
+                let message = pane.message(&content, MessageRole::User, watch_match.timestamp);
+                let mut agent = agent.lock().await;
+                agent.cache.push(message);
+                match agent.io_completion().await {
+                    Ok(response) => agent.cache.push(Message::new_assistant(&response)),
+                    Err(err) => warn!("Watcher completion failed: {:?}", err),
+                }
+            }
+            Self::Notify(sender) => {
+                if sender.send(watch_match).is_err() {
+                    warn!("Watcher notification receiver was dropped");
+                }
+            }
+            Self::CancelStream(canceller) => cancel_stream(canceller, &watch_match),
+        }
+    }
+}
+
+fn cancel_stream(canceller: &StreamCanceller, watch_match: &WatchMatch) {
+    match canceller.cancel(CancelReason::Superseded) {
+        true => info!(
+            "Cancelling stream, `{}` matched in {}",
+            watch_match.matched, watch_match.target
+        ),
+        false => info!(
+            "`{}` matched in {} with no stream in flight",
+            watch_match.matched, watch_match.target
+        ),
+    }
+}
+
+/// Runs the stream cancels among `ready` right away, so they aren't held up by a throttle.
+/// Returns the other matches
+fn cancel_now(
+    patterns: &[WatchPattern],
+    ready: Vec<(usize, WatchMatch)>,
+) -> Vec<(usize, WatchMatch)> {
+    ready
+        .into_iter()
+        .filter(|(i, watch_match)| match &patterns[*i].action {
+            WatchAction::CancelStream(canceller) => {
+                cancel_stream(canceller, watch_match);
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// A match waiting for its lines of after context
+#[derive(Debug)]
+struct PendingMatch {
+    pattern: usize,
+    watch_match: WatchMatch,
+    remaining: usize,
+}
+
+/// Splits output into lines & matches them. The trailing partial line of a chunk is held until
+/// the rest of it arrives, so matches spanning two chunks aren't missed
+#[derive(Debug, Default)]
+struct LineMatcher {
+    partial: String,
+    history: VecDeque<String>,
+    pending: Vec<PendingMatch>,
+    last_fired: HashMap<usize, Instant>,
+}
+
+impl LineMatcher {
+    /// Returns the index of the pattern & the match for every match whose context is complete
+    fn push(
+        &mut self,
+        patterns: &[WatchPattern],
+        target: &str,
+        text: &str,
+        now: Instant,
+    ) -> Vec<(usize, WatchMatch)> {
+        self.partial.push_str(text);
+        let mut ready = vec![];
+        while let Some(end) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=end).collect();
+            ready.extend(self.line(patterns, target, line.trim_end_matches('\n'), now));
+        }
+        ready
+    }
+
+    /// Matches any trailing partial line, then returns every pending match with the after
+    /// context it has
+    fn finish(
+        &mut self,
+        patterns: &[WatchPattern],
+        target: &str,
+        now: Instant,
+    ) -> Vec<(usize, WatchMatch)> {
+        let mut ready = vec![];
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            ready.extend(self.line(patterns, target, &line, now));
+        }
+        ready.extend(
+            self.pending
+                .drain(..)
+                .map(|pending| (pending.pattern, pending.watch_match)),
+        );
+        ready
+    }
+
+    fn line(
+        &mut self,
+        patterns: &[WatchPattern],
+        target: &str,
+        line: &str,
+        now: Instant,
+    ) -> Vec<(usize, WatchMatch)> {
+        let mut ready = vec![];
+        for pending in self.pending.iter_mut() {
+            pending.watch_match.context.push(line.to_owned());
+            pending.remaining -= 1;
+        }
+        let (done, waiting) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|pending| pending.remaining == 0);
+        self.pending = waiting;
+        ready.extend(
+            done.into_iter()
+                .map(|pending: PendingMatch| (pending.pattern, pending.watch_match)),
+        );
+
+        for (i, pattern) in patterns.iter().enumerate() {
+            let Some(found) = pattern.regex.find(line) else {
+                continue;
+            };
+            let cooling = self
+                .last_fired
+                .get(&i)
+                .is_some_and(|last| now.duration_since(*last) < pattern.cooldown);
+            if cooling {
+                continue;
+            }
+            self.last_fired.insert(i, now);
+            let skip = self.history.len().saturating_sub(pattern.context_before);
+            let mut context: Vec<String> = self.history.iter().skip(skip).cloned().collect();
+            context.push(line.to_owned());
+            let watch_match = WatchMatch {
+                pattern: pattern.regex.as_str().to_owned(),
+                target: target.to_owned(),
+                matched: found.as_str().to_owned(),
+                context,
+                timestamp: now_ms(),
+            };
+            match pattern.context_after {
+                0 => ready.push((i, watch_match)),
+                remaining => self.pending.push(PendingMatch {
+                    pattern: i,
+                    watch_match,
+                    remaining,
+                }),
+            }
+        }
+
+        let max_before = patterns
+            .iter()
+            .map(|p| p.context_before)
+            .max()
+            .unwrap_or_default();
+        self.history.push_back(line.to_owned());
+        while self.history.len() > max_before {
+            self.history.pop_front();
+        }
+        ready
+    }
+}
+
+/// Matches waiting for a `WatchThrottle` to let them be dispatched
+#[derive(Debug)]
+struct DispatchQueue {
+    throttle: Option<WatchThrottle>,
+    queued: VecDeque<(usize, WatchMatch)>,
+    last_dispatch: Option<Instant>,
+}
+
+impl DispatchQueue {
+    fn new(throttle: Option<WatchThrottle>) -> Self {
+        Self {
+            throttle,
+            queued: VecDeque::new(),
+            last_dispatch: None,
+        }
+    }
+
+    fn push(&mut self, target: &str, ready: Vec<(usize, WatchMatch)>) {
+        self.queued.extend(ready);
+        let Some(throttle) = self.throttle else {
+            return;
+        };
+        let over = self.queued.len().saturating_sub(throttle.max_queued);
+        if over > 0 {
+            self.queued.drain(..over);
+            warn!(
+                "Dropped {} matches in {} waiting for the watcher's throttle",
+                over, target
+            );
+        }
+    }
+
+    /// When the throttle next lets a match through, `None` if it already does
+    fn throttled_until(&self) -> Option<Instant> {
+        self.throttle
+            .zip(self.last_dispatch)
+            .map(|(throttle, last)| last + throttle.min_interval)
+    }
+
+    /// When the next match can be dispatched, `None` if none are waiting
+    fn next_due(&self) -> Option<Instant> {
+        self.queued.front()?;
+        Some(self.throttled_until().unwrap_or_else(Instant::now))
+    }
+
+    /// The next match, if it is due by `now`
+    fn pop(&mut self, now: Instant) -> Option<(usize, WatchMatch)> {
+        if self.queued.is_empty() || self.throttled_until().is_some_and(|until| until > now) {
+            return None;
+        }
+        self.last_dispatch = Some(now);
+        self.queued.pop_front()
+    }
+}
+
+/// Watches a pane's output for patterns
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    pane: PaneMatch,
+    socket: Option<String>,
+    patterns: Vec<WatchPattern>,
+    stream_opts: OutputStreamOpts,
+    throttle: Option<WatchThrottle>,
+    cancel: Option<CancellationToken>,
+}
+
+/// Description of a running watcher, see `Watchers::list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatcherInfo {
+    pub name: String,
+    pub target: String,
+    pub patterns: Vec<String>,
+    /// False once the pane has closed or the watcher failed
+    pub running: bool,
+}
+
+/// A watcher running on its own task
+#[derive(Debug)]
+pub struct WatcherHandle {
+    target: String,
+    patterns: Vec<String>,
+    cancel: CancellationToken,
+    task: JoinHandle<TmuxResult<()>>,
+}
+
+impl WatcherHandle {
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    /// Stops the watcher by cancelling its token, which closes its pipe from the pane. An
+    /// action already running is finished first, matches still waiting on the throttle are
+    /// dropped
+    pub fn stop(&self) {
+        self.cancel.cancel();
+    }
+
+    /// The token that stops this watcher when cancelled
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+}
+
+impl Drop for WatcherHandle {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl Watcher {
+    /// Watches the pane `pane` picks when the watcher is spawned
+    pub fn new(pane: impl Into<PaneMatch>, patterns: Vec<WatchPattern>) -> Self {
+        Self {
+            pane: pane.into(),
+            socket: None,
+            patterns,
+            stream_opts: OutputStreamOpts::default(),
+            throttle: None,
+            cancel: None,
+        }
+    }
+
+    /// Pick the pane on the server with the given socket name rather than the default server
+    pub fn on_socket(mut self, socket: &str) -> Self {
+        self.socket = Some(socket.to_owned());
+        self
+    }
+
+    /// Pace this watcher's actions, separately from any other watcher's, so a noisy pane
+    /// doesn't flood the agent
+    pub fn with_throttle(mut self, throttle: WatchThrottle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Stop the watcher once `token` is cancelled, such as when a program shuts down. The
+    /// watcher runs on a child of the token, so stopping its handle leaves others sharing the
+    /// token running
+    pub fn with_cancellation(mut self, token: &CancellationToken) -> Self {
+        self.cancel = Some(token.clone());
+        self
+    }
+
+    /// Options for the watched output stream. Escape sequences are stripped by default, so
+    /// patterns match the text as displayed
+    pub fn with_stream_opts(mut self, opts: OutputStreamOpts) -> Self {
+        self.stream_opts = opts;
+        self
+    }
+
+    /// Starts streaming the pane's output & watching it on a new task. The watcher runs until
+    /// the pane closes, its token is cancelled or the handle is stopped or dropped
+    pub async fn spawn(self) -> TmuxResult<WatcherHandle> {
+        let cancel = match &self.cancel {
+            Some(token) => token.child_token(),
+            None => CancellationToken::new(),
+        };
+        let pane = self.pane.resolve_first(self.socket.as_deref()).await?;
+        let mut stream = pane.output_stream(self.stream_opts.clone()).await?;
+        let target = pane.target();
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|p| p.regex.as_str().to_owned())
+            .collect();
+        let task_cancel = cancel.clone();
+        let task = tokio::spawn(async move {
+            let mut matcher = LineMatcher::default();
+            let mut queue = DispatchQueue::new(self.throttle);
+            let target = pane.target();
+            let mut open = true;
+            while open || queue.next_due().is_some() {
+                let due = queue.next_due();
+                let wait = async {
+                    match due {
+                        Some(due) => tokio::time::sleep_until(due.into()).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    biased;
+                    _ = task_cancel.cancelled() => {
+                        info!("Watcher on {} cancelled", target);
+                        break;
+                    }
+                    chunk = stream.next(), if open => match chunk {
+                        Some(chunk) => {
+                            let chunk = chunk?;
+                            let ready = matcher.push(
+                                &self.patterns,
+                                &chunk.target,
+                                &chunk.text,
+                                Instant::now(),
+                            );
+                            queue.push(&target, cancel_now(&self.patterns, ready));
+                        }
+                        None => {
+                            open = false;
+                            let ready = matcher.finish(&self.patterns, &target, Instant::now());
+                            queue.push(&target, cancel_now(&self.patterns, ready));
+                        }
+                    },
+                    _ = wait => {}
+                }
+                while let Some((i, watch_match)) = queue.pop(Instant::now()) {
+                    if task_cancel.is_cancelled() {
+                        break;
+                    }
+                    self.patterns[i].action.run(&pane, watch_match).await;
+                }
+            }
+            Ok(())
+        });
+        Ok(WatcherHandle {
+            target,
+            patterns,
+            cancel,
+            task,
+        })
+    }
+}
+
+/// Named watchers that can be added & removed while they run
+#[derive(Debug, Default)]
+pub struct Watchers {
+    handles: HashMap<String, WatcherHandle>,
+    cancel: Option<CancellationToken>,
+}
+
+impl Watchers {
+    /// Stop every watcher once `token` is cancelled, including those added afterwards. Replaces
+    /// the token watchers were given themselves
+    pub fn with_cancellation(mut self, token: &CancellationToken) -> Self {
+        self.cancel = Some(token.clone());
+        self
+    }
+
+    /// Spawns `watcher` under `name`, stopping any watcher already using the name
+    pub async fn add(&mut self, name: &str, watcher: Watcher) -> TmuxResult<()> {
+        let watcher = match &self.cancel {
+            Some(token) => watcher.with_cancellation(token),
+            None => watcher,
+        };
+        let handle = watcher.spawn().await?;
+        self.handles.insert(name.to_owned(), handle);
+        Ok(())
+    }
+
+    /// Stops & removes a watcher, returns false if there was none with the name
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.handles.remove(name).is_some()
+    }
+
+    /// Every watcher, sorted by name
+    pub fn list(&self) -> Vec<WatcherInfo> {
+        let mut infos: Vec<WatcherInfo> = self
+            .handles
+            .iter()
+            .map(|(name, handle)| WatcherInfo {
+                name: name.to_owned(),
+                target: handle.target.to_owned(),
+                patterns: handle.patterns.to_owned(),
+                running: handle.is_running(),
+            })
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        language_models::completions::{
+            streaming::{StreamError, StreamReplay},
+            CompletionModel,
+        },
+        tmux::tests::TestServer,
+    };
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const BUILD_ERRORS: &str = r"error\[E\d+\]|panicked at";
+
+    #[test]
+    fn matches_across_chunks_with_context_and_cooldown() {
+        let (sender, _receiver) = unbounded_channel();
+        let patterns = vec![WatchPattern::new(BUILD_ERRORS, WatchAction::Notify(sender))
+            .unwrap()
+            .with_context(1, 1)
+            .with_cooldown(Duration::from_secs(10))];
+        let mut matcher = LineMatcher::default();
+        let start = Instant::now();
+
+        assert!(matcher
+            .push(&patterns, "t:0.0", "Compiling app\nerr", start)
+            .is_empty());
+        // The match is completed by the next chunk & waits for a line of after context
+        assert!(matcher
+            .push(&patterns, "t:0.0", "or[E0308]: mismatched types\n", start)
+            .is_empty());
+        let ready = matcher.push(&patterns, "t:0.0", "  --> src/main.rs:2:5\n", start);
+        assert_eq!(ready.len(), 1);
+        let (pattern, watch_match) = &ready[0];
+        assert_eq!(*pattern, 0);
+        assert_eq!(watch_match.matched, "error[E0308]");
+        assert_eq!(
+            watch_match.context,
+            vec![
+                "Compiling app",
+                "error[E0308]: mismatched types",
+                "  --> src/main.rs:2:5"
+            ]
+        );
+
+        // Within the cooldown the pattern doesn't fire again
+        let soon = start + Duration::from_secs(1);
+        assert!(matcher
+            .push(&patterns, "t:0.0", "error[E0308]: again\nnext\n", soon)
+            .is_empty());
+        let later = start + Duration::from_secs(11);
+        assert!(matcher
+            .push(
+                &patterns,
+                "t:0.0",
+                "thread 'main' panicked at src/main.rs",
+                later
+            )
+            .is_empty());
+        let ready = matcher.finish(&patterns, "t:0.0", later);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].1.matched, "panicked at");
+        assert_eq!(
+            ready[0].1.context,
+            vec!["next", "thread 'main' panicked at src/main.rs"]
+        );
+    }
+
+    #[test]
+    fn throttled_matches_wait_their_turn() {
+        let watch_match = |matched: &str| WatchMatch {
+            pattern: BUILD_ERRORS.to_string(),
+            target: "db:0.0".to_string(),
+            matched: matched.to_string(),
+            context: vec![],
+            timestamp: 0,
+        };
+        let start = Instant::now();
+        let throttle = WatchThrottle::new(Duration::from_secs(60)).with_max_queued(2);
+        let mut queue = DispatchQueue::new(Some(throttle));
+        assert_eq!(queue.next_due(), None);
+        queue.push("db:0.0", vec![(0, watch_match("a")), (0, watch_match("b"))]);
+        assert_eq!(queue.pop(start).unwrap().1.matched, "a");
+        assert!(queue.pop(start + Duration::from_secs(59)).is_none());
+        assert_eq!(queue.next_due(), Some(start + Duration::from_secs(60)));
+        // Past `max_queued` the oldest waiting match is dropped
+        queue.push("db:0.0", vec![(0, watch_match("c")), (0, watch_match("d"))]);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(queue.pop(later).unwrap().1.matched, "c");
+        assert!(queue.pop(later).is_none());
+
+        let mut unthrottled = DispatchQueue::new(None);
+        unthrottled.push("t:0.0", vec![(0, watch_match("a")), (1, watch_match("b"))]);
+        assert!(unthrottled.pop(start).is_some());
+        assert!(unthrottled.pop(start).is_some());
+        assert!(unthrottled.pop(start).is_none());
+    }
+
+    #[test]
+    fn template_rendered_with_match() {
+        let watch_match = WatchMatch {
+            pattern: BUILD_ERRORS.to_string(),
+            target: "build:0.0".to_string(),
+            matched: "panicked at".to_string(),
+            context: vec!["a".to_string(), "b".to_string()],
+            timestamp: 0,
+        };
+        assert_eq!(
+            watch_match.render(DEFAULT_WATCH_TEMPLATE),
+            "Matched `panicked at` in pane build:0.0:\na\nb"
+        );
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn watcher_acts_on_pane_output() {
+        let command = "sleep 0.5; echo compiling; echo \"thread 'main' panicked at src/main.rs:2:5\"; sleep 30";
+        let Some(server) = TestServer::start(command).await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let agent: SharedAgent = Arc::new(Mutex::new(Agent::new(
+            None,
+            CompletionModel::default_openai(""),
+        )));
+        let (sender, mut receiver) = unbounded_channel();
+        let patterns = vec![
+            WatchPattern::new(
+                BUILD_ERRORS,
+                WatchAction::PushMessage {
+                    agent: Arc::clone(&agent),
+                    role: MessageRole::User,
+                    template: DEFAULT_WATCH_TEMPLATE.to_string(),
+                },
+            )
+            .unwrap()
+            .with_context(1, 0),
+            WatchPattern::new("panicked", WatchAction::Notify(sender)).unwrap(),
+        ];
+
+        let mut watchers = Watchers::default();
+        watchers
+            .add("build", Watcher::new(pane, patterns))
+            .await
+            .unwrap();
+        let notified = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(notified.target, "test:0.0");
+        assert_eq!(notified.matched, "panicked");
+
+        let agent = agent.lock().await;
+        let message = &agent.cache.as_ref()[0];
+        assert_eq!(
+            message.content,
+            "Matched `panicked at` in pane test:0.0:\ncompiling\nthread 'main' panicked at src/main.rs:2:5"
+        );
+
+        let listed = watchers.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name, "build");
+        assert_eq!(listed[0].patterns, vec![BUILD_ERRORS, "panicked"]);
+        assert!(listed[0].running);
+        assert!(watchers.remove("build"));
+        assert!(!watchers.remove("build"));
+        assert!(watchers.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_token_stops_every_watcher() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let watcher = || {
+            let (sender, _receiver) = unbounded_channel();
+            let pattern = WatchPattern::new(BUILD_ERRORS, WatchAction::Notify(sender)).unwrap();
+            Watcher::new(&pane, vec![pattern])
+        };
+
+        // Stopping a handle leaves other watchers on the same token running
+        let shutdown = CancellationToken::new();
+        let first = watcher()
+            .with_cancellation(&shutdown)
+            .spawn()
+            .await
+            .unwrap();
+        let second = watcher()
+            .with_cancellation(&shutdown)
+            .spawn()
+            .await
+            .unwrap();
+        first.stop();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while first.is_running() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(second.is_running());
+        assert!(!shutdown.is_cancelled());
+
+        let mut watchers = Watchers::default().with_cancellation(&shutdown);
+        watchers.add("a", watcher()).await.unwrap();
+        watchers.add("b", watcher()).await.unwrap();
+        assert!(watchers.list().iter().all(|w| w.running));
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while second.is_running() || watchers.list().iter().any(|w| w.running) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(first.cancellation_token().is_cancelled());
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn rerun_command_cancels_stream() {
+        let command = "sleep 0.5; echo '$ cargo build'; sleep 30";
+        let Some(server) = TestServer::start(command).await else {
+            return;
+        };
+        let pane = Pane::resolve_on(Some(&server.socket), "test:0.0")
+            .await
+            .unwrap();
+        let canceller = StreamCanceller::new();
+        let patterns = vec![WatchPattern::cancel_trigger(r"^\$ cargo build", &canceller).unwrap()];
+        let watcher = Watcher::new(pane, patterns)
+            .with_throttle(WatchThrottle::new(Duration::from_secs(60)))
+            .spawn()
+            .await
+            .unwrap();
+
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let tokens: String = (0..100)
+            .map(|_| "data: {\"choices\":[{\"delta\":{\"content\":\"still \"}}]}\n\n")
+            .collect();
+        let mut handler = StreamReplay::from_reader(std::io::Cursor::new(tokens.into_bytes()))
+            .with_cadence(Duration::from_millis(50))
+            .into_handler(&agent.completion_model.provider)
+            .with_canceller(&canceller);
+        assert!(matches!(
+            handler.collect(&mut agent).await,
+            Err(StreamError::Cancelled(CancelReason::Superseded))
+        ));
+        assert!(agent.cache.as_ref()[0].content.starts_with("still "));
+        assert!(watcher.is_running());
+    }
+}
@@ -0,0 +1,507 @@
+//! Agents bound to the lifecycle of tmux windows
+use super::{
+    control::{ControlConnection, ControlEvent},
+    run, Failure, MonitorConfig, Monitors, Pane, SharedAgent, TmuxError, TmuxResult, PANE_FORMAT,
+};
+use crate::agents::Agent;
+use regex::Regex;
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc::UnboundedSender, Mutex},
+    task::JoinHandle,
+};
+use tracing::warn;
+
+/// Fields of `list-windows` output, the window's id & name then its active pane
+const WINDOW_FORMAT_PREFIX: &str = "#{window_id}\t#{window_name}\t";
+
+/// Options for `WindowBinding`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowBindingOpts {
+    /// How often windows are listed when the binding is spawned
+    pub poll_interval: Duration,
+    /// Interval of the monitor snapshotting each bound window's active pane into its agent,
+    /// `None` for no monitor
+    pub monitor_interval: Option<Duration>,
+    /// Directory each agent's cache is written to as JSON when its window closes
+    pub export_dir: Option<PathBuf>,
+    /// Sync when a tmux control mode client reports windows being added, closed or renamed,
+    /// instead of every `poll_interval`. Polls while control mode is unavailable
+    pub control_mode: bool,
+}
+
+impl Default for WindowBindingOpts {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_millis(500),
+            monitor_interval: Some(Duration::from_secs(5)),
+            export_dir: None,
+            control_mode: false,
+        }
+    }
+}
+
+/// Sent when a window is bound or unbound, see `WindowBinding::with_notifications`
+#[derive(Debug, Clone)]
+pub enum BindingEvent {
+    Bound {
+        window_id: String,
+        window_name: String,
+        /// `session:window.pane` target of the window's active pane
+        target: String,
+    },
+    /// The window closed. Contains the agent as it was when it was unbound
+    Unbound {
+        window_id: String,
+        window_name: String,
+        agent: Box<Agent>,
+    },
+}
+
+/// A window with an agent bound to it
+#[derive(Debug, Clone)]
+pub struct BoundWindow {
+    /// tmux's unique id for the window, like `@3`
+    pub window_id: String,
+    pub window_name: String,
+    /// The window's active pane when it was bound
+    pub pane: Pane,
+    pub agent: SharedAgent,
+}
+
+/// A window as listed by `list-windows`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ListedWindow {
+    id: String,
+    name: String,
+    pane: Pane,
+}
+
+/// Regex matching the whole of a name against a glob, where `*` is any run of characters and
+/// `?` is any one character
+pub(super) fn glob_regex(glob: &str) -> Regex {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).expect("escaped glob is a valid regex")
+}
+
+/// Binds a new agent to every window whose name matches a glob, like `work-*`, and unbinds it
+/// once the window closes. Windows are found by polling `tmux list-windows` & diffing against
+/// the windows already bound
+#[derive(Debug)]
+pub struct WindowBinding {
+    template: Agent,
+    glob: String,
+    pattern: Regex,
+    opts: WindowBindingOpts,
+    socket: Option<String>,
+    events: Option<UnboundedSender<BindingEvent>>,
+    bound: HashMap<String, BoundWindow>,
+    monitors: Monitors,
+}
+
+impl WindowBinding {
+    /// Each bound window gets a clone of `template`
+    pub fn new(template: Agent, window_glob: &str, opts: WindowBindingOpts) -> Self {
+        Self {
+            template,
+            glob: window_glob.to_owned(),
+            pattern: glob_regex(window_glob),
+            opts,
+            socket: None,
+            events: None,
+            bound: HashMap::new(),
+            monitors: Monitors::default(),
+        }
+    }
+
+    /// Watch the server with the given socket name rather than the default server
+    pub fn on_socket(mut self, socket: &str) -> Self {
+        self.socket = Some(socket.to_owned());
+        self
+    }
+
+    /// Send a `BindingEvent` whenever a window is bound or unbound
+    pub fn with_notifications(mut self, sender: UnboundedSender<BindingEvent>) -> Self {
+        self.events = Some(sender);
+        self
+    }
+
+    pub fn glob(&self) -> &str {
+        &self.glob
+    }
+
+    /// Every bound window, sorted by name
+    pub fn bound(&self) -> Vec<BoundWindow> {
+        let mut bound: Vec<BoundWindow> = self.bound.values().cloned().collect();
+        bound.sort_by(|a, b| a.window_name.cmp(&b.window_name));
+        bound
+    }
+
+    /// Every window on the server, none if there is no server
+    async fn list_windows(&self) -> TmuxResult<Vec<ListedWindow>> {
+        let format = format!("{}{}", WINDOW_FORMAT_PREFIX, PANE_FORMAT);
+        let output = match run(
+            self.socket.as_deref(),
+            &["list-windows", "-a", "-F", &format],
+        )
+        .await
+        {
+            Ok(output) => output,
+            Err(Failure::Other(TmuxError::NoServer)) | Err(Failure::CantFind(_)) => {
+                return Ok(vec![])
+            }
+            Err(Failure::Other(err)) => return Err(err),
+        };
+        Ok(output
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let id = fields.next()?.to_owned();
+                let name = fields.next()?.to_owned();
+                let pane = Pane::parse_format(self.socket.as_deref(), fields.next()?)?;
+                Some(ListedWindow { id, name, pane })
+            })
+            .collect())
+    }
+
+    /// Lists windows once, binding agents to new matching windows & unbinding the agents of
+    /// windows that have closed. Windows are keyed by id, so a window that is renamed to no
+    /// longer match stays bound until it closes
+    pub async fn sync(&mut self) -> TmuxResult<()> {
+        let windows = self.list_windows().await?;
+        let closed: Vec<String> = self
+            .bound
+            .keys()
+            .filter(|id| !windows.iter().any(|w| &w.id == *id))
+            .cloned()
+            .collect();
+        for id in closed {
+            self.unbind(&id).await;
+        }
+        for window in windows {
+            if !self.bound.contains_key(&window.id) && self.pattern.is_match(&window.name) {
+                self.bind(window);
+            }
+        }
+        Ok(())
+    }
+
+    /// Binds an agent to a window. A window that closes straight after being listed is
+    /// unbound on the next sync, and its monitor stops as soon as it finds the pane gone
+    fn bind(&mut self, window: ListedWindow) {
+        let agent: SharedAgent = Arc::new(Mutex::new(self.template.clone()));
+        if let Some(interval) = self.opts.monitor_interval {
+            let config = MonitorConfig::new(window.pane.clone(), interval);
+            self.monitors.add(&window.id, config, &agent);
+        }
+        self.notify(BindingEvent::Bound {
+            window_id: window.id.to_owned(),
+            window_name: window.name.to_owned(),
+            target: window.pane.target(),
+        });
+        self.bound.insert(
+            window.id.to_owned(),
+            BoundWindow {
+                window_id: window.id,
+                window_name: window.name,
+                pane: window.pane,
+                agent,
+            },
+        );
+    }
+
+    /// Stops a window's monitor, exports its agent's cache & removes it
+    async fn unbind(&mut self, window_id: &str) {
+        let Some(bound) = self.bound.remove(window_id) else {
+            return;
+        };
+        self.monitors.remove(window_id);
+        let agent = bound.agent.lock().await.clone();
+        if let Some(dir) = &self.opts.export_dir {
+            let path = dir.join(format!(
+                "{}-{}.json",
+                bound.window_name,
+                bound.window_id.trim_start_matches('@')
+            ));
+            let exported = std::fs::create_dir_all(dir)
+                .and_then(|_| Ok(serde_json::to_string_pretty(&agent.cache)?))
+                .and_then(|json| std::fs::write(&path, json));
+            if let Err(err) = exported {
+                warn!("Failed to export cache to {}: {:?}", path.display(), err);
+            }
+        }
+        self.notify(BindingEvent::Unbound {
+            window_id: bound.window_id,
+            window_name: bound.window_name,
+            agent: Box::new(agent),
+        });
+    }
+
+    /// Whether a control mode event may have added, closed or renamed windows
+    fn changes_windows(event: &ControlEvent) -> bool {
+        matches!(
+            event,
+            ControlEvent::WindowAdd { .. }
+                | ControlEvent::WindowClose { .. }
+                | ControlEvent::WindowRenamed { .. }
+                | ControlEvent::SessionChanged { .. }
+                | ControlEvent::SessionsChanged
+                | ControlEvent::Exit { .. }
+        )
+    }
+
+    fn notify(&self, event: BindingEvent) {
+        if let Some(sender) = &self.events {
+            if sender.send(event).is_err() {
+                warn!("Window binding notification receiver was dropped");
+            }
+        }
+    }
+
+    /// Syncs every `poll_interval`, or on window events with `WindowBindingOpts::control_mode`,
+    /// on a new task until the handle is stopped or dropped
+    pub fn spawn(self) -> WindowBindingHandle {
+        let poll_interval = self.opts.poll_interval;
+        let control_mode = self.opts.control_mode;
+        let socket = self.socket.to_owned();
+        let binding = Arc::new(Mutex::new(self));
+        let task_binding = Arc::clone(&binding);
+        let task = tokio::spawn(async move {
+            let mut connection = match control_mode {
+                true => {
+                    match ControlConnection::connect(socket.as_deref(), Default::default()).await {
+                        Ok(connection) => Some(connection),
+                        Err(err) => {
+                            warn!("Control mode unavailable, polling windows: {}", err);
+                            None
+                        }
+                    }
+                }
+                false => None,
+            };
+            let mut ticks = tokio::time::interval(poll_interval);
+            // Polls while the control client is reconnecting
+            let mut polling = connection.is_none();
+            task_binding.lock().await.sync().await?;
+            loop {
+                let event = match connection.as_mut() {
+                    Some(connection) => tokio::select! {
+                        event = connection.next_event() => Some(event),
+                        _ = ticks.tick(), if polling => None,
+                    },
+                    None => {
+                        ticks.tick().await;
+                        None
+                    }
+                };
+                let sync = match event {
+                    None => true,
+                    Some(None) => {
+                        warn!("Control client gave up reconnecting, polling windows");
+                        connection = None;
+                        true
+                    }
+                    Some(Some(ControlEvent::Disconnected)) => {
+                        polling = true;
+                        false
+                    }
+                    Some(Some(ControlEvent::Reconnected)) => {
+                        polling = false;
+                        true
+                    }
+                    Some(Some(event)) => Self::changes_windows(&event),
+                };
+                if sync {
+                    task_binding.lock().await.sync().await?;
+                }
+            }
+        });
+        WindowBindingHandle { binding, task }
+    }
+}
+
+/// A window binding syncing on its own task
+#[derive(Debug)]
+pub struct WindowBindingHandle {
+    binding: Arc<Mutex<WindowBinding>>,
+    task: JoinHandle<TmuxResult<()>>,
+}
+
+impl WindowBindingHandle {
+    /// False once stopped, or after listing windows failed
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+
+    pub async fn bound(&self) -> Vec<BoundWindow> {
+        self.binding.lock().await.bound()
+    }
+}
+
+impl Drop for WindowBindingHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::memory::MessageStack, language_models::completions::CompletionModel,
+        tmux::tests::TestServer,
+    };
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn globs_match_whole_names() {
+        let work = glob_regex("work-*");
+        assert!(work.is_match("work-api"));
+        assert!(work.is_match("work-"));
+        assert!(!work.is_match("my-work-api"));
+        let single = glob_regex("w?.rs");
+        assert!(single.is_match("wa.rs"));
+        assert!(!single.is_match("wa-rs"));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn agents_bound_and_unbound_with_windows() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        let socket = Some(server.socket.as_str());
+        for name in ["work-api", "scratch"] {
+            let args = [
+                "new-window",
+                "-d",
+                "-n",
+                name,
+                "-t",
+                "test:",
+                "echo in window; sleep 30",
+            ];
+            assert!(run(socket, &args).await.is_ok());
+        }
+        let dir = std::env::temp_dir().join(format!("espionox-windows-{}", uuid::Uuid::new_v4()));
+        let opts = WindowBindingOpts {
+            monitor_interval: Some(Duration::from_millis(20)),
+            export_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let template = Agent::new(
+            Some("Review this window"),
+            CompletionModel::default_openai(""),
+        );
+        let (sender, mut receiver) = unbounded_channel();
+        let mut binding = WindowBinding::new(template, "work-*", opts)
+            .on_socket(&server.socket)
+            .with_notifications(sender);
+
+        binding.sync().await.unwrap();
+        let bound = binding.bound();
+        assert_eq!(bound.len(), 1);
+        assert_eq!(bound[0].window_name, "work-api");
+        assert_eq!(bound[0].pane.target(), "test:1.0");
+        assert!(matches!(
+            receiver.try_recv().unwrap(),
+            BindingEvent::Bound { window_name, target, .. } if window_name == "work-api" && target == "test:1.0"
+        ));
+        // Already bound windows aren't bound again
+        binding.sync().await.unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        let agent = Arc::clone(&bound[0].agent);
+        for _ in 0..50 {
+            if agent.lock().await.cache.len() > 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let kill = ["kill-window", "-t", &bound[0].window_id];
+        assert!(run(socket, &kill).await.is_ok());
+        binding.sync().await.unwrap();
+        assert!(binding.bound().is_empty());
+        let BindingEvent::Unbound { agent, .. } = receiver.try_recv().unwrap() else {
+            panic!("expected an unbound event");
+        };
+        assert!(agent.cache.as_ref()[1].content.contains("in window"));
+
+        let exported = std::fs::read_to_string(dir.join(format!(
+            "work-api-{}.json",
+            bound[0].window_id.trim_start_matches('@')
+        )))
+        .unwrap();
+        let cache: MessageStack = serde_json::from_str(&exported).unwrap();
+        assert_eq!(cache, agent.cache);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        server.kill().await;
+        binding.sync().await.unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn control_mode_binds_windows_without_polling() {
+        let Some(server) = TestServer::start("sleep 30").await else {
+            return;
+        };
+        let opts = WindowBindingOpts {
+            poll_interval: Duration::from_secs(60),
+            monitor_interval: None,
+            control_mode: true,
+            ..Default::default()
+        };
+        let (sender, mut receiver) = unbounded_channel();
+        let template = Agent::new(None, CompletionModel::default_openai(""));
+        let handle = WindowBinding::new(template, "work-*", opts)
+            .on_socket(&server.socket)
+            .with_notifications(sender)
+            .spawn();
+        // Long enough for the binding's first sync
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let args = [
+            "new-window",
+            "-d",
+            "-n",
+            "work-db",
+            "-t",
+            "test:",
+            "sleep 30",
+        ];
+        assert!(run(Some(&server.socket), &args).await.is_ok());
+        let event = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let BindingEvent::Bound { window_id, .. } = event else {
+            panic!("expected a bound event");
+        };
+        assert!(
+            run(Some(&server.socket), &["kill-window", "-t", &window_id])
+                .await
+                .is_ok()
+        );
+        let event = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            matches!(event, BindingEvent::Unbound { window_name, .. } if window_name == "work-db")
+        );
+        assert!(handle.is_running());
+    }
+}
@@ -0,0 +1,40 @@
+use crate::errors::error_chain_fmt;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+
+pub type VectorResult<T> = Result<T, VectorError>;
+
+#[derive(thiserror::Error)]
+pub enum VectorError {
+    /// A vector or query didn't have the index's number of dimensions
+    DimensionMismatch {
+        expected: usize,
+        got: usize,
+    },
+    /// A vector with no length can't be normalized for cosine similarity
+    ZeroVector,
+    Io(#[from] std::io::Error),
+    Json(#[from] serde_json::Error),
+    /// A file being loaded isn't an index written by `Index::save`
+    InvalidFile(String),
+}
+
+impl Debug for VectorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl Display for VectorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let display = match self {
+            Self::DimensionMismatch { expected, got } => {
+                format!("Expected a vector of {} dimensions, got {}", expected, got)
+            }
+            Self::ZeroVector => "Cannot normalize a zero vector".to_string(),
+            Self::Io(err) => err.to_string(),
+            Self::Json(err) => err.to_string(),
+            Self::InvalidFile(reason) => format!("Not a vector index file: {}", reason),
+        };
+        write!(f, "{}", display)
+    }
+}
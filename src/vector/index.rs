@@ -0,0 +1,511 @@
+use super::error::{VectorError, VectorResult};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+/// Identifies files written by `Index::save`, followed by the format's version
+const FILE_MAGIC: &[u8; 8] = b"ESPXVEC1";
+/// Values summed independently in `dot`, enough for the compiler to fill a few SIMD registers
+const LANES: usize = 16;
+/// Rows scored against every query of a batch before moving on, so the rows are read from
+/// memory once per batch rather than once per query
+const BATCH_ROWS: usize = 64;
+
+/// How vectors are compared. Cosine vectors are normalized when inserted & queried, so both
+/// are scored by their dot product, higher is more similar
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Metric {
+    Cosine,
+    DotProduct,
+}
+
+/// An exact in memory index of embeddings, each with an id & a payload, such as the text that
+/// was embedded. Vectors are stored contiguously and every search scores all of them, which
+/// stays fast up to a few hundred thousand vectors
+#[derive(Debug, Clone)]
+pub struct Index<P> {
+    metric: Metric,
+    dims: usize,
+    /// Rows of `dims` values, in the same order as `ids` & `payloads`
+    vectors: Vec<f32>,
+    ids: Vec<String>,
+    payloads: Vec<P>,
+    rows: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit<'i, P> {
+    pub id: &'i str,
+    pub score: f32,
+    pub payload: &'i P,
+}
+
+/// A row's score, ordered by score & then by row so ties are deterministic
+#[derive(Debug, Clone, Copy)]
+struct Scored {
+    score: f32,
+    row: usize,
+}
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Earlier rows rank higher among equal scores
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.row.cmp(&self.row))
+    }
+}
+
+/// The `k` highest scores seen, kept in a min heap so the lowest is replaced first
+struct TopK {
+    k: usize,
+    heap: BinaryHeap<Reverse<Scored>>,
+}
+
+impl TopK {
+    fn new(k: usize) -> Self {
+        Self {
+            k,
+            heap: BinaryHeap::with_capacity(k + 1),
+        }
+    }
+
+    fn push(&mut self, scored: Scored) {
+        if self.heap.len() < self.k {
+            self.heap.push(Reverse(scored));
+        } else if self.heap.peek().is_some_and(|lowest| scored > lowest.0) {
+            self.heap.pop();
+            self.heap.push(Reverse(scored));
+        }
+    }
+
+    /// Highest score first
+    fn into_sorted(self) -> Vec<Scored> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(scored)| scored)
+            .collect()
+    }
+}
+
+/// Written in lanes the compiler can vectorize, rather than as one long dependent sum
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let a_chunks = a.chunks_exact(LANES);
+    let b_chunks = b.chunks_exact(LANES);
+    let tail: f32 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    let mut lanes = [0f32; LANES];
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for i in 0..LANES {
+            lanes[i] += x[i] * y[i];
+        }
+    }
+    lanes.iter().sum::<f32>() + tail
+}
+
+impl<P> Index<P> {
+    pub fn new(dims: usize, metric: Metric) -> Self {
+        Self {
+            metric,
+            dims,
+            vectors: vec![],
+            ids: vec![],
+            payloads: vec![],
+            rows: HashMap::new(),
+        }
+    }
+
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.rows.contains_key(id)
+    }
+
+    fn row(&self, row: usize) -> &[f32] {
+        &self.vectors[row * self.dims..(row + 1) * self.dims]
+    }
+
+    /// Checks a vector's dimensions, normalizing it for cosine similarity
+    fn prepare(&self, mut vector: Vec<f32>) -> VectorResult<Vec<f32>> {
+        if vector.len() != self.dims {
+            return Err(VectorError::DimensionMismatch {
+                expected: self.dims,
+                got: vector.len(),
+            });
+        }
+        if self.metric == Metric::Cosine {
+            let norm = dot(&vector, &vector).sqrt();
+            if norm == 0.0 || !norm.is_finite() {
+                return Err(VectorError::ZeroVector);
+            }
+            vector.iter_mut().for_each(|v| *v /= norm);
+        }
+        Ok(vector)
+    }
+
+    /// Adds an entry, replacing any with the same id. Returns the replaced entry's payload
+    pub fn insert(&mut self, id: &str, vector: Vec<f32>, payload: P) -> VectorResult<Option<P>> {
+        let vector = self.prepare(vector)?;
+        if let Some(&row) = self.rows.get(id) {
+            self.vectors[row * self.dims..(row + 1) * self.dims].copy_from_slice(&vector);
+            return Ok(Some(std::mem::replace(&mut self.payloads[row], payload)));
+        }
+        self.rows.insert(id.to_owned(), self.ids.len());
+        self.vectors.extend_from_slice(&vector);
+        self.ids.push(id.to_owned());
+        self.payloads.push(payload);
+        Ok(None)
+    }
+
+    /// Removes an entry, returning its payload. The last entry takes its place, so removal
+    /// doesn't shift every row after it
+    pub fn remove(&mut self, id: &str) -> Option<P> {
+        let row = self.rows.remove(id)?;
+        let last = self.ids.len() - 1;
+        if row != last {
+            self.vectors
+                .copy_within(last * self.dims..(last + 1) * self.dims, row * self.dims);
+            self.rows.insert(self.ids[last].to_owned(), row);
+        }
+        self.vectors.truncate(last * self.dims);
+        self.ids.swap_remove(row);
+        Some(self.payloads.swap_remove(row))
+    }
+
+    /// The stored vector, normalized for cosine similarity, & payload of an entry
+    pub fn get(&self, id: &str) -> Option<(&[f32], &P)> {
+        let row = *self.rows.get(id)?;
+        Some((self.row(row), &self.payloads[row]))
+    }
+
+    fn hit(&self, scored: Scored) -> SearchHit<'_, P> {
+        SearchHit {
+            id: &self.ids[scored.row],
+            score: scored.score,
+            payload: &self.payloads[scored.row],
+        }
+    }
+
+    /// The `k` entries most similar to `query`, most similar first
+    pub fn search(&self, query: &[f32], k: usize) -> VectorResult<Vec<SearchHit<'_, P>>> {
+        self.search_filtered(query, k, |_| true)
+    }
+
+    /// The `k` entries most similar to `query` whose payloads pass `filter`
+    pub fn search_filtered(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: impl Fn(&P) -> bool,
+    ) -> VectorResult<Vec<SearchHit<'_, P>>> {
+        let mut batch = self.search_batch(&[query.to_vec()], k, filter)?;
+        Ok(batch.pop().unwrap_or_default())
+    }
+
+    /// `search_filtered` for each of `queries`, scoring every query against a block of rows
+    /// before moving to the next block. Faster than searching one query at a time when the
+    /// index doesn't fit in cache
+    pub fn search_batch(
+        &self,
+        queries: &[Vec<f32>],
+        k: usize,
+        filter: impl Fn(&P) -> bool,
+    ) -> VectorResult<Vec<Vec<SearchHit<'_, P>>>> {
+        let queries = queries
+            .iter()
+            .map(|query| self.prepare(query.to_owned()))
+            .collect::<VectorResult<Vec<_>>>()?;
+        let mut top: Vec<TopK> = queries.iter().map(|_| TopK::new(k)).collect();
+        if k > 0 {
+            for start in (0..self.len()).step_by(BATCH_ROWS) {
+                let end = (start + BATCH_ROWS).min(self.len());
+                let rows: Vec<usize> = (start..end)
+                    .filter(|row| filter(&self.payloads[*row]))
+                    .collect();
+                for (query, top) in queries.iter().zip(top.iter_mut()) {
+                    for &row in &rows {
+                        let score = dot(query, self.row(row));
+                        top.push(Scored { score, row });
+                    }
+                }
+            }
+        }
+        Ok(top
+            .into_iter()
+            .map(|top| {
+                top.into_sorted()
+                    .into_iter()
+                    .map(|scored| self.hit(scored))
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+impl<P: Serialize> Index<P> {
+    /// Writes the index to `path`: a header, the vectors as little endian floats, then the ids
+    /// & payloads as JSON
+    pub fn save(&self, path: impl AsRef<Path>) -> VectorResult<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(FILE_MAGIC)?;
+        file.write_all(&serde_json::to_vec(&self.metric)?)?;
+        file.write_all(b"\n")?;
+        file.write_all(&(self.dims as u64).to_le_bytes())?;
+        file.write_all(&(self.len() as u64).to_le_bytes())?;
+        for value in &self.vectors {
+            file.write_all(&value.to_le_bytes())?;
+        }
+        let entries: Vec<(&String, &P)> = self.ids.iter().zip(&self.payloads).collect();
+        serde_json::to_writer(&mut file, &entries)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl<P: DeserializeOwned> Index<P> {
+    /// Reads an index written by `save`
+    pub fn load(path: impl AsRef<Path>) -> VectorResult<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != FILE_MAGIC {
+            return Err(VectorError::InvalidFile("unrecognized header".to_owned()));
+        }
+        let mut metric_json = vec![];
+        for byte in file.by_ref().bytes() {
+            match byte? {
+                b'\n' => break,
+                byte => metric_json.push(byte),
+            }
+        }
+        let metric: Metric = serde_json::from_slice(&metric_json)?;
+        let mut word = [0u8; 8];
+        file.read_exact(&mut word)?;
+        let dims = u64::from_le_bytes(word) as usize;
+        file.read_exact(&mut word)?;
+        let len = u64::from_le_bytes(word) as usize;
+
+        // The header is checked against the file before allocating, so a corrupt length can't
+        // overflow or ask for more memory than the file holds
+        let header_len = (FILE_MAGIC.len() + metric_json.len() + 1 + 16) as u64;
+        let vector_bytes = len
+            .checked_mul(dims)
+            .and_then(|values| values.checked_mul(4))
+            .filter(|bytes| (*bytes as u64) <= file_len.saturating_sub(header_len))
+            .ok_or_else(|| {
+                VectorError::InvalidFile(format!(
+                    "{} vectors of {} dimensions don't fit in a file of {} bytes",
+                    len, dims, file_len
+                ))
+            })?;
+        let mut bytes = vec![0u8; vector_bytes];
+        file.read_exact(&mut bytes)?;
+        let vectors: Vec<f32> = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let entries: Vec<(String, P)> = serde_json::from_reader(file)?;
+        if entries.len() != len {
+            return Err(VectorError::InvalidFile(format!(
+                "expected {} entries, found {}",
+                len,
+                entries.len()
+            )));
+        }
+        let (ids, payloads): (Vec<String>, Vec<P>) = entries.into_iter().unzip();
+        let mut rows = HashMap::with_capacity(len);
+        for (row, id) in ids.iter().enumerate() {
+            if rows.insert(id.to_owned(), row).is_some() {
+                return Err(VectorError::InvalidFile(format!("duplicate id {}", id)));
+            }
+        }
+        Ok(Self {
+            metric,
+            dims,
+            vectors,
+            ids,
+            payloads,
+            rows,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids<P>(hits: &[SearchHit<'_, P>]) -> Vec<String> {
+        hits.iter().map(|hit| hit.id.to_owned()).collect()
+    }
+
+    #[test]
+    fn nearest_entries_found_with_filters() {
+        let mut index = Index::new(3, Metric::Cosine);
+        index.insert("build", vec![1.0, 0.1, 0.0], "cargo").unwrap();
+        index.insert("test", vec![0.9, 0.4, 0.0], "cargo").unwrap();
+        index
+            .insert("deploy", vec![0.0, 0.0, 5.0], "kubectl")
+            .unwrap();
+        index.insert("lint", vec![2.0, 0.2, 0.1], "clippy").unwrap();
+
+        let hits = index.search(&[10.0, 1.0, 0.0], 2).unwrap();
+        assert_eq!(ids(&hits), ["build", "lint"]);
+        assert!((hits[0].score - 1.0).abs() < 1e-6);
+
+        let cargo = index
+            .search_filtered(&[10.0, 1.0, 0.0], 5, |tool| *tool == "cargo")
+            .unwrap();
+        assert_eq!(ids(&cargo), ["build", "test"]);
+
+        let batch = index
+            .search_batch(&[vec![0.0, 0.0, 1.0], vec![1.0, 0.4, 0.0]], 1, |_| true)
+            .unwrap();
+        assert_eq!(ids(&batch[0]), ["deploy"]);
+        assert_eq!(ids(&batch[1]), ["test"]);
+
+        assert!(matches!(
+            index.search(&[1.0, 0.0], 1),
+            Err(VectorError::DimensionMismatch {
+                expected: 3,
+                got: 2
+            })
+        ));
+        assert!(matches!(
+            index.insert("empty", vec![0.0; 3], "none"),
+            Err(VectorError::ZeroVector)
+        ));
+    }
+
+    #[test]
+    fn removed_entries_replaced_by_last() {
+        let mut index = Index::new(2, Metric::DotProduct);
+        for (i, id) in ["a", "b", "c"].into_iter().enumerate() {
+            index.insert(id, vec![i as f32 + 1.0, 0.0], i).unwrap();
+        }
+        assert_eq!(index.insert("b", vec![10.0, 0.0], 9).unwrap(), Some(1));
+        assert_eq!(index.remove("a"), Some(0));
+        assert_eq!(index.remove("a"), None);
+
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get("c"), Some((&[3.0, 0.0][..], &2)));
+        let hits = index.search(&[1.0, 0.0], 3).unwrap();
+        assert_eq!(ids(&hits), ["b", "c"]);
+        assert_eq!(hits[0].score, 10.0);
+    }
+
+    #[test]
+    fn saved_index_loads_the_same() {
+        let dims = 37;
+        let mut index = Index::new(dims, Metric::Cosine);
+        for i in 0..20 {
+            let vector = (0..dims).map(|d| ((i * d) % 7) as f32 - 3.0).collect();
+            index
+                .insert(&format!("pane-{}", i), vector, format!("capture {}", i))
+                .unwrap();
+        }
+        let path =
+            std::env::temp_dir().join(format!("espionox-index-{}.vec", uuid::Uuid::new_v4()));
+        index.save(&path).unwrap();
+        let loaded: Index<String> = Index::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.metric(), Metric::Cosine);
+        assert_eq!(loaded.len(), index.len());
+        let query: Vec<f32> = (0..dims).map(|d| d as f32).collect();
+        assert_eq!(
+            loaded.search(&query, 5).unwrap(),
+            index.search(&query, 5).unwrap()
+        );
+        assert_eq!(loaded.get("pane-3"), index.get("pane-3"));
+    }
+
+    /// Saves `index`, edits the file's bytes & loads it back
+    fn load_edited(
+        index: &Index<String>,
+        edit: impl FnOnce(&mut Vec<u8>),
+    ) -> VectorResult<Index<String>> {
+        let path =
+            std::env::temp_dir().join(format!("espionox-index-{}.vec", uuid::Uuid::new_v4()));
+        index.save(&path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        edit(&mut bytes);
+        std::fs::write(&path, bytes).unwrap();
+        let loaded = Index::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn corrupt_files_rejected() {
+        let mut index = Index::new(2, Metric::DotProduct);
+        index.insert("a", vec![1.0, 0.0], "x".to_string()).unwrap();
+        index.insert("b", vec![0.0, 1.0], "y".to_string()).unwrap();
+        // The counts follow the magic & the metric's JSON line
+        let counts = FILE_MAGIC.len() + serde_json::to_vec(&Metric::DotProduct).unwrap().len() + 1;
+
+        for (dims, len) in [(2u64, 1u64 << 40), (u64::MAX, 2), (1 << 62, 1 << 62)] {
+            let loaded = load_edited(&index, |bytes| {
+                bytes[counts..counts + 8].copy_from_slice(&dims.to_le_bytes());
+                bytes[counts + 8..counts + 16].copy_from_slice(&len.to_le_bytes());
+            });
+            match loaded {
+                Err(VectorError::InvalidFile(reason)) => {
+                    assert!(reason.contains("don't fit"), "{}", reason)
+                }
+                other => panic!("expected a corrupt header error, got {:?}", other.err()),
+            }
+        }
+        let truncated = load_edited(&index, |bytes| bytes.truncate(counts + 16 + 4));
+        assert!(matches!(truncated, Err(VectorError::InvalidFile(_))));
+
+        let duplicated = load_edited(&index, |bytes| {
+            let at = bytes.len() - br#"["b","y"]]"#.len();
+            assert_eq!(&bytes[at..at + 4], br#"["b""#);
+            bytes[at + 2] = b'a';
+        });
+        match duplicated {
+            Err(VectorError::InvalidFile(reason)) => assert_eq!(reason, "duplicate id a"),
+            other => panic!("expected a duplicate id error, got {:?}", other.err()),
+        }
+        assert_eq!(load_edited(&index, |_| {}).unwrap().len(), 2);
+    }
+}
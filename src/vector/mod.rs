@@ -0,0 +1,78 @@
+//! Storing & searching embeddings. `Index` keeps them in memory, code looking up similar text
+//! should go through `VectorStore` so another store, such as a vector database, can be swapped
+//! in for it
+pub mod error;
+mod index;
+pub use error::{VectorError, VectorResult};
+pub use index::{Index, Metric, SearchHit};
+use std::{future::Future, pin::Pin};
+
+pub type VectorStoreFuture<'s, T> = Pin<Box<dyn Future<Output = VectorResult<T>> + Send + 's>>;
+
+/// An entry returned from a `VectorStore` search
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMatch<P> {
+    pub id: String,
+    pub score: f32,
+    pub payload: P,
+}
+
+/// Where embeddings are kept, each with an id & a payload. Searches return the `k` most
+/// similar entries whose payloads pass `filter`, most similar first. Stores that can't filter
+/// on their side may over fetch & filter what they receive
+pub trait VectorStore<P>: Send + Sync {
+    /// Adds an entry, replacing any with the same id
+    fn upsert<'s>(
+        &'s mut self,
+        id: &'s str,
+        vector: Vec<f32>,
+        payload: P,
+    ) -> VectorStoreFuture<'s, ()>;
+
+    fn delete<'s>(&'s mut self, id: &'s str) -> VectorStoreFuture<'s, Option<P>>;
+
+    fn nearest<'s>(
+        &'s self,
+        query: &'s [f32],
+        k: usize,
+        filter: &'s (dyn Fn(&P) -> bool + Send + Sync),
+    ) -> VectorStoreFuture<'s, Vec<StoredMatch<P>>>;
+}
+
+impl<P> VectorStore<P> for Index<P>
+where
+    P: Clone + Send + Sync,
+{
+    fn upsert<'s>(
+        &'s mut self,
+        id: &'s str,
+        vector: Vec<f32>,
+        payload: P,
+    ) -> VectorStoreFuture<'s, ()> {
+        let result = self.insert(id, vector, payload).map(|_| ());
+        Box::pin(std::future::ready(result))
+    }
+
+    fn delete<'s>(&'s mut self, id: &'s str) -> VectorStoreFuture<'s, Option<P>> {
+        let removed = self.remove(id);
+        Box::pin(std::future::ready(Ok(removed)))
+    }
+
+    fn nearest<'s>(
+        &'s self,
+        query: &'s [f32],
+        k: usize,
+        filter: &'s (dyn Fn(&P) -> bool + Send + Sync),
+    ) -> VectorStoreFuture<'s, Vec<StoredMatch<P>>> {
+        let matches = self.search_filtered(query, k, filter).map(|hits| {
+            hits.into_iter()
+                .map(|hit| StoredMatch {
+                    id: hit.id.to_owned(),
+                    score: hit.score,
+                    payload: hit.payload.clone(),
+                })
+                .collect()
+        });
+        Box::pin(std::future::ready(matches))
+    }
+}
@@ -0,0 +1,121 @@
+//! Sending stream statuses & other events between processes, such as from the process running
+//! agents to a separate UI. Each type's JSON is pinned by the golden files in `src/fixtures`,
+//! so changing it is a breaking change to the wire format & means bumping `WIRE_VERSION`
+use serde::{Deserialize, Serialize};
+
+/// Version of the JSON the wire types serialize to
+pub const WIRE_VERSION: u32 = 1;
+
+/// An event with the version of the wire format it was serialized with, so readers can tell
+/// events from a newer writer apart from malformed ones. Fields added within a version are
+/// ignored by older readers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WireEvent<E> {
+    pub version: u32,
+    pub event: E,
+}
+
+impl<E> WireEvent<E> {
+    /// Wraps `event` with the current `WIRE_VERSION`
+    pub fn new(event: E) -> Self {
+        Self {
+            version: WIRE_VERSION,
+            event,
+        }
+    }
+
+    /// Whether the event was written with a version this build can read
+    pub fn is_supported(&self) -> bool {
+        self.version <= WIRE_VERSION
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::streaming::{
+        CancelReason, CollectedCompletion, CompletionStreamStatus, ProviderStreamError,
+        StreamError, ToolCall, WireStreamError,
+    };
+    use serde::de::DeserializeOwned;
+    use std::fmt::Debug;
+
+    const GOLDEN_EVENTS: &str = include_str!("fixtures/wire-events.jsonl");
+
+    /// Checks `event` serializes to `golden` & back
+    fn pinned<E>(event: E, golden: &str)
+    where
+        E: Serialize + DeserializeOwned + PartialEq + Debug,
+    {
+        let event = WireEvent::new(event);
+        assert_eq!(serde_json::to_string(&event).unwrap(), golden);
+        assert_eq!(serde_json::from_str::<WireEvent<E>>(golden).unwrap(), event);
+    }
+
+    #[test]
+    fn events_match_golden_file() {
+        let mut golden = GOLDEN_EVENTS.lines();
+        let mut next = || golden.next().expect("golden file is missing events");
+        let call = ToolCall {
+            id: "call_1".to_owned(),
+            name: "send_keys".to_owned(),
+            arguments: "{\"keys\":\"ls\"}".to_owned(),
+        };
+
+        pinned(CompletionStreamStatus::Working("Build".to_owned()), next());
+        pinned(
+            CompletionStreamStatus::Finished("Build passed".to_owned()),
+            next(),
+        );
+        pinned(CompletionStreamStatus::ToolCalls(vec![call]), next());
+        pinned(
+            CompletionStreamStatus::Truncated("Build pa".to_owned()),
+            next(),
+        );
+        pinned(
+            CollectedCompletion::Finished("Build passed".to_owned()),
+            next(),
+        );
+        pinned(
+            CollectedCompletion::Partial {
+                content: "Build".to_owned(),
+                reason: CancelReason::Superseded,
+            },
+            next(),
+        );
+        let provider = StreamError::Provider(ProviderStreamError {
+            kind: Some("server_error".to_owned()),
+            code: None,
+            message: "The server had an error".to_owned(),
+        });
+        pinned(WireStreamError::from(&provider), next());
+        pinned(
+            WireStreamError::from(&StreamError::Cancelled(CancelReason::Deadline)),
+            next(),
+        );
+        #[cfg(feature = "tmux")]
+        pinned(
+            crate::tmux::MonitorInfo {
+                name: "build".to_owned(),
+                target: "dev:0.1".to_owned(),
+                running: true,
+                metrics: crate::tmux::MonitorMetrics {
+                    captures: 12,
+                    pushed: 4,
+                    skipped: 8,
+                    completions: 2,
+                    ..Default::default()
+                },
+            },
+            next(),
+        );
+    }
+
+    #[test]
+    fn newer_versions_told_apart() {
+        let newer = "{\"version\":2,\"event\":{\"type\":\"working\",\"content\":\"a\"},\"id\":7}";
+        let event: WireEvent<CompletionStreamStatus> = serde_json::from_str(newer).unwrap();
+        assert!(!event.is_supported());
+        assert!(WireEvent::new(CancelReason::User).is_supported());
+    }
+}
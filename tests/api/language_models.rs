@@ -1,5 +1,8 @@
 use crate::init_test;
-use espionox::{agents::Agent, language_models::completions::CompletionModel};
+use espionox::{
+    agents::{memory::Message, Agent, AgentError},
+    language_models::completions::{error::CompletionError, CompletionModel},
+};
 
 #[tokio::test]
 async fn failed_request_does_not_overflow_stack() {
@@ -12,3 +15,45 @@ async fn failed_request_does_not_overflow_stack() {
     println!("{:?}", res);
     assert!(res.is_err());
 }
+
+#[tokio::test]
+async fn completions_refused_once_spend_cap_is_hit() {
+    init_test();
+    let llm = CompletionModel::default_openai("invalid_key").with_spend_cap(0.0);
+    let mut a = Agent::new(None, llm);
+
+    let res = a.io_completion().await;
+    assert!(matches!(
+        res,
+        Err(AgentError::CompletionError(CompletionError::BudgetExceeded))
+    ));
+    let res = a.stream_completion().await;
+    assert!(matches!(
+        res,
+        Err(AgentError::CompletionError(CompletionError::BudgetExceeded))
+    ));
+}
+
+#[tokio::test]
+async fn regenerate_requires_last_message_to_be_assistant() {
+    init_test();
+    let llm = CompletionModel::default_openai("invalid_key").with_spend_cap(0.0);
+    let mut a = Agent::new(Some("system"), llm);
+    a.cache.push(Message::new_user("hello"));
+
+    let res = a.regenerate_io_completion().await;
+    assert!(matches!(res, Err(AgentError::LastMessageNotAssistant)));
+
+    a.cache.push(Message::new_assistant("hi"));
+    let res = a.regenerate_stream_completion().await;
+    assert!(matches!(
+        res,
+        Err(AgentError::CompletionError(CompletionError::BudgetExceeded))
+    ));
+    assert_eq!(
+        a.cache.len(),
+        3,
+        "failed regeneration should restore the message"
+    );
+    assert_eq!(a.cache.as_ref()[2], Message::new_assistant("hi"));
+}